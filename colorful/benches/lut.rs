@@ -0,0 +1,55 @@
+//! Compares nearest color lookups through a [ColorLut] against an exact palette search, for every distance method.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use colorful::{
+    lut::{ColorLut, DEFAULT_BITS},
+    palette::{DistanceMethod, Palette, CAM02, CIE76, CIE94, CIEDE2000, Oklab},
+};
+
+/// Colors spread over the whole RGB cube, stepping each channel by a different odd amount so they don't line up.
+fn colors() -> Vec<[u8; 3]> {
+    (0..4096u32)
+        .map(|i| [(i * 37) as u8, (i * 101 + 7) as u8, (i * 211 + 13) as u8])
+        .collect()
+}
+
+fn bench_method<T: DistanceMethod>(c: &mut Criterion) {
+    let palette = Palette::default();
+    let lut = ColorLut::build::<T>(&palette, DEFAULT_BITS);
+    let colors = colors();
+
+    let mut group = c.benchmark_group(T::NAME);
+    group.throughput(Throughput::Elements(colors.len() as u64));
+
+    group.bench_function("exact", |b| {
+        b.iter(|| {
+            colors
+                .iter()
+                .map(|color| T::closest(&palette, black_box(color)))
+                .sum::<usize>()
+        })
+    });
+
+    group.bench_function("lut", |b| {
+        b.iter(|| {
+            colors
+                .iter()
+                .map(|color| lut.index_of::<T>(&palette, black_box(color)))
+                .sum::<usize>()
+        })
+    });
+
+    group.finish();
+}
+
+fn lookups(c: &mut Criterion) {
+    bench_method::<CIE76>(c);
+    bench_method::<CIE94>(c);
+    bench_method::<CIEDE2000>(c);
+    bench_method::<CAM02>(c);
+    bench_method::<Oklab>(c);
+}
+
+criterion_group!(benches, lookups);
+criterion_main!(benches);
@@ -0,0 +1,263 @@
+//! Palettes fitted to an image, for terminals that let us redefine their colors.
+
+use image::RgbImage;
+
+use crate::{delta::cie76, palette::Palette};
+
+/// Palette entries that adaptive palettes leave alone: the base 16 colors are used by everything else in the terminal too.
+pub const RESERVED_COLORS: usize = 16;
+
+/// Upper bound on the pixels looked at when fitting, so that big frames don't make it slow.
+pub const MAX_SAMPLES: usize = 16384;
+const KMEANS_ROUNDS: usize = 3;
+
+/// Takes an evenly spread subset of an image's pixels.
+pub fn sample_pixels(image: &RgbImage, max_samples: usize) -> Vec<[u8; 3]> {
+    let total = (image.width() * image.height()) as usize;
+    let step = (total / max_samples.max(1)).max(1);
+
+    image.pixels().step_by(step).map(|p| p.0).collect()
+}
+
+#[inline(always)]
+fn distance(a: &[u8; 3], b: &[u8; 3]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (*a as i32 - *b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// Rounded mean of accumulated channel sums, the last element being the pixel count.
+fn mean(sum: &[u64; 4]) -> [u8; 3] {
+    let n = sum[3].max(1);
+    [
+        ((sum[0] + n / 2) / n) as u8,
+        ((sum[1] + n / 2) / n) as u8,
+        ((sum[2] + n / 2) / n) as u8,
+    ]
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 4];
+    for p in pixels {
+        for c in 0..3 {
+            sum[c] += p[c] as u64;
+        }
+    }
+
+    sum[3] = pixels.len() as u64;
+    mean(&sum)
+}
+
+/// The channel with the widest spread in a box, and that spread.
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    let mut min = [255u8; 3];
+    let mut max = [0u8; 3];
+    for p in pixels {
+        for c in 0..3 {
+            min[c] = min[c].min(p[c]);
+            max[c] = max[c].max(p[c]);
+        }
+    }
+
+    (0..3)
+        .map(|c| (c, max[c].saturating_sub(min[c])))
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+/// Median cut: keeps splitting the box with the widest color range at its median, until there are `count` boxes.
+pub fn median_cut(pixels: &[[u8; 3]], count: usize) -> Vec<[u8; 3]> {
+    if pixels.is_empty() || count == 0 {
+        return Vec::new();
+    }
+
+    let mut boxes: Vec<Vec<[u8; 3]>> = vec![pixels.to_vec()];
+
+    while boxes.len() < count {
+        // weight the range by the box's population, so busy areas of the image get more colors
+        let (idx, channel) = match boxes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let (channel, range) = widest_channel(b);
+                (i, channel, range as u64 * (b.len() as u64).max(1))
+            })
+            .filter(|(_, _, score)| *score > 0)
+            .max_by_key(|(_, _, score)| *score)
+        {
+            Some((idx, channel, _)) => (idx, channel),
+            None => break, // every box holds a single color
+        };
+
+        let mut b = boxes.swap_remove(idx);
+        b.sort_unstable_by_key(|p| p[channel]);
+        let upper = b.split_off(b.len() / 2);
+        boxes.push(b);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|b| average(b)).collect()
+}
+
+/// Moves every center to the mean of the pixels closest to it, a few times over.
+pub fn refine_kmeans(pixels: &[[u8; 3]], centers: &mut [[u8; 3]], rounds: usize) {
+    if centers.is_empty() {
+        return;
+    }
+
+    let mut sums = vec![[0u64; 4]; centers.len()];
+
+    for _ in 0..rounds {
+        sums.iter_mut().for_each(|v| *v = [0; 4]);
+
+        for p in pixels {
+            let closest = centers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, c)| distance(p, c))
+                .unwrap()
+                .0;
+
+            let sum = &mut sums[closest];
+            sum[0] += p[0] as u64;
+            sum[1] += p[1] as u64;
+            sum[2] += p[2] as u64;
+            sum[3] += 1;
+        }
+
+        for (center, sum) in centers.iter_mut().zip(&sums) {
+            // centers nobody picked stay where they are
+            if sum[3] > 0 {
+                *center = mean(sum);
+            }
+        }
+    }
+}
+
+/// Picks up to `count` colors that represent `pixels` well.
+pub fn fit_colors(pixels: &[[u8; 3]], count: usize) -> Vec<[u8; 3]> {
+    let mut centers = median_cut(pixels, count);
+    refine_kmeans(pixels, &mut centers, KMEANS_ROUNDS);
+    centers
+}
+
+/// Mean CIE76 ΔE between pixels and their closest palette colors, i.e. how well a palette fits them.
+pub fn mean_error(palette: &Palette, pixels: &[[u8; 3]]) -> f32 {
+    if pixels.is_empty() {
+        return 0.0;
+    }
+
+    let total: f32 = pixels
+        .iter()
+        .map(|p| cie76::closest_ansi(palette, p).1.sqrt())
+        .sum();
+
+    total / pixels.len() as f32
+}
+
+impl Palette {
+    /// Builds a palette whose entries from `first` onwards are fitted to an image, keeping the other entries of `base`.
+    /// If the image has fewer distinct colors than there are entries to fill, the remaining ones keep their `base` colors too.
+    pub fn adaptive(base: &Palette, image: &RgbImage, first: usize) -> Palette {
+        let first = first.min(256);
+        let pixels = sample_pixels(image, MAX_SAMPLES);
+        let fitted = fit_colors(&pixels, 256 - first);
+
+        base.with_overrides(
+            fitted
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| ((first + i) as u8, c)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const COLORS: [[u8; 3]; 4] = [[200, 30, 30], [20, 160, 40], [30, 40, 220], [240, 240, 90]];
+
+    /// Vertical stripes of [COLORS], equally wide, so that median cuts fall between them.
+    fn stripes(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, _| Rgb(COLORS[(x * 4 / width) as usize]))
+    }
+
+    fn sorted(mut colors: Vec<[u8; 3]>) -> Vec<[u8; 3]> {
+        colors.sort_unstable();
+        colors
+    }
+
+    #[test]
+    fn sampling_is_capped() {
+        let image = stripes(300, 200);
+        assert_eq!(sample_pixels(&image, 100_000).len(), 60_000);
+
+        let samples = sample_pixels(&image, 1000);
+        assert!((1000..2000).contains(&samples.len()), "{}", samples.len());
+        // spread over the whole image, so every stripe shows up
+        for color in COLORS {
+            assert!(samples.contains(&color));
+        }
+    }
+
+    #[test]
+    fn median_cut_finds_distinct_colors() {
+        let pixels = sample_pixels(&stripes(48, 10), MAX_SAMPLES);
+        assert_eq!(sorted(median_cut(&pixels, 4)), sorted(COLORS.to_vec()));
+        // boxes of a single color aren't split any further
+        assert_eq!(median_cut(&pixels, 10).len(), 4);
+
+        assert!(median_cut(&pixels, 0).is_empty());
+        assert!(median_cut(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn kmeans_moves_centers_to_their_pixels() {
+        let pixels = [[10, 10, 10], [20, 20, 20], [200, 200, 200], [220, 220, 220]];
+        let mut centers = [[0, 0, 0], [255, 255, 255], [128, 0, 255]];
+        refine_kmeans(&pixels, &mut centers, 2);
+
+        assert_eq!(centers[0], [15, 15, 15]);
+        assert_eq!(centers[1], [210, 210, 210]);
+        // nothing is closest to it, so it stays
+        assert_eq!(centers[2], [128, 0, 255]);
+    }
+
+    #[test]
+    fn fitted_colors_represent_the_image() {
+        let pixels = sample_pixels(&stripes(48, 10), MAX_SAMPLES);
+        let palette = Palette::default().with_overrides(
+            fit_colors(&pixels, 4)
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| ((RESERVED_COLORS + i) as u8, c)),
+        );
+
+        assert_eq!(mean_error(&palette, &pixels), 0.0);
+        assert!(mean_error(&Palette::default(), &pixels) > 1.0);
+        assert_eq!(mean_error(&palette, &[]), 0.0);
+    }
+
+    #[test]
+    fn adaptive_palettes_keep_the_reserved_colors() {
+        let base = Palette::default();
+        let palette = Palette::adaptive(&base, &stripes(48, 10), RESERVED_COLORS);
+
+        assert_eq!(
+            palette.colors()[..RESERVED_COLORS],
+            base.colors()[..RESERVED_COLORS]
+        );
+        assert_eq!(
+            sorted(palette.colors()[RESERVED_COLORS..RESERVED_COLORS + 4].to_vec()),
+            sorted(COLORS.to_vec())
+        );
+        // there's nothing to fit the rest to
+        assert_eq!(
+            palette.colors()[RESERVED_COLORS + 4..],
+            base.colors()[RESERVED_COLORS + 4..]
+        );
+    }
+}
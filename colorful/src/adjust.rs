@@ -0,0 +1,148 @@
+//! Image adjustments applied before dithering. Terminal palettes tend to render video darker and flatter than the source,
+//! which a little brightness, saturation and sharpening makes up for.
+
+use image::{imageops, RgbImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Adjustments to make to an image, in the order they are applied. The defaults leave it as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Adjustments {
+    pub denoise: f32,          // 0.0 - 1.0, how much of a 3x3 median to mix in
+    pub brightness: f32,       // -1.0 - 1.0, added to every channel
+    pub contrast: f32,         // multiplier around mid gray
+    pub gamma: f32,            // above 1.0 brightens shadows and midtones, below darkens them
+    pub saturation: f32,       // multiplier of the distance from gray
+    pub sharpen: f32,          // unsharp mask amount, 0.0 for none
+    pub sharpen_radius: f32,   // sigma of the unsharp mask's blur, in pixels
+    pub sharpen_threshold: u8, // differences from the blur below this are left alone, so flat areas don't get noisy
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Self {
+            denoise: 0.0,
+            brightness: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+            saturation: 1.0,
+            sharpen: 0.0,
+            sharpen_radius: 1.0,
+            sharpen_threshold: 2,
+        }
+    }
+}
+
+impl Adjustments {
+    /// Whether applying these would leave images as they are.
+    pub fn is_identity(&self) -> bool {
+        self.denoise <= 0.0 && !self.changes_tone() && self.saturation == 1.0 && self.sharpen <= 0.0
+    }
+
+    fn changes_tone(&self) -> bool {
+        self.brightness != 0.0 || self.contrast != 1.0 || self.gamma != 1.0
+    }
+
+    pub fn apply(&self, image: &mut RgbImage) {
+        if self.denoise > 0.0 {
+            denoise(image, self.denoise);
+        }
+
+        if self.changes_tone() {
+            let table = self.tone_table();
+            for v in image.iter_mut() {
+                *v = table[*v as usize];
+            }
+        }
+
+        if self.saturation != 1.0 {
+            saturate(image, self.saturation);
+        }
+
+        if self.sharpen > 0.0 {
+            unsharp_mask(
+                image,
+                self.sharpen_radius,
+                self.sharpen,
+                self.sharpen_threshold,
+            );
+        }
+    }
+
+    /// Brightness, contrast and gamma only depend on a channel's value, so they fold into one table.
+    fn tone_table(&self) -> [u8; 256] {
+        let mut table = [0; 256];
+        for (i, v) in table.iter_mut().enumerate() {
+            let c = i as f32 / 255.0;
+            let c = (c - 0.5) * self.contrast + 0.5 + self.brightness;
+            let c = c.clamp(0.0, 1.0).powf(1.0 / self.gamma.max(0.01));
+            *v = (c * 255.0).round() as u8;
+        }
+
+        table
+    }
+}
+
+/// Scales how far every pixel is from the gray of the same luma.
+pub fn saturate(image: &mut RgbImage, saturation: f32) {
+    image.par_chunks_exact_mut(3).for_each(|p| {
+        let luma = 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32;
+        for c in p.iter_mut() {
+            *c = (luma + (*c as f32 - luma) * saturation)
+                .round()
+                .clamp(0.0, 255.0) as u8;
+        }
+    });
+}
+
+/// Sharpens by adding back `amount` times the difference between the image and a blurred copy of it.
+pub fn unsharp_mask(image: &mut RgbImage, sigma: f32, amount: f32, threshold: u8) {
+    let blurred = imageops::blur(image, sigma);
+
+    image
+        .par_iter_mut()
+        .zip(blurred.as_raw().par_iter())
+        .for_each(|(v, b)| {
+            let diff = *v as f32 - *b as f32;
+            if diff.abs() >= threshold as f32 {
+                *v = (*v as f32 + diff * amount).round().clamp(0.0, 255.0) as u8;
+            }
+        });
+}
+
+/// Mixes in some of the median of every pixel's 3x3 neighbourhood, which removes grain and compression noise that
+/// dithering would otherwise spend colors on, while keeping edges.
+pub fn denoise(image: &mut RgbImage, strength: f32) {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let source = image.as_raw().clone();
+    let strength = strength.clamp(0.0, 1.0);
+
+    image
+        .par_chunks_exact_mut(width * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for x in 0..width {
+                for c in 0..3 {
+                    let mut window = [0u8; 9];
+                    let mut n = 0;
+                    for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                        for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                            window[n] = source[(ny * width + nx) * 3 + c];
+                            n += 1;
+                        }
+                    }
+
+                    let window = &mut window[..n];
+                    window.sort_unstable();
+                    let median = window[n / 2] as f32;
+                    let v = &mut row[x * 3 + c];
+                    *v = (*v as f32 + (median - *v as f32) * strength).round() as u8;
+                }
+            }
+        });
+}
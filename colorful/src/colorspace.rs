@@ -0,0 +1,399 @@
+//! Conversion of decoded Y'CbCr video to sRGB, with the right matrix and range, and tone mapping for HDR sources.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use image::RgbImage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::linear;
+
+/// The matrix Y'CbCr was derived from R'G'B' with, which also says which primaries the colors are in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Matrix {
+    Bt601,  // standard definition
+    Bt709,  // HD
+    Bt2020, // UHD and HDR, with wider primaries than sRGB
+}
+
+/// Limited ("TV") range puts black at 16 and white at 235, full ("PC") range uses 0 - 255.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Range {
+    Limited,
+    Full,
+}
+
+/// Transfer characteristics: how code values map to light.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transfer {
+    Sdr, // BT.709/BT.1886 gamma, like sRGB
+    Pq,  // SMPTE ST 2084, HDR10
+    Hlg, // ARIB STD-B67 hybrid log-gamma
+}
+
+/// How HDR light levels are brought down to what SDR can show.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToneMap {
+    Clip,     // cut off everything brighter than SDR white
+    Reinhard, // roll off highlights smoothly, keeping midtones
+    Hable,    // filmic curve, with more contrast than reinhard
+}
+
+macro_rules! named {
+    ($ty:ident, $what:literal, $($variant:ident => $name:literal),+) => {
+        impl Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $($ty::$variant => write!(f, $name),)+
+                }
+            }
+        }
+
+        impl FromStr for $ty {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<$ty, String> {
+                match s.to_ascii_lowercase().as_str() {
+                    $($name => Ok($ty::$variant),)+
+                    _ => Err(format!(
+                        concat!("unknown ", $what, " {:?} (expected ", $($name, " "),+, ")"),
+                        s
+                    )),
+                }
+            }
+        }
+    };
+}
+
+named!(Matrix, "matrix", Bt601 => "bt601", Bt709 => "bt709", Bt2020 => "bt2020");
+named!(Range, "range", Limited => "limited", Full => "full");
+named!(Transfer, "transfer", Sdr => "sdr", Pq => "pq", Hlg => "hlg");
+named!(ToneMap, "tone map", Clip => "clip", Reinhard => "reinhard", Hable => "hable");
+
+impl Matrix {
+    /// The red and blue luma coefficients.
+    fn coefficients(&self) -> (f32, f32) {
+        match self {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+            Matrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+
+    /// What players assume for video that doesn't say: HD sizes are BT.709, smaller ones BT.601, and HDR is BT.2020.
+    pub fn guess(width: usize, height: usize, transfer: Transfer) -> Matrix {
+        if transfer != Transfer::Sdr {
+            Matrix::Bt2020
+        } else if width >= 1280 || height > 576 {
+            Matrix::Bt709
+        } else {
+            Matrix::Bt601
+        }
+    }
+}
+
+/// Linear BT.2020 RGB to linear BT.709 (sRGB) RGB.
+const BT2020_TO_BT709: [[f32; 3]; 3] = [
+    [1.6605, -0.5876, -0.0728],
+    [-0.1246, 1.1329, -0.0083],
+    [-0.0182, -0.1006, 1.1187],
+];
+
+/// Gamma of SDR video displays (BT.1886), for when SDR colors have to be linearized.
+const SDR_GAMMA: f32 = 2.4;
+/// Light level SDR white is shown at when mixed with HDR, in nits (BT.2408).
+const SDR_WHITE: f32 = 203.0;
+/// Peak brightness HLG is displayed at, in nits.
+const HLG_PEAK: f32 = 1000.0;
+
+/// Steps of the tables transfer functions are looked up in, enough for 12 bit video.
+const TRANSFER_STEPS: usize = 4096;
+
+fn pq_to_nits(v: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+    let p = v.powf(1.0 / M2);
+    ((p - C1).max(0.0) / (C2 - C3 * p)).powf(1.0 / M1) * 10000.0
+}
+
+/// Scene light, from 0.0 to 1.0.
+fn hlg_to_scene(v: f32) -> f32 {
+    const A: f32 = 0.1788328;
+    const B: f32 = 0.2846689;
+    const C: f32 = 0.5599107;
+
+    if v <= 0.5 {
+        v * v / 3.0
+    } else {
+        (((v - C) / A).exp() + B) / 12.0
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+fn to_bt709(c: [f32; 3]) -> [f32; 3] {
+    let m = &BT2020_TO_BT709;
+    [
+        m[0][0] * c[0] + m[0][1] * c[1] + m[0][2] * c[2],
+        m[1][0] * c[0] + m[1][1] * c[1] + m[1][2] * c[2],
+        m[2][0] * c[0] + m[2][1] * c[1] + m[2][2] * c[2],
+    ]
+}
+
+/// Converts Y'CbCr pixels to sRGB.
+#[derive(Debug, Clone)]
+pub struct YuvConversion {
+    pub matrix: Matrix,
+    pub range: Range,
+    pub transfer: Transfer,
+    pub tone_map: ToneMap,
+    pub peak: f32,       // brightest HDR highlights to keep detail in, in nits
+    to_linear: Vec<f32>, // R'G'B' to linear light (relative to SDR white for HDR), unless the colors pass through as they are
+}
+
+impl YuvConversion {
+    pub fn new(
+        matrix: Matrix,
+        range: Range,
+        transfer: Transfer,
+        tone_map: ToneMap,
+        peak: f32,
+    ) -> YuvConversion {
+        let to_linear = if transfer == Transfer::Sdr && matrix != Matrix::Bt2020 {
+            Vec::new()
+        } else {
+            (0..TRANSFER_STEPS)
+                .map(|i| {
+                    let v = i as f32 / (TRANSFER_STEPS - 1) as f32;
+                    match transfer {
+                        Transfer::Sdr => v.powf(SDR_GAMMA),
+                        Transfer::Pq => pq_to_nits(v) / SDR_WHITE,
+                        Transfer::Hlg => hlg_to_scene(v),
+                    }
+                })
+                .collect()
+        };
+
+        YuvConversion {
+            matrix,
+            range,
+            transfer,
+            tone_map,
+            peak,
+            to_linear,
+        }
+    }
+
+    /// Converts one pixel, with its components scaled to 0.0 - 255.0 whatever the source's bit depth.
+    pub fn convert(&self, y: f32, cb: f32, cr: f32) -> [u8; 3] {
+        let (y, cb, cr) = match self.range {
+            Range::Limited => (
+                (y - 16.0) / 219.0,
+                (cb - 128.0) / 224.0,
+                (cr - 128.0) / 224.0,
+            ),
+            Range::Full => (y / 255.0, (cb - 128.0) / 255.0, (cr - 128.0) / 255.0),
+        };
+
+        let (kr, kb) = self.matrix.coefficients();
+        let kg = 1.0 - kr - kb;
+        let r = y + 2.0 * (1.0 - kr) * cr;
+        let b = y + 2.0 * (1.0 - kb) * cb;
+        let g = (y - kr * r - kb * b) / kg;
+
+        if self.to_linear.is_empty() {
+            return [r, g, b].map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8);
+        }
+
+        let lookup = |v: f32| {
+            self.to_linear[(v.clamp(0.0, 1.0) * (TRANSFER_STEPS - 1) as f32 + 0.5) as usize]
+        };
+        let mut rgb = [lookup(r), lookup(g), lookup(b)];
+        if self.matrix == Matrix::Bt2020 {
+            rgb = to_bt709(rgb);
+        }
+
+        match self.transfer {
+            Transfer::Sdr => {
+                rgb.map(|v| (v.clamp(0.0, 1.0).powf(1.0 / SDR_GAMMA) * 255.0).round() as u8)
+            }
+            Transfer::Pq => linear::from_linear_rgb(self.tone_map(rgb)),
+            Transfer::Hlg => {
+                // the OOTF: HLG is relative to the display's peak, with a system gamma of 1.2 at 1000 nits
+                let luma = 0.2627 * rgb[0] + 0.6780 * rgb[1] + 0.0593 * rgb[2];
+                let scale = HLG_PEAK / SDR_WHITE * luma.max(0.0).powf(0.2);
+                linear::from_linear_rgb(self.tone_map(rgb.map(|v| v * scale)))
+            }
+        }
+    }
+
+    /// Brings linear light relative to SDR white down to 0.0 - 1.0.
+    fn tone_map(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let rgb = rgb.map(|v| v.max(0.0));
+        let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+        if luma <= 0.0 {
+            return [0.0; 3];
+        }
+
+        let peak = (self.peak / SDR_WHITE).max(1.0);
+        let mapped = match self.tone_map {
+            ToneMap::Clip => return rgb.map(|v| v.min(1.0)),
+            ToneMap::Reinhard => luma * (1.0 + luma / (peak * peak)) / (1.0 + luma),
+            ToneMap::Hable => hable(luma) / hable(peak),
+        };
+
+        // scaling all channels alike keeps hues, where mapping each on its own would shift them
+        rgb.map(|v| (v * mapped / luma).min(1.0))
+    }
+
+    /// Converts planar 4:4:4 Y'CbCr, with every plane given as its data and the bytes between the starts of its rows.
+    /// Samples are bytes, or little endian 16 bit values if `sixteen_bit` is set.
+    pub fn convert_planes(
+        &self,
+        width: usize,
+        height: usize,
+        planes: [(&[u8], usize); 3],
+        sixteen_bit: bool,
+    ) -> RgbImage {
+        let sample = |(data, stride): (&[u8], usize), x: usize, y: usize| -> f32 {
+            if sixteen_bit {
+                let i = y * stride + x * 2;
+                u16::from_le_bytes([data[i], data[i + 1]]) as f32 / 257.0
+            } else {
+                data[y * stride + x] as f32
+            }
+        };
+
+        let mut image = RgbImage::new(width as u32, height as u32);
+        image
+            .par_chunks_exact_mut(width * 3)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                    pixel.copy_from_slice(&self.convert(
+                        sample(planes[0], x, y),
+                        sample(planes[1], x, y),
+                        sample(planes[2], x, y),
+                    ));
+                }
+            });
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sdr(matrix: Matrix, range: Range) -> YuvConversion {
+        YuvConversion::new(matrix, range, Transfer::Sdr, ToneMap::Reinhard, 1000.0)
+    }
+
+    fn assert_close(got: [u8; 3], expected: [u8; 3], tolerance: u8, what: &str) {
+        assert!(
+            got.iter()
+                .zip(expected)
+                .all(|(g, e)| g.abs_diff(e) <= tolerance),
+            "{what}: got {got:?}, expected {expected:?}"
+        );
+    }
+
+    #[test]
+    fn limited_range_stretches_to_full() {
+        let conversion = sdr(Matrix::Bt709, Range::Limited);
+        assert_eq!(conversion.convert(16.0, 128.0, 128.0), [0, 0, 0]);
+        assert_eq!(conversion.convert(235.0, 128.0, 128.0), [255, 255, 255]);
+        // whiter than white and blacker than black are clipped
+        assert_eq!(conversion.convert(4.0, 128.0, 128.0), [0, 0, 0]);
+        assert_eq!(conversion.convert(250.0, 128.0, 128.0), [255, 255, 255]);
+    }
+
+    #[test]
+    fn bt709_colour_bars() {
+        // 75% bars in 8 bit limited range BT.709 Y'CbCr, from ITU-R BT.709 / SMPTE RP 219
+        let bars = [
+            ("white", [180, 128, 128], [191, 191, 191]),
+            ("yellow", [168, 44, 136], [191, 191, 0]),
+            ("cyan", [145, 147, 44], [0, 191, 191]),
+            ("green", [133, 63, 52], [0, 191, 0]),
+            ("magenta", [63, 193, 204], [191, 0, 191]),
+            ("red", [51, 109, 212], [191, 0, 0]),
+            ("blue", [28, 212, 120], [0, 0, 191]),
+            ("black", [16, 128, 128], [0, 0, 0]),
+        ];
+
+        let conversion = sdr(Matrix::Bt709, Range::Limited);
+        for (name, [y, cb, cr], rgb) in bars {
+            assert_close(
+                conversion.convert(y as f32, cb as f32, cr as f32),
+                rgb,
+                2,
+                name,
+            );
+        }
+    }
+
+    #[test]
+    fn full_range_jpeg() {
+        // JFIF Y'CbCr: BT.601 over the full 0 - 255 range
+        let conversion = sdr(Matrix::Bt601, Range::Full);
+        assert_eq!(conversion.convert(0.0, 128.0, 128.0), [0, 0, 0]);
+        assert_eq!(conversion.convert(255.0, 128.0, 128.0), [255, 255, 255]);
+        assert_close(conversion.convert(76.0, 85.0, 255.0), [254, 0, 0], 1, "red");
+        assert_close(
+            conversion.convert(150.0, 44.0, 21.0),
+            [0, 255, 1],
+            1,
+            "green",
+        );
+        assert_close(
+            conversion.convert(29.0, 255.0, 107.0),
+            [0, 0, 254],
+            1,
+            "blue",
+        );
+    }
+
+    #[test]
+    fn tone_mapping_keeps_order() {
+        for transfer in [Transfer::Pq, Transfer::Hlg] {
+            for tone_map in [ToneMap::Clip, ToneMap::Reinhard, ToneMap::Hable] {
+                let conversion =
+                    YuvConversion::new(Matrix::Bt2020, Range::Limited, transfer, tone_map, 1000.0);
+                let what = format!("{transfer} {tone_map}");
+
+                let greys: Vec<u8> = (16 * 4..=235 * 4)
+                    .map(|y| conversion.convert(y as f32 / 4.0, 128.0, 128.0)[1])
+                    .collect();
+                assert_eq!(greys[0], 0, "{what}");
+                assert!(greys.windows(2).all(|w| w[0] <= w[1]), "{what}");
+                assert!(*greys.last().unwrap() >= 250, "{what}");
+
+                // and a brighter red stays at least as red
+                let reds: Vec<u8> = (16..=235)
+                    .map(|y| conversion.convert(y as f32, 110.0, 200.0)[0])
+                    .collect();
+                assert!(reds.windows(2).all(|w| w[0] <= w[1]), "{what}");
+            }
+        }
+    }
+}
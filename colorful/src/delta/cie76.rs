@@ -1,4 +1,4 @@
-use crate::palette::*;
+use crate::palette::Palette;
 use argmm::ArgMinMax;
 
 // D65 standard illuminant refs
@@ -10,7 +10,7 @@ use lab::Lab;
 /// Get closest ansi256 color using DeltaE distance. Accelerated with AVX instructions.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[target_feature(enable = "avx")]
-pub unsafe fn closest_ansi_avx(rgb: &[u8; 3]) -> (u8, f32) {
+pub unsafe fn closest_ansi_avx(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
@@ -23,7 +23,8 @@ pub unsafe fn closest_ansi_avx(rgb: &[u8; 3]) -> (u8, f32) {
     let mut res_array: [f32; 256] = [0.0; 256]; // full delta E
     let mut tmp: [f32; 8] = [0.0; 8]; // tmp array for storing intermediate values
 
-    LAB_PALETTE_FLATTENED
+    palette
+        .lab_flattened
         .chunks_exact(16)
         .enumerate()
         .for_each(|(i, step)| {
@@ -35,7 +36,7 @@ pub unsafe fn closest_ansi_avx(rgb: &[u8; 3]) -> (u8, f32) {
             let mut b = _mm256_sub_ps(lab_mm, pal_b); // subtract (lhs.l - rhs.l), (lhs.a - rhs.a), (lhs.b - rhs.b)
             b = _mm256_mul_ps(b, b); // raise to power of two
 
-            _mm256_store_ps(tmp.as_mut_ptr() as *mut f32, _mm256_hadd_ps(a, b)); // add up (l + a) for every value and then store
+            _mm256_storeu_ps(tmp.as_mut_ptr() as *mut f32, _mm256_hadd_ps(a, b)); // add up (l + a) for every value and then store
             let start = i * 4;
             // add up (l + a) + b
             *res_array.get_unchecked_mut(start) = tmp.get_unchecked(0) + tmp.get_unchecked(1);
@@ -51,7 +52,7 @@ pub unsafe fn closest_ansi_avx(rgb: &[u8; 3]) -> (u8, f32) {
 /// Get closest ansi256 color using DeltaE distance. Accelerated with SSE instructions.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[target_feature(enable = "sse")]
-pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
+pub unsafe fn closest_ansi_sse(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
@@ -64,31 +65,31 @@ pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
     let mut results: [f32; 256] = [0.0; 256];
     let mut res_array: [f32; 4] = [0.0; 4];
 
-    LAB_PALETTE_FLATTENED
+    palette
+        .lab_flattened
         .chunks_exact(4)
         .enumerate()
         .for_each(|(i, step)| {
             let pal_mm = _mm_loadu_ps(step.as_ptr() as *const f32);
             let mut res = _mm_sub_ps(lab_mm, pal_mm);
             res = _mm_mul_ps(res, res);
-            _mm_store_ps(res_array.as_mut_ptr() as *mut f32, res); // store back
+            _mm_storeu_ps(res_array.as_mut_ptr() as *mut f32, res); // store back
 
             *results.get_unchecked_mut(i) = res_array.get_unchecked(0) // add up left delta E
                 + res_array.get_unchecked(1)
                 + res_array.get_unchecked(2);
         });
 
-    let v = res_array.argmin().unwrap();
-    (v as u8, *res_array.get_unchecked(v))
+    let v = results.argmin().unwrap();
+    (v as u8, *results.get_unchecked(v))
 }
 
 /// Get closest ansi256 color using DeltaE distance. No acceleration.
-pub fn closest_ansi_scalar(rgb: &[u8; 3]) -> (u8, f32) {
+pub fn closest_ansi_scalar(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     let lab = Lab::from_rgb(rgb);
     let mut results: [f32; 256] = [0.0; 256];
-    for i in 0..256 {
-        let (p_l, p_a, p_b) = LAB_PALETTE[i];
-        results[i] = (lab.l - p_l).powi(2) + (lab.a - p_a).powi(2) + (lab.b - p_b).powi(2);
+    for (result, &(p_l, p_a, p_b)) in results.iter_mut().zip(palette.lab.iter()) {
+        *result = (lab.l - p_l).powi(2) + (lab.a - p_a).powi(2) + (lab.b - p_b).powi(2);
     }
 
     let v = results.argmin().unwrap();
@@ -96,17 +97,17 @@ pub fn closest_ansi_scalar(rgb: &[u8; 3]) -> (u8, f32) {
 }
 
 /// Get closest ansi256 color using DeltaE distance. Accelerated with SIMD intrinsics if available.
-pub fn closest_ansi(rgb: &[u8; 3]) -> (u8, f32) {
+pub fn closest_ansi(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     {
         if is_x86_feature_detected!("avx") {
-            return unsafe { closest_ansi_avx(rgb) };
+            return unsafe { closest_ansi_avx(palette, rgb) };
         } else if is_x86_feature_detected!("sse") {
-            return unsafe { closest_ansi_sse(rgb) };
+            return unsafe { closest_ansi_sse(palette, rgb) };
         }
     }
 
-    closest_ansi_scalar(rgb)
+    closest_ansi_scalar(palette, rgb)
 }
 
 pub fn rgb_to_xyz(r: f64, g: f64, b: f64) -> (f64, f64, f64) {
@@ -178,3 +179,21 @@ pub fn xyz_to_lab(x: f64, y: f64, z: f64) -> (f64, f64, f64) {
         200.0 * (y - z),    // b
     )
 }
+
+/// Get the closest of a few candidate colors using DeltaE distance, for when the rest of the palette is known to be further away.
+pub fn closest_of(palette: &Palette, rgb: &[u8; 3], candidates: &[u8]) -> (u8, f32) {
+    let lab = Lab::from_rgb(rgb);
+    candidates
+        .iter()
+        .map(|&i| {
+            let (p_l, p_a, p_b) = palette.lab[i as usize];
+            (
+                i,
+                (lab.l - p_l).powi(2) + (lab.a - p_a).powi(2) + (lab.b - p_b).powi(2),
+            )
+        })
+        .fold(
+            (0, f32::INFINITY),
+            |best, v| if v.1 < best.1 { v } else { best },
+        )
+}
@@ -1,44 +1,39 @@
-use crate::palette::*;
+use crate::palette::Palette;
 use argmm::ArgMinMax;
 use lab::Lab;
-use lazy_static::lazy_static;
 
 static K_L: f32 = 1.0;
 static K_ONE: f32 = 0.045;
 static K_TWO: f32 = 0.015;
 
-lazy_static! {
-    // ref_l, ref_a, ref_b, ref_c,
-    // s_l, s_c, s_h,0.0
-    static ref CIE94_DATA: [f32; 2048] = {
-        let mut pal = [0.0f32; 2048];
-        for i in 0..256 {
-            let offset = i * 8;
-
-            let (ref_l, ref_a, ref_b) = LAB_PALETTE[i];
-            let ref_c = (ref_a.powi(2) + ref_b.powi(2)).sqrt();
-            let s_c = 1.0 + K_ONE * ref_c;
-            let s_h = 1.0 + K_TWO * ref_c;
-
-            pal[offset] = ref_l;
-            pal[offset + 1] = ref_a;
-            pal[offset + 2] = ref_b;
-            pal[offset + 3] = ref_c;
-            pal[offset + 4] = 1.0;
-            pal[offset + 5] = s_c;
-            pal[offset + 6] = s_h;
-        }
+/// Precomputes the per-color CIE94 terms for a LAB palette, 8 values per color:
+/// ref_l, ref_a, ref_b, ref_c,
+/// s_l, s_c, s_h, 0.0
+pub(crate) fn palette_data(lab: &[(f32, f32, f32)]) -> Vec<f32> {
+    let mut pal = vec![0.0f32; lab.len() * 8];
+    for (i, &(ref_l, ref_a, ref_b)) in lab.iter().enumerate() {
+        let offset = i * 8;
+
+        let ref_c = (ref_a.powi(2) + ref_b.powi(2)).sqrt();
+        let s_c = 1.0 + K_ONE * ref_c;
+        let s_h = 1.0 + K_TWO * ref_c;
+
+        pal[offset] = ref_l;
+        pal[offset + 1] = ref_a;
+        pal[offset + 2] = ref_b;
+        pal[offset + 3] = ref_c;
+        pal[offset + 4] = 1.0;
+        pal[offset + 5] = s_c;
+        pal[offset + 6] = s_h;
+    }
 
-        pal
-    };
+    pal
 }
 
-pub fn closest_ansi_scalar(rgb: &[u8; 3]) -> (u8, f32) {
+pub fn closest_ansi_scalar(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     let pixel = Lab::from_rgb(rgb);
     let mut results: [f32; 256] = [0.0; 256];
-    for i in 0..256 {
-        let (ref_l, ref_a, ref_b) = LAB_PALETTE[i];
-
+    for (result, &(ref_l, ref_a, ref_b)) in results.iter_mut().zip(palette.lab.iter()) {
         let delta_l = ref_l - pixel.l;
         let ref_c = (ref_a.powi(2) + ref_b.powi(2)).sqrt();
         let pixel_c = (pixel.a.powi(2) + pixel.b.powi(2)).sqrt();
@@ -54,7 +49,7 @@ pub fn closest_ansi_scalar(rgb: &[u8; 3]) -> (u8, f32) {
             + (delta_c / (K_L * s_c)).powi(2)
             + (delta_h / (K_L * s_h)).powi(2);
 
-        results[i] = delta_e;
+        *result = delta_e;
     }
 
     let v = results.argmin().unwrap();
@@ -63,7 +58,7 @@ pub fn closest_ansi_scalar(rgb: &[u8; 3]) -> (u8, f32) {
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[target_feature(enable = "sse")]
-pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
+pub unsafe fn closest_ansi_sse(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
@@ -78,20 +73,21 @@ pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
     let mut res_array: [f32; 256] = [0.0; 256]; // full delta E
     let mut tmp: [f32; 4] = [0.0; 4]; // tmp array for storing intermediate values
 
-    CIE94_DATA
+    palette
+        .cie94
         .chunks_exact(8)
         .enumerate()
         .for_each(|(i, step)| {
             let ref_lab = _mm_loadu_ps(step.as_ptr()); // ref_l, ref_a, ref_b, ref_c
             let deltas = _mm_sub_ps(ref_lab, pixel_mm); // deltaL, deltaA, deltaB, deltaC
 
-            _mm_store_ps(tmp.as_mut_ptr(), deltas);
+            _mm_storeu_ps(tmp.as_mut_ptr(), deltas);
 
             let delta_l = *tmp.get_unchecked(0);
             let delta_c = *tmp.get_unchecked(3);
 
             let delta_h_mm = _mm_mul_ps(deltas, deltas);
-            _mm_store_ps(tmp.as_mut_ptr(), delta_h_mm);
+            _mm_storeu_ps(tmp.as_mut_ptr(), delta_h_mm);
             let delta_h = tmp.get_unchecked(1) + tmp.get_unchecked(2) - tmp.get_unchecked(3);
 
             let delta_e_weights = _mm_loadu_ps(step.as_ptr().add(4)); // s_l, s_c, s_h, 0.0
@@ -100,7 +96,7 @@ pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
             delta_e_mm = _mm_div_ps(delta_e_mm, delta_e_weights);
             delta_e_mm = _mm_mul_ps(delta_e_mm, delta_e_mm);
 
-            _mm_store_ps(tmp.as_mut_ptr(), delta_e_mm);
+            _mm_storeu_ps(tmp.as_mut_ptr(), delta_e_mm);
 
             *res_array.get_unchecked_mut(i) =
                 tmp.get_unchecked(0) + tmp.get_unchecked(1) + tmp.get_unchecked(2);
@@ -111,13 +107,41 @@ pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
 }
 
 /// Get closest ansi256 color using CIE94 DeltaE distance. Accelerated with SIMD intrinsics if available.
-pub fn closest_ansi(rgb: &[u8; 3]) -> (u8, f32) {
+pub fn closest_ansi(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     {
         if is_x86_feature_detected!("sse") {
-            return unsafe { closest_ansi_sse(rgb) };
+            return unsafe { closest_ansi_sse(palette, rgb) };
         }
     }
 
-    closest_ansi_scalar(rgb)
+    closest_ansi_scalar(palette, rgb)
+}
+
+/// Get the closest of a few candidate colors using CIE94 DeltaE distance, for when the rest of the palette is known to be further away.
+pub fn closest_of(palette: &Palette, rgb: &[u8; 3], candidates: &[u8]) -> (u8, f32) {
+    let pixel = Lab::from_rgb(rgb);
+    let pixel_c = (pixel.a.powi(2) + pixel.b.powi(2)).sqrt();
+
+    candidates
+        .iter()
+        .map(|&i| {
+            let step = &palette.cie94[i as usize * 8..i as usize * 8 + 8];
+            let (delta_l, delta_a, delta_b, delta_c) = (
+                step[0] - pixel.l,
+                step[1] - pixel.a,
+                step[2] - pixel.b,
+                step[3] - pixel_c,
+            );
+            let delta_h = delta_a.powi(2) + delta_b.powi(2) - delta_c.powi(2);
+
+            let delta_e = (delta_l / step[4]).powi(2)
+                + (delta_c / step[5]).powi(2)
+                + (delta_h / step[6]).powi(2);
+            (i, delta_e)
+        })
+        .fold(
+            (0, f32::INFINITY),
+            |best, v| if v.1 < best.1 { v } else { best },
+        )
 }
@@ -0,0 +1,422 @@
+//! CIEDE2000, following Sharma, Wu & Dalal, "The CIEDE2000 Color-Difference Formula: Implementation Notes,
+//! Supplementary Test Data, and Mathematical Observations" (2005).
+
+use crate::palette::Palette;
+use argmm::ArgMinMax;
+use lab::Lab;
+
+const POW25_7: f32 = 6103515625.0; // 25^7
+
+#[inline(always)]
+fn hue_angle(b: f32, a_prime: f32) -> f32 {
+    if a_prime == 0.0 && b == 0.0 {
+        return 0.0;
+    }
+
+    let h = b.atan2(a_prime).to_degrees();
+    if h < 0.0 {
+        h + 360.0
+    } else {
+        h
+    }
+}
+
+/// The hue dependent terms of CIEDE2000, from the hue angles of two colors and the product of their chroma:
+/// sin(Δh′ / 2), T, and sin(2Δθ). They take branches and trigonometry, so the AVX search works them out per color.
+#[inline(always)]
+fn hue_terms(h1_prime: f32, h2_prime: f32, chroma_product: f32) -> (f32, f32, f32) {
+    let h_diff = h2_prime - h1_prime;
+    let delta_h_angle = if chroma_product == 0.0 {
+        0.0
+    } else if h_diff.abs() <= 180.0 {
+        h_diff
+    } else if h_diff > 180.0 {
+        h_diff - 360.0
+    } else {
+        h_diff + 360.0
+    };
+
+    let h_sum = h1_prime + h2_prime;
+    let h_bar = if chroma_product == 0.0 {
+        h_sum
+    } else if h_diff.abs() <= 180.0 {
+        h_sum * 0.5
+    } else if h_sum < 360.0 {
+        (h_sum + 360.0) * 0.5
+    } else {
+        (h_sum - 360.0) * 0.5
+    };
+
+    let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+    let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+
+    (
+        (delta_h_angle.to_radians() * 0.5).sin(),
+        t,
+        (2.0 * delta_theta).to_radians().sin(),
+    )
+}
+
+/// Squared CIEDE2000 difference between two LAB colors, given along with their chroma (l, a, b, c).
+#[inline(always)]
+fn delta_e_squared(
+    (l1, a1, b1, c1): (f32, f32, f32, f32),
+    (l2, a2, b2, c2): (f32, f32, f32, f32),
+) -> f32 {
+    let c_bar = (c1 + c2) * 0.5;
+    let c_bar7 = c_bar.powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + POW25_7)).sqrt());
+
+    let a1_prime = a1 * (1.0 + g);
+    let a2_prime = a2 * (1.0 + g);
+    let c1_prime = (a1_prime * a1_prime + b1 * b1).sqrt();
+    let c2_prime = (a2_prime * a2_prime + b2 * b2).sqrt();
+    let chroma_product = c1_prime * c2_prime;
+    let (half_delta_h_sin, t, double_theta_sin) = hue_terms(
+        hue_angle(b1, a1_prime),
+        hue_angle(b2, a2_prime),
+        chroma_product,
+    );
+
+    let delta_l = l2 - l1;
+    let delta_c = c2_prime - c1_prime;
+    let delta_h = 2.0 * chroma_product.sqrt() * half_delta_h_sin;
+
+    let l_bar = (l1 + l2) * 0.5;
+    let c_bar_prime = (c1_prime + c2_prime) * 0.5;
+    let c_bar_prime7 = c_bar_prime.powi(7);
+    let r_c = 2.0 * (c_bar_prime7 / (c_bar_prime7 + POW25_7)).sqrt();
+    let l_offset = (l_bar - 50.0).powi(2);
+    let s_l = 1.0 + 0.015 * l_offset / (20.0 + l_offset).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar_prime;
+    let s_h = 1.0 + 0.015 * c_bar_prime * t;
+    let r_t = -double_theta_sin * r_c;
+
+    let l_term = delta_l / s_l;
+    let c_term = delta_c / s_c;
+    let h_term = delta_h / s_h;
+
+    l_term * l_term + c_term * c_term + h_term * h_term + r_t * c_term * h_term
+}
+
+#[inline(always)]
+fn with_chroma(lab: (f32, f32, f32)) -> (f32, f32, f32, f32) {
+    (lab.0, lab.1, lab.2, (lab.1 * lab.1 + lab.2 * lab.2).sqrt())
+}
+
+/// CIEDE2000 difference between two LAB colors.
+pub fn delta_e(lab1: (f32, f32, f32), lab2: (f32, f32, f32)) -> f32 {
+    delta_e_squared(with_chroma(lab1), with_chroma(lab2))
+        .max(0.0)
+        .sqrt()
+}
+
+#[inline(always)]
+fn distances(palette: &Palette, rgb: &[u8; 3]) -> [f32; 256] {
+    let pixel = Lab::from_rgb(rgb);
+    let pixel = with_chroma((pixel.l, pixel.a, pixel.b));
+
+    let mut results: [f32; 256] = [0.0; 256];
+    for (result, lab) in results.iter_mut().zip(&palette.lab) {
+        *result = delta_e_squared(pixel, with_chroma(*lab));
+    }
+
+    results
+}
+
+/// Precomputes a LAB palette for the AVX search, in blocks of 8 colors: their l, a, b and chroma values, 8 of each.
+pub(crate) fn palette_data(lab: &[(f32, f32, f32)]) -> Vec<f32> {
+    let mut pal = vec![0.0f32; lab.len() * 4];
+    for (i, &lab) in lab.iter().enumerate() {
+        let offset = (i / 8) * 32 + i % 8;
+        let (l, a, b, c) = with_chroma(lab);
+
+        pal[offset] = l;
+        pal[offset + 8] = a;
+        pal[offset + 16] = b;
+        pal[offset + 24] = c;
+    }
+
+    pal
+}
+
+/// Get closest ansi256 color using CIEDE2000 distance. Accelerated with AVX instructions, 8 palette colors at a time;
+/// only the hue angles and [hue_terms] are worked out per color.
+///
+/// # Safety
+/// The CPU must support AVX.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+pub unsafe fn closest_ansi_avx(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    let pixel = Lab::from_rgb(rgb);
+    let (l1, a1, b1, c1) = with_chroma((pixel.l, pixel.a, pixel.b));
+    let l1_mm = _mm256_set1_ps(l1);
+    let a1_mm = _mm256_set1_ps(a1);
+    let b1_mm = _mm256_set1_ps(b1);
+    let c1_mm = _mm256_set1_ps(c1);
+
+    let half = _mm256_set1_ps(0.5);
+    let one = _mm256_set1_ps(1.0);
+    let two = _mm256_set1_ps(2.0);
+    let pow25_7 = _mm256_set1_ps(POW25_7);
+
+    // x^7 / (x^7 + 25^7), the chroma weight used by both G and R_C
+    let chroma_weight = |x: __m256| {
+        let x2 = _mm256_mul_ps(x, x);
+        let x7 = _mm256_mul_ps(_mm256_mul_ps(x2, x2), _mm256_mul_ps(x2, x));
+        _mm256_sqrt_ps(_mm256_div_ps(x7, _mm256_add_ps(x7, pow25_7)))
+    };
+
+    let mut results: [f32; 256] = [0.0; 256];
+    let mut a1_prime: [f32; 8] = [0.0; 8];
+    let mut a2_prime: [f32; 8] = [0.0; 8];
+    let mut b2: [f32; 8] = [0.0; 8];
+    let mut chroma_product: [f32; 8] = [0.0; 8];
+    let mut half_delta_h_sin: [f32; 8] = [0.0; 8];
+    let mut t: [f32; 8] = [0.0; 8];
+    let mut double_theta_sin: [f32; 8] = [0.0; 8];
+
+    palette
+        .ciede2000
+        .chunks_exact(32)
+        .enumerate()
+        .for_each(|(i, step)| {
+            let l2_mm = _mm256_loadu_ps(step.as_ptr());
+            let a2_mm = _mm256_loadu_ps(step.as_ptr().add(8));
+            let b2_mm = _mm256_loadu_ps(step.as_ptr().add(16));
+            let c2_mm = _mm256_loadu_ps(step.as_ptr().add(24));
+
+            let c_bar = _mm256_mul_ps(_mm256_add_ps(c1_mm, c2_mm), half);
+            let g = _mm256_mul_ps(half, _mm256_sub_ps(one, chroma_weight(c_bar)));
+            let a1_prime_mm = _mm256_mul_ps(a1_mm, _mm256_add_ps(one, g));
+            let a2_prime_mm = _mm256_mul_ps(a2_mm, _mm256_add_ps(one, g));
+            let c1_prime = _mm256_sqrt_ps(_mm256_add_ps(
+                _mm256_mul_ps(a1_prime_mm, a1_prime_mm),
+                _mm256_mul_ps(b1_mm, b1_mm),
+            ));
+            let c2_prime = _mm256_sqrt_ps(_mm256_add_ps(
+                _mm256_mul_ps(a2_prime_mm, a2_prime_mm),
+                _mm256_mul_ps(b2_mm, b2_mm),
+            ));
+            let chroma_product_mm = _mm256_mul_ps(c1_prime, c2_prime);
+
+            _mm256_storeu_ps(a1_prime.as_mut_ptr(), a1_prime_mm);
+            _mm256_storeu_ps(a2_prime.as_mut_ptr(), a2_prime_mm);
+            _mm256_storeu_ps(b2.as_mut_ptr(), b2_mm);
+            _mm256_storeu_ps(chroma_product.as_mut_ptr(), chroma_product_mm);
+            for j in 0..8 {
+                (half_delta_h_sin[j], t[j], double_theta_sin[j]) = hue_terms(
+                    hue_angle(b1, a1_prime[j]),
+                    hue_angle(b2[j], a2_prime[j]),
+                    chroma_product[j],
+                );
+            }
+
+            let delta_l = _mm256_sub_ps(l2_mm, l1_mm);
+            let delta_c = _mm256_sub_ps(c2_prime, c1_prime);
+            let delta_h = _mm256_mul_ps(
+                _mm256_mul_ps(two, _mm256_sqrt_ps(chroma_product_mm)),
+                _mm256_loadu_ps(half_delta_h_sin.as_ptr()),
+            );
+
+            let l_offset = _mm256_sub_ps(
+                _mm256_mul_ps(_mm256_add_ps(l1_mm, l2_mm), half),
+                _mm256_set1_ps(50.0),
+            );
+            let l_offset = _mm256_mul_ps(l_offset, l_offset);
+            let s_l = _mm256_add_ps(
+                one,
+                _mm256_div_ps(
+                    _mm256_mul_ps(_mm256_set1_ps(0.015), l_offset),
+                    _mm256_sqrt_ps(_mm256_add_ps(_mm256_set1_ps(20.0), l_offset)),
+                ),
+            );
+            let c_bar_prime = _mm256_mul_ps(_mm256_add_ps(c1_prime, c2_prime), half);
+            let s_c = _mm256_add_ps(one, _mm256_mul_ps(_mm256_set1_ps(0.045), c_bar_prime));
+            let s_h = _mm256_add_ps(
+                one,
+                _mm256_mul_ps(
+                    _mm256_mul_ps(_mm256_set1_ps(0.015), c_bar_prime),
+                    _mm256_loadu_ps(t.as_ptr()),
+                ),
+            );
+            let r_c = _mm256_mul_ps(two, chroma_weight(c_bar_prime));
+            let r_t = _mm256_mul_ps(
+                _mm256_sub_ps(
+                    _mm256_setzero_ps(),
+                    _mm256_loadu_ps(double_theta_sin.as_ptr()),
+                ),
+                r_c,
+            );
+
+            let l_term = _mm256_div_ps(delta_l, s_l);
+            let c_term = _mm256_div_ps(delta_c, s_c);
+            let h_term = _mm256_div_ps(delta_h, s_h);
+            let delta_e = _mm256_add_ps(
+                _mm256_add_ps(_mm256_mul_ps(l_term, l_term), _mm256_mul_ps(c_term, c_term)),
+                _mm256_add_ps(
+                    _mm256_mul_ps(h_term, h_term),
+                    _mm256_mul_ps(_mm256_mul_ps(r_t, c_term), h_term),
+                ),
+            );
+
+            _mm256_storeu_ps(results.as_mut_ptr().add(i * 8), delta_e);
+        });
+
+    let v = results.argmin().unwrap();
+    (v as u8, *results.get_unchecked(v))
+}
+
+/// Get closest ansi256 color using CIEDE2000 distance. No acceleration.
+pub fn closest_ansi_scalar(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
+    let results = distances(palette, rgb);
+    let v = results.argmin().unwrap();
+    (v as u8, results[v])
+}
+
+/// Get closest ansi256 color using CIEDE2000 distance. Accelerated with AVX if available.
+pub fn closest_ansi(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            return unsafe { closest_ansi_avx(palette, rgb) };
+        }
+    }
+
+    closest_ansi_scalar(palette, rgb)
+}
+
+/// Get the closest of a few candidate colors using CIEDE2000 distance, for when the rest of the palette is known to be further away.
+pub fn closest_of(palette: &Palette, rgb: &[u8; 3], candidates: &[u8]) -> (u8, f32) {
+    let pixel = Lab::from_rgb(rgb);
+    let pixel = with_chroma((pixel.l, pixel.a, pixel.b));
+
+    candidates
+        .iter()
+        .map(|&i| {
+            (
+                i,
+                delta_e_squared(pixel, with_chroma(palette.lab[i as usize])),
+            )
+        })
+        .fold(
+            (0, f32::INFINITY),
+            |best, v| if v.1 < best.1 { v } else { best },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two LAB colors and their difference.
+    type Pair = ((f32, f32, f32), (f32, f32, f32), f32);
+
+    /// Sharma, Wu & Dalal's test data, to 4 decimals.
+    const SHARMA: [Pair; 34] = [
+        ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+        ((50.0, 3.1571, -77.2803), (50.0, 0.0, -82.7485), 2.8615),
+        ((50.0, 2.8361, -74.0200), (50.0, 0.0, -82.7485), 3.4412),
+        ((50.0, -1.3802, -84.2814), (50.0, 0.0, -82.7485), 1.0000),
+        ((50.0, -1.1848, -84.8006), (50.0, 0.0, -82.7485), 1.0000),
+        ((50.0, -0.9009, -85.5211), (50.0, 0.0, -82.7485), 1.0000),
+        ((50.0, 0.0, 0.0), (50.0, -1.0, 2.0), 2.3669),
+        ((50.0, -1.0, 2.0), (50.0, 0.0, 0.0), 2.3669),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0009), 7.1792),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0010), 7.1792),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0011), 7.2195),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0012), 7.2195),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0009, -2.4900), 4.8045),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0010, -2.4900), 4.8045),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0011, -2.4900), 4.7461),
+        ((50.0, 2.5, 0.0), (50.0, 0.0, -2.5), 4.3065),
+        ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+        ((50.0, 2.5, 0.0), (61.0, -5.0, 29.0), 22.8977),
+        ((50.0, 2.5, 0.0), (56.0, -27.0, -3.0), 31.9030),
+        ((50.0, 2.5, 0.0), (58.0, 24.0, 15.0), 19.4535),
+        ((50.0, 2.5, 0.0), (50.0, 3.1736, 0.5854), 1.0000),
+        ((50.0, 2.5, 0.0), (50.0, 3.2972, 0.0), 1.0000),
+        ((50.0, 2.5, 0.0), (50.0, 1.8634, 0.5757), 1.0000),
+        ((50.0, 2.5, 0.0), (50.0, 3.2592, 0.3350), 1.0000),
+        (
+            (60.2574, -34.0099, 36.2677),
+            (60.4626, -34.1751, 39.4387),
+            1.2644,
+        ),
+        (
+            (63.0109, -31.0961, -5.8663),
+            (62.8187, -29.7946, -4.0864),
+            1.2630,
+        ),
+        (
+            (61.2901, 3.7196, -5.3901),
+            (61.4292, 2.2480, -4.9620),
+            1.8731,
+        ),
+        (
+            (35.0831, -44.1164, 3.7933),
+            (35.0232, -40.0716, 1.5901),
+            1.8645,
+        ),
+        (
+            (22.7233, 20.0904, -46.6940),
+            (23.0331, 14.9730, -42.5619),
+            2.0373,
+        ),
+        (
+            (36.4612, 47.8580, 18.3852),
+            (36.2715, 50.5065, 21.2231),
+            1.4146,
+        ),
+        (
+            (90.8027, -2.0831, 1.4410),
+            (91.1528, -1.6435, 0.0447),
+            1.4441,
+        ),
+        (
+            (90.9257, -0.5406, -0.9208),
+            (88.6381, -0.8985, -0.7239),
+            1.5381,
+        ),
+        (
+            (6.7747, -0.2908, -2.4247),
+            (5.8714, -0.0985, -2.2286),
+            0.6377,
+        ),
+        (
+            (2.0776, 0.0795, -1.1350),
+            (0.9033, -0.0636, -0.5514),
+            0.9082,
+        ),
+    ];
+
+    #[test]
+    fn matches_sharma_test_data() {
+        for (i, (lab1, lab2, expected)) in SHARMA.iter().enumerate() {
+            for found in [delta_e(*lab1, *lab2), delta_e(*lab2, *lab1)] {
+                assert!(
+                    (found - expected).abs() < 1e-4,
+                    "pair {}: expected {}, got {}",
+                    i + 1,
+                    expected,
+                    found
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn identical_colors_have_no_difference() {
+        for (lab, _, _) in SHARMA {
+            assert_eq!(delta_e(lab, lab), 0.0);
+        }
+    }
+}
@@ -1,11 +1,11 @@
-use crate::palette::*;
+use crate::palette::Palette;
 use argmm::ArgMinMax;
 use kasi_kule::{Jab, UCS};
 
 /// Get closest ansi256 color using DeltaE distance. Accelerated with AVX instructions.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[target_feature(enable = "avx")]
-pub unsafe fn closest_ansi_avx(rgb: &[u8; 3]) -> (u8, f32) {
+pub unsafe fn closest_ansi_avx(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
@@ -18,7 +18,8 @@ pub unsafe fn closest_ansi_avx(rgb: &[u8; 3]) -> (u8, f32) {
     let mut res_array: [f32; 256] = [0.0; 256]; // full delta E
     let mut tmp: [f32; 8] = [0.0; 8]; // tmp array for storing intermediate values
 
-    JAB_PALETTE_FLATTENED
+    palette
+        .jab_flattened
         .chunks_exact(16)
         .enumerate()
         .for_each(|(i, step)| {
@@ -46,7 +47,7 @@ pub unsafe fn closest_ansi_avx(rgb: &[u8; 3]) -> (u8, f32) {
 /// Get closest ansi256 color using DeltaE distance. Accelerated with SSE instructions.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[target_feature(enable = "sse")]
-pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
+pub unsafe fn closest_ansi_sse(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
@@ -59,31 +60,31 @@ pub unsafe fn closest_ansi_sse(rgb: &[u8; 3]) -> (u8, f32) {
     let mut results: [f32; 256] = [0.0; 256];
     let mut res_array: [f32; 4] = [0.0; 4];
 
-    JAB_PALETTE_FLATTENED
+    palette
+        .jab_flattened
         .chunks_exact(4)
         .enumerate()
         .for_each(|(i, step)| {
             let pal_mm = _mm_loadu_ps(step.as_ptr() as *const f32);
             let mut res = _mm_sub_ps(lab_mm, pal_mm);
             res = _mm_mul_ps(res, res);
-            _mm_store_ps(res_array.as_mut_ptr() as *mut f32, res); // store back
+            _mm_storeu_ps(res_array.as_mut_ptr() as *mut f32, res); // store back
 
             *results.get_unchecked_mut(i) = res_array.get_unchecked(0) // add up left delta E
                 + res_array.get_unchecked(1)
                 + res_array.get_unchecked(2);
         });
 
-    let v = res_array.argmin().unwrap();
-    (v as u8, *res_array.get_unchecked(v))
+    let v = results.argmin().unwrap();
+    (v as u8, *results.get_unchecked(v))
 }
 
 /// Get closest ansi256 color using Jab/CAM02 distance. No acceleration.
-pub fn closest_ansi_scalar(rgb: &[u8; 3]) -> (u8, f32) {
+pub fn closest_ansi_scalar(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     let jab = Jab::<UCS>::from(*rgb);
     let mut results: [f32; 256] = [0.0; 256];
-    for i in 0..256 {
-        let (p_j, p_a, p_b) = JAB_PALETTE[i];
-        results[i] = (jab.J - p_j).powi(2) + (jab.a - p_a).powi(2) + (jab.b - p_b).powi(2);
+    for (result, &(p_j, p_a, p_b)) in results.iter_mut().zip(palette.jab.iter()) {
+        *result = (jab.J - p_j).powi(2) + (jab.a - p_a).powi(2) + (jab.b - p_b).powi(2);
     }
 
     let v = results.argmin().unwrap();
@@ -91,15 +92,33 @@ pub fn closest_ansi_scalar(rgb: &[u8; 3]) -> (u8, f32) {
 }
 
 /// Get closest ansi256 color using Jab/CAM02 distance. Accelerated with SIMD intrinsics if available.
-pub fn closest_ansi(rgb: &[u8; 3]) -> (u8, f32) {
+pub fn closest_ansi(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     {
         if is_x86_feature_detected!("avx") {
-            return unsafe { closest_ansi_avx(rgb) };
+            return unsafe { closest_ansi_avx(palette, rgb) };
         } else if is_x86_feature_detected!("sse") {
-            return unsafe { closest_ansi_sse(rgb) };
+            return unsafe { closest_ansi_sse(palette, rgb) };
         }
     }
 
-    closest_ansi_scalar(rgb)
+    closest_ansi_scalar(palette, rgb)
+}
+
+/// Get the closest of a few candidate colors using Jab/CAM02 distance, for when the rest of the palette is known to be further away.
+pub fn closest_of(palette: &Palette, rgb: &[u8; 3], candidates: &[u8]) -> (u8, f32) {
+    let jab = Jab::<UCS>::from(*rgb);
+    candidates
+        .iter()
+        .map(|&i| {
+            let (p_j, p_a, p_b) = palette.jab[i as usize];
+            (
+                i,
+                (jab.J - p_j).powi(2) + (jab.a - p_a).powi(2) + (jab.b - p_b).powi(2),
+            )
+        })
+        .fold(
+            (0, f32::INFINITY),
+            |best, v| if v.1 < best.1 { v } else { best },
+        )
 }
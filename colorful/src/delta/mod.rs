@@ -1,3 +1,69 @@
 pub mod cie76;
 pub mod cie94;
+pub mod ciede2000;
 pub mod jab;
+pub mod oklab;
+
+#[cfg(test)]
+mod tests {
+    use super::{cie76, cie94, ciede2000, jab, oklab};
+    use crate::palette::{Palette, DEFAULT_PALETTE};
+
+    /// Colors spread over the whole RGB cube, including the corners.
+    fn sample_colors() -> impl Iterator<Item = [u8; 3]> {
+        (0..=255u32)
+            .step_by(17)
+            .flat_map(|r| (0..=255u32).step_by(17).map(move |g| (r, g)))
+            .flat_map(|(r, g)| {
+                (0..=255u32)
+                    .step_by(51)
+                    .map(move |b| [r as u8, g as u8, b as u8])
+            })
+    }
+
+    /// Checks that an accelerated search picks a color as close as the scalar search does.
+    /// Ties may pick a different index, so only the distances are compared.
+    fn assert_agrees(
+        scalar: fn(&Palette, &[u8; 3]) -> (u8, f32),
+        simd: unsafe fn(&Palette, &[u8; 3]) -> (u8, f32),
+    ) {
+        for rgb in sample_colors() {
+            let (_, expected) = scalar(&DEFAULT_PALETTE, &rgb);
+            let (_, found) = unsafe { simd(&DEFAULT_PALETTE, &rgb) };
+            assert!(
+                (found - expected).abs() <= expected.abs() * 1e-4 + 1e-4,
+                "{:?}: scalar found {}, simd {}",
+                rgb,
+                expected,
+                found
+            );
+        }
+    }
+
+    // the sse searches once took the argmin of their 4 wide scratch array instead of all 256 distances
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn sse_matches_scalar() {
+        if !is_x86_feature_detected!("sse") {
+            return;
+        }
+
+        assert_agrees(cie76::closest_ansi_scalar, cie76::closest_ansi_sse);
+        assert_agrees(cie94::closest_ansi_scalar, cie94::closest_ansi_sse);
+        assert_agrees(jab::closest_ansi_scalar, jab::closest_ansi_sse);
+        assert_agrees(oklab::closest_ansi_scalar, oklab::closest_ansi_sse);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn avx_matches_scalar() {
+        if !is_x86_feature_detected!("avx") {
+            return;
+        }
+
+        assert_agrees(cie76::closest_ansi_scalar, cie76::closest_ansi_avx);
+        assert_agrees(ciede2000::closest_ansi_scalar, ciede2000::closest_ansi_avx);
+        assert_agrees(jab::closest_ansi_scalar, jab::closest_ansi_avx);
+        assert_agrees(oklab::closest_ansi_scalar, oklab::closest_ansi_avx);
+    }
+}
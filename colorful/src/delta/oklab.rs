@@ -0,0 +1,185 @@
+use crate::linear::to_linear;
+use crate::palette::Palette;
+use argmm::ArgMinMax;
+
+/// Converts an sRGB color to Oklab (L, a, b), as described in https://bottosson.github.io/posts/oklab/
+pub fn rgb_to_oklab(rgb: &[u8; 3]) -> (f32, f32, f32) {
+    let (r, g, b) = (to_linear(rgb[0]), to_linear(rgb[1]), to_linear(rgb[2]));
+
+    let l = (0.41222147 * r + 0.53633254 * g + 0.051445993 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+    let s = (0.08830246 * r + 0.28171884 * g + 0.6299787 * b).cbrt();
+
+    (
+        0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+    )
+}
+
+/// Get closest ansi256 color using Oklab distance. Accelerated with AVX instructions.
+///
+/// # Safety
+/// The CPU must support AVX.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+pub unsafe fn closest_ansi_avx(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    let (l, a, b) = rgb_to_oklab(rgb);
+    let lab_arr = [l, a, b, 0.0, l, a, b, 0.0];
+    let lab_mm = _mm256_loadu_ps(lab_arr.as_ptr());
+
+    let mut res_array: [f32; 256] = [0.0; 256]; // full delta E
+    let mut tmp: [f32; 8] = [0.0; 8]; // tmp array for storing intermediate values
+
+    palette
+        .oklab_flattened
+        .chunks_exact(16)
+        .enumerate()
+        .for_each(|(i, step)| {
+            let pal_a = _mm256_loadu_ps(step.as_ptr()); // load in 8 values (l,a,b,0,l,a,b,0)
+            let mut a = _mm256_sub_ps(lab_mm, pal_a);
+            a = _mm256_mul_ps(a, a);
+
+            let pal_b = _mm256_loadu_ps(step.as_ptr().add(8));
+            let mut b = _mm256_sub_ps(lab_mm, pal_b);
+            b = _mm256_mul_ps(b, b);
+
+            _mm256_storeu_ps(tmp.as_mut_ptr(), _mm256_hadd_ps(a, b)); // add up (l + a) for every value and then store
+            let start = i * 4;
+            // add up (l + a) + b
+            *res_array.get_unchecked_mut(start) = tmp.get_unchecked(0) + tmp.get_unchecked(1);
+            *res_array.get_unchecked_mut(start + 1) = tmp.get_unchecked(4) + tmp.get_unchecked(5);
+            *res_array.get_unchecked_mut(start + 2) = tmp.get_unchecked(2) + tmp.get_unchecked(3);
+            *res_array.get_unchecked_mut(start + 3) = tmp.get_unchecked(6) + tmp.get_unchecked(7);
+        });
+
+    let v = res_array.argmin().unwrap();
+    (v as u8, *res_array.get_unchecked(v))
+}
+
+/// Get closest ansi256 color using Oklab distance. Accelerated with SSE instructions.
+///
+/// # Safety
+/// The CPU must support SSE.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse")]
+pub unsafe fn closest_ansi_sse(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    let (l, a, b) = rgb_to_oklab(rgb);
+    let lab_arr = [l, a, b, 0.0];
+    let lab_mm = _mm_loadu_ps(lab_arr.as_ptr());
+
+    let mut results: [f32; 256] = [0.0; 256];
+    let mut res_array: [f32; 4] = [0.0; 4];
+
+    palette
+        .oklab_flattened
+        .chunks_exact(4)
+        .enumerate()
+        .for_each(|(i, step)| {
+            let pal_mm = _mm_loadu_ps(step.as_ptr());
+            let mut res = _mm_sub_ps(lab_mm, pal_mm);
+            res = _mm_mul_ps(res, res);
+            _mm_storeu_ps(res_array.as_mut_ptr(), res);
+
+            *results.get_unchecked_mut(i) = res_array.get_unchecked(0)
+                + res_array.get_unchecked(1)
+                + res_array.get_unchecked(2);
+        });
+
+    let v = results.argmin().unwrap();
+    (v as u8, *results.get_unchecked(v))
+}
+
+/// Get closest ansi256 color using Oklab distance. No acceleration.
+pub fn closest_ansi_scalar(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
+    let (l, a, b) = rgb_to_oklab(rgb);
+    let mut results: [f32; 256] = [0.0; 256];
+    for (result, &(p_l, p_a, p_b)) in results.iter_mut().zip(palette.oklab.iter()) {
+        *result = (l - p_l).powi(2) + (a - p_a).powi(2) + (b - p_b).powi(2);
+    }
+
+    let v = results.argmin().unwrap();
+    (v as u8, results[v])
+}
+
+/// Get closest ansi256 color using Oklab distance. Accelerated with SIMD intrinsics if available.
+pub fn closest_ansi(palette: &Palette, rgb: &[u8; 3]) -> (u8, f32) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            return unsafe { closest_ansi_avx(palette, rgb) };
+        } else if is_x86_feature_detected!("sse") {
+            return unsafe { closest_ansi_sse(palette, rgb) };
+        }
+    }
+
+    closest_ansi_scalar(palette, rgb)
+}
+
+/// Get the closest of a few candidate colors using Oklab distance, for when the rest of the palette is known to be further away.
+pub fn closest_of(palette: &Palette, rgb: &[u8; 3], candidates: &[u8]) -> (u8, f32) {
+    let (l, a, b) = rgb_to_oklab(rgb);
+    candidates
+        .iter()
+        .map(|&i| {
+            let (p_l, p_a, p_b) = palette.oklab[i as usize];
+            (i, (l - p_l).powi(2) + (a - p_a).powi(2) + (b - p_b).powi(2))
+        })
+        .fold(
+            (0, f32::INFINITY),
+            |best, v| if v.1 < best.1 { v } else { best },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// sRGB black, white and primaries in Oklab, from Björn Ottosson's reference implementation
+    /// (the same values CSS Color 4 gives).
+    const REFERENCE: [([u8; 3], (f32, f32, f32)); 5] = [
+        ([0, 0, 0], (0.0, 0.0, 0.0)),
+        ([255, 255, 255], (1.0, 0.0, 0.0)),
+        ([255, 0, 0], (0.62796, 0.22486, 0.12585)),
+        ([0, 255, 0], (0.86644, -0.23389, 0.1795)),
+        ([0, 0, 255], (0.45201, -0.03246, -0.31153)),
+    ];
+
+    #[test]
+    fn matches_reference_values() {
+        for (rgb, expected) in REFERENCE {
+            let found = rgb_to_oklab(&rgb);
+            for (found, expected) in [
+                (found.0, expected.0),
+                (found.1, expected.1),
+                (found.2, expected.2),
+            ] {
+                assert!(
+                    (found - expected).abs() < 1e-4,
+                    "{:?}: expected {:?}, got {:?}",
+                    rgb,
+                    expected,
+                    rgb_to_oklab(&rgb)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn grays_have_no_chroma() {
+        for v in (0..=255).step_by(15) {
+            let (_, a, b) = rgb_to_oklab(&[v, v, v]);
+            assert!(a.abs() < 1e-4 && b.abs() < 1e-4, "{}: {} {}", v, a, b);
+        }
+    }
+}
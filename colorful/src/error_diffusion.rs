@@ -0,0 +1,451 @@
+//! Error diffusion dithering with a choice of kernels, see https://tannerhelland.com/2012/12/28/dithering-eleven-algorithms-source-code.html
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::linear;
+use crate::palette::{AnsiColorMap, DistanceMethod};
+use image::imageops::ColorMap;
+use image::{Rgb, RgbImage};
+use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
+
+#[derive(TryFromPrimitive, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kernel {
+    FloydSteinberg = 0,
+    Atkinson = 1,
+    JarvisJudiceNinke = 2,
+    Stucki = 3,
+    Burkes = 4,
+    Sierra = 5,
+    TwoRowSierra = 6,
+    SierraLite = 7,
+}
+
+impl Display for Kernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kernel::FloydSteinberg => write!(f, "floyd-steinberg"),
+            Kernel::Atkinson => write!(f, "atkinson"),
+            Kernel::JarvisJudiceNinke => write!(f, "jarvis-judice-ninke"),
+            Kernel::Stucki => write!(f, "stucki"),
+            Kernel::Burkes => write!(f, "burkes"),
+            Kernel::Sierra => write!(f, "sierra"),
+            Kernel::TwoRowSierra => write!(f, "two-row sierra"),
+            Kernel::SierraLite => write!(f, "sierra lite"),
+        }
+    }
+}
+
+impl FromStr for Kernel {
+    type Err = String;
+
+    /// Parses the names kernels display as, with dashes or spaces.
+    fn from_str(s: &str) -> Result<Kernel, String> {
+        let name = s.to_ascii_lowercase().replace(' ', "-");
+        Kernel::ALL
+            .into_iter()
+            .find(|kernel| kernel.to_string().replace(' ', "-") == name)
+            .ok_or_else(|| {
+                let names: Vec<String> = Kernel::ALL
+                    .iter()
+                    .map(|kernel| kernel.to_string().replace(' ', "-"))
+                    .collect();
+                format!(
+                    "unknown kernel {s:?} (expected one of {})",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// (dx, dy, weight) for every neighbour that receives error, in raster order
+type Taps = &'static [(i32, u32, f32)];
+
+impl Kernel {
+    pub const ALL: [Kernel; 8] = [
+        Kernel::FloydSteinberg,
+        Kernel::Atkinson,
+        Kernel::JarvisJudiceNinke,
+        Kernel::Stucki,
+        Kernel::Burkes,
+        Kernel::Sierra,
+        Kernel::TwoRowSierra,
+        Kernel::SierraLite,
+    ];
+
+    /// The neighbours error is spread to, and the divisor for their weights.
+    /// Atkinson's weights only add up to 6/8 on purpose: it drops some error to keep contrast.
+    fn taps(&self) -> (Taps, f32) {
+        match self {
+            Kernel::FloydSteinberg => {
+                (&[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)], 16.0)
+            }
+            Kernel::Atkinson => (
+                &[
+                    (1, 0, 1.0),
+                    (2, 0, 1.0),
+                    (-1, 1, 1.0),
+                    (0, 1, 1.0),
+                    (1, 1, 1.0),
+                    (0, 2, 1.0),
+                ],
+                8.0,
+            ),
+            Kernel::JarvisJudiceNinke => (
+                &[
+                    (1, 0, 7.0),
+                    (2, 0, 5.0),
+                    (-2, 1, 3.0),
+                    (-1, 1, 5.0),
+                    (0, 1, 7.0),
+                    (1, 1, 5.0),
+                    (2, 1, 3.0),
+                    (-2, 2, 1.0),
+                    (-1, 2, 3.0),
+                    (0, 2, 5.0),
+                    (1, 2, 3.0),
+                    (2, 2, 1.0),
+                ],
+                48.0,
+            ),
+            Kernel::Stucki => (
+                &[
+                    (1, 0, 8.0),
+                    (2, 0, 4.0),
+                    (-2, 1, 2.0),
+                    (-1, 1, 4.0),
+                    (0, 1, 8.0),
+                    (1, 1, 4.0),
+                    (2, 1, 2.0),
+                    (-2, 2, 1.0),
+                    (-1, 2, 2.0),
+                    (0, 2, 4.0),
+                    (1, 2, 2.0),
+                    (2, 2, 1.0),
+                ],
+                42.0,
+            ),
+            Kernel::Burkes => (
+                &[
+                    (1, 0, 8.0),
+                    (2, 0, 4.0),
+                    (-2, 1, 2.0),
+                    (-1, 1, 4.0),
+                    (0, 1, 8.0),
+                    (1, 1, 4.0),
+                    (2, 1, 2.0),
+                ],
+                32.0,
+            ),
+            Kernel::Sierra => (
+                &[
+                    (1, 0, 5.0),
+                    (2, 0, 3.0),
+                    (-2, 1, 2.0),
+                    (-1, 1, 4.0),
+                    (0, 1, 5.0),
+                    (1, 1, 4.0),
+                    (2, 1, 2.0),
+                    (-1, 2, 2.0),
+                    (0, 2, 3.0),
+                    (1, 2, 2.0),
+                ],
+                32.0,
+            ),
+            Kernel::TwoRowSierra => (
+                &[
+                    (1, 0, 4.0),
+                    (2, 0, 3.0),
+                    (-2, 1, 1.0),
+                    (-1, 1, 2.0),
+                    (0, 1, 3.0),
+                    (1, 1, 2.0),
+                    (2, 1, 1.0),
+                ],
+                16.0,
+            ),
+            Kernel::SierraLite => (&[(1, 0, 2.0), (-1, 1, 1.0), (0, 1, 1.0)], 4.0),
+        }
+    }
+}
+
+/// Rows of error the kernels reach: the current one and up to two below.
+const ERROR_ROWS: usize = 3;
+
+/// Dithers an image to palette indices.
+///
+/// With `serpentine`, every other row is scanned right to left (with the kernel mirrored), which breaks up the diagonal
+/// artifacts raster order leaves. `strength` scales the error that gets diffused: 1.0 is the textbook algorithm,
+/// lower values trade accuracy for less noise.
+pub fn dither(
+    image: &RgbImage,
+    kernel: Kernel,
+    serpentine: bool,
+    strength: f32,
+    color_map: &AnsiColorMap<impl DistanceMethod>,
+) -> Vec<u8> {
+    dither_with(image, kernel, serpentine, strength, color_map, None, false)
+}
+
+/// Like [dither], but pixels with an index in `reused` (see [crate::temporal]) keep it. Their error is still diffused,
+/// so that the pixels around them make up for it the same way every frame.
+/// With `linear`, error is measured and spread in linear light (see [crate::linear]).
+pub fn dither_with(
+    image: &RgbImage,
+    kernel: Kernel,
+    serpentine: bool,
+    strength: f32,
+    color_map: &AnsiColorMap<impl DistanceMethod>,
+    reused: Option<&[Option<u8>]>,
+    linear: bool,
+) -> Vec<u8> {
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+    let mut out: Vec<u8> = vec![0; width * height];
+
+    let (taps, divisor) = kernel.taps();
+    let scale = strength / divisor;
+
+    let mut errors = vec![[0.0f32; 3]; width * ERROR_ROWS];
+
+    // error is kept in 0.0 - 255.0 sRGB values, or 0.0 - 1.0 linear light
+    let max = if linear { 1.0 } else { 255.0 };
+    let decode = |c: &[u8; 3]| {
+        if linear {
+            linear::to_linear_rgb(c)
+        } else {
+            [c[0] as f32, c[1] as f32, c[2] as f32]
+        }
+    };
+    let encode = |c: [f32; 3]| {
+        if linear {
+            linear::from_linear_rgb(c)
+        } else {
+            [c[0].round() as u8, c[1].round() as u8, c[2].round() as u8]
+        }
+    };
+
+    for y in 0..height {
+        let reverse = serpentine && y % 2 == 1;
+        let row = y % ERROR_ROWS;
+
+        for i in 0..width {
+            let x = if reverse { width - 1 - i } else { i };
+            let pixel = image.get_pixel(x as u32, y as u32);
+            let error = std::mem::take(&mut errors[row * width + x]);
+
+            let pixel = decode(&pixel.0);
+            let wanted = [
+                (pixel[0] + error[0]).clamp(0.0, max),
+                (pixel[1] + error[1]).clamp(0.0, max),
+                (pixel[2] + error[2]).clamp(0.0, max),
+            ];
+
+            let idx = match reused.and_then(|r| r[y * width + x]) {
+                Some(idx) => idx as usize,
+                None => color_map.index_of(&Rgb(encode(wanted))),
+            };
+            out[y * width + x] = idx as u8;
+
+            let chosen = decode(&color_map.palette().get(idx));
+            let diff = [
+                (wanted[0] - chosen[0]) * scale,
+                (wanted[1] - chosen[1]) * scale,
+                (wanted[2] - chosen[2]) * scale,
+            ];
+
+            for &(dx, dy, weight) in taps {
+                let tx = if reverse {
+                    x as i32 - dx
+                } else {
+                    x as i32 + dx
+                };
+                if tx < 0 || tx >= width as i32 {
+                    continue; // error falling off the edge is dropped
+                }
+
+                let target = &mut errors[(y + dy as usize) % ERROR_ROWS * width + tx as usize];
+                target[0] += diff[0] * weight;
+                target[1] += diff[1] * weight;
+                target[2] += diff[2] * weight;
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::CIE76;
+
+    /// What every kernel turns [golden_image] into, row by row. They only differ where error builds up differently,
+    /// so any change to a kernel's weights or to how error is carried shows up here.
+    const GOLDEN: [(Kernel, [u8; 32]); 8] = [
+        (
+            Kernel::FloydSteinberg,
+            [
+                26, 61, 61, 61, 97, 97, 133, 133, 61, 68, 61, 61, 96, 97, 96, 132, 67, 32, 103, 67,
+                103, 103, 139, 138, 31, 67, 67, 109, 67, 247, 246, 247,
+            ],
+        ),
+        (
+            Kernel::Atkinson,
+            [
+                26, 61, 61, 61, 97, 97, 133, 133, 25, 61, 61, 61, 60, 96, 96, 132, 67, 67, 67, 67,
+                103, 103, 139, 139, 31, 74, 67, 67, 110, 246, 246, 247,
+            ],
+        ),
+        (
+            Kernel::JarvisJudiceNinke,
+            [
+                26, 61, 61, 61, 97, 97, 133, 133, 25, 61, 61, 61, 60, 96, 96, 132, 67, 67, 67, 103,
+                103, 103, 139, 139, 31, 74, 67, 67, 110, 246, 247, 247,
+            ],
+        ),
+        (
+            Kernel::Stucki,
+            [
+                26, 61, 61, 61, 97, 97, 133, 133, 25, 61, 61, 61, 60, 96, 96, 132, 67, 67, 67, 103,
+                103, 103, 139, 139, 31, 74, 67, 110, 67, 247, 247, 247,
+            ],
+        ),
+        (
+            Kernel::Burkes,
+            [
+                26, 61, 61, 61, 97, 97, 133, 133, 25, 61, 61, 61, 96, 96, 96, 132, 67, 67, 67, 67,
+                103, 103, 139, 139, 31, 74, 67, 110, 67, 247, 247, 247,
+            ],
+        ),
+        (
+            Kernel::Sierra,
+            [
+                26, 61, 61, 61, 97, 97, 133, 133, 25, 61, 61, 61, 96, 96, 96, 132, 67, 67, 67, 67,
+                103, 103, 103, 139, 31, 74, 67, 67, 110, 247, 247, 138,
+            ],
+        ),
+        (
+            Kernel::TwoRowSierra,
+            [
+                26, 61, 61, 61, 97, 97, 133, 133, 25, 61, 61, 104, 96, 96, 96, 132, 67, 67, 67, 67,
+                103, 103, 103, 139, 31, 67, 74, 67, 67, 247, 247, 138,
+            ],
+        ),
+        (
+            Kernel::SierraLite,
+            [
+                26, 61, 61, 97, 97, 97, 133, 132, 61, 68, 61, 61, 60, 96, 97, 139, 32, 67, 67, 67,
+                103, 103, 139, 138, 31, 67, 67, 110, 66, 247, 103, 138,
+            ],
+        ),
+    ];
+
+    /// An 8x4 blend of muted blues and greens. None of the colors it dithers to are repeated elsewhere in the xterm
+    /// palette, so the indices don't depend on how ties between equal colors are broken.
+    fn golden_image() -> RgbImage {
+        RgbImage::from_fn(8, 4, |x, y| {
+            Rgb([60 + x as u8 * 16, 90 + y as u8 * 20, 190 - x as u8 * 5])
+        })
+    }
+
+    #[test]
+    fn kernels_match_their_golden_output() {
+        let image = golden_image();
+        let color_map = AnsiColorMap::<CIE76>::new();
+
+        for (kernel, expected) in GOLDEN {
+            assert_eq!(
+                dither(&image, kernel, false, 1.0, &color_map),
+                expected,
+                "{}",
+                kernel
+            );
+        }
+        assert_eq!(GOLDEN.map(|(kernel, _)| kernel), Kernel::ALL);
+    }
+
+    #[test]
+    fn flat_grey_dithers_to_a_stable_mix() {
+        // between xterm's 128 and 138 greys and the cube's 135, so no single color matches it
+        let image = RgbImage::from_pixel(48, 24, Rgb([133, 133, 133]));
+        let color_map = AnsiColorMap::<CIE76>::new();
+        let out = dither(&image, Kernel::FloydSteinberg, false, 1.0, &color_map);
+
+        let colors: Vec<[u8; 3]> = out
+            .iter()
+            .map(|idx| color_map.palette().get(*idx as usize))
+            .collect();
+        assert!(
+            colors.iter().all(|c| c[0] == c[1] && c[1] == c[2]),
+            "a grey should only mix greys"
+        );
+        assert!(
+            out.iter().any(|idx| *idx != out[0]),
+            "a single color isn't a mix"
+        );
+
+        let mean = colors.iter().map(|c| c[0] as f32).sum::<f32>() / colors.len() as f32;
+        assert!((mean - 133.0).abs() < 0.5, "mix averages to {}", mean);
+
+        assert_eq!(
+            dither(&image, Kernel::FloydSteinberg, false, 1.0, &color_map),
+            out
+        );
+    }
+
+    #[test]
+    fn serpentine_mirrors_the_kernel_on_odd_rows() {
+        // the first row is an exact palette color, so it leaves no error for the second row,
+        // and the second runs from dark orange to light teal, so error builds up differently each way
+        let image = RgbImage::from_fn(40, 2, |x, y| match y {
+            0 => Rgb([95, 135, 175]),
+            _ => Rgb([190 - x as u8 * 3, 60 + x as u8 * 4, 20 + x as u8 * 5]),
+        });
+        let mirrored = image::imageops::flip_horizontal(&image);
+        let color_map = AnsiColorMap::<CIE76>::new();
+
+        for kernel in Kernel::ALL {
+            let serpentine = dither(&image, kernel, true, 1.0, &color_map);
+            let raster = dither(&mirrored, kernel, false, 1.0, &color_map);
+
+            assert_eq!(serpentine[..40], raster[..40], "{}: even rows", kernel);
+            let mut odd_row = raster[40..].to_vec();
+            odd_row.reverse();
+            assert_eq!(serpentine[40..], odd_row[..], "{}: odd rows", kernel);
+        }
+
+        assert_ne!(
+            dither(&image, Kernel::FloydSteinberg, true, 1.0, &color_map),
+            dither(&image, Kernel::FloydSteinberg, false, 1.0, &color_map),
+            "the gradient should dither differently in each direction"
+        );
+    }
+
+    #[test]
+    fn zero_strength_is_nearest_color() {
+        // colors from all over the cube, few of them in the palette
+        let image = RgbImage::from_fn(48, 32, |x, y| {
+            Rgb([x as u8 * 5, y as u8 * 8, (x * y % 256) as u8])
+        });
+        let color_map = AnsiColorMap::<CIE76>::new();
+        let nearest: Vec<u8> = image
+            .pixels()
+            .map(|p| color_map.index_of(p) as u8)
+            .collect();
+
+        for kernel in Kernel::ALL {
+            for serpentine in [false, true] {
+                assert_eq!(
+                    dither(&image, kernel, serpentine, 0.0, &color_map),
+                    nearest,
+                    "{}",
+                    kernel
+                );
+            }
+        }
+    }
+}
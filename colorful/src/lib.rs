@@ -1,3 +1,13 @@
+pub mod adjust;
+pub mod colorspace;
+pub mod adaptive;
 pub mod delta;
+pub mod error_diffusion;
+pub mod linear;
+pub mod lut;
+pub mod metrics;
 pub mod palette;
 pub mod pattern_dithering;
+pub mod temporal;
+pub mod theme;
+pub mod yliluoma;
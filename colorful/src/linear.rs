@@ -0,0 +1,219 @@
+//! sRGB transfer functions, for mixing and scaling colors in linear light.
+//!
+//! sRGB values aren't proportional to light: averaging them, which is what dithering and scaling both do, comes out too
+//! dark wherever bright and dark colors meet. Converting to linear light first and back afterwards fixes that.
+
+use image::RgbImage;
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+
+/// Steps of the linear -> sRGB table, fine enough that neighbouring steps never skip an sRGB value.
+const FROM_LINEAR_STEPS: usize = 16384;
+
+lazy_static! {
+    /// sRGB channel values converted to linear light.
+    static ref TO_LINEAR: [f32; 256] = {
+        let mut out = [0.0; 256];
+        for (i, v) in out.iter_mut().enumerate() {
+            let c = i as f32 / 255.0;
+            *v = if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            };
+        }
+
+        out
+    };
+
+    static ref FROM_LINEAR: Vec<u8> = (0..FROM_LINEAR_STEPS)
+        .map(|i| {
+            let c = i as f32 / (FROM_LINEAR_STEPS - 1) as f32;
+            let v = if c <= 0.0031308 {
+                c * 12.92
+            } else {
+                1.055 * c.powf(1.0 / 2.4) - 0.055
+            };
+            (v * 255.0).round() as u8
+        })
+        .collect();
+}
+
+/// Converts an sRGB channel value to linear light, from 0.0 to 1.0.
+#[inline(always)]
+pub fn to_linear(v: u8) -> f32 {
+    TO_LINEAR[v as usize]
+}
+
+/// Converts linear light back to an sRGB channel value, clamping it to 0.0 - 1.0 first.
+#[inline(always)]
+pub fn from_linear(v: f32) -> u8 {
+    FROM_LINEAR[(v.clamp(0.0, 1.0) * (FROM_LINEAR_STEPS - 1) as f32 + 0.5) as usize]
+}
+
+#[inline(always)]
+pub fn to_linear_rgb(c: &[u8; 3]) -> [f32; 3] {
+    [to_linear(c[0]), to_linear(c[1]), to_linear(c[2])]
+}
+
+#[inline(always)]
+pub fn from_linear_rgb(c: [f32; 3]) -> [u8; 3] {
+    [from_linear(c[0]), from_linear(c[1]), from_linear(c[2])]
+}
+
+/// For every pixel along one axis of the output, the source pixels it covers and their weights.
+fn coverage(source: usize, target: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = source as f32 / target as f32;
+
+    (0..target)
+        .map(|i| {
+            let (start, end) = (i as f32 * scale, (i + 1) as f32 * scale);
+            (start.floor() as usize..(end.ceil() as usize).min(source))
+                .filter_map(|x| {
+                    let overlap = end.min(x as f32 + 1.0) - start.max(x as f32);
+                    (overlap > 0.0).then(|| (x, overlap / scale))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Resizes an image by averaging the area every output pixel covers, in linear light.
+/// Meant for downscaling, where it keeps fine detail from darkening; upscaling just repeats pixels.
+pub fn downscale(image: &RgbImage, width: u32, height: u32) -> RgbImage {
+    area_downscale(image, width, height, true)
+}
+
+/// Like [downscale], averaging sRGB values instead of linear light if `linear` isn't set.
+pub fn area_downscale(image: &RgbImage, width: u32, height: u32, linear: bool) -> RgbImage {
+    let decode = |v: u8| {
+        if linear {
+            to_linear(v)
+        } else {
+            v as f32 / 255.0
+        }
+    };
+    let encode = |c: [f32; 3]| {
+        if linear {
+            from_linear_rgb(c)
+        } else {
+            c.map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+        }
+    };
+
+    let columns = coverage(image.width() as usize, width as usize);
+    let rows = coverage(image.height() as usize, height as usize);
+
+    // scale every row horizontally first...
+    let horizontal: Vec<Vec<[f32; 3]>> = image
+        .as_raw()
+        .par_chunks_exact(image.width() as usize * 3)
+        .map(|row| {
+            columns
+                .iter()
+                .map(|taps| {
+                    let mut sum = [0.0; 3];
+                    for &(x, weight) in taps {
+                        for c in 0..3 {
+                            sum[c] += decode(row[x * 3 + c]) * weight;
+                        }
+                    }
+                    sum
+                })
+                .collect()
+        })
+        .collect();
+
+    // ...then the columns of the result vertically
+    let horizontal = &horizontal;
+    let data: Vec<u8> = rows
+        .par_iter()
+        .flat_map_iter(|taps| {
+            (0..width as usize).flat_map(move |x| {
+                let mut sum = [0.0; 3];
+                for &(y, weight) in taps {
+                    for c in 0..3 {
+                        sum[c] += horizontal[y][x][c] * weight;
+                    }
+                }
+                encode(sum)
+            })
+        })
+        .collect();
+
+    RgbImage::from_vec(width, height, data).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn round_trips_keep_every_value() {
+        for v in 0..=255 {
+            assert_eq!(from_linear(to_linear(v)), v);
+        }
+
+        assert_eq!(to_linear(0), 0.0);
+        assert_eq!(to_linear(255), 1.0);
+        assert!((1..=255).all(|v| to_linear(v) > to_linear(v - 1)));
+        assert_eq!((from_linear(-0.5), from_linear(1.5)), (0, 255));
+    }
+
+    #[test]
+    fn coverage_weights_add_up_to_one() {
+        for (source, target) in [(1920, 192), (1080, 108), (100, 7), (7, 3), (5, 5), (3, 8)] {
+            let taps = coverage(source, target);
+            assert_eq!(taps.len(), target);
+
+            for (i, pixel) in taps.iter().enumerate() {
+                let sum: f32 = pixel.iter().map(|(_, weight)| weight).sum();
+                assert!(
+                    (sum - 1.0).abs() < 1e-4,
+                    "{source} -> {target}, pixel {i}: {sum}"
+                );
+                assert!(pixel.iter().all(|&(x, _)| x < source));
+            }
+        }
+    }
+
+    #[test]
+    fn checkerboards_average_to_half_the_light() {
+        let checkerboard = RgbImage::from_fn(64, 64, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([255; 3])
+            } else {
+                Rgb([0; 3])
+            }
+        });
+
+        // half the light is sRGB 188, not the 128 averaging the values gives
+        let linear = downscale(&checkerboard, 8, 8);
+        assert!(
+            linear.pixels().all(|p| p.0 == [188; 3]),
+            "{:?}",
+            linear.get_pixel(0, 0)
+        );
+
+        let gamma = area_downscale(&checkerboard, 8, 8, false);
+        assert!(
+            gamma.pixels().all(|p| p.0 == [128; 3]),
+            "{:?}",
+            gamma.get_pixel(0, 0)
+        );
+    }
+
+    #[test]
+    fn flat_images_stay_flat() {
+        let image = RgbImage::from_pixel(30, 20, Rgb([12, 140, 250]));
+        for linear in [true, false] {
+            let scaled = area_downscale(&image, 7, 3, linear);
+            assert_eq!(scaled.dimensions(), (7, 3));
+            assert!(
+                scaled.pixels().all(|p| p.0 == [12, 140, 250]),
+                "linear {linear}"
+            );
+        }
+    }
+}
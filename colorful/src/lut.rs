@@ -0,0 +1,335 @@
+//! Precomputed RGB → palette index lookup tables, so that dithering doesn't need a full palette search for every pixel.
+//!
+//! The RGB cube is split into cells of `2^(8 - bits)` values per channel. A cell stores the palette index found for its center
+//! if its corners agree on that index as well. Cells that straddle a boundary between palette colors instead store the
+//! indices found at those points, and colors falling into them are compared against just those candidates.
+//! Results thus only differ from an exact search where a palette color's region is smaller than a cell and slips between
+//! the sampled points.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use arrayvec::ArrayVec;
+use rayon::prelude::*;
+
+use crate::palette::{DistanceMethod, Palette};
+
+/// Bits per channel of a table asked for without a size: 64³ cells, around 1.5MiB per table.
+pub const DEFAULT_BITS: u8 = 6;
+
+const MAGIC: &[u8; 4] = b"aLUT";
+
+#[derive(Clone)]
+pub struct ColorLut {
+    bits: u8,
+    /// palette index for uniform cells; otherwise 256 + the offset of the cell's candidates
+    table: Vec<u32>,
+    /// candidate lists for boundary cells, each prefixed with its length
+    candidates: Vec<u8>,
+}
+
+impl ColorLut {
+    /// Builds a table for a palette and distance method, with `bits` (1 to 8) bits per channel.
+    pub fn build<T: DistanceMethod>(palette: &Palette, bits: u8) -> ColorLut {
+        assert!(
+            (1..=8).contains(&bits),
+            "lookup tables need 1 to 8 bits per channel"
+        );
+
+        let cells = 1usize << bits;
+        let size = 1usize << (8 - bits); // channel values per cell
+        let vertices = cells + 1;
+
+        let vertex_value = |i: usize| (i * size).min(255) as u8;
+        let corners: Vec<u8> = (0..vertices.pow(3))
+            .into_par_iter()
+            .map(|i| {
+                let color = [
+                    vertex_value(i / (vertices * vertices)),
+                    vertex_value(i / vertices % vertices),
+                    vertex_value(i % vertices),
+                ];
+                T::closest(palette, &color) as u8
+            })
+            .collect();
+
+        let center_value = |i: usize| (i * size + size / 2) as u8;
+        let cell_candidates: Vec<ArrayVec<u8, 9>> = (0..cells.pow(3))
+            .into_par_iter()
+            .map(|i| {
+                let (r, g, b) = (i / (cells * cells), i / cells % cells, i % cells);
+                let center = T::closest(
+                    palette,
+                    &[center_value(r), center_value(g), center_value(b)],
+                ) as u8;
+
+                let mut found = ArrayVec::new();
+                found.push(center);
+                for corner in 0..8 {
+                    let (dr, dg, db) = (corner >> 2, (corner >> 1) & 1, corner & 1);
+                    let idx = corners[((r + dr) * vertices + g + dg) * vertices + b + db];
+                    if !found.contains(&idx) {
+                        found.push(idx);
+                    }
+                }
+
+                found
+            })
+            .collect();
+
+        let mut table = Vec::with_capacity(cell_candidates.len());
+        let mut candidates = Vec::new();
+        for found in cell_candidates {
+            if found.len() == 1 {
+                table.push(found[0] as u32);
+            } else {
+                table.push(256 + candidates.len() as u32);
+                candidates.push(found.len() as u8);
+                candidates.extend_from_slice(&found);
+            }
+        }
+
+        ColorLut {
+            bits,
+            table,
+            candidates,
+        }
+    }
+
+    #[inline(always)]
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Looks up the closest palette color, comparing against the candidates near boundaries.
+    /// `palette` and `T` must be the ones the table was built for.
+    #[inline(always)]
+    pub fn index_of<T: DistanceMethod>(&self, palette: &Palette, color: &[u8; 3]) -> usize {
+        let shift = 8 - self.bits;
+        let idx = ((color[0] as usize >> shift) << (2 * self.bits))
+            | ((color[1] as usize >> shift) << self.bits)
+            | (color[2] as usize >> shift);
+
+        match self.table[idx] {
+            v @ 0..=255 => v as usize,
+            v => {
+                let offset = v as usize - 256;
+                let len = self.candidates[offset] as usize;
+                T::closest_of(
+                    palette,
+                    color,
+                    &self.candidates[offset + 1..offset + 1 + len],
+                )
+            }
+        }
+    }
+
+    /// Fraction of cells (0.0 - 1.0) on a boundary, which need their candidates compared.
+    pub fn boundary_fraction(&self) -> f32 {
+        self.table.iter().filter(|v| **v > 255).count() as f32 / self.table.len() as f32
+    }
+
+    /// Loads a table saved with [ColorLut::save], if it was built for the same palette, distance method and bits.
+    pub fn load<T: DistanceMethod>(
+        path: impl AsRef<Path>,
+        palette: &Palette,
+        bits: u8,
+    ) -> io::Result<Option<ColorLut>> {
+        let mut data = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut data)?;
+
+        let header = header::<T>(palette, bits);
+        if !data.starts_with(&header) {
+            return Ok(None);
+        }
+
+        let table_len = (1usize << (3 * bits)) * 4;
+        let body = &data[header.len()..];
+        if body.len() < table_len {
+            return Ok(None);
+        }
+
+        let (table, candidates) = body.split_at(table_len);
+        let table: Vec<u32> = table
+            .chunks_exact(4)
+            .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+            .collect();
+
+        // make sure every offset points at a complete candidate list, so lookups can't go out of bounds
+        let valid = table.iter().filter(|v| **v > 255).all(|v| {
+            let offset = *v as usize - 256;
+            candidates
+                .get(offset)
+                .map(|len| offset + 1 + *len as usize <= candidates.len())
+                .unwrap_or(false)
+        });
+        if !valid {
+            return Ok(None);
+        }
+
+        Ok(Some(ColorLut {
+            bits,
+            table,
+            candidates: candidates.to_vec(),
+        }))
+    }
+
+    /// Saves the table, along with what it was built for.
+    pub fn save<T: DistanceMethod>(
+        &self,
+        path: impl AsRef<Path>,
+        palette: &Palette,
+    ) -> io::Result<()> {
+        let mut out = header::<T>(palette, self.bits);
+        out.reserve(self.table.len() * 4 + self.candidates.len());
+        for v in &self.table {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&self.candidates);
+
+        // write then rename, so that concurrent encodes never read a partial file
+        let path = path.as_ref();
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::File::create(&tmp)?.write_all(&out)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Loads the table from a cache directory, or builds it and stores it there. Cache errors only cost the rebuild.
+    pub fn load_or_build<T: DistanceMethod>(
+        cache_dir: impl AsRef<Path>,
+        palette: &Palette,
+        bits: u8,
+    ) -> ColorLut {
+        let path = cache_path::<T>(cache_dir.as_ref(), palette, bits);
+        if let Ok(Some(lut)) = ColorLut::load::<T>(&path, palette, bits) {
+            return lut;
+        }
+
+        let lut = ColorLut::build::<T>(palette, bits);
+        let _ = fs::create_dir_all(cache_dir).and_then(|_| lut.save::<T>(&path, palette));
+        lut
+    }
+}
+
+impl std::fmt::Debug for ColorLut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ColorLut")
+            .field("bits", &self.bits)
+            .field("boundary_fraction", &self.boundary_fraction())
+            .finish()
+    }
+}
+
+fn header<T: DistanceMethod>(palette: &Palette, bits: u8) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + 2 + T::NAME.len() + 768);
+    out.extend_from_slice(MAGIC);
+    out.push(bits);
+    out.push(T::NAME.len() as u8);
+    out.extend_from_slice(T::NAME.as_bytes());
+    out.extend(palette.colors().iter().flatten());
+    out
+}
+
+fn cache_path<T: DistanceMethod>(dir: &Path, palette: &Palette, bits: u8) -> PathBuf {
+    // FNV-1a over the colors, to tell palettes apart in file names; the header has the final say
+    let hash = palette
+        .colors()
+        .iter()
+        .flatten()
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ *b as u64).wrapping_mul(0x100000001b3)
+        });
+
+    dir.join(format!(
+        "{}-{}-{:016x}.lut",
+        T::NAME.to_ascii_lowercase(),
+        bits,
+        hash
+    ))
+}
+
+/// A table that's only built, or loaded from `cache_dir`, the first time it's needed.
+#[derive(Debug)]
+pub struct LazyLut {
+    pub bits: u8,
+    pub cache_dir: Option<PathBuf>,
+    lut: OnceLock<ColorLut>,
+}
+
+impl LazyLut {
+    pub fn new(bits: u8, cache_dir: Option<PathBuf>) -> LazyLut {
+        LazyLut {
+            bits,
+            cache_dir,
+            lut: OnceLock::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get<T: DistanceMethod>(&self, palette: &Palette) -> &ColorLut {
+        self.lut.get_or_init(|| match &self.cache_dir {
+            Some(dir) => ColorLut::load_or_build::<T>(dir, palette, self.bits),
+            None => ColorLut::build::<T>(palette, self.bits),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::{Oklab, CIE76, DEFAULT_PALETTE};
+
+    /// How much further the colors a table picks are than the ones an exact search picks, in the method's own space:
+    /// the largest and mean extra distance, and the fraction of colors that got a different index at all.
+    fn extra_error<T: DistanceMethod>(bits: u8) -> (f32, f32, f32) {
+        let palette = &DEFAULT_PALETTE;
+        let lut = ColorLut::build::<T>(palette, bits);
+        let distance = |color: &[u8; 3], idx: usize| {
+            let (a, b) = (T::to_space(color), T::palette_space(palette)[idx]);
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)).sqrt()
+        };
+
+        let (mut max, mut sum, mut differing, mut count) = (0.0f32, 0.0, 0, 0);
+        // offset from the cell grid, so most samples fall inside cells rather than on their corners
+        for r in (1..=255).step_by(6) {
+            for g in (2..=255).step_by(6) {
+                for b in (3..=255).step_by(6) {
+                    let color = [r as u8, g as u8, b as u8];
+                    let exact = T::closest(palette, &color);
+                    let found = lut.index_of::<T>(palette, &color);
+                    count += 1;
+                    if found != exact {
+                        let extra = distance(&color, found) - distance(&color, exact);
+                        max = max.max(extra);
+                        sum += extra;
+                        differing += 1;
+                    }
+                }
+            }
+        }
+
+        (max, sum / count as f32, differing as f32 / count as f32)
+    }
+
+    // ΔE76 of 1 is about the smallest difference that can be seen side by side
+    #[test]
+    fn cie76_lookups_stay_close_to_exact_search() {
+        let (max, mean, differing) = extra_error::<CIE76>(DEFAULT_BITS);
+        assert!(max < 1.0, "max extra ΔE {}", max);
+        assert!(mean < 0.001, "mean extra ΔE {}", mean);
+        assert!(differing < 0.001, "{} of colors differ", differing);
+    }
+
+    // oklab distances are about a hundredth of ΔE76
+    #[test]
+    fn oklab_lookups_stay_close_to_exact_search() {
+        let (max, mean, differing) = extra_error::<Oklab>(DEFAULT_BITS);
+        assert!(max < 0.01, "max extra distance {}", max);
+        assert!(mean < 0.00001, "mean extra distance {}", mean);
+        assert!(differing < 0.001, "{} of colors differ", differing);
+    }
+}
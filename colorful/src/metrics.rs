@@ -0,0 +1,340 @@
+//! Objective image quality metrics, for comparing dithering methods and catching quality regressions.
+
+use std::fmt::Display;
+
+use crate::delta::ciede2000;
+use crate::palette::Palette;
+use image::RgbImage;
+use lab::Lab;
+use rayon::prelude::*;
+
+/// Side of the windows SSIM compares, and how far apart they start.
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4;
+
+/// PSNR identical frames count as when averaging, so that they don't make the average infinite.
+const MAX_PSNR: f32 = 100.0;
+
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    pub psnr: f32,         // dB over all three channels; infinite for identical images
+    pub ssim: f32,         // on luma, 1.0 for identical images
+    pub mean_delta_e: f32, // CIEDE2000
+    pub max_delta_e: f32,
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "psnr {:.2} dB, ssim {:.4}, ΔE mean {:.2} max {:.2}",
+            self.psnr, self.ssim, self.mean_delta_e, self.max_delta_e
+        )
+    }
+}
+
+/// Compares an image to the source it was made from. Both must have the same dimensions.
+pub fn compare(source: &RgbImage, output: &RgbImage) -> Metrics {
+    assert_eq!(source.dimensions(), output.dimensions());
+
+    let (mean_delta_e, max_delta_e) = delta_e(source, output);
+    Metrics {
+        psnr: psnr(source, output),
+        ssim: ssim(source, output),
+        mean_delta_e,
+        max_delta_e,
+    }
+}
+
+/// Compares palette indices, as produced by dithering, to the image they were dithered from.
+pub fn compare_indexed(source: &RgbImage, indices: &[u8], palette: &Palette) -> Metrics {
+    compare(
+        source,
+        &expand(source.width(), source.height(), indices, palette),
+    )
+}
+
+/// Turns palette indices back into an image.
+pub fn expand(width: u32, height: u32, indices: &[u8], palette: &Palette) -> RgbImage {
+    let data = indices
+        .iter()
+        .flat_map(|&idx| palette.get(idx as usize))
+        .collect();
+    RgbImage::from_vec(width, height, data).unwrap()
+}
+
+/// Peak signal-to-noise ratio, in dB.
+pub fn psnr(source: &RgbImage, output: &RgbImage) -> f32 {
+    let squared_error: u64 = source
+        .as_raw()
+        .par_iter()
+        .zip(output.as_raw().par_iter())
+        .map(|(a, b)| (*a as i64 - *b as i64).pow(2) as u64)
+        .sum();
+
+    if squared_error == 0 {
+        return f32::INFINITY;
+    }
+
+    let mse = squared_error as f64 / source.as_raw().len() as f64;
+    (10.0 * (255.0 * 255.0 / mse).log10()) as f32
+}
+
+fn luma(image: &RgbImage) -> Vec<f64> {
+    image
+        .pixels()
+        .map(|p| 0.299 * p.0[0] as f64 + 0.587 * p.0[1] as f64 + 0.114 * p.0[2] as f64)
+        .collect()
+}
+
+/// Structural similarity of the luma channel, averaged over overlapping windows.
+pub fn ssim(source: &RgbImage, output: &RgbImage) -> f32 {
+    let (width, height) = (source.width() as usize, source.height() as usize);
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+
+    let (a, b) = (luma(source), luma(output));
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+
+    let starts = |size: usize, window: usize| (0..=size - window).step_by(SSIM_STEP);
+    let windows: Vec<(usize, usize)> = starts(height, window_height)
+        .flat_map(|y| starts(width, window_width).map(move |x| (x, y)))
+        .collect();
+
+    let total: f64 = windows
+        .par_iter()
+        .map(|&(x0, y0)| {
+            let n = (window_width * window_height) as f64;
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in y0..y0 + window_height {
+                for x in x0..x0 + window_width {
+                    let (va, vb) = (a[y * width + x], b[y * width + x]);
+                    sum_a += va;
+                    sum_b += vb;
+                    sum_aa += va * va;
+                    sum_bb += vb * vb;
+                    sum_ab += va * vb;
+                }
+            }
+
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2))
+        })
+        .sum();
+
+    (total / windows.len() as f64) as f32
+}
+
+/// Mean and largest CIEDE2000 difference between corresponding pixels.
+pub fn delta_e(source: &RgbImage, output: &RgbImage) -> (f32, f32) {
+    let pixels = source.as_raw().len() / 3;
+    if pixels == 0 {
+        return (0.0, 0.0);
+    }
+
+    let (sum, max) = source
+        .as_raw()
+        .par_chunks_exact(3)
+        .zip(output.as_raw().par_chunks_exact(3))
+        .map(|(a, b)| {
+            if a == b {
+                return 0.0;
+            }
+
+            let a = Lab::from_rgb(&[a[0], a[1], a[2]]);
+            let b = Lab::from_rgb(&[b[0], b[1], b[2]]);
+            ciede2000::delta_e((a.l, a.a, a.b), (b.l, b.a, b.b))
+        })
+        .fold(
+            || (0.0f64, 0.0f32),
+            |(sum, max), v| (sum + v as f64, max.max(v)),
+        )
+        .reduce(|| (0.0, 0.0), |a, b| (a.0 + b.0, a.1.max(b.1)));
+
+    ((sum / pixels as f64) as f32, max)
+}
+
+/// Metrics accumulated over many frames.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub frames: usize,
+    sum: [f64; 4],
+    /// lowest PSNR and SSIM, highest mean and max ΔE
+    worst: Option<Metrics>,
+}
+
+impl Summary {
+    pub fn add(&mut self, metrics: &Metrics) {
+        self.frames += 1;
+        self.sum[0] += metrics.psnr.min(MAX_PSNR) as f64;
+        self.sum[1] += metrics.ssim as f64;
+        self.sum[2] += metrics.mean_delta_e as f64;
+        self.sum[3] += metrics.max_delta_e as f64;
+
+        self.worst = Some(match self.worst {
+            Some(worst) => Metrics {
+                psnr: worst.psnr.min(metrics.psnr),
+                ssim: worst.ssim.min(metrics.ssim),
+                mean_delta_e: worst.mean_delta_e.max(metrics.mean_delta_e),
+                max_delta_e: worst.max_delta_e.max(metrics.max_delta_e),
+            },
+            None => *metrics,
+        });
+    }
+
+    /// Averages over all frames, or None if there weren't any.
+    pub fn mean(&self) -> Option<Metrics> {
+        (self.frames > 0).then(|| {
+            let n = self.frames as f64;
+            Metrics {
+                psnr: (self.sum[0] / n) as f32,
+                ssim: (self.sum[1] / n) as f32,
+                mean_delta_e: (self.sum[2] / n) as f32,
+                max_delta_e: (self.sum[3] / n) as f32,
+            }
+        })
+    }
+
+    /// The worst value of every metric on its own, which may come from different frames.
+    pub fn worst(&self) -> Option<Metrics> {
+        self.worst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn gradient(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+        })
+    }
+
+    fn filled(width: u32, height: u32, color: [u8; 3]) -> RgbImage {
+        RgbImage::from_pixel(width, height, Rgb(color))
+    }
+
+    #[test]
+    fn identical_images_are_perfect() {
+        let image = gradient(32, 24);
+        let metrics = compare(&image, &image.clone());
+
+        assert_eq!(metrics.psnr, f32::INFINITY);
+        assert!((metrics.ssim - 1.0).abs() < 1e-6, "{}", metrics.ssim);
+        assert_eq!(metrics.mean_delta_e, 0.0);
+        assert_eq!(metrics.max_delta_e, 0.0);
+    }
+
+    #[test]
+    fn psnr_follows_the_mean_squared_error() {
+        // every channel off by 10: MSE 100, so 10 log10(255² / 100)
+        let psnr = psnr(&filled(16, 16, [0, 0, 0]), &filled(16, 16, [10, 10, 10]));
+        assert!((psnr - 28.1308).abs() < 1e-3, "{psnr}");
+
+        // an error of 255 in one of every three channels: MSE 255² / 3
+        let psnr = super::psnr(&filled(16, 16, [0, 0, 0]), &filled(16, 16, [255, 0, 0]));
+        assert!((psnr - 10.0 * 3f32.log10()).abs() < 1e-3, "{psnr}");
+    }
+
+    #[test]
+    fn ssim_drops_with_structure() {
+        let source = gradient(32, 32);
+        let flat = filled(32, 32, [128, 128, 128]);
+        let noisy = RgbImage::from_fn(32, 32, |x, y| {
+            let p = source.get_pixel(x, y).0;
+            let d = if (x + y) % 2 == 0 { 12 } else { -12 };
+            Rgb(p.map(|v| (v as i32 + d).clamp(0, 255) as u8))
+        });
+
+        let (flat, noisy) = (ssim(&source, &flat), ssim(&source, &noisy));
+        assert!(flat < noisy && noisy < 1.0, "flat {flat}, noisy {noisy}");
+    }
+
+    #[test]
+    fn delta_e_reports_mean_and_max() {
+        let black = filled(2, 1, [0, 0, 0]);
+        let mut half = black.clone();
+        half.put_pixel(1, 0, Rgb([255, 255, 255]));
+
+        // black to white is ΔE 100 in every CIE formula
+        let (mean, max) = delta_e(&black, &half);
+        assert!((max - 100.0).abs() < 0.5, "{max}");
+        assert!((mean - max / 2.0).abs() < 1e-3, "{mean}");
+    }
+
+    #[test]
+    fn indexed_output_is_expanded_through_the_palette() {
+        let palette = Palette::default();
+        let indices: Vec<u8> = (0..=255).collect();
+        let source = RgbImage::from_fn(16, 16, |x, y| Rgb(palette.get((y * 16 + x) as usize)));
+
+        assert_eq!(expand(16, 16, &indices, &palette), source);
+        assert_eq!(
+            compare_indexed(&source, &indices, &palette),
+            compare(&source, &source)
+        );
+
+        let shifted: Vec<u8> = indices.iter().map(|i| i.wrapping_add(1)).collect();
+        assert!(compare_indexed(&source, &shifted, &palette).max_delta_e > 0.0);
+    }
+
+    #[test]
+    fn summary_averages_and_keeps_the_worst() {
+        let mut summary = Summary::default();
+        assert_eq!(summary.mean(), None);
+        assert_eq!(summary.worst(), None);
+
+        summary.add(&Metrics {
+            psnr: f32::INFINITY,
+            ssim: 1.0,
+            mean_delta_e: 0.0,
+            max_delta_e: 0.0,
+        });
+        summary.add(&Metrics {
+            psnr: 30.0,
+            ssim: 0.8,
+            mean_delta_e: 2.0,
+            max_delta_e: 10.0,
+        });
+        summary.add(&Metrics {
+            psnr: 20.0,
+            ssim: 0.9,
+            mean_delta_e: 4.0,
+            max_delta_e: 6.0,
+        });
+
+        assert_eq!(summary.frames, 3);
+        // identical frames count as MAX_PSNR
+        assert_eq!(
+            summary.mean(),
+            Some(Metrics {
+                psnr: 50.0,
+                ssim: 0.9,
+                mean_delta_e: 2.0,
+                max_delta_e: 16.0 / 3.0,
+            })
+        );
+        assert_eq!(
+            summary.worst(),
+            Some(Metrics {
+                psnr: 20.0,
+                ssim: 0.8,
+                mean_delta_e: 4.0,
+                max_delta_e: 10.0,
+            })
+        );
+    }
+}
@@ -1,12 +1,12 @@
 use super::delta;
+use crate::lut::LazyLut;
 use arrayvec::ArrayString;
 use kasi_kule::{Jab, UCS};
 use lab::Lab;
 use lazy_static::lazy_static;
-use std::{collections::HashMap, marker::PhantomData};
-
-// TODO: configurable palettes
+use std::{collections::HashMap, fmt, marker::PhantomData, path::PathBuf, sync::Arc};
 
+/// The default xterm palette, used whenever the terminal's actual colors aren't known.
 pub const PALETTE: [[u8; 3]; 256] = [
     // idx: ansi colorid. tuple is r,g,b
     [0, 0, 0],
@@ -276,53 +276,8 @@ lazy_static! {
         pal
     };
 
-    /// Static ansi256 palette in LAB format, converted from RGB.
-    pub static ref LAB_PALETTE: [(f32, f32, f32); 256] = {
-        let mut pal = [(0.0f32,0.0f32,0.0f32); 256];
-        for i in 0..256 {
-            let lab = Lab::from_rgb(&PALETTE[i]);
-            pal[i] = (lab.l, lab.a, lab.b)
-        }
-
-        pal
-    };
-
-    /// Static ansi256 palette in LAB format, converted from RGB. Flattened from tuple representation and with an extra zero added for easier handling with SIMD intrinsics.
-    pub static ref LAB_PALETTE_FLATTENED: [f32; 1024] = {
-        let mut pal = [0.0f32; 1024];
-        for i in 0..256 {
-            let lab = Lab::from_rgb(&PALETTE[i]);
-            pal[i * 4] = lab.l;
-            pal[i * 4 + 1] = lab.a;
-            pal[i * 4 + 2] = lab.b;
-            pal[i * 4 + 3] = 0.0;
-        }
-
-        pal
-    };
-
-    pub static ref JAB_PALETTE: [(f32, f32, f32); 256] = {
-        let mut pal = [(0.0f32,0.0f32,0.0f32); 256];
-        for i in 0..256 {
-            let jab = Jab::<UCS>::from(PALETTE[i]);
-            pal[i] = (jab.J, jab.a, jab.b)
-        }
-
-        pal
-    };
-
-    pub static ref JAB_PALETTE_FLATTENED: [f32; 1024] = {
-        let mut pal = [0.0f32; 1024];
-        for i in 0..256 {
-            let jab = Jab::<UCS>::from(PALETTE[i]);
-            pal[i * 4] = jab.J;
-            pal[i * 4 + 1] = jab.a;
-            pal[i * 4 + 2] = jab.b;
-            pal[i * 4 + 3] = 0.0;
-        }
-
-        pal
-    };
+    /// The xterm palette, with its lookup tables.
+    pub static ref DEFAULT_PALETTE: Arc<Palette> = Arc::new(Palette::new(PALETTE));
 
     pub static ref PALETTE_FG_CODES: [ArrayString<20>; 256] = {
         let mut out = [ArrayString::new_const(); 256];
@@ -365,41 +320,266 @@ lazy_static! {
     };
 }
 
+/// A 256 color terminal palette, along with the lookup tables the distance methods need.
+/// Building one converts every color to LAB and Jab, so it's best built once and shared behind an [Arc].
+#[derive(Clone)]
+pub struct Palette {
+    colors: [[u8; 3]; 256],
+    /// default foreground color, if known
+    pub foreground: Option<[u8; 3]>,
+    /// default background color, if known
+    pub background: Option<[u8; 3]>,
+    /// palette in LAB format, converted from RGB.
+    pub(crate) lab: Vec<(f32, f32, f32)>,
+    /// palette in LAB format, flattened from tuple representation and with an extra zero added for easier handling with SIMD intrinsics.
+    pub(crate) lab_flattened: Vec<f32>,
+    pub(crate) jab: Vec<(f32, f32, f32)>,
+    pub(crate) jab_flattened: Vec<f32>,
+    pub(crate) cie94: Vec<f32>,
+    pub(crate) ciede2000: Vec<f32>,
+    pub(crate) oklab: Vec<(f32, f32, f32)>,
+    pub(crate) oklab_flattened: Vec<f32>,
+    reverse: HashMap<[u8; 3], u8>,
+}
+
+impl Palette {
+    pub fn new(colors: [[u8; 3]; 256]) -> Palette {
+        let mut lab = Vec::with_capacity(256);
+        let mut lab_flattened = Vec::with_capacity(1024);
+        let mut jab = Vec::with_capacity(256);
+        let mut jab_flattened = Vec::with_capacity(1024);
+        let mut oklab = Vec::with_capacity(256);
+        let mut oklab_flattened = Vec::with_capacity(1024);
+        let mut reverse = HashMap::with_capacity(256);
+
+        for (i, c) in colors.iter().enumerate() {
+            let l = Lab::from_rgb(c);
+            lab.push((l.l, l.a, l.b));
+            lab_flattened.extend_from_slice(&[l.l, l.a, l.b, 0.0]);
+
+            let j = Jab::<UCS>::from(*c);
+            jab.push((j.J, j.a, j.b));
+            jab_flattened.extend_from_slice(&[j.J, j.a, j.b, 0.0]);
+
+            let o = delta::oklab::rgb_to_oklab(c);
+            oklab.push(o);
+            oklab_flattened.extend_from_slice(&[o.0, o.1, o.2, 0.0]);
+
+            // keep the lowest index for duplicated colors
+            reverse.entry(*c).or_insert(i as u8);
+        }
+
+        let cie94 = delta::cie94::palette_data(&lab);
+        let ciede2000 = delta::ciede2000::palette_data(&lab);
+
+        Palette {
+            colors,
+            foreground: None,
+            background: None,
+            lab,
+            lab_flattened,
+            jab,
+            jab_flattened,
+            cie94,
+            ciede2000,
+            oklab,
+            oklab_flattened,
+            reverse,
+        }
+    }
+
+    /// Returns a copy of this palette with some colors replaced, e.g. the 16 base colors from a terminal theme.
+    pub fn with_overrides(&self, overrides: impl IntoIterator<Item = (u8, [u8; 3])>) -> Palette {
+        let mut colors = self.colors;
+        for (idx, color) in overrides {
+            colors[idx as usize] = color;
+        }
+
+        Palette {
+            foreground: self.foreground,
+            background: self.background,
+            ..Palette::new(colors)
+        }
+    }
+
+    #[inline(always)]
+    pub fn colors(&self) -> &[[u8; 3]; 256] {
+        &self.colors
+    }
+
+    #[inline(always)]
+    pub fn get(&self, idx: usize) -> [u8; 3] {
+        self.colors[idx]
+    }
+
+    /// Finds the index of a color that's exactly in the palette.
+    #[inline(always)]
+    pub fn index_of_exact(&self, color: &[u8; 3]) -> Option<u8> {
+        self.reverse.get(color).copied()
+    }
+
+    pub fn lab(&self) -> &[(f32, f32, f32)] {
+        &self.lab
+    }
+
+    pub fn jab(&self) -> &[(f32, f32, f32)] {
+        &self.jab
+    }
+
+    pub fn oklab(&self) -> &[(f32, f32, f32)] {
+        &self.oklab
+    }
+
+    /// Whether this is the plain xterm palette, which every terminal shows unless themed.
+    pub fn is_default(&self) -> bool {
+        self.colors == PALETTE
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new(PALETTE)
+    }
+}
+
+impl PartialEq for Palette {
+    fn eq(&self, other: &Palette) -> bool {
+        self.colors == other.colors
+            && self.foreground == other.foreground
+            && self.background == other.background
+    }
+}
+
+impl fmt::Debug for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the extended colors are almost always the standard cube, so the base 16 say enough
+        f.debug_struct("Palette")
+            .field("base", &&self.colors[..16])
+            .field("default_extended", &(self.colors[16..] == PALETTE[16..]))
+            .field("foreground", &self.foreground)
+            .field("background", &self.background)
+            .finish()
+    }
+}
+
 use image::{imageops::ColorMap, Rgb};
 
 pub trait DistanceMethod {
-    fn closest(color: &[u8; 3]) -> usize;
+    /// name of the method, used to tell cached lookup tables apart
+    const NAME: &'static str;
+
+    fn closest(palette: &Palette, color: &[u8; 3]) -> usize;
+
+    /// Like [DistanceMethod::closest], but only considering some of the palette's indices.
+    fn closest_of(palette: &Palette, color: &[u8; 3], candidates: &[u8]) -> usize;
+
+    /// Converts a color to the perceptual space the method works in. Colors can be mixed by averaging them there,
+    /// and their squared euclidean distance approximates the method's.
+    fn to_space(color: &[u8; 3]) -> (f32, f32, f32);
+
+    /// The palette's colors in [DistanceMethod::to_space]'s space.
+    fn palette_space(palette: &Palette) -> &[(f32, f32, f32)];
+}
+
+fn to_lab(color: &[u8; 3]) -> (f32, f32, f32) {
+    let lab = Lab::from_rgb(color);
+    (lab.l, lab.a, lab.b)
+}
+
+fn to_jab(color: &[u8; 3]) -> (f32, f32, f32) {
+    let jab = Jab::<UCS>::from(*color);
+    (jab.J, jab.a, jab.b)
 }
 
 macro_rules! distance_method {
-    ($name:ident : $func:path) => {
+    ($name:ident : $module:ident, $space:ident, $to_space:path) => {
         #[derive(Copy, Clone, Debug)]
         pub struct $name;
 
         impl DistanceMethod for $name {
+            const NAME: &'static str = stringify!($name);
+
+            #[inline(always)]
+            fn closest(palette: &Palette, color: &[u8; 3]) -> usize {
+                delta::$module::closest_ansi(palette, color).0 as usize
+            }
+
+            #[inline(always)]
+            fn closest_of(palette: &Palette, color: &[u8; 3], candidates: &[u8]) -> usize {
+                delta::$module::closest_of(palette, color, candidates).0 as usize
+            }
+
+            #[inline(always)]
+            fn to_space(color: &[u8; 3]) -> (f32, f32, f32) {
+                $to_space(color)
+            }
+
             #[inline(always)]
-            fn closest(color: &[u8; 3]) -> usize {
-                $func(color).0 as usize
+            fn palette_space(palette: &Palette) -> &[(f32, f32, f32)] {
+                &palette.$space
             }
         }
     };
 }
 
-distance_method!(CAM02: delta::jab::closest_ansi);
-distance_method!(CIE94: delta::cie94::closest_ansi);
-distance_method!(CIE76: delta::cie76::closest_ansi);
+distance_method!(CAM02: jab, jab, to_jab);
+distance_method!(CIE94: cie94, lab, to_lab);
+distance_method!(CIE76: cie76, lab, to_lab);
+distance_method!(CIEDE2000: ciede2000, lab, to_lab);
+distance_method!(Oklab: oklab, oklab, delta::oklab::rgb_to_oklab);
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct AnsiColorMap<T: DistanceMethod> {
+    palette: Arc<Palette>,
+    lut: Option<Arc<LazyLut>>,
     _spooky: PhantomData<T>,
 }
 
 impl<T: DistanceMethod> AnsiColorMap<T> {
+    /// Maps to the default xterm palette.
     pub fn new() -> AnsiColorMap<T> {
+        AnsiColorMap::with_palette(DEFAULT_PALETTE.clone())
+    }
+
+    pub fn with_palette(palette: Arc<Palette>) -> AnsiColorMap<T> {
         AnsiColorMap {
+            palette,
+            lut: None,
             _spooky: PhantomData,
         }
     }
+
+    /// Looks colors up in a table with `bits` bits per channel (see [crate::lut]), built the first time it's needed.
+    /// Clones of the map share the table.
+    pub fn with_lut(mut self, bits: u8) -> AnsiColorMap<T> {
+        self.lut = Some(Arc::new(LazyLut::new(bits, None)));
+        self
+    }
+
+    /// Like [AnsiColorMap::with_lut], but keeps the table in a cache directory across runs.
+    pub fn with_cached_lut(mut self, bits: u8, cache_dir: impl Into<PathBuf>) -> AnsiColorMap<T> {
+        self.lut = Some(Arc::new(LazyLut::new(bits, Some(cache_dir.into()))));
+        self
+    }
+
+    /// Builds the lookup table now, if there is one and it hasn't been built yet.
+    /// Call this before using the map from rayon tasks: a table built from inside one would block the others.
+    pub fn prepare(&self) {
+        if let Some(lut) = &self.lut {
+            lut.get::<T>(&self.palette);
+        }
+    }
+
+    #[inline(always)]
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+}
+
+impl<T: DistanceMethod> Default for AnsiColorMap<T> {
+    fn default() -> AnsiColorMap<T> {
+        AnsiColorMap::new()
+    }
 }
 
 impl<T: DistanceMethod> ColorMap for AnsiColorMap<T> {
@@ -407,12 +587,17 @@ impl<T: DistanceMethod> ColorMap for AnsiColorMap<T> {
 
     #[inline(always)]
     fn index_of(&self, color: &Rgb<u8>) -> usize {
-        T::closest(&color.0)
+        match &self.lut {
+            Some(lut) => lut
+                .get::<T>(&self.palette)
+                .index_of::<T>(&self.palette, &color.0),
+            None => T::closest(&self.palette, &color.0),
+        }
     }
 
     #[inline(always)]
     fn lookup(&self, idx: usize) -> Option<Self::Color> {
-        Some(Rgb(PALETTE[idx]))
+        Some(Rgb(self.palette.get(idx)))
     }
 
     #[inline(always)]
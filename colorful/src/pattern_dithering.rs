@@ -1,13 +1,16 @@
 //! Thomas Knoll dithering algorithm, based on https://bisqwit.iki.fi/story/howto/dither/jy/#PatternDitheringThePatentedAlgorithmUsedInAdobePhotoshop
 
 use std::fmt::Display;
+use std::str::FromStr;
 
-use crate::palette::{AnsiColorMap, DistanceMethod, PALETTE};
+use crate::linear;
+use crate::palette::{AnsiColorMap, DistanceMethod};
 use arrayvec::ArrayVec;
 use image::imageops::ColorMap;
 use image::{Rgb, RgbImage};
 use num_enum::TryFromPrimitive;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 static BAYER_8X8: [usize; 64] = [
     0, 48, 12, 60, 3, 51, 15, 63, 32, 16, 44, 28, 35, 19, 47, 31, 8, 56, 4, 52, 11, 59, 7, 55, 40,
@@ -17,12 +20,21 @@ static BAYER_8X8: [usize; 64] = [
 static BAYER_4X4: [usize; 16] = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5];
 static BAYER_2X2: [usize; 4] = [0, 2, 3, 1];
 
-#[derive(TryFromPrimitive, Clone, Copy, Debug, PartialEq)]
+/// Blue noise threshold map, from the ranks of a 32x32 void-and-cluster pattern (Ulichney, gaussian sigma 1.5, wrapping
+/// around) scaled down to 64 levels. Unlike Bayer matrices it has no regular structure, so it doesn't leave a crosshatch.
+static BLUE_NOISE_32X32: [usize; 1024] = include!("blue_noise_32x32.in");
+
+#[derive(TryFromPrimitive, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum MatrixSize {
+    #[serde(rename = "8x8")]
     Eight = 0,
+    #[serde(rename = "4x4")]
     Four = 1,
+    #[serde(rename = "2x2")]
     Two = 2,
+    #[serde(rename = "blue-noise")]
+    BlueNoise = 3,
 }
 
 impl Display for MatrixSize {
@@ -31,11 +43,39 @@ impl Display for MatrixSize {
             MatrixSize::Eight => write!(f, "8x8"),
             MatrixSize::Four => write!(f, "4x4"),
             MatrixSize::Two => write!(f, "2x2"),
+            MatrixSize::BlueNoise => write!(f, "blue noise"),
+        }
+    }
+}
+
+impl FromStr for MatrixSize {
+    type Err = String;
+
+    /// Parses the names [MatrixSize] displays as, with dashes or spaces.
+    fn from_str(s: &str) -> Result<MatrixSize, String> {
+        match s.to_ascii_lowercase().replace('-', " ").as_str() {
+            "8x8" => Ok(MatrixSize::Eight),
+            "4x4" => Ok(MatrixSize::Four),
+            "2x2" => Ok(MatrixSize::Two),
+            "blue noise" => Ok(MatrixSize::BlueNoise),
+            _ => Err(format!(
+                "unknown matrix size {s:?} (expected 2x2, 4x4, 8x8 or blue-noise)"
+            )),
         }
     }
 }
 
-fn to_luma(c: [u8; 3]) -> f32 {
+/// A pixel's entry in the threshold map, along with how many levels the map has.
+pub(crate) fn threshold(matrix_size: MatrixSize, x: usize, y: usize) -> (usize, usize) {
+    match matrix_size {
+        MatrixSize::Two => (BAYER_2X2[(y % 2) * 2 + (x % 2)], 4),
+        MatrixSize::Four => (BAYER_4X4[(y % 4) * 4 + (x % 4)], 16),
+        MatrixSize::Eight => (BAYER_8X8[(y % 8) * 8 + (x % 8)], 64),
+        MatrixSize::BlueNoise => (BLUE_NOISE_32X32[(y % 32) * 32 + (x % 32)], 64),
+    }
+}
+
+pub(crate) fn to_luma(c: [u8; 3]) -> f32 {
     (c[0] as f32 * 299.0 + c[1] as f32 * 587.0 + c[2] as f32 * 114.0) / 255000.0
 }
 
@@ -44,7 +84,7 @@ macro_rules! mix_def {
         pub fn $name(
             color: [u8; 3],
             multiplier: f32,
-            color_map: AnsiColorMap<impl DistanceMethod>,
+            color_map: &AnsiColorMap<impl DistanceMethod>,
             pick_idx: usize,
         ) -> u8 {
             let mut err_acc: [u8; 3] = [0, 0, 0];
@@ -60,7 +100,7 @@ macro_rules! mix_def {
 
                 let chosen = color_map.index_of(&Rgb(tmp));
 
-                let chosen_c = PALETTE[chosen];
+                let chosen_c = color_map.palette().get(chosen);
                 candidates.push((chosen_c, chosen as u8));
 
                 err_acc[0] = err_acc[0].saturating_add(color[0].saturating_sub(chosen_c[0]));
@@ -79,48 +119,94 @@ mix_def!(mix_2x2, 4);
 mix_def!(mix_4x4, 16);
 mix_def!(mix_8x8, 64);
 
+// the same in linear light, where the error also stays signed: candidates brighter than the pixel count against it too
+macro_rules! mix_linear_def {
+    ($name:ident, $size:literal) => {
+        pub fn $name(
+            color: [u8; 3],
+            multiplier: f32,
+            color_map: &AnsiColorMap<impl DistanceMethod>,
+            pick_idx: usize,
+        ) -> u8 {
+            let target = linear::to_linear_rgb(&color);
+            let mut err_acc: [f32; 3] = [0.0, 0.0, 0.0];
+            let mut candidates: ArrayVec<([u8; 3], u8), $size> = ArrayVec::new();
+
+            for _ in 0..$size {
+                let tmp = linear::from_linear_rgb([
+                    target[0] + err_acc[0] * multiplier,
+                    target[1] + err_acc[1] * multiplier,
+                    target[2] + err_acc[2] * multiplier,
+                ]);
+
+                let chosen = color_map.index_of(&Rgb(tmp));
+
+                let chosen_c = color_map.palette().get(chosen);
+                candidates.push((chosen_c, chosen as u8));
+
+                let chosen_linear = linear::to_linear_rgb(&chosen_c);
+                err_acc[0] += target[0] - chosen_linear[0];
+                err_acc[1] += target[1] - chosen_linear[1];
+                err_acc[2] += target[2] - chosen_linear[2];
+            }
+
+            candidates.sort_by(|a, b| to_luma(a.0).partial_cmp(&to_luma(b.0)).unwrap());
+
+            candidates[pick_idx].1
+        }
+    };
+}
+
+mix_linear_def!(mix_linear_2x2, 4);
+mix_linear_def!(mix_linear_4x4, 16);
+mix_linear_def!(mix_linear_8x8, 64);
+
 pub fn dither(
     image: &RgbImage,
     matrix_size: MatrixSize,
     multiplier: f32,
-    color_map: AnsiColorMap<impl DistanceMethod + Send + Sync + Copy>,
+    color_map: &AnsiColorMap<impl DistanceMethod + Send + Sync>,
+) -> Vec<u8> {
+    dither_with(image, matrix_size, multiplier, color_map, None, false)
+}
+
+/// Like [dither], but pixels with an index in `reused` (see [crate::temporal]) keep it instead of being dithered,
+/// and with `linear`, candidates are mixed in linear light (see [crate::linear]).
+pub fn dither_with(
+    image: &RgbImage,
+    matrix_size: MatrixSize,
+    multiplier: f32,
+    color_map: &AnsiColorMap<impl DistanceMethod + Send + Sync>,
+    reused: Option<&[Option<u8>]>,
+    linear: bool,
 ) -> Vec<u8> {
     let height = image.height() as usize;
     let width = image.width() as usize;
     let mut out: Vec<u8> = vec![0; width as usize * height as usize];
+    color_map.prepare();
 
-    match matrix_size {
-        MatrixSize::Two => out.par_iter_mut().enumerate().for_each(|(i, pixel_out)| {
-            let (x, y) = (i % width, i / width);
-            let pixel = image.get_pixel(x as u32, y as u32);
-            *pixel_out = mix_2x2(
-                pixel.0,
-                multiplier,
-                color_map,
-                BAYER_2X2[(y as usize % 2) * 2 + (x as usize % 2)],
-            );
-        }),
-        MatrixSize::Four => out.par_iter_mut().enumerate().for_each(|(i, pixel_out)| {
-            let (x, y) = (i % width, i / width);
-            let pixel = image.get_pixel(x as u32, y as u32);
-            *pixel_out = mix_4x4(
-                pixel.0,
-                multiplier,
-                color_map,
-                BAYER_4X4[(y as usize % 4) * 4 + (x as usize % 4)],
-            );
-        }),
-        MatrixSize::Eight => out.par_iter_mut().enumerate().for_each(|(i, pixel_out)| {
-            let (x, y) = (i % width, i / width);
-            let pixel = image.get_pixel(x as u32, y as u32);
-            *pixel_out = mix_8x8(
-                pixel.0,
-                multiplier,
-                color_map,
-                BAYER_8X8[(y as usize % 8) * 8 + (x as usize % 8)],
-            );
-        }),
-    };
+    out.par_iter_mut().enumerate().for_each(|(i, pixel_out)| {
+        if let Some(idx) = reused.and_then(|r| r[i]) {
+            *pixel_out = idx;
+            return;
+        }
+
+        let (x, y) = (i % width, i / width);
+        let pixel = image.get_pixel(x as u32, y as u32).0;
+        let (pick_idx, _) = threshold(matrix_size, x, y);
+        *pixel_out = match (matrix_size, linear) {
+            (MatrixSize::Two, false) => mix_2x2(pixel, multiplier, color_map, pick_idx),
+            (MatrixSize::Four, false) => mix_4x4(pixel, multiplier, color_map, pick_idx),
+            (MatrixSize::Eight | MatrixSize::BlueNoise, false) => {
+                mix_8x8(pixel, multiplier, color_map, pick_idx)
+            }
+            (MatrixSize::Two, true) => mix_linear_2x2(pixel, multiplier, color_map, pick_idx),
+            (MatrixSize::Four, true) => mix_linear_4x4(pixel, multiplier, color_map, pick_idx),
+            (MatrixSize::Eight | MatrixSize::BlueNoise, true) => {
+                mix_linear_8x8(pixel, multiplier, color_map, pick_idx)
+            }
+        };
+    });
 
     out
 }
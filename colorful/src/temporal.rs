@@ -0,0 +1,152 @@
+//! Keeping dithered video steady from frame to frame.
+//!
+//! Dithering every frame on its own makes the pattern crawl over flat areas as soon as their colors move even slightly,
+//! which both looks like shimmering and stops the encoder from skipping unchanged cells. Instead, pixels whose color is still
+//! close to the one their previous palette index was picked for keep that index.
+
+use image::RgbImage;
+use lab::Lab;
+use rayon::prelude::*;
+
+#[derive(Debug, Clone)]
+pub struct TemporalState {
+    pub threshold: f32, // CIE76 ΔE below which a pixel keeps its previous index
+    width: u32,
+    height: u32,
+    /// for every pixel, the color its index was picked for and the index
+    reference: Vec<(Lab, u8)>,
+}
+
+impl TemporalState {
+    pub fn new(threshold: f32) -> TemporalState {
+        TemporalState {
+            threshold,
+            width: 0,
+            height: 0,
+            reference: Vec::new(),
+        }
+    }
+
+    /// The index every pixel of a frame can keep, if any.
+    pub fn reusable(&self, image: &RgbImage) -> Vec<Option<u8>> {
+        if self.reference.is_empty() || image.dimensions() != (self.width, self.height) {
+            return vec![None; (image.width() * image.height()) as usize];
+        }
+
+        let threshold = self.threshold * self.threshold;
+        image
+            .as_raw()
+            .par_chunks_exact(3)
+            .zip(self.reference.par_iter())
+            .map(|(rgb, (lab, idx))| {
+                let color = Lab::from_rgb(&[rgb[0], rgb[1], rgb[2]]);
+                (color.squared_distance(lab) < threshold).then_some(*idx)
+            })
+            .collect()
+    }
+
+    /// Remembers the indices a frame was dithered to, given what [TemporalState::reusable] returned for it.
+    /// Reused pixels stay tied to the color their index was picked for, so that a slow fade builds up until it gets
+    /// dithered anew instead of drifting away from its index unnoticed.
+    pub fn update(&mut self, image: &RgbImage, reused: &[Option<u8>], indices: &[u8]) {
+        if self.reference.len() != indices.len() {
+            self.reference = vec![(Lab::default(), 0); indices.len()];
+        }
+
+        (self.width, self.height) = image.dimensions();
+        self.reference
+            .par_iter_mut()
+            .zip(image.as_raw().par_chunks_exact(3))
+            .zip(reused.par_iter().zip(indices.par_iter()))
+            .for_each(|((reference, rgb), (reused, idx))| {
+                if reused.is_none() {
+                    *reference = (Lab::from_rgb(&[rgb[0], rgb[1], rgb[2]]), *idx);
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn grey(width: u32, height: u32, v: u8) -> RgbImage {
+        RgbImage::from_pixel(width, height, Rgb([v, v, v]))
+    }
+
+    fn distance(a: u8, b: u8) -> f32 {
+        Lab::from_rgb(&[a, a, a])
+            .squared_distance(&Lab::from_rgb(&[b, b, b]))
+            .sqrt()
+    }
+
+    /// A state that has seen one frame, dithered entirely to `idx`.
+    fn seen(image: &RgbImage, threshold: f32, idx: u8) -> TemporalState {
+        let mut state = TemporalState::new(threshold);
+        let pixels = (image.width() * image.height()) as usize;
+        state.update(image, &vec![None; pixels], &vec![idx; pixels]);
+        state
+    }
+
+    #[test]
+    fn nothing_is_reused_without_a_previous_frame() {
+        let state = TemporalState::new(5.0);
+        assert_eq!(state.reusable(&grey(4, 3, 100)), vec![None; 12]);
+    }
+
+    #[test]
+    fn pixels_within_the_threshold_keep_their_index() {
+        assert!(distance(100, 103) < 2.0 && distance(100, 106) > 2.0);
+        let state = seen(&grey(4, 3, 100), 2.0, 7);
+
+        assert_eq!(state.reusable(&grey(4, 3, 100)), vec![Some(7); 12]);
+        assert_eq!(state.reusable(&grey(4, 3, 103)), vec![Some(7); 12]);
+        assert_eq!(state.reusable(&grey(4, 3, 106)), vec![None; 12]);
+
+        let mut mixed = grey(4, 3, 100);
+        mixed.put_pixel(1, 2, Rgb([255, 0, 0]));
+        let reusable = state.reusable(&mixed);
+        assert_eq!(reusable[2 * 4 + 1], None);
+        assert_eq!(reusable.iter().filter(|r| r.is_some()).count(), 11);
+    }
+
+    #[test]
+    fn zero_threshold_reuses_nothing() {
+        let state = seen(&grey(4, 3, 100), 0.0, 7);
+        assert_eq!(state.reusable(&grey(4, 3, 100)), vec![None; 12]);
+    }
+
+    #[test]
+    fn dimension_changes_reset_the_state() {
+        let mut state = seen(&grey(4, 3, 100), 2.0, 7);
+
+        // same number of pixels, laid out differently
+        assert_eq!(state.reusable(&grey(6, 2, 100)), vec![None; 12]);
+        assert_eq!(state.reusable(&grey(8, 8, 100)), vec![None; 64]);
+
+        state.update(&grey(8, 8, 100), &[None; 64], &[3; 64]);
+        assert_eq!(state.reusable(&grey(8, 8, 100)), vec![Some(3); 64]);
+        assert_eq!(state.reusable(&grey(4, 3, 100)), vec![None; 12]);
+    }
+
+    #[test]
+    fn reused_pixels_keep_their_reference_color() {
+        // a slow fade: every step is within the threshold of the last, but two steps aren't of the first
+        assert!(distance(103, 106) < 2.0 && distance(100, 106) > 2.0);
+        let mut state = seen(&grey(4, 3, 100), 2.0, 7);
+
+        let step = grey(4, 3, 103);
+        let reused = state.reusable(&step);
+        assert_eq!(reused, vec![Some(7); 12]);
+        state.update(&step, &reused, &[7; 12]);
+
+        // compared against 100, not 103, so the fade gets dithered anew instead of drifting further
+        let step = grey(4, 3, 106);
+        let reused = state.reusable(&step);
+        assert_eq!(reused, vec![None; 12]);
+        state.update(&step, &reused, &[8; 12]);
+
+        assert_eq!(state.reusable(&grey(4, 3, 106)), vec![Some(8); 12]);
+    }
+}
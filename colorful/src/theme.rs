@@ -0,0 +1,379 @@
+//! Loading palettes from terminal themes.
+//!
+//! The parser is line based and deliberately forgiving, so that one function covers the common formats:
+//! - plain lists, one color per line, in palette order
+//! - Xresources (`*.color4: #3465a4`, `URxvt*foreground: ...`)
+//! - kitty (`color4 #3465a4`, `background #000000`)
+//! - alacritty, both TOML (`[colors.bright]` then `blue = '0x729fcf'`) and YAML (`bright:` then `blue: '#729fcf'`)
+//! - Windows Terminal schemes (`"brightBlue": "#729FCF",`)
+//!
+//! Colors may be written as `#rrggbb`, `0xrrggbb`, `rrggbb`, `rgb:rr/gg/bb` (as terminals report them) or `r,g,b`.
+//! Colors that the theme doesn't mention keep their xterm defaults.
+
+use std::{fmt, path::Path};
+
+use crate::palette::{Palette, PALETTE};
+
+#[derive(Debug)]
+pub enum ThemeError {
+    Io(std::io::Error),
+    /// a line looked like a color assignment, but the color couldn't be parsed (line number, line)
+    InvalidColor(usize, String),
+    /// a color index past 255 (line number, index)
+    InvalidIndex(usize, usize),
+    /// nothing in the file looked like a color
+    Empty,
+}
+
+impl fmt::Display for ThemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThemeError::Io(e) => write!(f, "couldn't read theme: {}", e),
+            ThemeError::InvalidColor(line, s) => {
+                write!(f, "invalid color on line {}: {:?}", line, s)
+            }
+            ThemeError::InvalidIndex(line, idx) => {
+                write!(f, "color index {} on line {} is out of range", idx, line)
+            }
+            ThemeError::Empty => write!(f, "no colors found in theme"),
+        }
+    }
+}
+
+impl std::error::Error for ThemeError {}
+
+impl From<std::io::Error> for ThemeError {
+    fn from(e: std::io::Error) -> ThemeError {
+        ThemeError::Io(e)
+    }
+}
+
+const COLOR_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Normal,
+    Bright,
+    Dim,
+}
+
+/// What a key on a theme line refers to.
+enum Slot {
+    Index(usize),
+    Foreground,
+    Background,
+    Ignored,
+}
+
+/// Parses a color in any of the supported notations.
+pub fn parse_color(value: &str) -> Option<[u8; 3]> {
+    let value = value.trim();
+
+    if let Some(rgb) = value.strip_prefix("rgb:") {
+        // X11 style, 1 to 4 hex digits per channel
+        let mut out = [0u8; 3];
+        let mut channels = rgb.split('/');
+        for c in out.iter_mut() {
+            let digits = channels.next()?;
+            if digits.is_empty() || digits.len() > 4 {
+                return None;
+            }
+
+            let v = u32::from_str_radix(digits, 16).ok()?;
+            let max = (1u32 << (digits.len() * 4)) - 1;
+            *c = ((v * 255 + max / 2) / max) as u8;
+        }
+
+        return channels.next().is_none().then_some(out);
+    }
+
+    if value.contains(',') {
+        let mut out = [0u8; 3];
+        let mut channels = value.split(',');
+        for c in out.iter_mut() {
+            *c = channels.next()?.trim().parse().ok()?;
+        }
+
+        return channels.next().is_none().then_some(out);
+    }
+
+    let hex = value
+        .strip_prefix('#')
+        .or_else(|| value.strip_prefix("0x"))
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let v = u32::from_str_radix(hex, 16).ok()?;
+    Some([(v >> 16) as u8, (v >> 8) as u8, v as u8])
+}
+
+fn parse_slot(key: &str, bright_section: bool) -> Slot {
+    // drop resource prefixes like `URxvt*` or `*.`, and normalize `bright_black`/`brightBlack`/`bright-black`
+    let key = key
+        .rsplit(['*', '.'])
+        .next()
+        .unwrap_or(key)
+        .to_ascii_lowercase()
+        .replace(['_', '-', ' '], "");
+
+    match key.as_str() {
+        "foreground" => return Slot::Foreground,
+        "background" => return Slot::Background,
+        _ => {}
+    }
+
+    if let Some(idx) = key
+        .strip_prefix("color")
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        return Slot::Index(idx);
+    }
+
+    let (name, bright) = match key
+        .strip_prefix("bright")
+        .or_else(|| key.strip_prefix("light"))
+    {
+        Some(name) => (name, true),
+        None => (key.as_str(), bright_section),
+    };
+    let name = if name == "purple" { "magenta" } else { name };
+
+    match COLOR_NAMES.iter().position(|v| *v == name) {
+        Some(idx) => Slot::Index(idx + if bright { 8 } else { 0 }),
+        None => Slot::Ignored,
+    }
+}
+
+/// Parses a theme, starting from the xterm palette.
+pub fn parse_theme(contents: &str) -> Result<Palette, ThemeError> {
+    parse_theme_onto(&Palette::new(PALETTE), contents)
+}
+
+/// Parses a theme, overriding the colors of an existing palette.
+pub fn parse_theme_onto<'a>(base: &Palette, contents: &'a str) -> Result<Palette, ThemeError> {
+    let mut overrides = Vec::with_capacity(16);
+    let mut foreground = base.foreground;
+    let mut background = base.background;
+    let mut next_index = 0usize; // for plain lists
+    let mut section = Section::Normal;
+    let mut found = false;
+
+    for (line_no, line) in contents.lines().enumerate() {
+        let line_no = line_no + 1;
+        let line = line.trim();
+
+        if line.is_empty()
+            || line.starts_with('!')
+            || line.starts_with(';')
+            || line.starts_with("//")
+        {
+            continue;
+        }
+
+        // a bare color on its own line, in a plain list
+        if let Some(color) = parse_color(line) {
+            if next_index > 255 {
+                return Err(ThemeError::InvalidIndex(line_no, next_index));
+            }
+
+            overrides.push((next_index as u8, color));
+            next_index += 1;
+            found = true;
+            continue;
+        }
+
+        if line.starts_with('#') {
+            continue; // comment
+        }
+
+        // section headers: `[colors.bright]` in TOML, `bright:` in YAML
+        let header = line.trim_start_matches('[').trim_end_matches([']', ':']);
+        if line.starts_with('[') || (line.ends_with(':') && !header.contains(':')) {
+            section = match header.rsplit('.').next().unwrap_or(header).trim() {
+                "bright" => Section::Bright,
+                "dim" => Section::Dim,
+                _ => Section::Normal,
+            };
+            continue;
+        }
+
+        let split = line
+            .find(|c: char| c == ':' || c == '=' || c.is_whitespace())
+            .unwrap_or(line.len());
+        let (key, value) = line.split_at(split);
+
+        let key = key.trim().trim_matches('"');
+        let value = value
+            .trim_start_matches(|c: char| c == ':' || c == '=' || c.is_whitespace())
+            .trim_end_matches(',')
+            .trim();
+
+        let slot = match parse_slot(key, section == Section::Bright) {
+            // alacritty's dim colors would otherwise overwrite the normal ones
+            Slot::Index(_) if section == Section::Dim => continue,
+            Slot::Ignored => continue,
+            slot => slot,
+        };
+
+        // allow trailing comments after the color
+        let unquote = |v: &'a str| v.trim_matches(|c| c == '"' || c == '\'');
+        let color = parse_color(unquote(value))
+            .or_else(|| {
+                value
+                    .split_whitespace()
+                    .next()
+                    .and_then(|v| parse_color(unquote(v)))
+            })
+            .ok_or_else(|| ThemeError::InvalidColor(line_no, line.to_owned()))?;
+        found = true;
+
+        match slot {
+            Slot::Index(idx) if idx > 255 => return Err(ThemeError::InvalidIndex(line_no, idx)),
+            Slot::Index(idx) => overrides.push((idx as u8, color)),
+            Slot::Foreground => foreground = Some(color),
+            Slot::Background => background = Some(color),
+            Slot::Ignored => {}
+        }
+    }
+
+    if !found {
+        return Err(ThemeError::Empty);
+    }
+
+    let mut palette = base.with_overrides(overrides);
+    palette.foreground = foreground;
+    palette.background = background;
+    Ok(palette)
+}
+
+/// Loads a theme file, see [parse_theme] for the supported formats.
+pub fn load_theme(path: impl AsRef<Path>) -> Result<Palette, ThemeError> {
+    parse_theme(&std::fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TANGO: [[u8; 3]; 16] = [
+        [0x2e, 0x34, 0x36],
+        [0xcc, 0x00, 0x00],
+        [0x4e, 0x9a, 0x06],
+        [0xc4, 0xa0, 0x00],
+        [0x34, 0x65, 0xa4],
+        [0x75, 0x50, 0x7b],
+        [0x06, 0x98, 0x9a],
+        [0xd3, 0xd7, 0xcf],
+        [0x55, 0x57, 0x53],
+        [0xef, 0x29, 0x29],
+        [0x8a, 0xe2, 0x34],
+        [0xfc, 0xe9, 0x4f],
+        [0x72, 0x9f, 0xcf],
+        [0xad, 0x7f, 0xa8],
+        [0x34, 0xe2, 0xe2],
+        [0xee, 0xee, 0xec],
+    ];
+
+    fn fixture(name: &str) -> Palette {
+        let path = format!("{}/tests/themes/{name}", env!("CARGO_MANIFEST_DIR"));
+        load_theme(&path).unwrap_or_else(|e| panic!("{name}: {e}"))
+    }
+
+    #[test]
+    fn every_format_loads_the_same_theme() {
+        for name in [
+            "tango.Xresources",
+            "tango.conf",
+            "tango.toml",
+            "tango.yml",
+            "tango.json",
+        ] {
+            let palette = fixture(name);
+            assert_eq!(palette.colors()[..16], TANGO, "{name}");
+            assert_eq!(palette.colors()[16..], PALETTE[16..], "{name}");
+            assert_eq!(palette.foreground, Some([0xd3, 0xd7, 0xcf]), "{name}");
+            assert_eq!(palette.background, Some([0x2e, 0x34, 0x36]), "{name}");
+        }
+
+        // plain lists have no way to say which color is the foreground or background
+        let palette = fixture("tango.txt");
+        assert_eq!(palette.colors()[..16], TANGO);
+        assert_eq!((palette.foreground, palette.background), (None, None));
+    }
+
+    #[test]
+    fn missing_entries_keep_their_defaults() {
+        let palette = parse_theme("color4 #3465a4\nbrightRed: '#ef2929'").unwrap();
+        for (i, color) in palette.colors().iter().enumerate() {
+            let expected = match i {
+                4 => [0x34, 0x65, 0xa4],
+                9 => [0xef, 0x29, 0x29],
+                _ => PALETTE[i],
+            };
+            assert_eq!(*color, expected, "color {i}");
+        }
+        assert_eq!((palette.foreground, palette.background), (None, None));
+
+        // and the colors of a palette it's loaded onto, rather than xterm's
+        let base = Palette::new(PALETTE).with_overrides([(1, [1, 2, 3])]);
+        assert_eq!(
+            parse_theme_onto(&base, "color4 #3465a4").unwrap().get(1),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn malformed_colors_are_errors() {
+        for (theme, line) in [
+            ("color1 #cc0000\ncolor4 #3465a", 2),
+            ("*.color4: #gggggg", 1),
+            ("blue = '0x3465a4ff'", 1),
+            ("foreground rgb:d3/d7", 1),
+            ("\n\nbrightBlue: 300,0,0", 3),
+        ] {
+            match parse_theme(theme) {
+                Err(ThemeError::InvalidColor(n, _)) => assert_eq!(n, line, "{theme:?}"),
+                other => panic!("{theme:?}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn indexes_past_the_palette_are_errors() {
+        assert!(matches!(
+            parse_theme("color256 #000000"),
+            Err(ThemeError::InvalidIndex(1, 256))
+        ));
+
+        let list = "#000000\n".repeat(257);
+        assert!(matches!(
+            parse_theme(&list),
+            Err(ThemeError::InvalidIndex(257, 256))
+        ));
+    }
+
+    #[test]
+    fn unknown_formats_find_nothing() {
+        // iTerm2's plist: nothing to go on, line by line
+        let plist = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<plist version=\"1.0\">\n<dict>\n\
+            \t<key>Ansi 0 Color</key>\n\t<dict>\n\t\t<key>Red Component</key>\n\t\t<real>0.18</real>\n\
+            \t</dict>\n</dict>\n</plist>";
+        for theme in ["", "# just a comment\n! and another", plist] {
+            assert!(
+                matches!(parse_theme(theme), Err(ThemeError::Empty)),
+                "{theme:?}"
+            );
+        }
+
+        assert!(matches!(
+            load_theme("/nonexistent/theme"),
+            Err(ThemeError::Io(_))
+        ));
+    }
+}
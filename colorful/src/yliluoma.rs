@@ -0,0 +1,394 @@
+//! Yliluoma's ordered dithering algorithms, from the same article as [crate::pattern_dithering]:
+//! https://bisqwit.iki.fi/story/howto/dither/jy/
+//!
+//! Every pixel gets a mixing plan: palette colors, sorted by luma, whose average comes as close to it as possible.
+//! The threshold map then picks one of them. Colors are mixed and compared in the distance method's perceptual space,
+//! with signed error, so that colors darker or more saturated than their neighbours in the palette don't bias the plan
+//! the way the Knoll algorithm's clamped error does.
+//!
+//! The original algorithms search 16 color palettes; with 256 colors, plans are made from the colors closest to the pixel.
+
+use std::fmt::Display;
+
+use crate::palette::{AnsiColorMap, DistanceMethod};
+use crate::pattern_dithering::{self, to_luma, MatrixSize};
+use arrayvec::ArrayVec;
+use image::RgbImage;
+use num_enum::TryFromPrimitive;
+use rayon::prelude::*;
+
+/// Palette colors considered for every plan.
+const CANDIDATES: usize = 16;
+
+/// Largest number of colors in a plan, which is the number of levels in the largest threshold map.
+const MAX_PLAN: usize = 64;
+
+/// Algorithm 3 doesn't mix colors further apart in luma than this, as those make for visible noise.
+const MAX_LUMA_DIFFERENCE: f32 = 80000.0 / 255000.0;
+
+#[derive(TryFromPrimitive, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Algorithm {
+    /// Mixes two colors, penalizing pairs that are far apart.
+    One = 0,
+    /// Adds colors to the plan one at a time, each bringing the average closest to the pixel.
+    Two = 1,
+    /// Starts from the closest color and keeps splitting plan entries in two colors while that gets closer. Slowest, best.
+    Three = 2,
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Algorithm::One => write!(f, "yliluoma 1"),
+            Algorithm::Two => write!(f, "yliluoma 2"),
+            Algorithm::Three => write!(f, "yliluoma 3"),
+        }
+    }
+}
+
+type Color = (f32, f32, f32);
+
+#[inline(always)]
+fn distance(a: Color, b: Color) -> f32 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)
+}
+
+#[inline(always)]
+fn mix(a: Color, b: Color, ratio: f32) -> Color {
+    (
+        a.0 + (b.0 - a.0) * ratio,
+        a.1 + (b.1 - a.1) * ratio,
+        a.2 + (b.2 - a.2) * ratio,
+    )
+}
+
+/// The palette colors closest to a color.
+fn candidates(space: &[Color], color: Color) -> ArrayVec<u8, CANDIDATES> {
+    let mut distances: ArrayVec<(f32, u8), 256> = space
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (distance(color, *c), i as u8))
+        .collect();
+
+    let nth = CANDIDATES.min(distances.len()) - 1;
+    distances.select_nth_unstable_by(nth, |a, b| a.0.total_cmp(&b.0));
+    distances[..=nth].iter().map(|v| v.1).collect()
+}
+
+fn plan_one(
+    space: &[Color],
+    candidates: &[u8],
+    color: Color,
+    levels: usize,
+    plan: &mut ArrayVec<u8, MAX_PLAN>,
+) {
+    let mut best = (f32::INFINITY, candidates[0], candidates[0], 0);
+
+    for (n, &first) in candidates.iter().enumerate() {
+        for &second in &candidates[n..] {
+            let (a, b) = (space[first as usize], space[second as usize]);
+            let spread = distance(a, b);
+
+            for ratio in 0..levels {
+                if first == second && ratio != 0 {
+                    break;
+                }
+
+                let portion = ratio as f32 / levels as f32;
+                let penalty = distance(color, mix(a, b, portion))
+                    + spread * 0.1 * ((portion - 0.5).abs() + 0.5);
+                if penalty < best.0 {
+                    best = (penalty, first, second, ratio);
+                }
+            }
+        }
+    }
+
+    let (_, first, second, ratio) = best;
+    plan.extend((0..levels).map(|i| if i < ratio { second } else { first }));
+}
+
+fn plan_two(
+    space: &[Color],
+    candidates: &[u8],
+    color: Color,
+    levels: usize,
+    plan: &mut ArrayVec<u8, MAX_PLAN>,
+) {
+    let mut sum = (0.0, 0.0, 0.0);
+
+    while plan.len() < levels {
+        let count = plan.len();
+        // try adding every candidate 1, 2, 4... times, up to doubling the plan
+        let max_amount = count.max(1).min(levels - count);
+
+        let mut best = (f32::INFINITY, candidates[0], 1);
+        for &idx in candidates {
+            let c = space[idx as usize];
+            let mut amount = 1;
+            while amount <= max_amount {
+                let total = (count + amount) as f32;
+                let test = (
+                    (sum.0 + c.0 * amount as f32) / total,
+                    (sum.1 + c.1 * amount as f32) / total,
+                    (sum.2 + c.2 * amount as f32) / total,
+                );
+
+                let penalty = distance(color, test);
+                if penalty < best.0 {
+                    best = (penalty, idx, amount);
+                }
+                amount *= 2;
+            }
+        }
+
+        let (_, idx, amount) = best;
+        let c = space[idx as usize];
+        sum = (
+            sum.0 + c.0 * amount as f32,
+            sum.1 + c.1 * amount as f32,
+            sum.2 + c.2 * amount as f32,
+        );
+        plan.extend(std::iter::repeat_n(idx, amount));
+    }
+}
+
+fn plan_three(
+    space: &[Color],
+    lumas: &[f32],
+    candidates: &[u8],
+    color: Color,
+    levels: usize,
+    plan: &mut ArrayVec<u8, MAX_PLAN>,
+) {
+    let closest = *candidates
+        .iter()
+        .min_by(|a, b| {
+            distance(color, space[**a as usize]).total_cmp(&distance(color, space[**b as usize]))
+        })
+        .unwrap();
+
+    // (palette index, how many entries of the plan it makes up)
+    let mut solution: ArrayVec<(u8, usize), MAX_PLAN> = ArrayVec::new();
+    solution.push((closest, levels));
+    let mut penalty = distance(color, space[closest as usize]);
+    let scale = 1.0 / levels as f32;
+
+    while penalty > 0.0 {
+        // find the entry that gets closest to the pixel when split in two colors
+        let mut best: Option<(f32, usize, u8, u8)> = None;
+
+        for (split, &(_, split_count)) in solution.iter().enumerate() {
+            let mut rest = (0.0, 0.0, 0.0);
+            for (j, &(idx, count)) in solution.iter().enumerate() {
+                if j != split {
+                    let c = space[idx as usize];
+                    let portion = count as f32 * scale;
+                    rest = (
+                        rest.0 + c.0 * portion,
+                        rest.1 + c.1 * portion,
+                        rest.2 + c.2 * portion,
+                    );
+                }
+            }
+
+            let portion1 = (split_count / 2) as f32 * scale;
+            let portion2 = (split_count - split_count / 2) as f32 * scale;
+            let mut consider = |a: u8, b: u8| {
+                let (ca, cb) = (space[a as usize], space[b as usize]);
+                let test = (
+                    rest.0 + ca.0 * portion1 + cb.0 * portion2,
+                    rest.1 + ca.1 * portion1 + cb.1 * portion2,
+                    rest.2 + ca.2 * portion1 + cb.2 * portion2,
+                );
+
+                let test_penalty = distance(color, test);
+                if test_penalty < best.map(|v| v.0).unwrap_or(penalty) {
+                    best = Some((test_penalty, split, a, b));
+                }
+            };
+
+            // a single entry can't be split, only replaced
+            if split_count == 1 {
+                for &b in candidates {
+                    consider(b, b);
+                }
+                continue;
+            }
+
+            for (n, &a) in candidates.iter().enumerate() {
+                // with equal portions, (a, b) and (b, a) are the same mix
+                let from = if split_count % 2 == 0 { n + 1 } else { 0 };
+                for &b in &candidates[from..] {
+                    if a != b
+                        && (lumas[a as usize] - lumas[b as usize]).abs() <= MAX_LUMA_DIFFERENCE
+                    {
+                        consider(a, b);
+                    }
+                }
+            }
+        }
+
+        let (new_penalty, split, a, b) = match best {
+            Some(v) => v,
+            None => break,
+        };
+
+        let (_, split_count) = solution.remove(split);
+        for (idx, count) in [(a, split_count / 2), (b, split_count - split_count / 2)] {
+            if count == 0 {
+                continue;
+            }
+
+            match solution.iter_mut().find(|v| v.0 == idx) {
+                Some(entry) => entry.1 += count,
+                None => solution.push((idx, count)),
+            }
+        }
+
+        penalty = new_penalty;
+    }
+
+    for (idx, count) in solution {
+        plan.extend(std::iter::repeat_n(idx, count));
+    }
+}
+
+/// Dithers an image to palette indices.
+pub fn dither<T: DistanceMethod + Send + Sync>(
+    image: &RgbImage,
+    algorithm: Algorithm,
+    matrix_size: MatrixSize,
+    color_map: &AnsiColorMap<T>,
+) -> Vec<u8> {
+    dither_reusing(image, algorithm, matrix_size, color_map, None)
+}
+
+/// Like [dither], but pixels with an index in `reused` (see [crate::temporal]) keep it instead of being dithered.
+pub fn dither_reusing<T: DistanceMethod + Send + Sync>(
+    image: &RgbImage,
+    algorithm: Algorithm,
+    matrix_size: MatrixSize,
+    color_map: &AnsiColorMap<T>,
+    reused: Option<&[Option<u8>]>,
+) -> Vec<u8> {
+    let width = image.width() as usize;
+    let palette = color_map.palette();
+    let space = T::palette_space(palette);
+    let lumas: Vec<f32> = palette.colors().iter().map(|c| to_luma(*c)).collect();
+
+    let mut out: Vec<u8> = vec![0; width * image.height() as usize];
+    out.par_iter_mut().enumerate().for_each(|(i, pixel_out)| {
+        if let Some(idx) = reused.and_then(|r| r[i]) {
+            *pixel_out = idx;
+            return;
+        }
+
+        let (x, y) = (i % width, i / width);
+        let pixel = image.get_pixel(x as u32, y as u32);
+        let color = T::to_space(&pixel.0);
+        let candidates = candidates(space, color);
+        let (threshold, levels) = pattern_dithering::threshold(matrix_size, x, y);
+
+        let mut plan = ArrayVec::new();
+        match algorithm {
+            Algorithm::One => plan_one(space, &candidates, color, levels, &mut plan),
+            Algorithm::Two => plan_two(space, &candidates, color, levels, &mut plan),
+            Algorithm::Three => plan_three(space, &lumas, &candidates, color, levels, &mut plan),
+        }
+
+        plan.sort_by(|a, b| lumas[*a as usize].total_cmp(&lumas[*b as usize]));
+        *pixel_out = plan[threshold];
+    });
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linear;
+    use crate::palette::CIE76;
+    use image::Rgb;
+    use lab::Lab;
+
+    /// The color a dithered patch averages out to from a distance, which happens in linear light.
+    fn seen_as(indices: &[u8], color_map: &AnsiColorMap<CIE76>) -> Lab {
+        let mut sum = [0.0f32; 3];
+        for idx in indices {
+            let c = linear::to_linear_rgb(&color_map.palette().get(*idx as usize));
+            sum = [sum[0] + c[0], sum[1] + c[1], sum[2] + c[2]];
+        }
+
+        Lab::from_rgb(&linear::from_linear_rgb(
+            sum.map(|v| v / indices.len() as f32),
+        ))
+    }
+
+    #[test]
+    fn dark_saturated_patches_are_less_biased_than_knoll() {
+        let color_map = AnsiColorMap::<CIE76>::new();
+
+        for color in [[40, 10, 60], [30, 30, 50]] {
+            let image = RgbImage::from_pixel(16, 16, Rgb(color));
+            let wanted = Lab::from_rgb(&color);
+
+            // knoll drops error from candidates brighter than the pixel, so its plans only ever get brighter
+            let knoll = seen_as(
+                &pattern_dithering::dither(&image, MatrixSize::Eight, 0.09, &color_map),
+                &color_map,
+            );
+            let knoll_error = knoll.squared_distance(&wanted).sqrt();
+            assert!(knoll.l - wanted.l > 10.0, "{:?}: knoll isn't biased", color);
+
+            for algorithm in [Algorithm::One, Algorithm::Two, Algorithm::Three] {
+                let seen = seen_as(
+                    &dither(&image, algorithm, MatrixSize::Eight, &color_map),
+                    &color_map,
+                );
+                let error = seen.squared_distance(&wanted).sqrt();
+
+                assert!(
+                    error < knoll_error * 0.75,
+                    "{:?}: {} is off by ΔE {}, knoll by {}",
+                    color,
+                    algorithm,
+                    error,
+                    knoll_error
+                );
+                assert!(
+                    (seen.l - wanted.l).abs() < knoll.l - wanted.l,
+                    "{:?}: {} is brighter by {}, knoll by {}",
+                    color,
+                    algorithm,
+                    seen.l - wanted.l,
+                    knoll.l - wanted.l
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn palette_colors_dither_to_themselves() {
+        let color_map = AnsiColorMap::<CIE76>::new();
+        for idx in [0u8, 21, 88, 160, 232, 244] {
+            let image = RgbImage::from_pixel(8, 8, Rgb(color_map.palette().get(idx as usize)));
+            for algorithm in [Algorithm::One, Algorithm::Two, Algorithm::Three] {
+                let out = dither(&image, algorithm, MatrixSize::Eight, &color_map);
+                let colors: Vec<[u8; 3]> = out
+                    .iter()
+                    .map(|i| color_map.palette().get(*i as usize))
+                    .collect();
+                assert!(
+                    colors
+                        .iter()
+                        .all(|c| *c == color_map.palette().get(idx as usize)),
+                    "{} with {}",
+                    idx,
+                    algorithm
+                );
+            }
+        }
+    }
+}
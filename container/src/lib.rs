@@ -86,6 +86,8 @@ pub mod packet_flags {
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub enum VideoPacketFlags {
         Keyframe,
+        /// the packet holds OSC 4 sequences redefining palette colors, to be applied before the next frame
+        Palette,
     }
 }
 
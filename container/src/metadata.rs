@@ -2,7 +2,7 @@ use derive_builder::Builder;
 
 use num_enum::TryFromPrimitive;
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use std::{fmt, time::Duration};
 
 #[derive(TryFromPrimitive, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Hash)]
 #[repr(u8)]
@@ -72,6 +72,60 @@ impl fmt::Display for SubtitleFormat {
     }
 }
 
+/// When the encoder forces a keyframe instead of picking whichever of keyframe/diff is smaller.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KeyframeSettings {
+    pub max_interval: Option<Duration>, // longest stretch of presentation time without a keyframe
+    pub max_frames: Option<u32>,        // most frames between two keyframes
+    pub scene_cut_threshold: Option<f32>, // fraction of changed cells (0.0 - 1.0) that counts as a scene cut
+}
+
+impl Default for KeyframeSettings {
+    fn default() -> Self {
+        Self {
+            max_interval: Some(Duration::from_secs(10)),
+            max_frames: None,
+            scene_cut_threshold: Some(0.6),
+        }
+    }
+}
+
+impl fmt::Display for KeyframeSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rules = Vec::new();
+        if let Some(interval) = self.max_interval {
+            rules.push(format!("every {:.1}s", interval.as_secs_f64()));
+        }
+        if let Some(frames) = self.max_frames {
+            rules.push(format!("every {} frames", frames));
+        }
+        if let Some(threshold) = self.scene_cut_threshold {
+            rules.push(format!("on scene cuts ({:.0}% changed)", threshold * 100.0));
+        }
+
+        if rules.is_empty() {
+            write!(f, "smallest size")
+        } else {
+            write!(f, "{}", rules.join(", "))
+        }
+    }
+}
+
+/// Part of the source picture a track shows, in source pixels.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32, // left edge
+    pub y: u32, // top edge
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}:{}", self.width, self.height, self.x, self.y)
+    }
+}
+
 #[derive(Builder, Serialize, Deserialize, Debug, Clone)]
 pub struct VideoTrack {
     #[builder(default)]
@@ -82,6 +136,15 @@ pub struct VideoTrack {
     #[builder(default)]
     pub codec_private: Option<Vec<u8>>,
     pub index: u16,
+    #[builder(default)]
+    #[serde(default)]
+    pub keyframes: Option<KeyframeSettings>, // keyframe placement used by the encoder, if known
+    #[builder(default)]
+    #[serde(default)]
+    pub adaptive_palette: bool, // whether the track redefines palette colors (OSC 4) as it plays
+    #[builder(default)]
+    #[serde(default)]
+    pub crop: Option<Crop>, // part of the source the track shows, if the encoder cropped it
 }
 
 #[derive(Serialize, Deserialize, Debug, Builder, Clone)]
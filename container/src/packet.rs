@@ -76,6 +76,11 @@ pub trait ToPacket {
     type Err;
 
     fn encode_packet(&mut self, src: Self::Source) -> Result<Packet<BytesMut>, Self::Err>;
+
+    /// Packets that have to be sent before the one last returned by [ToPacket::encode_packet], e.g. state it depends on.
+    fn take_preceding(&mut self) -> Option<Packet<BytesMut>> {
+        None
+    }
 }
 
 /// A transformer that takes a [Packet] and converts it into an object if possible; else returning none.
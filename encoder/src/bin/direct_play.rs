@@ -2,13 +2,15 @@ use clap::clap_app;
 
 use futures::{FutureExt, StreamExt};
 use player::subtitles::SSAParser;
-use player::{play, PacketFilterTransformer};
+use player::terminal::SyncMode;
+use player::{play, PacketFilterTransformer, PlayOptions};
 use tokio_stream::wrappers::ReceiverStream;
 
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use bytes::{Bytes, BytesMut};
-use container::metadata::{ColorMode, SubtitleFormat};
+use container::metadata::SubtitleFormat;
 use container::packet::*;
 use encoder::tool_utils::*;
 use encoder::video_encoder::*;
@@ -22,10 +24,16 @@ fn main() -> anyhow::Result<()> {
         (@arg INPUT: +required "input file or url for the encoder")
         (@arg SHOW_SSA_NAMES: --subtitle_names "show subtitle entry character names in ssa subtitles (not always used)")
         (@arg SHOW_SSA_LAYERS: --ssa_layer ... +takes_value "ssa layers to show (all if not passed)")
+        (@arg PALETTE: --palette +takes_value "terminal theme or palette file to dither 256 color tracks for, or 'terminal' to ask the current terminal (default xterm colors)")
+        (@arg LUT: --lut +takes_value "bits per channel of a color lookup table to dither with instead of searching the palette for every color, or 'on' for 6 (default off)")
+        (@arg LUT_CACHE: --("lut-cache") +takes_value "directory to keep color lookup tables in across runs")
+        (@arg VIDEO_TRACK: --("video-track") +takes_value "the video track to play, as comma separated key:value pairs (e.g. color:256,width:192,height:108,dither:pattern); asked for if not passed")
+        (@arg SUBTITLE_TRACK: --("subtitle-track") +takes_value "subtitles to show along with --video-track, as comma separated key:value pairs (stream, name)")
+        (@arg SYNC: --sync +takes_value "wrap frames in synchronized updates to avoid tearing: auto (ask the terminal), on or off [default: auto]")
     )
     .get_matches();
 
-    let ssa_layers = matches
+    let mut ssa_layers = matches
         .values_of("SHOW_SSA_LAYERS")
         .map(|s| {
             s.filter_map(|v| v.parse::<isize>().ok())
@@ -33,6 +41,14 @@ fn main() -> anyhow::Result<()> {
         })
         .unwrap_or_default();
     let show_ssa_names = matches.is_present("SHOW_SSA_NAMES");
+    let sync_mode = matches
+        .value_of("SYNC")
+        .unwrap_or("auto")
+        .parse::<SyncMode>()
+        .map_err(anyhow::Error::msg)?;
+    let palette = cli::load_palette(matches.value_of("PALETTE"))?;
+    let lookup_table = cli::lookup_table_bits(matches.value_of("LUT"))?;
+    let lookup_table_cache = matches.value_of("LUT_CACHE").map(PathBuf::from);
 
     let theme = dialoguer::theme::ColorfulTheme::default();
     let ff_source = FFMpegSource::open_url(matches.value_of("INPUT").unwrap())?;
@@ -47,6 +63,7 @@ fn main() -> anyhow::Result<()> {
                 source_kind: kind,
                 codec_name: stream.codec_parameters().decoder_name(),
                 title: stream.get_metadata("title"),
+                language: stream.get_metadata("language"),
             };
 
             if meta.source_kind == SourceKind::Video {
@@ -57,19 +74,40 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
-    let mut tracks: Vec<AnsiTrack> = Vec::with_capacity(2);
     let mut video_tracks = Vec::new();
     let mut subtitle_tracks = Vec::new();
 
-    tracks.push(cli::select_video_track(&video_sources, 0)?);
+    let tracks: Vec<AnsiTrack> = match matches.value_of("VIDEO_TRACK") {
+        Some(spec) => cli::parse_tracks(
+            &[spec],
+            &matches
+                .value_of("SUBTITLE_TRACK")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            &video_sources,
+            &subtitle_sources,
+            0,
+        )?,
+        None => {
+            anyhow::ensure!(
+                !matches.is_present("SUBTITLE_TRACK"),
+                "--subtitle-track needs a --video-track to go with it"
+            );
+
+            let mut tracks = Vec::with_capacity(2);
+            tracks.push(cli::select_video_track(&video_sources, 0)?);
+
+            if dialoguer::Select::with_theme(&theme)
+                .item("add subtitles")
+                .item("finish & play")
+                .interact()?
+                == 0
+            {
+                tracks.push(cli::select_subtitle_track(&subtitle_sources, 1)?);
+            };
 
-    if dialoguer::Select::with_theme(&theme)
-        .item("add subtitles")
-        .item("finish & play")
-        .interact()?
-        == 0
-    {
-        tracks.push(cli::select_subtitle_track(&subtitle_sources, 1)?);
+            tracks
+        }
     };
 
     let rt = tokio::runtime::Runtime::new().unwrap();
@@ -83,40 +121,53 @@ fn main() -> anyhow::Result<()> {
 
     for track in tracks.iter().cloned() {
         match track {
-            AnsiTrack::VideoTrack(t) => {
+            AnsiTrack::VideoTrack(mut t) => {
+                cli::check_palette(&t.dither_mode.method, &palette)?;
+                t.dither_mode.palette = palette.clone();
+                t.dither_mode.lookup_table = lookup_table;
+                t.dither_mode.lookup_table_cache = lookup_table_cache.clone();
+
+                if t.auto_crop && t.crop.is_none() {
+                    let mut sampled = FFMpegSource::open_url(matches.value_of("INPUT").unwrap())?;
+                    t.crop = encoder::crop::detect_crop(&mut sampled, t.source_stream_index)?;
+                }
+
+                let stream = &ff_source.streams()[t.source_stream_index];
+                let (source_width, source_height) = video_size(stream).unwrap();
+                let layout =
+                    t.fit_to_source(display_aspect_ratio(stream), source_width, source_height);
                 let decoder = FFMpegVideoDecoder::from_stream(
-                    &ff_source.streams()[t.source_stream_index],
-                    ac_ffmpeg::codec::video::scaler::Algorithm::Lanczos,
-                    t.track_width,
-                    t.track_height,
+                    stream,
+                    t.scaler.algorithm(),
+                    layout.width,
+                    layout.height,
                 )
+                .and_then(|d| d.with_linear_light(t.linear_light))
+                .and_then(|d| d.with_downscale(t.downscale))
+                .and_then(|d| d.with_source_color(&t.source_color))
+                .and_then(|d| d.with_crop(t.crop))
+                .map(|d| {
+                    d.with_padding(
+                        layout.frame_width,
+                        layout.frame_height,
+                        t.padding.unwrap_or_default(),
+                    )
+                })
                 .unwrap();
 
-                let encoder = FrameEncoder {
-                    stream_index: t.track_id as u16,
-                    width: t.track_width as u32,
-                    height: t.track_height as u32,
-                    color: t.color_mode,
-                    use_diffing: false,
-                    last_frame: None,
-                };
+                let encoder = FrameEncoder::new(
+                    t.track_id as u16,
+                    t.track_width as u32,
+                    t.track_height as u32,
+                    t.color_mode,
+                )
+                .with_keyframes(t.keyframes);
 
-                match t.color_mode {
-                    ColorMode::True => {
-                        pipes.push(pipeline! {
-                            receive from source_packet_receiver;
-                            send to encoded_packet_tx;
-                            stream t.source_stream_index => decoder => passthrough => encoder
-                        });
-                    }
-                    ColorMode::EightBit => {
-                        pipes.push(pipeline! {
-                            receive from source_packet_receiver;
-                            send to encoded_packet_tx;
-                            stream t.source_stream_index => decoder => t.dither_mode.build() => encoder
-                        });
-                    }
-                }
+                pipes.push(pipeline! {
+                    receive from source_packet_receiver;
+                    send to encoded_packet_tx;
+                    stream t.source_stream_index => decoder => t.build_processor() => encoder
+                });
 
                 video_tracks.push(container::metadata::VideoTrack {
                     name: Some(t.track_name.clone()),
@@ -125,9 +176,15 @@ fn main() -> anyhow::Result<()> {
                     width: t.track_width as u32,
                     codec_private: None,
                     index: t.track_id as u16,
+                    keyframes: Some(t.keyframes),
+                    adaptive_palette: t.uses_adaptive_palette(),
+                    crop: t.crop,
                 })
             }
             AnsiTrack::SubtitleTrack(t) => {
+                // layers from the track spec add to --ssa_layer
+                ssa_layers.extend(t.layers.iter().copied());
+
                 pipes.push(pipeline! {
                     receive from source_packet_receiver;
                     send to encoded_packet_tx;
@@ -217,6 +274,11 @@ fn main() -> anyhow::Result<()> {
 
     let router = route_source(source_packet_pipe, ff_source, pipes);
 
+    // ask the terminal only once the prompts are done, so its reply can't get mixed up with their input
+    let play_options = PlayOptions {
+        synchronized_update: sync_mode.enabled(),
+    };
+
     rt.block_on(async move {
         let (video_tx, video_rx) = tokio::sync::mpsc::channel::<Packet<Bytes>>(255);
         let (subtitle_tx, subtitle_rx) = tokio::sync::mpsc::channel::<Packet<Bytes>>(8000);
@@ -225,9 +287,10 @@ fn main() -> anyhow::Result<()> {
             ReceiverStream::new(video_rx),
             ReceiverStream::new(subtitle_rx)
                 .filter_map(move |f| futures::future::ready(subtitle_mapper.filter_map_packet(f))),
+            play_options,
         ));
 
-        let sender_task = tokio::task::spawn(async move {
+        tokio::task::spawn(async move {
             while let Some(packet) = encoded_packet_rx.recv().await {
                 let packet = packet.freeze();
 
@@ -239,8 +302,13 @@ fn main() -> anyhow::Result<()> {
             }
         });
 
-        tokio::join!(router, player_task, sender_task);
+        tokio::task::spawn(router);
+
+        // playback ends on ctrl-c too, in which case decoding is simply abandoned
+        let _ = player_task.await;
     });
 
+    rt.shutdown_background();
+
     Ok(())
 }
@@ -2,11 +2,13 @@ use clap::clap_app;
 
 use futures::FutureExt;
 
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use bytes::BytesMut;
-use container::metadata::{ColorMode, CompressionMode, SubtitleFormat};
+use container::metadata::{CompressionMode, SubtitleFormat};
 use container::packet::*;
+use encoder::job::{Job, JobTrack};
 use encoder::tool_utils::*;
 use encoder::video_encoder::*;
 use encoder::*;
@@ -17,21 +19,82 @@ fn main() -> anyhow::Result<()> {
     let matches = clap_app!(encoder =>
         (version: "1.0")
         (author: "emily signet <emily@cat-girl.gay>")
-        (@arg INPUT: +required "input file or url for the encoder")
-        (@arg OUTPUT: -o --output +takes_value +required "output file")
+        (@arg INPUT: required_unless[JOB] "input file or url for the encoder")
+        (@arg OUTPUT: -o --output +takes_value required_unless[JOB] "output file")
+        (@arg JOB: --job +takes_value "TOML or JSON job file to take the input, output, settings and tracks from; flags override it")
+        (@arg SAVE_JOB: --("save-job") +takes_value "write the settings and tracks of this encode to a job file (JSON if it ends in .json, TOML otherwise)")
         (@arg COMPRESSION_LEVEL: --compression +takes_value)
         (@arg NOCOMPRESSION: --no-compress)
+        (@arg KEYFRAME_INTERVAL: --("keyframe-interval") +takes_value "maximum seconds between keyframes, or 'off' (default 10)")
+        (@arg KEYFRAME_FRAMES: --("keyframe-frames") +takes_value "maximum frames between keyframes, or 'off' (default off)")
+        (@arg SCENE_CUT: --("scene-cut") +takes_value "fraction of changed cells that forces a keyframe, or 'off' (default 0.6)")
+        (@arg PALETTE: --palette +takes_value "terminal theme or palette file to dither 256 color tracks for, or 'terminal' to ask the current terminal (default xterm colors)")
+        (@arg LUT: --lut +takes_value "bits per channel of a color lookup table to dither with instead of searching the palette for every color, or 'on' for 6 (default off)")
+        (@arg LUT_CACHE: --("lut-cache") +takes_value "directory to keep color lookup tables in across runs")
+        (@arg VIDEO_TRACK: --("video-track") ... +takes_value number_of_values(1) "add a video track, as comma separated key:value pairs (e.g. color:256,width:192,height:108,dither:pattern); tracks are only asked for if none are passed")
+        (@arg SUBTITLE_TRACK: --("subtitle-track") ... +takes_value number_of_values(1) "add a subtitle track, as comma separated key:value pairs (stream, name, layer)")
+        (@arg METRICS: --metrics +takes_value "file to log every video frame's PSNR, SSIM and ΔE against its source to, along with per-track averages")
     )
     .get_matches();
 
-    let compression_level = matches
-        .value_of("COMPRESSION_LEVEL")
-        .and_then(|v| v.parse::<i32>().ok())
-        .unwrap_or(5);
-    let compress = !matches.is_present("NOCOMPRESSION");
+    let mut job = match matches.value_of("JOB") {
+        Some(path) => Job::load(path)?,
+        None => Job::default(),
+    };
+
+    if let Some(v) = matches.value_of("INPUT") {
+        job.input = v.to_string();
+    }
+    if let Some(v) = matches.value_of("OUTPUT") {
+        job.output = PathBuf::from(v);
+    }
+    if let Some(v) = matches.value_of("COMPRESSION_LEVEL") {
+        job.compression_level = v.parse::<i32>()?;
+    }
+    if matches.is_present("NOCOMPRESSION") {
+        job.compress = false;
+    }
+    for (flag, setting) in [
+        ("KEYFRAME_INTERVAL", &mut job.keyframe_interval),
+        ("KEYFRAME_FRAMES", &mut job.keyframe_frames),
+        ("SCENE_CUT", &mut job.scene_cut),
+        ("PALETTE", &mut job.palette),
+        ("LUT", &mut job.lut),
+    ] {
+        if let Some(v) = matches.value_of(flag) {
+            *setting = Some(v.to_string());
+        }
+    }
+    if let Some(v) = matches.value_of("LUT_CACHE") {
+        job.lut_cache = Some(PathBuf::from(v));
+    }
+    if let Some(v) = matches.value_of("METRICS") {
+        job.metrics = Some(PathBuf::from(v));
+    }
+
+    anyhow::ensure!(!job.input.is_empty(), "the job file has no input");
+    anyhow::ensure!(
+        !job.output.as_os_str().is_empty(),
+        "the job file has no output"
+    );
+
+    let keyframes = cli::keyframe_settings(
+        job.keyframe_interval.as_deref(),
+        job.keyframe_frames.as_deref(),
+        job.scene_cut.as_deref(),
+    )?;
+    let palette = cli::load_palette(job.palette.as_deref())?;
+    let lookup_table = cli::lookup_table_bits(job.lut.as_deref())?;
+    let lookup_table_cache = job.lut_cache.clone();
+    let quality_report = job
+        .metrics
+        .as_ref()
+        .map(QualityReport::create)
+        .transpose()?
+        .map(Arc::new);
 
     let theme = dialoguer::theme::ColorfulTheme::default();
-    let ff_source = FFMpegSource::open_url(matches.value_of("INPUT").unwrap())?;
+    let ff_source = FFMpegSource::open_url(&job.input)?;
 
     let mut video_sources = Vec::new();
     let mut subtitle_sources = Vec::new();
@@ -43,6 +106,7 @@ fn main() -> anyhow::Result<()> {
                 source_kind: kind,
                 codec_name: stream.codec_parameters().decoder_name(),
                 title: stream.get_metadata("title"),
+                language: stream.get_metadata("language"),
             };
 
             if meta.source_kind == SourceKind::Video {
@@ -53,29 +117,53 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
-    let mut tracks: Vec<AnsiTrack> = Vec::with_capacity(2);
-
-    loop {
-        match dialoguer::Select::with_theme(&theme)
-            .item("add video track")
-            .item("add subtitle track")
-            .item("finalize & render")
-            .interact()?
-        {
-            0 => {
-                tracks.push(cli::select_video_track(&video_sources, tracks.len() + 1)?);
-            }
-            1 => {
-                tracks.push(cli::select_subtitle_track(
-                    &subtitle_sources,
-                    tracks.len() + 1,
-                )?);
+    let specs_given = matches.is_present("VIDEO_TRACK") || matches.is_present("SUBTITLE_TRACK");
+    let mut tracks = if specs_given || job.tracks.is_empty() {
+        cli::parse_tracks(
+            &matches
+                .values_of("VIDEO_TRACK")
+                .map(|v| v.collect::<Vec<_>>())
+                .unwrap_or_default(),
+            &matches
+                .values_of("SUBTITLE_TRACK")
+                .map(|v| v.collect::<Vec<_>>())
+                .unwrap_or_default(),
+            &video_sources,
+            &subtitle_sources,
+            1,
+        )?
+    } else {
+        job.resolve_tracks(&video_sources, &subtitle_sources)?
+    };
+
+    if tracks.is_empty() {
+        loop {
+            match dialoguer::Select::with_theme(&theme)
+                .item("add video track")
+                .item("add subtitle track")
+                .item("finalize & render")
+                .interact()?
+            {
+                0 => {
+                    tracks.push(cli::select_video_track(&video_sources, tracks.len() + 1)?);
+                }
+                1 => {
+                    tracks.push(cli::select_subtitle_track(
+                        &subtitle_sources,
+                        tracks.len() + 1,
+                    )?);
+                }
+                2 => break,
+                _ => unreachable!(),
             }
-            2 => break,
-            _ => unreachable!(),
         }
     }
 
+    if let Some(path) = matches.value_of("SAVE_JOB") {
+        job.tracks = tracks.iter().map(JobTrack::from).collect();
+        job.save(path)?;
+    }
+
     let mut video_tracks = Vec::new();
     let mut subtitle_tracks = Vec::new();
 
@@ -90,40 +178,54 @@ fn main() -> anyhow::Result<()> {
 
     for track in tracks.iter().cloned() {
         match track {
-            AnsiTrack::VideoTrack(t) => {
+            AnsiTrack::VideoTrack(mut t) => {
+                t.keyframes = keyframes;
+                cli::check_palette(&t.dither_mode.method, &palette)?;
+                t.dither_mode.palette = palette.clone();
+                t.dither_mode.lookup_table = lookup_table;
+                t.dither_mode.lookup_table_cache = lookup_table_cache.clone();
+
+                if t.auto_crop && t.crop.is_none() {
+                    let mut sampled = FFMpegSource::open_url(&job.input)?;
+                    t.crop = encoder::crop::detect_crop(&mut sampled, t.source_stream_index)?;
+                }
+
+                let stream = &ff_source.streams()[t.source_stream_index];
+                let (source_width, source_height) = video_size(stream).unwrap();
+                let layout =
+                    t.fit_to_source(display_aspect_ratio(stream), source_width, source_height);
                 let decoder = FFMpegVideoDecoder::from_stream(
-                    &ff_source.streams()[t.source_stream_index],
-                    ac_ffmpeg::codec::video::scaler::Algorithm::Lanczos,
-                    t.track_width,
-                    t.track_height,
+                    stream,
+                    t.scaler.algorithm(),
+                    layout.width,
+                    layout.height,
                 )
+                .and_then(|d| d.with_linear_light(t.linear_light))
+                .and_then(|d| d.with_downscale(t.downscale))
+                .and_then(|d| d.with_source_color(&t.source_color))
+                .and_then(|d| d.with_crop(t.crop))
+                .map(|d| {
+                    d.with_padding(
+                        layout.frame_width,
+                        layout.frame_height,
+                        t.padding.unwrap_or_default(),
+                    )
+                })
                 .unwrap();
 
-                let encoder = FrameEncoder {
-                    stream_index: t.track_id as u16,
-                    width: t.track_width as u32,
-                    height: t.track_height as u32,
-                    color: t.color_mode,
-                    use_diffing: false,
-                    last_frame: None,
-                };
-
-                match t.color_mode {
-                    ColorMode::True => {
-                        pipes.push(pipeline! {
-                            receive from source_packet_receiver;
-                            send to encoded_packet_tx;
-                            stream t.source_stream_index => decoder => passthrough => encoder
-                        });
-                    }
-                    ColorMode::EightBit => {
-                        pipes.push(pipeline! {
-                            receive from source_packet_receiver;
-                            send to encoded_packet_tx;
-                            stream t.source_stream_index => decoder => t.dither_mode.build() => encoder
-                        });
-                    }
-                }
+                let encoder = FrameEncoder::new(
+                    t.track_id as u16,
+                    t.track_width as u32,
+                    t.track_height as u32,
+                    t.color_mode,
+                )
+                .with_keyframes(t.keyframes);
+
+                pipes.push(pipeline! {
+                    receive from source_packet_receiver;
+                    send to encoded_packet_tx;
+                    stream t.source_stream_index => decoder => t.build_measured_processor(quality_report.as_ref()) => encoder
+                });
 
                 video_tracks.push(container::metadata::VideoTrack {
                     name: Some(t.track_name.clone()),
@@ -132,15 +234,12 @@ fn main() -> anyhow::Result<()> {
                     width: t.track_width as u32,
                     codec_private: None,
                     index: t.track_id as u16,
+                    keyframes: Some(t.keyframes),
+                    adaptive_palette: t.uses_adaptive_palette(),
+                    crop: t.crop,
                 })
             }
             AnsiTrack::SubtitleTrack(t) => {
-                pipes.push(pipeline! {
-                    receive from source_packet_receiver;
-                    send to encoded_packet_tx;
-                    stream t.source_stream_index => GenericPacketDecoder::override_stream_index(t.track_id as u16) => passthrough => passthrough
-                });
-
                 let parameters = ff_source.streams()[t.source_stream_index].codec_parameters();
 
                 let mut codec_private = parameters.extradata().map(|v| v.to_vec());
@@ -179,6 +278,17 @@ fn main() -> anyhow::Result<()> {
                     }
                 }
 
+                let mut decoder = GenericPacketDecoder::override_stream_index(t.track_id as u16);
+                if let Some(filter) = codec_private.as_deref().and_then(|v| t.packet_filter(v)) {
+                    decoder = decoder.with_filter(filter);
+                }
+
+                pipes.push(pipeline! {
+                    receive from source_packet_receiver;
+                    send to encoded_packet_tx;
+                    stream t.source_stream_index => decoder => passthrough => passthrough
+                });
+
                 subtitle_tracks.push(container::metadata::SubtitleTrack {
                     name: Some(t.track_name.clone()),
                     lang: None,
@@ -199,7 +309,7 @@ fn main() -> anyhow::Result<()> {
         video_tracks,
         subtitle_tracks,
         attachments: Vec::new(),
-        compression: if compress {
+        compression: if job.compress {
             CompressionMode::Zstd
         } else {
             CompressionMode::None
@@ -210,13 +320,9 @@ fn main() -> anyhow::Result<()> {
     let (state_tx, mut state_rx) = tokio::sync::watch::channel((0.0, 1));
 
     rt.block_on(async move {
-        let output_file = BufWriter::new(
-            File::create(matches.value_of("OUTPUT").unwrap())
-                .await
-                .unwrap(),
-        );
+        let output_file = BufWriter::new(File::create(&job.output).await.unwrap());
 
-        let writer = if compress {
+        let writer = if job.compress {
             #[cfg(feature = "compression")]
             {
                 write_with_container_metadata(
@@ -224,7 +330,7 @@ fn main() -> anyhow::Result<()> {
                     output_file,
                     encoded_packet_rx,
                     state_tx,
-                    PacketCompressor::with_level(compression_level).unwrap(),
+                    PacketCompressor::with_level(job.compression_level).unwrap(),
                 )
                 .boxed()
             }
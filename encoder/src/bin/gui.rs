@@ -2,7 +2,7 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use bytes::BytesMut;
-use container::metadata::{ColorMode, CompressionMode, SubtitleFormat};
+use container::metadata::{CompressionMode, SubtitleFormat};
 use container::packet::*;
 use eframe::{
     egui::{self, CollapsingHeader, RichText},
@@ -43,6 +43,7 @@ fn main() {
 #[derive(Default)]
 struct App {
     ff_source: Option<FFMpegSource>,
+    source_path: Option<PathBuf>,
     picked_path: Option<RichText>,
     ansi_tracks: Vec<AnsiTrack>,
     deleting: Option<usize>,        // popup to confirm delete is open
@@ -67,12 +68,14 @@ impl App {
                             source_kind: kind,
                             codec_name: stream.codec_parameters().decoder_name(),
                             title: stream.get_metadata("title"),
+                            language: stream.get_metadata("language"),
                         };
                         self.source_streams.push(meta);
                     }
                 }
 
                 self.ff_source = Some(source);
+                self.source_path = Some(path);
             }
             Err(e) => {
                 self.picked_path = Some(RichText::new(e.to_string()).color(Color32::RED));
@@ -216,39 +219,40 @@ impl eframe::App for App {
 
                             for track in self.ansi_tracks.iter().cloned() {
                                 match track {
-                                    AnsiTrack::VideoTrack(t) => {
-                                        let decoder = FFMpegVideoDecoder::from_stream(
-                                            &self.ff_source.as_ref().unwrap().streams()[t.source_stream_index],
-                                            ac_ffmpeg::codec::video::scaler::Algorithm::Lanczos,
-                                            t.track_width,
-                                            t.track_height,
-                                        ).unwrap();
-
-                                        let encoder = FrameEncoder {
-                                            stream_index: t.track_id as u16,
-                                            width: t.track_width as u32,
-                                            height: t.track_height as u32,
-                                            color: t.color_mode,
-                                            use_diffing: false,
-                                            last_frame: None,
-                                        };
-
-                                        match t.color_mode {
-                                            ColorMode::True => {
-                                                pipes.push(pipeline! {
-                                                    receive from source_packet_receiver;
-                                                    send to encoded_packet_tx;
-                                                    stream t.source_stream_index => decoder => passthrough => encoder
-                                                });
-                                            }
-                                            ColorMode::EightBit => {
-                                                pipes.push(pipeline! {
-                                                    receive from source_packet_receiver;
-                                                    send to encoded_packet_tx;
-                                                    stream t.source_stream_index => decoder => t.dither_mode.build() => encoder
-                                                });
-                                            },
+                                    AnsiTrack::VideoTrack(mut t) => {
+                                        if t.auto_crop && t.crop.is_none() {
+                                            let mut sampled = FFMpegSource::open_url(&self.source_path.as_ref().unwrap().display().to_string()).unwrap();
+                                            t.crop = encoder::crop::detect_crop(&mut sampled, t.source_stream_index).unwrap();
                                         }
+
+                                        let stream = &self.ff_source.as_ref().unwrap().streams()[t.source_stream_index];
+                                        let (source_width, source_height) = video_size(stream).unwrap();
+                                        let layout = t.fit_to_source(display_aspect_ratio(stream), source_width, source_height);
+                                        let decoder = FFMpegVideoDecoder::from_stream(
+                                            stream,
+                                            t.scaler.algorithm(),
+                                            layout.width,
+                                            layout.height,
+                                        ).and_then(|d| d.with_linear_light(t.linear_light))
+                                        .and_then(|d| d.with_downscale(t.downscale))
+                                        .and_then(|d| d.with_source_color(&t.source_color))
+                                        .and_then(|d| d.with_crop(t.crop))
+                                        .map(|d| d.with_padding(layout.frame_width, layout.frame_height, t.padding.unwrap_or_default()))
+                                        .unwrap();
+
+                                        let encoder = FrameEncoder::new(
+                                            t.track_id as u16,
+                                            t.track_width as u32,
+                                            t.track_height as u32,
+                                            t.color_mode,
+                                        )
+                                        .with_keyframes(t.keyframes);
+
+                                        pipes.push(pipeline! {
+                                            receive from source_packet_receiver;
+                                            send to encoded_packet_tx;
+                                            stream t.source_stream_index => decoder => t.build_processor() => encoder
+                                        });
                                         video_tracks.push(container::metadata::VideoTrack {
                                             name: Some(t.track_name.clone()),
                                             color_mode: t.color_mode,
@@ -256,6 +260,9 @@ impl eframe::App for App {
                                             width: t.track_width as u32,
                                             codec_private: None,
                                             index: t.track_id as u16,
+                                            keyframes: Some(t.keyframes),
+                                            adaptive_palette: t.uses_adaptive_palette(),
+                                            crop: t.crop,
                                         })
                                     }
                                     AnsiTrack::SubtitleTrack(t) => {
@@ -0,0 +1,151 @@
+use clap::clap_app;
+
+use container::metadata::ColorMode;
+use container::packet::ToPacket;
+use encoder::tool_utils::*;
+use encoder::video_encoder::*;
+use encoder::*;
+
+// dithers a stretch of a video stream at several temporal stability thresholds and prints how much the output flickers
+// and what it costs to encode
+fn main() -> anyhow::Result<()> {
+    let matches = clap_app!(temporal_report =>
+        (version: "1.0")
+        (author: "emily signet <emily@cat-girl.gay>")
+        (@arg INPUT: +required "input file or url")
+        (@arg STREAM: -s --stream +takes_value "source stream index (first video stream if not passed)")
+        (@arg FRAMES: -f --frames +takes_value "number of frames to encode (default 300)")
+        (@arg WIDTH: --width +takes_value "video width (default 192)")
+        (@arg HEIGHT: --height +takes_value "video height (default 108)")
+        (@arg METHOD: -m --method +takes_value "dither method: floyd-steinberg, pattern, error-diffusion or yliluoma-1/2/3 (default floyd-steinberg)")
+        (@arg THRESHOLDS: -t --thresholds +takes_value "comma-separated ΔE thresholds, 0 for off (default 0,1,2,3,5)")
+    )
+    .get_matches();
+
+    let frame_count = matches
+        .value_of("FRAMES")
+        .map(|v| v.parse::<usize>())
+        .transpose()?
+        .unwrap_or(300);
+    let width = matches
+        .value_of("WIDTH")
+        .map(|v| v.parse::<usize>())
+        .transpose()?
+        .unwrap_or(192);
+    let height = matches
+        .value_of("HEIGHT")
+        .map(|v| v.parse::<usize>())
+        .transpose()?
+        .unwrap_or(108);
+    let method = matches
+        .value_of("METHOD")
+        .unwrap_or("floyd-steinberg")
+        .parse::<DitherMethod>()
+        .map_err(anyhow::Error::msg)?;
+    anyhow::ensure!(
+        method != DitherMethod::Cuda,
+        "the cuda ditherer can't keep indices across frames"
+    );
+    let thresholds = matches
+        .value_of("THRESHOLDS")
+        .unwrap_or("0,1,2,3,5")
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()?;
+
+    let mut source = FFMpegSource::open_url(matches.value_of("INPUT").unwrap())?;
+    let stream_index = match matches.value_of("STREAM") {
+        Some(v) => v.parse::<usize>()?,
+        None => source
+            .first_video_stream()
+            .ok_or_else(|| anyhow::anyhow!("no video stream found"))?,
+    };
+
+    let frames = source.decode_frames(stream_index, width, height, frame_count)?;
+
+    println!(
+        "{} frames of stream {} at {}x{}, {} dithering",
+        frames.len(),
+        stream_index,
+        width,
+        height,
+        method
+    );
+    // changed: pixels whose index differs from the last frame; shimmer: the same, among pixels whose source didn't change
+    println!(
+        "{:>9} | {:>12} | {:>7} | {:>9} | {:>8} | {:>8}",
+        "threshold", "bytes", "vs first", "keyframes", "changed", "shimmer"
+    );
+
+    let mut baseline_size = None;
+
+    for threshold in thresholds {
+        let config = DitherConfig {
+            method: method.clone(),
+            width: width as u32,
+            height: height as u32,
+            temporal_threshold: Some(threshold).filter(|v| *v > 0.0),
+            ..Default::default()
+        };
+        let mut ditherer = config.build();
+        let mut encoder = FrameEncoder::new(0, width as u32, height as u32, ColorMode::EightBit);
+
+        let mut previous: Option<(&DecodedVideoFrame, Vec<u8>)> = None;
+        let mut size = 0usize;
+        let mut keyframes = 0usize;
+        let (mut changed, mut still, mut shimmering) = (0usize, 0usize, 0usize);
+
+        for frame in frames.iter() {
+            let mut dithered = DecodedVideoFrame {
+                pts: frame.pts,
+                duration: frame.duration,
+                image: frame.image.clone(),
+                palette: None,
+            };
+            ditherer.map(&mut dithered);
+
+            let indices = match &dithered.image {
+                img2ansi::VideoImage::EightBit { data, .. } => data.clone(),
+                img2ansi::VideoImage::FullColor(_) => unreachable!(),
+            };
+
+            if let Some((previous_frame, previous_indices)) = &previous {
+                let source = frame.image.as_full_color().pixels();
+                let previous_source = previous_frame.image.as_full_color().pixels();
+                for ((a, b), (old, new)) in source
+                    .zip(previous_source)
+                    .zip(previous_indices.iter().zip(indices.iter()))
+                {
+                    let is_still = a.0.iter().zip(b.0.iter()).all(|(a, b)| a.abs_diff(*b) <= 2);
+                    still += is_still as usize;
+                    if old != new {
+                        changed += 1;
+                        shimmering += is_still as usize;
+                    }
+                }
+            }
+
+            let packet = encoder.encode_packet(dithered).unwrap();
+            size += packet.data.len();
+            if encoder.frames_since_keyframe == 0 {
+                keyframes += 1;
+            }
+
+            previous = Some((frame, indices));
+        }
+
+        let compared = frames.len().saturating_sub(1) * width * height;
+        let baseline_size = *baseline_size.get_or_insert(size);
+        println!(
+            "{:>9.2} | {:>12} | {:>6.1}% | {:>9} | {:>7.2}% | {:>7.2}%",
+            threshold,
+            size,
+            size as f64 / baseline_size as f64 * 100.0,
+            keyframes,
+            changed as f64 / compared.max(1) as f64 * 100.0,
+            shimmering as f64 / still.max(1) as f64 * 100.0
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use clap::clap_app;
+
+use container::metadata::ColorMode;
+use container::packet::ToPacket;
+use encoder::video_encoder::*;
+use encoder::*;
+use img2ansi::quantize::{quantize_truecolor, QuantizeStats};
+
+// encodes a stretch of a video stream as true color at several ΔE thresholds and prints the quality/size tradeoff
+fn main() -> anyhow::Result<()> {
+    let matches = clap_app!(truecolor_report =>
+        (version: "1.0")
+        (author: "emily signet <emily@cat-girl.gay>")
+        (@arg INPUT: +required "input file or url")
+        (@arg STREAM: -s --stream +takes_value "source stream index (first video stream if not passed)")
+        (@arg FRAMES: -f --frames +takes_value "number of frames to encode (default 300)")
+        (@arg WIDTH: --width +takes_value "video width (default 192)")
+        (@arg HEIGHT: --height +takes_value "video height (default 108)")
+        (@arg THRESHOLDS: -t --thresholds +takes_value "comma-separated ΔE thresholds (default 0,0.5,1,2,3,5,8)")
+    )
+    .get_matches();
+
+    let frame_count = matches
+        .value_of("FRAMES")
+        .map(|v| v.parse::<usize>())
+        .transpose()?
+        .unwrap_or(300);
+    let width = matches
+        .value_of("WIDTH")
+        .map(|v| v.parse::<usize>())
+        .transpose()?
+        .unwrap_or(192);
+    let height = matches
+        .value_of("HEIGHT")
+        .map(|v| v.parse::<usize>())
+        .transpose()?
+        .unwrap_or(108);
+    let thresholds = matches
+        .value_of("THRESHOLDS")
+        .unwrap_or("0,0.5,1,2,3,5,8")
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()?;
+
+    let mut source = FFMpegSource::open_url(matches.value_of("INPUT").unwrap())?;
+    let stream_index = match matches.value_of("STREAM") {
+        Some(v) => v.parse::<usize>()?,
+        None => source
+            .first_video_stream()
+            .ok_or_else(|| anyhow::anyhow!("no video stream found"))?,
+    };
+
+    let frames = source.decode_frames(stream_index, width, height, frame_count)?;
+
+    println!(
+        "{} frames of stream {} at {}x{}",
+        frames.len(),
+        stream_index,
+        width,
+        height
+    );
+    println!(
+        "{:>9} | {:>12} | {:>7} | {:>9} | {:>8} | {:>8}",
+        "threshold", "bytes", "vs first", "keyframes", "mean ΔE", "max ΔE"
+    );
+
+    let mut baseline_size = None;
+
+    for threshold in thresholds {
+        let mut encoder = FrameEncoder::new(0, width as u32, height as u32, ColorMode::True);
+        let mut stats = QuantizeStats::default();
+        let mut previous = None;
+        let mut size = 0usize;
+        let mut keyframes = 0usize;
+
+        for frame in frames.iter() {
+            let mut image = frame.image.as_full_color().clone();
+            if threshold > 0.0 {
+                stats.merge(&quantize_truecolor(
+                    &mut image,
+                    previous.as_ref(),
+                    threshold,
+                ));
+                previous = Some(image.clone());
+            }
+
+            let packet = encoder
+                .encode_packet(DecodedVideoFrame {
+                    pts: frame.pts,
+                    duration: frame.duration,
+                    image: img2ansi::VideoImage::FullColor(image),
+                    palette: None,
+                })
+                .unwrap();
+
+            size += packet.data.len();
+            if encoder.frames_since_keyframe == 0 {
+                keyframes += 1;
+            }
+        }
+
+        let baseline_size = *baseline_size.get_or_insert(size);
+        println!(
+            "{:>9.2} | {:>12} | {:>6.1}% | {:>9} | {:>8.3} | {:>8.3}",
+            threshold,
+            size,
+            size as f64 / baseline_size as f64 * 100.0,
+            keyframes,
+            stats.mean_delta_e(),
+            stats.delta_e_max
+        );
+    }
+
+    Ok(())
+}
@@ -1,8 +1,18 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use colorful::adjust::Adjustments;
+use colorful::colorspace::{ToneMap, Transfer};
+use colorful::error_diffusion::Kernel;
+use colorful::palette::{Palette, DEFAULT_PALETTE};
 use colorful::pattern_dithering::MatrixSize;
-use container::metadata::ColorMode;
+use container::metadata::{ColorMode, KeyframeSettings};
 
 use crate::tool_utils::{
-    AnsiTrack, DistanceFunction, DitherMethod, SourceStreamMetadata, SubtitleTrack, VideoTrack,
+    parse_aspect, parse_color, parse_crop, AnsiTrack, DistanceFunction, DitherMethod, Downscale,
+    ScalingAlgorithm, Sizing, SourceStreamMetadata, SubtitleTrack, VideoTrack,
 };
 
 pub fn select_video_track(
@@ -44,6 +54,89 @@ pub fn select_video_track(
         .interact_text()?
         .parse::<usize>()?;
 
+    track.auto_crop = dialoguer::Confirm::with_theme(&theme)
+        .with_prompt("detect and crop black bars")
+        .default(false)
+        .interact()?;
+
+    track.sizing = Sizing::try_from(
+        dialoguer::Select::with_theme(&theme)
+            .with_prompt("sizing")
+            .item("stretch to width x height")
+            .item("fit width, keeping the aspect ratio")
+            .item("fit height, keeping the aspect ratio")
+            .item("fit within width x height, keeping the aspect ratio")
+            .interact()? as u8,
+    )?;
+
+    if track.sizing != Sizing::Stretch {
+        track.cell_aspect = parse_aspect(
+            &dialoguer::Input::with_theme(&theme)
+                .with_prompt("terminal cell aspect ratio (width:height)")
+                .default("1:2".to_string())
+                .validate_with(|v: &String| parse_aspect(v).map(|_| ()))
+                .interact_text()?,
+        )
+        .map_err(anyhow::Error::msg)?;
+
+        let padding = dialoguer::Input::with_theme(&theme)
+            .with_prompt("letterbox color (RRGGBB, empty for none)")
+            .allow_empty(true)
+            .validate_with(|v: &String| {
+                if v.is_empty() {
+                    Ok(())
+                } else {
+                    parse_color(v).map(|_| ())
+                }
+            })
+            .interact_text()?;
+        track.padding = (!padding.is_empty())
+            .then(|| parse_color(&padding))
+            .transpose()
+            .map_err(anyhow::Error::msg)?;
+    }
+
+    track.scaler = ScalingAlgorithm::ALL[dialoguer::Select::with_theme(&theme)
+        .with_prompt("scaling algorithm")
+        .items(&ScalingAlgorithm::ALL)
+        .default(0)
+        .interact()?];
+
+    track.downscale = Downscale::try_from(
+        dialoguer::Select::with_theme(&theme)
+            .with_prompt("downscaling")
+            .item("direct (fastest)")
+            .item("area averaging (least aliasing)")
+            .item("multi-step (the scaling algorithm, then halving)")
+            .default(0)
+            .interact()? as u8,
+    )?;
+
+    track.source_color.transfer = [Transfer::Sdr, Transfer::Pq, Transfer::Hlg]
+        [dialoguer::Select::with_theme(&theme)
+            .with_prompt("source dynamic range")
+            .item("SDR")
+            .item("HDR10 (PQ)")
+            .item("HLG")
+            .default(0)
+            .interact()?];
+
+    if track.source_color.transfer != Transfer::Sdr {
+        let tone_maps = [ToneMap::Reinhard, ToneMap::Hable, ToneMap::Clip];
+        track.source_color.tone_map = tone_maps[dialoguer::Select::with_theme(&theme)
+            .with_prompt("tone mapping")
+            .items(&tone_maps)
+            .default(0)
+            .interact()?];
+
+        track.source_color.peak = dialoguer::Input::with_theme(&theme)
+            .with_prompt("brightest highlights to keep detail in (nits)")
+            .default("1000".to_string())
+            .validate_with(|v: &String| v.parse::<f32>().map(|_| ()))
+            .interact_text()?
+            .parse::<f32>()?;
+    }
+
     track.color_mode = ColorMode::try_from(
         dialoguer::Select::with_theme(&theme)
             .with_prompt("color mode")
@@ -52,53 +145,146 @@ pub fn select_video_track(
             .interact()? as u8,
     )?;
 
+    track.linear_light = dialoguer::Confirm::with_theme(&theme)
+        .with_prompt("scale and dither in linear light (gamma-correct, slower)")
+        .default(false)
+        .interact()?;
+
+    if dialoguer::Confirm::with_theme(&theme)
+        .with_prompt("adjust the picture (brightness, contrast, saturation, sharpening...)")
+        .default(false)
+        .interact()?
+    {
+        track.adjustments = select_adjustments(&theme)?;
+    }
+
     if track.color_mode == ColorMode::True {
+        let threshold = dialoguer::Input::with_theme(&theme)
+            .with_prompt("color reuse threshold (ΔE, 0 for lossless)")
+            .default("0".to_string())
+            .validate_with(|v: &String| v.parse::<f32>().map(|_| ()))
+            .interact_text()?
+            .parse::<f32>()?;
+        track.truecolor_threshold = Some(threshold).filter(|v| *v > 0.0);
+
         return Ok(AnsiTrack::VideoTrack(track));
     }
 
-    let dither_choices: &'static [&str] = if cfg!(feature = "cuda") {
-        &[
-            "floyd-steinberg",
-            "ordered pattern dithering",
-            "cuda-accelerated pattern dithering",
-        ]
-    } else {
-        &["floyd-steinberg", "ordered pattern dithering"]
-    };
+    let mut dither_choices = vec![
+        ("floyd-steinberg", DitherMethod::FloydSteinberg),
+        ("ordered pattern dithering", DitherMethod::Pattern),
+        (
+            "error diffusion (other kernels, serpentine scanning)",
+            DitherMethod::ErrorDiffusion,
+        ),
+        (
+            "yliluoma 1 (ordered, two color mixes)",
+            DitherMethod::Yliluoma1,
+        ),
+        ("yliluoma 2 (ordered, accurate)", DitherMethod::Yliluoma2),
+        (
+            "yliluoma 3 (ordered, most accurate, slowest)",
+            DitherMethod::Yliluoma3,
+        ),
+    ];
+    if cfg!(feature = "cuda") {
+        dither_choices.push(("cuda-accelerated pattern dithering", DitherMethod::Cuda));
+    }
 
-    track.dither_mode.method = DitherMethod::try_from(
-        dialoguer::Select::with_theme(&theme)
-            .with_prompt("dither method")
-            .items(dither_choices)
-            .interact()? as u8,
-    )?;
+    track.dither_mode.method = dither_choices[dialoguer::Select::with_theme(&theme)
+        .with_prompt("dither method")
+        .items(&dither_choices.iter().map(|v| v.0).collect::<Vec<_>>())
+        .interact()?]
+    .1
+    .clone();
 
     track.dither_mode.distance_function = if track.dither_mode.method != DitherMethod::Cuda {
         DistanceFunction::try_from(
             dialoguer::Select::with_theme(&theme)
                 .with_prompt("color distance function")
-                .item("CAM02 (best, fast)")
-                .item("CIE94 (medium, slowest)")
+                .item("CAM02 (great, fast)")
+                .item("CIE94 (medium, slow)")
                 .item("CIE76 (worst, fastest)")
+                .item("CIEDE2000 (best, slowest - for offline encodes)")
+                .item("Oklab (great, faster than CAM02)")
                 .interact()? as u8,
         )?
     } else {
         DistanceFunction::CAM02
     };
 
+    if track.dither_mode.method != DitherMethod::Cuda
+        && dialoguer::Select::with_theme(&theme)
+            .with_prompt("palette")
+            .item("fixed")
+            .item("adaptive, fitted to every scene (needs a terminal supporting OSC 4)")
+            .interact()?
+            == 1
+    {
+        track.dither_mode.adaptive_palette = Some(
+            dialoguer::Input::with_theme(&theme)
+                .with_prompt("palette refit threshold (ΔE)")
+                .default("2".to_string())
+                .validate_with(|v: &String| v.parse::<f32>().map(|_| ()))
+                .interact_text()?
+                .parse::<f32>()?,
+        );
+    }
+
+    if track.dither_mode.method != DitherMethod::Cuda {
+        let threshold = dialoguer::Input::with_theme(&theme)
+            .with_prompt("temporal stability threshold (ΔE, 0 to dither every frame anew)")
+            .default("0".to_string())
+            .validate_with(|v: &String| v.parse::<f32>().map(|_| ()))
+            .interact_text()?
+            .parse::<f32>()?;
+        track.dither_mode.temporal_threshold = Some(threshold).filter(|v| *v > 0.0);
+    }
+
     if track.dither_mode.method == DitherMethod::FloydSteinberg {
         return Ok(AnsiTrack::VideoTrack(track));
     }
 
+    if track.dither_mode.method == DitherMethod::ErrorDiffusion {
+        track.dither_mode.kernel = Kernel::ALL[dialoguer::Select::with_theme(&theme)
+            .with_prompt("error diffusion kernel")
+            .items(&Kernel::ALL)
+            .interact()?];
+
+        track.dither_mode.serpentine = dialoguer::Confirm::with_theme(&theme)
+            .with_prompt("serpentine scanning")
+            .default(true)
+            .interact()?;
+
+        track.dither_mode.error_strength = dialoguer::Input::with_theme(&theme)
+            .with_prompt("error strength (0 - 1)")
+            .default("1.0".to_string())
+            .validate_with(|v: &String| match v.parse::<f32>() {
+                Ok(strength) if (0.0..=1.0).contains(&strength) => Ok(()),
+                Ok(_) => Err("must be between 0 and 1".to_string()),
+                Err(e) => Err(e.to_string()),
+            })
+            .interact_text()?
+            .parse::<f32>()?;
+
+        return Ok(AnsiTrack::VideoTrack(track));
+    }
+
     track.dither_mode.matrix_size = MatrixSize::try_from(
         dialoguer::Select::with_theme(&theme)
             .with_prompt("matrix size")
             .item("8x8 (best, slowest)")
             .item("4x4")
             .item("2x2 (worst, fastest)")
+            .item("blue noise (most natural, as slow as 8x8)")
             .interact()? as u8,
     )?;
 
+    // yliluoma's algorithms only need the threshold map
+    if track.dither_mode.method.yliluoma_algorithm().is_some() {
+        return Ok(AnsiTrack::VideoTrack(track));
+    }
+
     track.dither_mode.multiplier = dialoguer::Input::with_theme(&theme)
         .with_prompt("color difference multiplier")
         .default("0.09".to_string())
@@ -136,3 +322,598 @@ pub fn select_subtitle_track(
 
     Ok(AnsiTrack::SubtitleTrack(track))
 }
+
+fn select_adjustments(theme: &dyn dialoguer::theme::Theme) -> anyhow::Result<Adjustments> {
+    let number = |prompt: &str, default: f32| -> anyhow::Result<f32> {
+        Ok(dialoguer::Input::with_theme(theme)
+            .with_prompt(prompt)
+            .default(default.to_string())
+            .validate_with(|v: &String| v.parse::<f32>().map(|_| ()))
+            .interact_text()?
+            .parse::<f32>()?)
+    };
+
+    let defaults = Adjustments::default();
+    let mut adjustments = Adjustments {
+        denoise: number("denoise (0 - 1)", defaults.denoise)?,
+        brightness: number("brightness (-1 - 1)", defaults.brightness)?,
+        contrast: number("contrast", defaults.contrast)?,
+        gamma: number("gamma (above 1 brightens shadows)", defaults.gamma)?,
+        saturation: number("saturation", defaults.saturation)?,
+        sharpen: number("sharpening amount (0 for none)", defaults.sharpen)?,
+        ..defaults
+    };
+
+    if adjustments.sharpen > 0.0 {
+        adjustments.sharpen_radius = number("sharpening radius (pixels)", defaults.sharpen_radius)?;
+    }
+
+    Ok(adjustments)
+}
+
+/// Splits a track spec like `color:256,width:192` into its keys and values.
+/// Keys other than the `repeatable` ones may only be given once, so a typo'd spec doesn't quietly keep the last value.
+fn spec_entries<'a>(
+    spec: &'a str,
+    repeatable: &'a [&str],
+) -> impl Iterator<Item = anyhow::Result<(&'a str, &'a str)>> {
+    let mut seen = HashSet::new();
+    spec.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(move |entry| {
+            let (key, value) = entry
+                .split_once(':')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| anyhow::anyhow!("'{}' should look like key:value", entry.trim()))?;
+
+            anyhow::ensure!(
+                seen.insert(key) || repeatable.contains(&key),
+                "'{key}' is given more than once"
+            );
+            Ok((key, value))
+        })
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> anyhow::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse::<T>()
+        .map_err(|e| anyhow::anyhow!("invalid value '{value}' for {key}: {e}"))
+}
+
+/// Like [parse_value], for values parsed by a function rather than [std::str::FromStr].
+fn parse_with<T>(
+    key: &str,
+    value: &str,
+    parse: fn(&str) -> Result<T, String>,
+) -> anyhow::Result<T> {
+    parse(value).map_err(|e| anyhow::anyhow!("invalid value '{value}' for {key}: {e}"))
+}
+
+fn parse_optional_with<T>(
+    key: &str,
+    value: &str,
+    parse: fn(&str) -> Result<T, String>,
+) -> anyhow::Result<Option<T>> {
+    match value {
+        "off" | "none" => Ok(None),
+        _ => parse_with(key, value, parse).map(Some),
+    }
+}
+
+fn parse_switch(key: &str, value: &str) -> anyhow::Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "yes" | "true" => Ok(true),
+        "off" | "no" | "false" => Ok(false),
+        _ => anyhow::bail!("invalid value '{value}' for {key}: expected on or off"),
+    }
+}
+
+/// Picks the source stream a track spec names with `stream:`, or the first one of the right kind.
+fn spec_source<'a>(
+    sources: &'a [SourceStreamMetadata],
+    stream: Option<&str>,
+    kind: &str,
+) -> anyhow::Result<&'a SourceStreamMetadata> {
+    match stream {
+        Some(v) => {
+            let idx = parse_value::<usize>("stream", v)?;
+            sources
+                .iter()
+                .find(|source| source.idx == idx)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "invalid value '{v}' for stream: stream {idx} isn't a {kind} stream"
+                    )
+                })
+        }
+        None => sources
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("the input has no {kind} streams")),
+    }
+}
+
+/// Keys a `--video-track` spec can set. Anything left out keeps the same default the prompts suggest.
+pub const VIDEO_TRACK_KEYS: &str = "stream, name, width, height, size (stretch, fit-columns, fit-rows or fit-box), \
+cell-aspect (like 1:2), source-aspect (like 16:9, or off), pad (RRGGBB letterbox color, or off), \
+crop (auto, off, or width:height:x:y in source pixels), scaler (lanczos, bicubic, bilinear, fast-bilinear, area, point, gauss, sinc or spline), \
+downscale (direct, area or multi-step), colorspace (bt601, bt709, bt2020 or auto), range (limited, full or auto), \
+transfer (sdr, pq or hlg), tone-map (reinhard, hable or clip), peak (HDR nits to keep detail up to), denoise (0 - 1), brightness (-1 - 1), contrast, gamma, saturation, \
+sharpen (unsharp mask amount), sharpen-radius, sharpen-threshold, \
+color (true or 256), linear (on/off), \
+truecolor-threshold (ΔE or off), dither (floyd-steinberg, pattern, error-diffusion, yliluoma-1/2/3 or cuda), \
+distance (cam02, cie94, cie76, ciede2000 or oklab), adaptive-palette (refit ΔE or off), temporal (ΔE or off), \
+matrix (2x2, 4x4, 8x8 or blue-noise), multiplier, kernel, serpentine (on/off), strength";
+
+/// Builds a video track from a spec of comma separated `key:value` pairs, like
+/// `color:256,width:192,height:108,dither:pattern,matrix:8x8`. See [VIDEO_TRACK_KEYS] for the keys.
+pub fn parse_video_track(
+    spec: &str,
+    video_sources: &[SourceStreamMetadata],
+    track_id: usize,
+) -> anyhow::Result<AnsiTrack> {
+    let mut track = VideoTrack {
+        track_id,
+        ..VideoTrack::default()
+    };
+    let mut stream = None;
+    let mut name = None;
+
+    for entry in spec_entries(spec, &[]) {
+        let (key, value) = entry?;
+        match key {
+            "stream" => stream = Some(value),
+            "name" => name = Some(value.to_string()),
+            "width" => track.track_width = parse_value(key, value)?,
+            "height" => track.track_height = parse_value(key, value)?,
+            "size" => track.sizing = parse_value(key, value)?,
+            "cell-aspect" => track.cell_aspect = parse_with(key, value, parse_aspect)?,
+            "source-aspect" => track.source_aspect = parse_optional_with(key, value, parse_aspect)?,
+            "pad" => track.padding = parse_optional_with(key, value, parse_color)?,
+            "crop" => {
+                track.auto_crop = value == "auto";
+                track.crop = match value {
+                    "auto" | "off" | "none" => None,
+                    _ => Some(parse_with(key, value, parse_crop)?),
+                };
+            }
+            "scaler" => track.scaler = parse_value(key, value)?,
+            "downscale" => track.downscale = parse_value(key, value)?,
+            "colorspace" => {
+                track.source_color.matrix = match value {
+                    "auto" => None,
+                    _ => Some(parse_value(key, value)?),
+                }
+            }
+            "range" => {
+                track.source_color.range = match value {
+                    "auto" => None,
+                    _ => Some(parse_value(key, value)?),
+                }
+            }
+            "transfer" => track.source_color.transfer = parse_value(key, value)?,
+            "tone-map" => track.source_color.tone_map = parse_value(key, value)?,
+            "peak" => track.source_color.peak = parse_value(key, value)?,
+            "denoise" => track.adjustments.denoise = parse_value(key, value)?,
+            "brightness" => track.adjustments.brightness = parse_value(key, value)?,
+            "contrast" => track.adjustments.contrast = parse_value(key, value)?,
+            "gamma" => {
+                track.adjustments.gamma = parse_value(key, value)?;
+                anyhow::ensure!(
+                    track.adjustments.gamma > 0.0,
+                    "invalid value '{value}' for gamma: must be above 0"
+                );
+            }
+            "saturation" => track.adjustments.saturation = parse_value(key, value)?,
+            "sharpen" => track.adjustments.sharpen = parse_value(key, value)?,
+            "sharpen-radius" => track.adjustments.sharpen_radius = parse_value(key, value)?,
+            "sharpen-threshold" => track.adjustments.sharpen_threshold = parse_value(key, value)?,
+            "color" => {
+                track.color_mode = match value {
+                    "true" | "truecolor" | "24bit" => ColorMode::True,
+                    "256" | "8bit" => ColorMode::EightBit,
+                    _ => anyhow::bail!("invalid value '{value}' for color: expected true or 256"),
+                }
+            }
+            "linear" => track.linear_light = parse_switch(key, value)?,
+            "truecolor-threshold" => track.truecolor_threshold = parse_optional(key, value)?,
+            "dither" => track.dither_mode.method = parse_value(key, value)?,
+            "distance" => track.dither_mode.distance_function = parse_value(key, value)?,
+            "adaptive-palette" => track.dither_mode.adaptive_palette = parse_optional(key, value)?,
+            "temporal" => track.dither_mode.temporal_threshold = parse_optional(key, value)?,
+            "matrix" => track.dither_mode.matrix_size = parse_value(key, value)?,
+            "multiplier" => track.dither_mode.multiplier = parse_value(key, value)?,
+            "kernel" => track.dither_mode.kernel = parse_value(key, value)?,
+            "serpentine" => track.dither_mode.serpentine = parse_switch(key, value)?,
+            "strength" => {
+                track.dither_mode.error_strength = parse_value(key, value)?;
+                anyhow::ensure!(
+                    (0.0..=1.0).contains(&track.dither_mode.error_strength),
+                    "invalid value '{value}' for strength: must be between 0 and 1"
+                );
+            }
+            _ => anyhow::bail!(
+                "unknown video track key '{key}' (expected one of {VIDEO_TRACK_KEYS})"
+            ),
+        }
+    }
+
+    anyhow::ensure!(
+        track.track_width > 0 && track.track_height > 0,
+        "width and height must be above 0"
+    );
+    if cfg!(not(feature = "cuda")) {
+        anyhow::ensure!(
+            track.dither_mode.method != DitherMethod::Cuda,
+            "invalid value 'cuda' for dither: this encoder was built without cuda support"
+        );
+    }
+
+    let source = spec_source(video_sources, stream, "video")?;
+    track.source_stream_index = source.idx;
+    track.track_name = name.unwrap_or_else(|| {
+        source
+            .title
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| format!("Video {}", track.track_id))
+    });
+    track.dither_mode.width = track.track_width as u32;
+    track.dither_mode.height = track.track_height as u32;
+    // zero turns these off in the prompts too
+    track.truecolor_threshold = track.truecolor_threshold.filter(|v| *v > 0.0);
+    track.dither_mode.temporal_threshold =
+        track.dither_mode.temporal_threshold.filter(|v| *v > 0.0);
+
+    Ok(AnsiTrack::VideoTrack(track))
+}
+
+/// Keys a `--subtitle-track` spec can set.
+pub const SUBTITLE_TRACK_KEYS: &str =
+    "stream, name, layer (an ssa layer to keep, repeatable; all are kept if not passed)";
+
+/// Builds a subtitle track from a spec of comma separated `key:value` pairs, like `stream:2,name:english,layer:0`.
+pub fn parse_subtitle_track(
+    spec: &str,
+    subtitle_sources: &[SourceStreamMetadata],
+    track_id: usize,
+) -> anyhow::Result<AnsiTrack> {
+    let mut stream = None;
+    let mut name = None;
+    let mut layers = Vec::new();
+
+    for entry in spec_entries(spec, &["layer"]) {
+        let (key, value) = entry?;
+        match key {
+            "stream" => stream = Some(value),
+            "name" => name = Some(value.to_string()),
+            "layer" => layers.push(parse_value(key, value)?),
+            _ => anyhow::bail!(
+                "unknown subtitle track key '{key}' (expected one of {SUBTITLE_TRACK_KEYS})"
+            ),
+        }
+    }
+
+    let source = spec_source(subtitle_sources, stream, "subtitle")?;
+    Ok(AnsiTrack::SubtitleTrack(SubtitleTrack {
+        source_stream_index: source.idx,
+        track_id,
+        track_name: name.unwrap_or_else(|| {
+            source
+                .title
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| format!("Subtitles {}", track_id))
+        }),
+        layers,
+    }))
+}
+
+/// Builds tracks from `--video-track` and `--subtitle-track` specs, numbering them from `first_id` with video tracks first.
+pub fn parse_tracks(
+    video_specs: &[&str],
+    subtitle_specs: &[&str],
+    video_sources: &[SourceStreamMetadata],
+    subtitle_sources: &[SourceStreamMetadata],
+    first_id: usize,
+) -> anyhow::Result<Vec<AnsiTrack>> {
+    let mut tracks = Vec::with_capacity(video_specs.len() + subtitle_specs.len());
+
+    for spec in video_specs {
+        let track = parse_video_track(spec, video_sources, first_id + tracks.len())
+            .with_context(|| format!("invalid video track '{spec}'"))?;
+        tracks.push(track);
+    }
+
+    for spec in subtitle_specs {
+        let track = parse_subtitle_track(spec, subtitle_sources, first_id + tracks.len())
+            .with_context(|| format!("invalid subtitle track '{spec}'"))?;
+        tracks.push(track);
+    }
+
+    Ok(tracks)
+}
+
+fn parse_optional<T: std::str::FromStr>(name: &str, value: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match value {
+        "off" | "none" => Ok(None),
+        v => v
+            .parse::<T>()
+            .map(Some)
+            .with_context(|| format!("invalid value '{v}' for {name}")),
+    }
+}
+
+/// Builds keyframe settings from command line values, starting from the defaults. Any of them can be set to "off".
+pub fn keyframe_settings(
+    interval: Option<&str>,
+    frames: Option<&str>,
+    scene_cut: Option<&str>,
+) -> anyhow::Result<KeyframeSettings> {
+    let mut settings = KeyframeSettings::default();
+
+    if let Some(v) = interval {
+        settings.max_interval =
+            parse_optional::<f64>("keyframe interval", v)?.map(Duration::from_secs_f64);
+    }
+
+    if let Some(v) = frames {
+        settings.max_frames = parse_optional::<u32>("keyframe frame count", v)?;
+    }
+
+    if let Some(v) = scene_cut {
+        settings.scene_cut_threshold = parse_optional::<f32>("scene cut threshold", v)?;
+        if let Some(threshold) = settings.scene_cut_threshold {
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&threshold),
+                "scene cut threshold must be between 0.0 and 1.0"
+            );
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Loads the palette passed with `--palette`, falling back to the xterm palette.
+/// `terminal` asks the current terminal for its colors instead of reading a file.
+pub fn load_palette(path: Option<&str>) -> anyhow::Result<Arc<Palette>> {
+    match path {
+        Some("terminal") => {
+            let colors = player::terminal::query_colors(Duration::from_secs(1))
+                .context("the terminal didn't report its colors")?;
+            Ok(Arc::new(colorful::theme::parse_theme(&colors.to_theme())?))
+        }
+        Some(path) => Ok(Arc::new(
+            colorful::theme::load_theme(path)
+                .with_context(|| format!("couldn't load palette from {}", path))?,
+        )),
+        None => Ok(DEFAULT_PALETTE.clone()),
+    }
+}
+
+/// The cuda ditherer has the xterm palette baked in, so it can't dither for any other palette.
+pub fn check_palette(method: &DitherMethod, palette: &Arc<Palette>) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        *method != DitherMethod::Cuda || Arc::ptr_eq(palette, &DEFAULT_PALETTE),
+        "cuda-accelerated dithering only supports the default xterm palette, drop --palette or choose another dither method"
+    );
+
+    Ok(())
+}
+
+/// Parses `--lut`: bits per channel for the dithering lookup table, "on" for the default size, or "off" to search the
+/// palette for every color. Tables can pick slightly different colors than a search, so they're off unless asked for.
+pub fn lookup_table_bits(value: Option<&str>) -> anyhow::Result<Option<u8>> {
+    let bits = match value {
+        Some("on") => Some(colorful::lut::DEFAULT_BITS),
+        Some(v) => parse_optional::<u8>("lookup table bits", v)?,
+        None => None,
+    };
+
+    if let Some(bits) = bits {
+        anyhow::ensure!(
+            (1..=8).contains(&bits),
+            "lookup tables need 1 to 8 bits per channel"
+        );
+    }
+
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tool_utils::SourceKind;
+    use container::metadata::Crop;
+
+    fn source(
+        idx: usize,
+        source_kind: SourceKind,
+        title: Option<&'static str>,
+    ) -> SourceStreamMetadata {
+        SourceStreamMetadata {
+            idx,
+            source_kind,
+            codec_name: None,
+            title,
+            language: None,
+        }
+    }
+
+    fn video_sources() -> Vec<SourceStreamMetadata> {
+        vec![
+            source(0, SourceKind::Video, Some("main")),
+            source(3, SourceKind::Video, None),
+        ]
+    }
+
+    fn subtitle_sources() -> Vec<SourceStreamMetadata> {
+        vec![source(2, SourceKind::Subtitles, Some("english"))]
+    }
+
+    fn video(spec: &str) -> anyhow::Result<VideoTrack> {
+        match parse_video_track(spec, &video_sources(), 1)? {
+            AnsiTrack::VideoTrack(track) => Ok(track),
+            AnsiTrack::SubtitleTrack(_) => panic!("{spec:?} made a subtitle track"),
+        }
+    }
+
+    fn subtitles(spec: &str) -> anyhow::Result<SubtitleTrack> {
+        match parse_subtitle_track(spec, &subtitle_sources(), 1)? {
+            AnsiTrack::SubtitleTrack(track) => Ok(track),
+            AnsiTrack::VideoTrack(_) => panic!("{spec:?} made a video track"),
+        }
+    }
+
+    /// The error message of a spec that shouldn't parse, including its context.
+    fn video_error(spec: &str) -> String {
+        format!("{:#}", video(spec).expect_err(spec))
+    }
+
+    #[test]
+    fn left_out_keys_keep_their_defaults() {
+        let track = video("").unwrap();
+        let defaults = VideoTrack::default();
+
+        assert_eq!(track.source_stream_index, 0);
+        assert_eq!(track.track_id, 1);
+        assert_eq!(track.track_name, "main");
+        assert_eq!(
+            (track.track_width, track.track_height),
+            (defaults.track_width, defaults.track_height)
+        );
+        assert_eq!(track.dither_mode.method, defaults.dither_mode.method);
+        assert_eq!(track.crop, None);
+        assert!(!track.auto_crop);
+    }
+
+    #[test]
+    fn specs_set_what_they_name() {
+        let track = video(
+            " stream:3 , width:80,height:24,color:256,dither:pattern,matrix:8x8,serpentine:off,strength:0.5,",
+        )
+        .unwrap();
+
+        assert_eq!(track.source_stream_index, 3);
+        assert_eq!(track.track_name, "Video 1");
+        assert_eq!((track.track_width, track.track_height), (80, 24));
+        assert_eq!(
+            (track.dither_mode.width, track.dither_mode.height),
+            (80, 24)
+        );
+        assert_eq!(track.color_mode, ColorMode::EightBit);
+        assert_eq!(track.dither_mode.method, DitherMethod::Pattern);
+        assert_eq!(track.dither_mode.matrix_size, MatrixSize::Eight);
+        assert!(!track.dither_mode.serpentine);
+        assert_eq!(track.dither_mode.error_strength, 0.5);
+    }
+
+    #[test]
+    fn values_can_contain_colons() {
+        let track =
+            video("crop:1440:1080:240:0,source-aspect:16:9,cell-aspect:1:2,name:a:b").unwrap();
+
+        assert_eq!(
+            track.crop,
+            Some(Crop {
+                width: 1440,
+                height: 1080,
+                x: 240,
+                y: 0,
+            })
+        );
+        assert!(!track.auto_crop);
+        assert_eq!(track.source_aspect, Some(16.0 / 9.0));
+        assert_eq!(track.cell_aspect, 0.5);
+        assert_eq!(track.track_name, "a:b");
+
+        let track = video("crop:auto,source-aspect:off").unwrap();
+        assert!(track.auto_crop);
+        assert_eq!((track.crop, track.source_aspect), (None, None));
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        assert!(video_error("colour:256").contains("unknown video track key 'colour'"));
+        assert!(video_error("Width:80").contains("unknown video track key 'Width'"));
+        // keys of the other kind of track don't carry over
+        assert!(video_error("layer:0").contains("unknown video track key 'layer'"));
+        assert!(format!("{:#}", subtitles("width:80").unwrap_err())
+            .contains("unknown subtitle track key 'width'"));
+
+        assert!(video_error("width").contains("'width' should look like key:value"));
+    }
+
+    #[test]
+    fn keys_can_only_be_given_once() {
+        assert!(video_error("width:80,width:100").contains("'width' is given more than once"));
+        assert!(video_error("name:a,name:b").contains("'name' is given more than once"));
+        assert!(subtitles("stream:2,stream:2").is_err());
+
+        assert_eq!(subtitles("layer:0,layer:2").unwrap().layers, [0, 2]);
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        for (spec, message) in [
+            ("width:wide", "invalid value 'wide' for width"),
+            ("width:0", "width and height must be above 0"),
+            ("height:-1", "invalid value '-1' for height"),
+            ("color:16", "invalid value '16' for color"),
+            ("serpentine:maybe", "invalid value 'maybe' for serpentine"),
+            ("strength:1.5", "invalid value '1.5' for strength"),
+            ("gamma:0", "invalid value '0' for gamma"),
+            (
+                "crop:1440:1080:240",
+                "invalid value '1440:1080:240' for crop",
+            ),
+            ("crop:0:1080:0:0", "invalid value '0:1080:0:0' for crop"),
+            (
+                "source-aspect:16:0",
+                "invalid value '16:0' for source-aspect",
+            ),
+            ("pad:fff", "invalid value 'fff' for pad"),
+            ("dither:ordered", "invalid value 'ordered' for dither"),
+            ("stream:1", "stream 1 isn't a video stream"),
+        ] {
+            let error = video_error(spec);
+            assert!(error.contains(message), "{spec:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn tracks_are_numbered_videos_first() {
+        let tracks = parse_tracks(
+            &["width:80,height:24", "stream:3"],
+            &["layer:0"],
+            &video_sources(),
+            &subtitle_sources(),
+            5,
+        )
+        .unwrap();
+
+        let ids: Vec<_> = tracks
+            .iter()
+            .map(|track| match track {
+                AnsiTrack::VideoTrack(t) => ("video", t.track_id),
+                AnsiTrack::SubtitleTrack(t) => ("subtitles", t.track_id),
+            })
+            .collect();
+        assert_eq!(ids, [("video", 5), ("video", 6), ("subtitles", 7)]);
+
+        let error =
+            parse_tracks(&[], &["stream:0"], &video_sources(), &subtitle_sources(), 0).unwrap_err();
+        let error = format!("{error:#}");
+        assert!(
+            error.starts_with("invalid subtitle track 'stream:0'"),
+            "{error}"
+        );
+        assert!(
+            error.contains("stream 0 isn't a subtitle stream"),
+            "{error}"
+        );
+    }
+}
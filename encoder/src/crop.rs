@@ -0,0 +1,235 @@
+//! Detection of black bars burned into a source, so that tracks don't spend terminal rows on them.
+
+use container::metadata::Crop;
+use image::RgbImage;
+
+use crate::{FFMPEGResult, FFMpegSource};
+
+/// Luma at or below which a pixel counts as black. Bars are rarely pure black after compression.
+const BLACK_LEVEL: f32 = 32.0;
+/// Fraction of a line's pixels that may be brighter than [BLACK_LEVEL] with it still counting as black, so that noise
+/// and small logos in the bars don't stop them from being cropped.
+const MAX_BRIGHT_FRACTION: f32 = 0.02;
+/// Frames with any picture in them that need to agree before a crop is trusted.
+const MIN_FRAMES: usize = 4;
+
+/// How many frames apart the frames sampled for crop detection are, and how many are sampled.
+const SAMPLE_EVERY: usize = 24;
+const SAMPLE_COUNT: usize = 48;
+
+fn is_black(pixels: impl Iterator<Item = [u8; 3]>, len: usize) -> bool {
+    let bright = pixels
+        .filter(|p| 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32 > BLACK_LEVEL)
+        .count();
+    bright as f32 <= len as f32 * MAX_BRIGHT_FRACTION
+}
+
+/// The box around everything in a frame that isn't black border, as (left, top, right, bottom) with the right and
+/// bottom edges exclusive. None for frames that are black all over, like fades.
+fn picture_bounds(image: &RgbImage) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = image.dimensions();
+    let row_is_black =
+        |y: u32| is_black((0..width).map(|x| image.get_pixel(x, y).0), width as usize);
+    let column_is_black = |x: u32| {
+        is_black(
+            (0..height).map(|y| image.get_pixel(x, y).0),
+            height as usize,
+        )
+    };
+
+    let top = (0..height).find(|&y| !row_is_black(y))?;
+    let bottom = (0..height).rev().find(|&y| !row_is_black(y))? + 1;
+    let left = (0..width).find(|&x| !column_is_black(x))?;
+    let right = (0..width).rev().find(|&x| !column_is_black(x))? + 1;
+
+    Some((left, top, right, bottom))
+}
+
+/// Finds the black borders a stretch of frames all share.
+pub struct CropDetector {
+    width: u32,
+    height: u32,
+    frames: usize,                        // that weren't black all over
+    bounds: Option<(u32, u32, u32, u32)>, // of the picture in all of them together
+}
+
+impl CropDetector {
+    pub fn new(width: u32, height: u32) -> CropDetector {
+        CropDetector {
+            width,
+            height,
+            frames: 0,
+            bounds: None,
+        }
+    }
+
+    /// Frames that are black all over, like fades, are skipped.
+    pub fn add(&mut self, image: &RgbImage) {
+        assert_eq!(image.dimensions(), (self.width, self.height));
+
+        if let Some((left, top, right, bottom)) = picture_bounds(image) {
+            self.frames += 1;
+            self.bounds = Some(match self.bounds {
+                Some(b) => (b.0.min(left), b.1.min(top), b.2.max(right), b.3.max(bottom)),
+                None => (left, top, right, bottom),
+            });
+        }
+    }
+
+    /// The crop that removes the borders, or None if too few frames had any picture to go by or there's nothing to crop.
+    pub fn crop(&self) -> Option<Crop> {
+        if self.frames < MIN_FRAMES {
+            return None;
+        }
+
+        // keep to even coordinates, rounding outwards, which chroma subsampled video is happier with
+        let (left, top, right, bottom) = self.bounds?;
+        let (left, top) = (left / 2 * 2, top / 2 * 2);
+        let (right, bottom) = (
+            (right.div_ceil(2) * 2).min(self.width),
+            (bottom.div_ceil(2) * 2).min(self.height),
+        );
+        let crop = Crop {
+            width: right - left,
+            height: bottom - top,
+            x: left,
+            y: top,
+        };
+
+        (crop.width < self.width || crop.height < self.height).then_some(crop)
+    }
+}
+
+/// Samples frames from a video stream and looks for black bars in them, see [CropDetector]. This reads the source from
+/// where it is, so it's best given one opened just for this.
+pub fn detect_crop(source: &mut FFMpegSource, stream_index: usize) -> FFMPEGResult<Option<Crop>> {
+    let (width, height) = match crate::video_size(&source.streams()[stream_index]) {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+
+    let mut detector = CropDetector::new(width as u32, height as u32);
+    source.sample_frames(
+        stream_index,
+        width,
+        height,
+        SAMPLE_EVERY,
+        SAMPLE_COUNT,
+        |frame| detector.add(frame.image.as_full_color()),
+    )?;
+
+    Ok(detector.crop())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+
+    /// A black frame with a picture in the given box (right and bottom exclusive), which has some detail but is
+    /// nowhere near black.
+    fn framed(left: u32, top: u32, right: u32, bottom: u32) -> RgbImage {
+        RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+            if (left..right).contains(&x) && (top..bottom).contains(&y) {
+                Rgb([80 + (x * 2) as u8, 80 + (y * 2) as u8, 120])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        })
+    }
+
+    fn detect(frames: &[RgbImage]) -> Option<Crop> {
+        let mut detector = CropDetector::new(WIDTH, HEIGHT);
+        for frame in frames {
+            detector.add(frame);
+        }
+        detector.crop()
+    }
+
+    fn crop(width: u32, height: u32, x: u32, y: u32) -> Option<Crop> {
+        Some(Crop {
+            width,
+            height,
+            x,
+            y,
+        })
+    }
+
+    #[test]
+    fn letterboxes_are_cropped() {
+        let frames = vec![framed(0, 6, WIDTH, 42); MIN_FRAMES];
+        assert_eq!(detect(&frames), crop(WIDTH, 36, 0, 6));
+    }
+
+    #[test]
+    fn pillarboxes_are_cropped() {
+        let frames = vec![framed(8, 0, 56, HEIGHT); MIN_FRAMES];
+        assert_eq!(detect(&frames), crop(48, HEIGHT, 8, 0));
+    }
+
+    #[test]
+    fn frames_without_borders_need_no_crop() {
+        let frames = vec![framed(0, 0, WIDTH, HEIGHT); MIN_FRAMES];
+        assert_eq!(detect(&frames), None);
+    }
+
+    #[test]
+    fn the_crop_keeps_every_frames_picture() {
+        let frames = [
+            framed(0, 6, WIDTH, 42),
+            framed(0, 10, WIDTH, 38),
+            framed(4, 8, WIDTH, 44),
+            framed(0, 6, 60, 42),
+        ];
+        assert_eq!(detect(&frames), crop(WIDTH, 38, 0, 6));
+    }
+
+    #[test]
+    fn black_frames_are_skipped() {
+        let black = RgbImage::new(WIDTH, HEIGHT);
+        assert_eq!(picture_bounds(&black), None);
+
+        let mut frames = vec![black; 20];
+        frames.extend(vec![framed(0, 6, WIDTH, 42); MIN_FRAMES - 1]);
+        assert_eq!(detect(&frames), None);
+
+        frames.push(framed(0, 6, WIDTH, 42));
+        assert_eq!(detect(&frames), crop(WIDTH, 36, 0, 6));
+    }
+
+    #[test]
+    fn too_few_frames_give_no_crop() {
+        for count in 0..MIN_FRAMES {
+            assert_eq!(
+                detect(&vec![framed(0, 6, WIDTH, 42); count]),
+                None,
+                "{count} frames"
+            );
+        }
+    }
+
+    #[test]
+    fn a_little_noise_in_the_bars_is_still_black() {
+        // one bright pixel per bar row is under MAX_BRIGHT_FRACTION of it, two aren't
+        let mut noisy = framed(0, 6, WIDTH, 42);
+        let mut logo = noisy.clone();
+        for y in 0..6 {
+            noisy.put_pixel((y * 11) % WIDTH, y, Rgb([255, 255, 255]));
+            logo.put_pixel(50, y, Rgb([255, 255, 255]));
+            logo.put_pixel(51, y, Rgb([255, 255, 255]));
+        }
+
+        assert_eq!(picture_bounds(&noisy), Some((0, 6, WIDTH, 42)));
+        assert_eq!(picture_bounds(&logo), Some((0, 0, WIDTH, 42)));
+        assert_eq!(detect(&vec![noisy; MIN_FRAMES]), crop(WIDTH, 36, 0, 6));
+    }
+
+    #[test]
+    fn odd_edges_round_outwards_to_even_ones() {
+        let frames = vec![framed(3, 5, 61, 43); MIN_FRAMES];
+        assert_eq!(detect(&frames), crop(60, 40, 2, 4));
+    }
+}
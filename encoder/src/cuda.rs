@@ -46,6 +46,7 @@ impl CudaDitherer {
                     MatrixSize::Eight => module.get_function("dither_8x8"),
                     MatrixSize::Four => module.get_function("dither_4x4"),
                     MatrixSize::Two => module.get_function("dither_2x2"),
+                    MatrixSize::BlueNoise => module.get_function("dither_blue_noise"),
                 }
                 .unwrap()
             },
@@ -3,6 +3,7 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::tool_utils::{Downscale, SourceColor};
 use crate::{PullSource, Transformer};
 use img2ansi::VideoImage;
 
@@ -10,6 +11,7 @@ use super::video_encoder::DecodedVideoFrame;
 use ac_ffmpeg::codec::video::scaler::Algorithm;
 use ac_ffmpeg::codec::video::PixelFormat;
 use ac_ffmpeg::codec::Decoder;
+use ac_ffmpeg::codec::VideoCodecParameters;
 use ac_ffmpeg::time::TimeBase;
 use ac_ffmpeg::Error as FFMPEGError;
 use ac_ffmpeg::{
@@ -20,8 +22,11 @@ use ac_ffmpeg::{
     },
 };
 use bytes::BytesMut;
+use colorful::colorspace::{Matrix, Range, Transfer, YuvConversion};
+use colorful::linear::area_downscale;
+use container::metadata::Crop;
 use container::packet::Packet as MoePacket;
-use image::RgbImage;
+use image::{imageops, Rgb, RgbImage};
 use postage::sink::Sink;
 use tokio::task::JoinError;
 
@@ -52,6 +57,81 @@ impl FFMpegSource {
     pub fn get_format_names(&self) -> Option<&'static str> {
         self.dmxr.get_format_names()
     }
+
+    pub fn first_video_stream(&self) -> Option<usize> {
+        self.streams().iter().position(|s| {
+            crate::tool_utils::SourceKind::from_parameters(s.codec_parameters())
+                == Some(crate::tool_utils::SourceKind::Video)
+        })
+    }
+
+    /// Decodes up to `count` frames of a video stream, scaled to the given size. Meant for tools that compare encoder settings
+    /// on a stretch of video, which is kept in memory.
+    pub fn decode_frames(
+        &mut self,
+        stream_index: usize,
+        width: usize,
+        height: usize,
+        count: usize,
+    ) -> FFMPEGResult<Vec<DecodedVideoFrame>> {
+        let mut frames: Vec<DecodedVideoFrame> = Vec::with_capacity(count);
+        self.sample_frames(stream_index, width, height, 1, count, |frame| {
+            frames.push(frame)
+        })?;
+
+        Ok(frames)
+    }
+
+    /// Decodes a video stream, scaled to the given size, handing every `every`th frame to `sample` until it's had `count`
+    /// of them or the stream ends.
+    pub fn sample_frames(
+        &mut self,
+        stream_index: usize,
+        width: usize,
+        height: usize,
+        every: usize,
+        count: usize,
+        mut sample: impl FnMut(DecodedVideoFrame),
+    ) -> FFMPEGResult<()> {
+        let mut decoder = FFMpegVideoDecoder::from_stream(
+            &self.streams()[stream_index],
+            Algorithm::Lanczos,
+            width,
+            height,
+        )?;
+
+        let (mut decoded, mut sampled) = (0, 0);
+        // hands a frame on if it's due, and says whether enough have been
+        let mut take = |frame: DecodedVideoFrame| {
+            if decoded % every.max(1) == 0 && sampled < count {
+                sample(frame);
+                sampled += 1;
+            }
+            decoded += 1;
+            sampled >= count
+        };
+
+        let mut done = count == 0;
+        while !done {
+            match self.pull()? {
+                Some(packet) if packet.0 == stream_index => decoder.push(&packet)?,
+                Some(_) => continue,
+                None => {
+                    decoder.handle_input_close()?;
+                    while let Some(frame) = decoder.pull()? {
+                        take(frame);
+                    }
+                    break;
+                }
+            }
+
+            while let Some(frame) = decoder.pull()? {
+                done = take(frame);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl PullSource for FFMpegSource {
@@ -78,6 +158,188 @@ pub struct FFMpegVideoDecoder {
     target_height: usize,
     time_base: TimeBase,
     scaling_algorithm: Algorithm,
+    linear_light: bool,
+    downscale: Downscale,
+    crop: Option<(usize, usize, usize, usize)>, // x, y, width and height of the picture in scaled frames
+    padding: Option<(usize, usize, [u8; 3])>,   // frame size and color to center the picture in
+    scaler_format: &'static str, // pixel format the scaler outputs: rgb24, or Y'CbCr for the conversion to read
+    conversion: Option<YuvConversion>, // to RGB, for Y'CbCr sources
+}
+
+/// Width and height of a video stream's frames.
+pub fn video_size(stream: &Stream) -> Option<(usize, usize)> {
+    let parameters = stream.codec_parameters();
+    let video_parameters = parameters.as_video_codec_parameters()?;
+    Some((video_parameters.width(), video_parameters.height()))
+}
+
+/// Display aspect ratio (width / height) of a video stream.
+/// ac-ffmpeg doesn't expose the sample aspect ratio, so this assumes square pixels; anamorphic sources need
+/// [crate::tool_utils::VideoTrack::source_aspect] set, and [crate::tool_utils::VideoTrack::aspect_warning] points out
+/// sizes that usually are.
+pub fn display_aspect_ratio(stream: &Stream) -> Option<f32> {
+    let parameters = stream.codec_parameters();
+    let video_parameters = parameters.as_video_codec_parameters()?;
+    if video_parameters.height() == 0 {
+        return None;
+    }
+
+    Some(video_parameters.width() as f32 / video_parameters.height() as f32)
+}
+
+fn is_yuv(pixel_format: &str) -> bool {
+    ["yuv", "nv", "p0", "p2", "yuyv", "uyvy"]
+        .iter()
+        .any(|prefix| pixel_format.starts_with(prefix))
+}
+
+/// Bits per sample of a pixel format, from the digits after its last 'p', like "yuv420p10le" or "p010le".
+fn bit_depth(pixel_format: &str) -> usize {
+    let name = pixel_format.trim_end_matches("le").trim_end_matches("be");
+    name.rfind('p')
+        .and_then(|i| name[i + 1..].parse().ok())
+        .unwrap_or(8)
+}
+
+/// Whether a plane holds `height` rows of `row_bytes` bytes, starting `stride` bytes apart.
+fn plane_fits(data: &[u8], stride: usize, row_bytes: usize, height: usize) -> bool {
+    stride >= row_bytes && (height == 0 || data.len() >= stride * (height - 1) + row_bytes)
+}
+
+/// Copies a packed RGB24 plane out of a frame. ffmpeg pads rows for alignment, so they start `stride` bytes apart
+/// rather than right after each other, unless the width happens to work out. Even then the pixels get copied once,
+/// as the frame's buffer belongs to ffmpeg, which reuses it for the next frame.
+fn plane_image(data: &[u8], stride: usize, width: usize, height: usize) -> Option<RgbImage> {
+    let row_bytes = width * 3;
+    if row_bytes == 0 || !plane_fits(data, stride, row_bytes, height) {
+        return None;
+    }
+
+    let mut image = RgbImage::new(width as u32, height as u32);
+    for (row, source) in image.chunks_exact_mut(row_bytes).zip(data.chunks(stride)) {
+        row.copy_from_slice(&source[..row_bytes]);
+    }
+    Some(image)
+}
+
+/// Converts planar 4:4:4 Y'CbCr out of a frame, see [YuvConversion::convert_planes].
+fn yuv_image(
+    conversion: &YuvConversion,
+    planes: [(&[u8], usize); 3],
+    width: usize,
+    height: usize,
+    sixteen_bit: bool,
+) -> Option<RgbImage> {
+    let row_bytes = if sixteen_bit { width * 2 } else { width };
+    if row_bytes == 0
+        || !planes
+            .iter()
+            .all(|(data, stride)| plane_fits(data, *stride, row_bytes, height))
+    {
+        return None;
+    }
+
+    Some(conversion.convert_planes(width, height, planes, sixteen_bit))
+}
+
+/// The matrix and range a Y'CbCr source is converted with. ac-ffmpeg doesn't expose the colorspace tags of streams, so
+/// what `color` doesn't set is guessed like players do for untagged video: the matrix from the frame size, and the
+/// range from the pixel format. Comes with notes on what was guessed, and on high bit depth sources decoded as SDR,
+/// which may well be HDR: nothing but those tags tells HDR10 or HLG apart from 10 bit SDR.
+fn source_conversion(
+    color: &SourceColor,
+    pixel_format: &str,
+    width: usize,
+    height: usize,
+) -> (Matrix, Range, Vec<String>) {
+    let mut notes = Vec::new();
+    let source = format!("{width}x{height} {pixel_format} source");
+
+    let matrix = color.matrix.unwrap_or_else(|| {
+        let matrix = Matrix::guess(width, height, color.transfer);
+        notes.push(format!(
+            "guessed the {matrix} matrix for the {source}, set one if colors look off"
+        ));
+        matrix
+    });
+    let range = color.range.unwrap_or_else(|| {
+        let range = if pixel_format.starts_with("yuvj") {
+            Range::Full
+        } else {
+            Range::Limited
+        };
+        notes.push(format!(
+            "guessed {range} range for the {source}, set one if blacks look crushed or grey"
+        ));
+        range
+    });
+
+    let depth = bit_depth(pixel_format);
+    if depth > 8 && color.transfer == Transfer::Sdr {
+        notes.push(format!(
+            "decoding the {depth} bit {source} as SDR; if it's HDR and looks washed out, set transfer to pq or hlg"
+        ));
+    }
+
+    (matrix, range, notes)
+}
+
+/// Size the scaler brings frames to, before [FFMpegVideoDecoder::pull_from_decoder] takes them the rest of the way.
+/// Area averaging, which downscaling in linear light also does, starts from the source's size; multi-step scaling
+/// from the largest power of two of the target's size that fits in it.
+fn scaler_size(
+    video_parameters: &VideoCodecParameters,
+    target_width: usize,
+    target_height: usize,
+    linear_light: bool,
+    downscale: Downscale,
+) -> (usize, usize) {
+    let (source_width, source_height) = (video_parameters.width(), video_parameters.height());
+    if source_width < target_width || source_height < target_height {
+        return (target_width, target_height);
+    }
+
+    match downscale {
+        Downscale::Direct if !linear_light => (target_width, target_height),
+        Downscale::Direct | Downscale::Area => (source_width, source_height),
+        Downscale::MultiStep => {
+            let (mut width, mut height) = (target_width.max(1), target_height.max(1));
+            while width * 2 <= source_width && height * 2 <= source_height {
+                width *= 2;
+                height *= 2;
+            }
+            (width, height)
+        }
+    }
+}
+
+/// Builds the scaler from decoded frames to the given pixel format, see [scaler_size] for the size it scales to.
+fn build_scaler(
+    video_parameters: &VideoCodecParameters,
+    target_pixel_format: &str,
+    scaling_algorithm: Algorithm,
+    target_width: usize,
+    target_height: usize,
+    linear_light: bool,
+    downscale: Downscale,
+) -> FFMPEGResult<VideoFrameScaler> {
+    let (width, height) = scaler_size(
+        video_parameters,
+        target_width,
+        target_height,
+        linear_light,
+        downscale,
+    );
+
+    VideoFrameScaler::builder()
+        .source_pixel_format(video_parameters.pixel_format())
+        .source_height(video_parameters.height())
+        .source_width(video_parameters.width())
+        .target_height(height)
+        .target_width(width)
+        .target_pixel_format(PixelFormat::from_str(target_pixel_format).unwrap())
+        .algorithm(scaling_algorithm)
+        .build()
 }
 
 impl FFMpegVideoDecoder {
@@ -91,15 +353,15 @@ impl FFMpegVideoDecoder {
         let video_parameters = parameters.as_video_codec_parameters().unwrap();
 
         let decoder = VideoDecoder::from_stream(stream)?.build()?;
-        let scaler = VideoFrameScaler::builder()
-            .source_pixel_format(video_parameters.pixel_format())
-            .source_height(video_parameters.height())
-            .source_width(video_parameters.width())
-            .target_height(target_height)
-            .target_width(target_width)
-            .target_pixel_format(PixelFormat::from_str("rgb24").unwrap())
-            .algorithm(scaling_algorithm)
-            .build()?;
+        let scaler = build_scaler(
+            video_parameters,
+            "rgb24",
+            scaling_algorithm,
+            target_width,
+            target_height,
+            false,
+            Downscale::Direct,
+        )?;
 
         Ok(FFMpegVideoDecoder {
             decoder,
@@ -108,8 +370,132 @@ impl FFMpegVideoDecoder {
             target_width,
             time_base: stream.time_base(),
             scaling_algorithm,
+            linear_light: false,
+            downscale: Downscale::Direct,
+            crop: None,
+            padding: None,
+            scaler_format: "rgb24",
+            conversion: None,
         })
     }
+
+    fn rebuild_scaler(&mut self) -> FFMPEGResult<()> {
+        self.scaler = build_scaler(
+            &self.decoder.codec_parameters(),
+            self.scaler_format,
+            self.scaling_algorithm,
+            self.target_width,
+            self.target_height,
+            self.linear_light,
+            self.downscale,
+        )?;
+        Ok(())
+    }
+
+    /// Downscales in linear light (see [colorful::linear]) instead of on sRGB values, which keeps fine detail from darkening.
+    pub fn with_linear_light(mut self, linear_light: bool) -> FFMPEGResult<FFMpegVideoDecoder> {
+        self.linear_light = linear_light;
+        self.rebuild_scaler()?;
+        Ok(self)
+    }
+
+    /// Sets how frames are brought down to the target size, see [Downscale].
+    pub fn with_downscale(mut self, downscale: Downscale) -> FFMPEGResult<FFMpegVideoDecoder> {
+        self.downscale = downscale;
+        self.rebuild_scaler()?;
+        Ok(self)
+    }
+
+    /// Converts Y'CbCr sources to RGB with the right matrix and range, and tone maps HDR ones, instead of leaving it to
+    /// swscale, which assumes BT.601, and limited range unless the pixel format says otherwise. What isn't set is
+    /// guessed, see [source_conversion], and the guesses are logged. RGB and gray sources are left to the scaler.
+    pub fn with_source_color(mut self, color: &SourceColor) -> FFMPEGResult<FFMpegVideoDecoder> {
+        let parameters = self.decoder.codec_parameters();
+        let pixel_format = parameters.pixel_format().name();
+        if !is_yuv(pixel_format) {
+            return Ok(self);
+        }
+
+        let (matrix, range, notes) =
+            source_conversion(color, pixel_format, parameters.width(), parameters.height());
+        for note in notes {
+            eprintln!("{note}");
+        }
+        self.conversion = Some(YuvConversion::new(
+            matrix,
+            range,
+            color.transfer,
+            color.tone_map,
+            color.peak,
+        ));
+        // planar 4:4:4 at the source's range, which the scaler only resamples
+        self.scaler_format = if bit_depth(pixel_format) > 8 {
+            "yuv444p16le"
+        } else if pixel_format.starts_with("yuvj") {
+            "yuvj444p"
+        } else {
+            "yuv444p"
+        };
+
+        self.rebuild_scaler()?;
+        Ok(self)
+    }
+
+    /// Size of the pictures that come out, before any padding.
+    fn picture_size(&self) -> (usize, usize) {
+        match self.crop {
+            Some((_, _, width, height)) => (width, height),
+            None => (self.target_width, self.target_height),
+        }
+    }
+
+    /// Shows only part of the source, given in source pixels, at the size the decoder was made for.
+    /// The whole frame is scaled so that the part comes out at that size, and cut out afterwards.
+    pub fn with_crop(mut self, crop: Option<Crop>) -> FFMPEGResult<FFMpegVideoDecoder> {
+        let crop = match crop {
+            Some(crop) => crop,
+            None => return Ok(self),
+        };
+
+        let parameters = self.decoder.codec_parameters();
+        let (source_width, source_height) = (parameters.width(), parameters.height());
+        if crop.width == 0
+            || crop.height == 0
+            || (crop.x + crop.width) as usize > source_width
+            || (crop.y + crop.height) as usize > source_height
+        {
+            return Err(FFMPEGError::new(format!(
+                "crop {crop} doesn't fit in the {source_width}x{source_height} source"
+            )));
+        }
+
+        let (width, height) = self.picture_size();
+        let scale_x = width as f64 / crop.width as f64;
+        let scale_y = height as f64 / crop.height as f64;
+        self.target_width = ((source_width as f64 * scale_x).round() as usize).max(width);
+        self.target_height = ((source_height as f64 * scale_y).round() as usize).max(height);
+
+        let x = ((crop.x as f64 * scale_x).round() as usize).min(self.target_width - width);
+        let y = ((crop.y as f64 * scale_y).round() as usize).min(self.target_height - height);
+        self.crop = Some((x, y, width, height));
+
+        self.rebuild_scaler()?;
+        Ok(self)
+    }
+
+    /// Centers the scaled picture in a frame of the given size, filling the rest with a color.
+    /// Frames no larger than the picture leave it as it is.
+    pub fn with_padding(
+        mut self,
+        frame_width: usize,
+        frame_height: usize,
+        color: [u8; 3],
+    ) -> FFMpegVideoDecoder {
+        let (width, height) = self.picture_size();
+        self.padding = (frame_width > width || frame_height > height)
+            .then(|| (frame_width.max(width), frame_height.max(height), color));
+        self
+    }
 }
 
 impl Clone for FFMpegVideoDecoder {
@@ -120,16 +506,16 @@ impl Clone for FFMpegVideoDecoder {
             .time_base(self.time_base)
             .build()
             .unwrap();
-        let scaler = VideoFrameScaler::builder()
-            .source_pixel_format(video_parameters.pixel_format())
-            .source_height(video_parameters.height())
-            .source_width(video_parameters.width())
-            .target_height(self.target_height)
-            .target_width(self.target_width)
-            .target_pixel_format(PixelFormat::from_str("rgb24").unwrap())
-            .algorithm(self.scaling_algorithm)
-            .build()
-            .unwrap();
+        let scaler = build_scaler(
+            &video_parameters,
+            self.scaler_format,
+            self.scaling_algorithm,
+            self.target_width,
+            self.target_height,
+            self.linear_light,
+            self.downscale,
+        )
+        .unwrap();
 
         Self {
             decoder,
@@ -138,6 +524,12 @@ impl Clone for FFMpegVideoDecoder {
             target_height: self.target_height,
             time_base: self.time_base,
             scaling_algorithm: self.scaling_algorithm,
+            linear_light: self.linear_light,
+            downscale: self.downscale,
+            crop: self.crop,
+            padding: self.padding,
+            scaler_format: self.scaler_format,
+            conversion: self.conversion.clone(),
         }
 
         // Self { decoder: self.decoder.clone(), scaler: self.scaler.clone(), target_width: self.target_width.clone(), target_height: self.target_height.clone(), time_base: self.time_base.clone() }
@@ -178,17 +570,58 @@ impl FFMpegVideoDecoder {
                 .as_nanos()
                 .map(|v| Duration::from_nanos(v as u64))
                 .unwrap_or_default();
-            let image = RgbImage::from_vec(
-                self.target_width as u32,
-                self.target_height as u32,
-                v.planes()[0].data().to_vec(),
-            )
+            let (width, height) = (v.width(), v.height());
+            let planes = v.planes();
+            let mut image = match &self.conversion {
+                Some(conversion) => yuv_image(
+                    conversion,
+                    [0, 1, 2].map(|i| (planes[i].data(), planes[i].line_size())),
+                    width,
+                    height,
+                    self.scaler_format == "yuv444p16le",
+                ),
+                None => plane_image(planes[0].data(), planes[0].line_size(), width, height),
+            }
             .ok_or(FFMPEGError::new("invalid videoframe data"))?;
 
+            let (target_width, target_height) =
+                (self.target_width as u32, self.target_height as u32);
+            if self.downscale == Downscale::MultiStep {
+                while image.width() >= target_width * 2 && image.height() >= target_height * 2 {
+                    image = area_downscale(
+                        &image,
+                        image.width() / 2,
+                        image.height() / 2,
+                        self.linear_light,
+                    );
+                }
+            }
+
+            if image.dimensions() != (target_width, target_height) {
+                image = area_downscale(&image, target_width, target_height, self.linear_light);
+            }
+
+            if let Some((x, y, width, height)) = self.crop {
+                image = imageops::crop_imm(&image, x as u32, y as u32, width as u32, height as u32)
+                    .to_image();
+            }
+
+            if let Some((frame_width, frame_height, color)) = self.padding {
+                let (width, height) = self.picture_size();
+                let mut frame =
+                    RgbImage::from_pixel(frame_width as u32, frame_height as u32, Rgb(color));
+                // keep the picture's top edge on a cell boundary, as cells are two pixels tall
+                let x = (frame_width - width) / 2;
+                let y = (frame_height - height) / 4 * 2;
+                imageops::replace(&mut frame, &image, x as u32, y as u32);
+                image = frame;
+            }
+
             return Ok(Some(DecodedVideoFrame {
                 pts,
                 duration,
                 image: VideoImage::FullColor(image),
+                palette: None,
             }));
         }
 
@@ -200,9 +633,13 @@ impl FFMpegVideoDecoder {
 decoding for generic packets
 */
 
+/// Says whether to keep a packet, given its data.
+type PacketFilter = Box<dyn Fn(&[u8]) -> bool + Send>;
+
 pub struct GenericPacketDecoder {
     new_stream_index: u16,
     buffer: Option<MoePacket<BytesMut>>,
+    filter: Option<PacketFilter>,
 }
 
 impl GenericPacketDecoder {
@@ -210,8 +647,15 @@ impl GenericPacketDecoder {
         Self {
             new_stream_index: idx,
             buffer: None,
+            filter: None,
         }
     }
+
+    /// Drops packets whose data the filter returns false for.
+    pub fn with_filter(mut self, filter: impl Fn(&[u8]) -> bool + Send + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
 }
 
 impl Transformer for GenericPacketDecoder {
@@ -220,6 +664,12 @@ impl Transformer for GenericPacketDecoder {
     type Err = Infallible;
 
     fn push(&mut self, src: &Self::Src) -> Result<(), Self::Err> {
+        if let Some(filter) = &self.filter {
+            if !filter(src.1.data()) {
+                return Ok(());
+            }
+        }
+
         self.buffer = Some(MoePacket {
             stream_index: self.new_stream_index,
             presentation_length: src
@@ -275,7 +725,11 @@ macro_rules! pipeline {
                     let res = transformer.pull();
                     if let Some(mut v) = res.unwrap() {
                         processor.map(&mut v);
-                        tx.blocking_send(encoder.encode_packet(v).unwrap());
+                        let packet = encoder.encode_packet(v).unwrap();
+                        while let Some(preceding) = encoder.take_preceding() {
+                            tx.blocking_send(preceding);
+                        }
+                        tx.blocking_send(packet);
                         continue;
                     } else if input_closed {
                         break;
@@ -298,6 +752,9 @@ macro_rules! pipeline {
 
                 }
 
+                $crate::finish_processor(&mut processor, &transformer)
+                    .map_err(|e| ac_ffmpeg::Error::new(format!("couldn't finish track: {e}")))?;
+
                 Ok(())
             });
 
@@ -331,3 +788,186 @@ where
 
     futures::future::join_all(handles).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colorful::colorspace::ToneMap;
+
+    /// Lays rows out `stride` bytes apart, filling the padding with junk, the way ffmpeg hands planes over.
+    /// The last row isn't padded, as ffmpeg doesn't guarantee it is.
+    fn padded(rows: &[Vec<u8>], stride: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            data.extend_from_slice(row);
+            if i + 1 < rows.len() {
+                data.resize(data.len() + stride - row.len(), 0xaa);
+            }
+        }
+        data
+    }
+
+    /// Rows of `width` samples of `bytes` bytes each, all different so that misplaced rows show.
+    fn rows(width: usize, height: usize, bytes: usize, seed: usize) -> Vec<Vec<u8>> {
+        (0..height)
+            .map(|y| {
+                (0..width * bytes)
+                    .map(|x| ((x * 7 + y * 13 + seed) % 251) as u8)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// ffmpeg aligns rows to 32 or 64 bytes.
+    fn aligned(row_bytes: usize) -> usize {
+        row_bytes.div_ceil(64) * 64
+    }
+
+    #[test]
+    fn packed_planes_skip_row_padding() {
+        for (width, height) in [(191, 54), (97, 40)] {
+            let rows = rows(width, height, 3, 0);
+            let expected = RgbImage::from_vec(width as u32, height as u32, rows.concat()).unwrap();
+
+            for stride in [width * 3, aligned(width * 3)] {
+                let image = plane_image(&padded(&rows, stride), stride, width, height);
+                assert_eq!(
+                    image.as_ref(),
+                    Some(&expected),
+                    "{} wide, stride {}",
+                    width,
+                    stride
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn yuv_planes_skip_row_padding() {
+        let conversion = YuvConversion::new(
+            Matrix::Bt709,
+            Range::Limited,
+            Transfer::Sdr,
+            ToneMap::Clip,
+            1000.0,
+        );
+
+        for (width, height) in [(191, 54), (97, 40)] {
+            for sixteen_bit in [false, true] {
+                let bytes = if sixteen_bit { 2 } else { 1 };
+                let planes: Vec<Vec<Vec<u8>>> =
+                    (0..3).map(|i| rows(width, height, bytes, i * 50)).collect();
+
+                let packed: Vec<Vec<u8>> = planes.iter().map(|p| p.concat()).collect();
+                let expected = conversion.convert_planes(
+                    width,
+                    height,
+                    [0, 1, 2].map(|i| (&packed[i][..], width * bytes)),
+                    sixteen_bit,
+                );
+
+                let stride = aligned(width * bytes);
+                let data: Vec<Vec<u8>> = planes.iter().map(|p| padded(p, stride)).collect();
+                let image = yuv_image(
+                    &conversion,
+                    [0, 1, 2].map(|i| (&data[i][..], stride)),
+                    width,
+                    height,
+                    sixteen_bit,
+                );
+                assert_eq!(image.as_ref(), Some(&expected), "{} wide", width);
+
+                // and the conversion itself reads the right samples
+                let (x, y) = (width - 1, height - 1);
+                let sample = |plane: &[Vec<u8>]| {
+                    if sixteen_bit {
+                        u16::from_le_bytes([plane[y][x * 2], plane[y][x * 2 + 1]]) as f32 / 257.0
+                    } else {
+                        plane[y][x] as f32
+                    }
+                };
+                assert_eq!(
+                    expected.get_pixel(x as u32, y as u32).0,
+                    conversion.convert(sample(&planes[0]), sample(&planes[1]), sample(&planes[2]))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn short_planes_are_rejected() {
+        let (width, height) = (97, 40);
+        let stride = aligned(width * 3);
+        let data = padded(&rows(width, height, 3, 0), stride);
+
+        assert!(plane_image(&data[..data.len() - 1], stride, width, height).is_none());
+        assert!(plane_image(&data, width * 3 - 1, width, height).is_none());
+        assert!(plane_image(&data, stride, width, height + 1).is_none());
+
+        let conversion = YuvConversion::new(
+            Matrix::Bt601,
+            Range::Full,
+            Transfer::Sdr,
+            ToneMap::Clip,
+            1000.0,
+        );
+        let plane = padded(&rows(width, height, 1, 0), aligned(width));
+        let short = &plane[..plane.len() - 1];
+        for planes in [
+            [
+                (short, aligned(width)),
+                (&plane[..], aligned(width)),
+                (&plane[..], aligned(width)),
+            ],
+            [
+                (&plane[..], aligned(width)),
+                (&plane[..], aligned(width)),
+                (short, aligned(width)),
+            ],
+        ] {
+            assert!(yuv_image(&conversion, planes, width, height, false).is_none());
+        }
+        // 16 bit samples need twice the bytes
+        let planes = [(&plane[..], aligned(width)); 3];
+        assert!(yuv_image(&conversion, planes, width, height, true).is_none());
+    }
+
+    #[test]
+    fn untagged_sources_are_guessed_like_players_do() {
+        let color = SourceColor::default();
+
+        let (matrix, range, notes) = source_conversion(&color, "yuv420p", 1920, 1080);
+        assert_eq!((matrix, range), (Matrix::Bt709, Range::Limited));
+        assert_eq!(notes.len(), 2);
+        assert!(notes[0].contains("bt709") && notes[1].contains("limited"));
+
+        let (matrix, range, _) = source_conversion(&color, "yuvj420p", 640, 480);
+        assert_eq!((matrix, range), (Matrix::Bt601, Range::Full));
+    }
+
+    #[test]
+    fn high_bit_depth_sdr_is_flagged_as_maybe_hdr() {
+        let color = SourceColor {
+            matrix: Some(Matrix::Bt709),
+            range: Some(Range::Limited),
+            ..SourceColor::default()
+        };
+        assert!(source_conversion(&color, "yuv420p", 1920, 1080)
+            .2
+            .is_empty());
+
+        let (_, _, notes) = source_conversion(&color, "yuv420p10le", 3840, 2160);
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].contains("10 bit") && notes[0].contains("pq or hlg"));
+
+        // told it's HDR, so the matrix is guessed for that, and there's nothing to warn about
+        let hdr = SourceColor {
+            transfer: Transfer::Pq,
+            range: Some(Range::Limited),
+            ..SourceColor::default()
+        };
+        let (matrix, _, notes) = source_conversion(&hdr, "yuv420p10le", 3840, 2160);
+        assert_eq!(matrix, Matrix::Bt2020);
+        assert_eq!(notes.len(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+//! Encode jobs: everything `encode` takes as flags or asks for, in a TOML or JSON file that can be kept around and rerun.
+//!
+//! ```toml
+//! input = "input.mkv"
+//! output = "out.ansi"
+//! palette = "theme.toml"
+//! keyframe_interval = "10"
+//!
+//! [[tracks]]
+//! source = { language = "jpn" }
+//! [tracks.video]
+//! track_name = "eightbit"
+//! track_width = 192
+//! track_height = 108
+//! sizing = "fit-box"
+//! padding = [0, 0, 0]
+//! color_mode = "EightBit"
+//! dither_mode = { method = "pattern", matrix_size = "8x8" }
+//!
+//! [[tracks]]
+//! source = { title = "Signs & Songs" }
+//! [tracks.subtitles]
+//! track_name = "signs"
+//! layers = [0]
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::tool_utils::{AnsiTrack, SourceStreamMetadata};
+
+/// Picks a track's source stream among the ones of the right kind.
+/// Goes through [SelectorTable] rather than serde's enum layout, which TOML can only write for unit variants, and
+/// only read from inline tables.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "SelectorTable", into = "SelectorTable")]
+pub enum StreamSelector {
+    Index(usize),     // the stream's index in the input
+    Language(String), // the stream's language tag, like "jpn"
+    Title(String),
+}
+
+impl StreamSelector {
+    pub fn select<'a>(
+        &self,
+        sources: &'a [SourceStreamMetadata],
+    ) -> Option<&'a SourceStreamMetadata> {
+        sources.iter().find(|source| match self {
+            StreamSelector::Index(idx) => source.idx == *idx,
+            StreamSelector::Language(language) => source
+                .language
+                .is_some_and(|v| v.eq_ignore_ascii_case(language)),
+            StreamSelector::Title(title) => source.title == Some(title.as_str()),
+        })
+    }
+}
+
+/// How a [StreamSelector] is written down: a table with one of its keys set.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SelectorTable {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+}
+
+impl TryFrom<SelectorTable> for StreamSelector {
+    type Error = &'static str;
+
+    fn try_from(table: SelectorTable) -> Result<StreamSelector, Self::Error> {
+        match table {
+            SelectorTable {
+                index: Some(idx),
+                language: None,
+                title: None,
+            } => Ok(StreamSelector::Index(idx)),
+            SelectorTable {
+                index: None,
+                language: Some(language),
+                title: None,
+            } => Ok(StreamSelector::Language(language)),
+            SelectorTable {
+                index: None,
+                language: None,
+                title: Some(title),
+            } => Ok(StreamSelector::Title(title)),
+            _ => Err("a source needs exactly one of index, language or title"),
+        }
+    }
+}
+
+impl From<StreamSelector> for SelectorTable {
+    fn from(selector: StreamSelector) -> SelectorTable {
+        match selector {
+            StreamSelector::Index(idx) => SelectorTable {
+                index: Some(idx),
+                ..Default::default()
+            },
+            StreamSelector::Language(language) => SelectorTable {
+                language: Some(language),
+                ..Default::default()
+            },
+            StreamSelector::Title(title) => SelectorTable {
+                title: Some(title),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobTrack {
+    /// the first stream of the track's kind if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<StreamSelector>,
+    #[serde(flatten)]
+    pub track: AnsiTrack,
+}
+
+impl From<&AnsiTrack> for JobTrack {
+    fn from(track: &AnsiTrack) -> JobTrack {
+        let source_stream_index = match track {
+            AnsiTrack::VideoTrack(t) => t.source_stream_index,
+            AnsiTrack::SubtitleTrack(t) => t.source_stream_index,
+        };
+
+        JobTrack {
+            source: Some(StreamSelector::Index(source_stream_index)),
+            track: track.clone(),
+        }
+    }
+}
+
+/// The palette, lookup table and keyframe settings take the same values as their flags, and apply to every track.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Job {
+    pub input: String,
+    pub output: PathBuf,
+    pub compress: bool,
+    pub compression_level: i32,
+    pub palette: Option<String>,
+    pub lut: Option<String>,
+    pub lut_cache: Option<PathBuf>,
+    pub keyframe_interval: Option<String>,
+    pub keyframe_frames: Option<String>,
+    pub scene_cut: Option<String>,
+    pub metrics: Option<PathBuf>,
+    pub tracks: Vec<JobTrack>,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        Self {
+            input: String::new(),
+            output: PathBuf::new(),
+            compress: true,
+            compression_level: 5,
+            palette: None,
+            lut: None,
+            lut_cache: None,
+            keyframe_interval: None,
+            keyframe_frames: None,
+            scene_cut: None,
+            metrics: None,
+            tracks: Vec::new(),
+        }
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("json"))
+}
+
+impl Job {
+    /// Reads a job file: JSON if its name ends in .json, TOML otherwise.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Job> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read job file {}", path.display()))?;
+
+        if is_json(path) {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("invalid job file {}", path.display()))
+        } else {
+            toml::from_str(&contents)
+                .with_context(|| format!("invalid job file {}", path.display()))
+        }
+    }
+
+    /// Writes the job out, in the format [Job::load] expects for the path.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let contents = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+
+        std::fs::write(path, contents)
+            .with_context(|| format!("couldn't write job file {}", path.display()))
+    }
+
+    /// Resolves the job's tracks against the input's streams, numbering them from 1.
+    pub fn resolve_tracks(
+        &self,
+        video_sources: &[SourceStreamMetadata],
+        subtitle_sources: &[SourceStreamMetadata],
+    ) -> anyhow::Result<Vec<AnsiTrack>> {
+        self.tracks
+            .iter()
+            .enumerate()
+            .map(|(i, job_track)| {
+                let (sources, kind) = match job_track.track {
+                    AnsiTrack::VideoTrack(_) => (video_sources, "video"),
+                    AnsiTrack::SubtitleTrack(_) => (subtitle_sources, "subtitle"),
+                };
+
+                let source = match &job_track.source {
+                    Some(selector) => selector.select(sources),
+                    None => sources.first(),
+                }
+                .with_context(|| {
+                    format!("no {kind} stream matches the source of track {}", i + 1)
+                })?;
+
+                let mut track = job_track.track.clone();
+                match &mut track {
+                    AnsiTrack::VideoTrack(t) => {
+                        t.source_stream_index = source.idx;
+                        t.track_id = i + 1;
+                        t.dither_mode.width = t.track_width as u32;
+                        t.dither_mode.height = t.track_height as u32;
+                    }
+                    AnsiTrack::SubtitleTrack(t) => {
+                        t.source_stream_index = source.idx;
+                        t.track_id = i + 1;
+                    }
+                }
+
+                Ok(track)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tool_utils::DitherMethod;
+
+    /// The TOML example in the module docs.
+    fn doc_example() -> String {
+        include_str!("job.rs")
+            .lines()
+            .filter_map(|line| line.strip_prefix("//!"))
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .skip_while(|line| *line != "```toml")
+            .skip(1)
+            .take_while(|line| *line != "```")
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("job-{}-{name}", std::process::id()))
+    }
+
+    /// Saves the job to a file of the given name and loads it back.
+    fn round_trip(job: &Job, name: &str) -> Job {
+        let path = temp_path(name);
+        job.save(&path).unwrap();
+        let loaded = Job::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap()
+    }
+
+    fn summary(job: &Job) -> String {
+        serde_json::to_string(job).unwrap()
+    }
+
+    #[test]
+    fn doc_example_parses() {
+        let job: Job = toml::from_str(&doc_example()).unwrap();
+        assert_eq!(job.input, "input.mkv");
+        assert_eq!(job.keyframe_interval.as_deref(), Some("10"));
+        assert!(job.compress);
+        assert_eq!(job.tracks.len(), 2);
+
+        match (&job.tracks[0].source, &job.tracks[0].track) {
+            (Some(StreamSelector::Language(language)), AnsiTrack::VideoTrack(t)) => {
+                assert_eq!(language, "jpn");
+                assert_eq!((t.track_width, t.track_height), (192, 108));
+                assert_eq!(t.dither_mode.method, DitherMethod::Pattern);
+            }
+            other => panic!("unexpected first track {other:?}"),
+        }
+        match (&job.tracks[1].source, &job.tracks[1].track) {
+            (Some(StreamSelector::Title(title)), AnsiTrack::SubtitleTrack(t)) => {
+                assert_eq!(title, "Signs & Songs");
+                assert_eq!(t.layers, [0]);
+            }
+            other => panic!("unexpected second track {other:?}"),
+        }
+    }
+
+    #[test]
+    fn jobs_survive_saving_and_loading() {
+        let mut job: Job = toml::from_str(&doc_example()).unwrap();
+        // what --save-job writes: tracks that point at their streams by index
+        job.tracks
+            .push(JobTrack::from(&job.tracks[0].track.clone()));
+
+        for name in ["job.toml", "job.json"] {
+            let loaded = round_trip(&job, name);
+            assert_eq!(summary(&loaded), summary(&job), "{name}");
+            assert!(matches!(
+                loaded.tracks[2].source,
+                Some(StreamSelector::Index(0))
+            ));
+            // and again, from what was written rather than what was written by hand
+            assert_eq!(summary(&round_trip(&loaded, name)), summary(&job), "{name}");
+        }
+    }
+
+    #[test]
+    fn sources_need_exactly_one_key() {
+        for source in ["{}", "{ index = 1, title = \"x\" }", "{ name = \"x\" }"] {
+            let job =
+                format!("[[tracks]]\nsource = {source}\n[tracks.subtitles]\ntrack_name = \"s\"");
+            assert!(toml::from_str::<Job>(&job).is_err(), "{source}");
+        }
+    }
+}
@@ -16,6 +16,8 @@ use container::{
 };
 pub use ffmpeg::*;
 pub mod cli;
+pub mod crop;
+pub mod job;
 pub mod pre_processor;
 use futures::SinkExt;
 pub use pre_processor::*;
@@ -1,5 +1,21 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use colorful::adjust::Adjustments;
+use colorful::metrics::{self, Metrics, Summary};
+use colorful::{adaptive, palette::Palette};
+use img2ansi::VideoImage;
+
 pub trait PreProcessor<V> {
     fn map(&mut self, frame: &mut V);
+
+    /// Called once after the last frame, to write out anything kept until the end.
+    fn finish(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct ProcessorPipeline<V>(Vec<Box<dyn PreProcessor<V>>>);
@@ -21,18 +37,49 @@ impl<V> PreProcessor<V> for ProcessorPipeline<V> {
             processor.map(frame);
         }
     }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.0
+            .iter_mut()
+            .try_for_each(|processor| processor.finish())
+    }
 }
 
 impl<V> PreProcessor<V> for () {
     fn map(&mut self, _: &mut V) {}
 }
 
+impl<V, P: PreProcessor<V> + ?Sized> PreProcessor<V> for Box<P> {
+    #[inline(always)]
+    fn map(&mut self, frame: &mut V) {
+        self.as_mut().map(frame)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.as_mut().finish()
+    }
+}
+
+/// Finishes the pre-processor that the transformer's frames go through. Used by [crate::pipeline], where a `()`
+/// pre-processor would otherwise not say which frames it takes.
+pub fn finish_processor<T: crate::Transformer, P: PreProcessor<T::Output>>(
+    processor: &mut P,
+    _transformer: &T,
+) -> std::io::Result<()> {
+    processor.finish()
+}
+
 pub mod ditherers {
+    use std::sync::Arc;
+
     use colorful::{
-        palette::{AnsiColorMap, DistanceMethod},
+        error_diffusion::{self, Kernel},
+        palette::{AnsiColorMap, DistanceMethod, Palette, DEFAULT_PALETTE},
         pattern_dithering::{self, MatrixSize},
+        temporal::TemporalState,
+        yliluoma::{self, Algorithm},
     };
-    use image::imageops;
+    use image::{imageops, RgbImage};
 
     use img2ansi::VideoImage;
 
@@ -44,48 +91,476 @@ pub mod ditherers {
         pub fn new() -> FloydSteinberg<T> {
             FloydSteinberg(AnsiColorMap::new())
         }
+
+        pub fn with_palette(palette: Arc<Palette>) -> FloydSteinberg<T> {
+            FloydSteinberg(AnsiColorMap::with_palette(palette))
+        }
     }
 
     pub struct Pattern<T: DistanceMethod> {
         pub map: AnsiColorMap<T>,
         pub matrix_size: MatrixSize,
         pub multiplier: f32,
+        pub linear: bool, // mix candidates in linear light
     }
 
     impl<T: DistanceMethod> Pattern<T> {
         pub fn new(matrix_size: MatrixSize, multiplier: f32) -> Pattern<T> {
+            Pattern::with_palette(matrix_size, multiplier, DEFAULT_PALETTE.clone())
+        }
+
+        pub fn with_palette(
+            matrix_size: MatrixSize,
+            multiplier: f32,
+            palette: Arc<Palette>,
+        ) -> Pattern<T> {
             Pattern {
-                map: AnsiColorMap::new(),
+                map: AnsiColorMap::with_palette(palette),
                 matrix_size,
                 multiplier,
+                linear: false,
             }
         }
     }
 
-    impl<T: DistanceMethod> PreProcessor<crate::video_encoder::DecodedVideoFrame>
-        for FloydSteinberg<T>
-    {
-        #[inline(always)]
-        fn map(&mut self, frame: &mut crate::video_encoder::DecodedVideoFrame) {
-            imageops::dither(frame.image.as_full_color_mut(), &self.0);
+    /// Error diffusion with any of [colorful::error_diffusion]'s kernels.
+    pub struct ErrorDiffusion<T: DistanceMethod> {
+        pub map: AnsiColorMap<T>,
+        pub kernel: Kernel,
+        pub serpentine: bool,
+        pub strength: f32,
+        pub linear: bool, // diffuse error in linear light
+    }
+
+    /// Ordered dithering with one of [colorful::yliluoma]'s algorithms.
+    pub struct Yliluoma<T: DistanceMethod> {
+        pub map: AnsiColorMap<T>,
+        pub algorithm: Algorithm,
+        pub matrix_size: MatrixSize,
+    }
+
+    /// Ditherers that can keep some pixels at given indices, which [Temporal] relies on.
+    pub trait Dither {
+        fn dither(&mut self, image: &RgbImage, reused: Option<&[Option<u8>]>) -> Vec<u8>;
+    }
+
+    impl<T: DistanceMethod> Dither for FloydSteinberg<T> {
+        fn dither(&mut self, image: &RgbImage, reused: Option<&[Option<u8>]>) -> Vec<u8> {
+            match reused {
+                // reused pixels have to take part in diffusing error, which the image crate's implementation can't do.
+                // it rounds and clamps error a little differently, so this pattern isn't quite the one below
+                Some(reused) => error_diffusion::dither_with(
+                    image,
+                    Kernel::FloydSteinberg,
+                    false,
+                    1.0,
+                    &self.0,
+                    Some(reused),
+                    false,
+                ),
+                None => {
+                    let mut dithered = image.clone();
+                    imageops::dither(&mut dithered, &self.0);
+                    imageops::index_colors(&dithered, &self.0).into_raw()
+                }
+            }
+        }
+    }
+
+    impl<T: DistanceMethod + Send + Sync> Dither for Pattern<T> {
+        fn dither(&mut self, image: &RgbImage, reused: Option<&[Option<u8>]>) -> Vec<u8> {
+            pattern_dithering::dither_with(
+                image,
+                self.matrix_size,
+                self.multiplier,
+                &self.map,
+                reused,
+                self.linear,
+            )
         }
     }
 
-    impl<T: DistanceMethod + Send + Sync + Copy>
-        PreProcessor<crate::video_encoder::DecodedVideoFrame> for Pattern<T>
-    {
-        #[inline(always)]
+    impl<T: DistanceMethod> Dither for ErrorDiffusion<T> {
+        fn dither(&mut self, image: &RgbImage, reused: Option<&[Option<u8>]>) -> Vec<u8> {
+            error_diffusion::dither_with(
+                image,
+                self.kernel,
+                self.serpentine,
+                self.strength,
+                &self.map,
+                reused,
+                self.linear,
+            )
+        }
+    }
+
+    impl<T: DistanceMethod + Send + Sync> Dither for Yliluoma<T> {
+        fn dither(&mut self, image: &RgbImage, reused: Option<&[Option<u8>]>) -> Vec<u8> {
+            yliluoma::dither_reusing(image, self.algorithm, self.matrix_size, &self.map, reused)
+        }
+    }
+
+    macro_rules! dither_pre_processor {
+        ($($name:ident),*) => {
+            $(
+                impl<T: DistanceMethod + Send + Sync> PreProcessor<crate::video_encoder::DecodedVideoFrame>
+                    for $name<T>
+                {
+                    #[inline(always)]
+                    fn map(&mut self, frame: &mut crate::video_encoder::DecodedVideoFrame) {
+                        let image = frame.image.as_full_color();
+                        frame.image = VideoImage::EightBit {
+                            width: image.width(),
+                            height: image.height(),
+                            data: self.dither(image, None),
+                        };
+                    }
+                }
+            )*
+        };
+    }
+
+    dither_pre_processor!(FloydSteinberg, Pattern, ErrorDiffusion, Yliluoma);
+
+    /// Keeps a ditherer steady across frames: pixels whose color stays within a ΔE threshold of what their last index
+    /// was picked for keep that index, instead of shimmering as the pattern gets laid out anew. See [colorful::temporal].
+    pub struct Temporal<D: Dither> {
+        pub ditherer: D,
+        pub state: TemporalState,
+    }
+
+    impl<D: Dither> Temporal<D> {
+        pub fn new(ditherer: D, threshold: f32) -> Temporal<D> {
+            Temporal {
+                ditherer,
+                state: TemporalState::new(threshold),
+            }
+        }
+    }
+
+    impl<D: Dither> PreProcessor<crate::video_encoder::DecodedVideoFrame> for Temporal<D> {
         fn map(&mut self, frame: &mut crate::video_encoder::DecodedVideoFrame) {
+            let image = frame.image.as_full_color();
+            let reused = self.state.reusable(image);
+            let data = self.ditherer.dither(image, Some(&reused));
+            self.state.update(image, &reused, &data);
+
             frame.image = VideoImage::EightBit {
-                width: frame.image.as_full_color().width(),
-                height: frame.image.as_full_color().height(),
-                data: pattern_dithering::dither(
-                    frame.image.as_full_color(),
-                    self.matrix_size,
-                    self.multiplier,
-                    self.map,
-                ),
+                width: image.width(),
+                height: image.height(),
+                data,
             };
         }
     }
 }
+
+/// Adjusts frames (see [colorful::adjust]) before handing them to another pre-processor, usually a ditherer.
+pub struct Adjusted {
+    pub adjustments: Adjustments,
+    pub processor: Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send>,
+}
+
+impl PreProcessor<crate::video_encoder::DecodedVideoFrame> for Adjusted {
+    fn map(&mut self, frame: &mut crate::video_encoder::DecodedVideoFrame) {
+        self.adjustments.apply(frame.image.as_full_color_mut());
+        self.processor.map(frame);
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.processor.finish()
+    }
+}
+
+/// Lossy truecolor quantization, reusing on-screen colors that are within a ΔE threshold. See [img2ansi::quantize].
+pub struct TrueColorQuantizer {
+    pub threshold: f32,
+    previous: Option<image::RgbImage>,
+}
+
+impl TrueColorQuantizer {
+    pub fn new(threshold: f32) -> TrueColorQuantizer {
+        TrueColorQuantizer {
+            threshold,
+            previous: None,
+        }
+    }
+}
+
+impl PreProcessor<crate::video_encoder::DecodedVideoFrame> for TrueColorQuantizer {
+    fn map(&mut self, frame: &mut crate::video_encoder::DecodedVideoFrame) {
+        let image = frame.image.as_full_color_mut();
+        img2ansi::quantize::quantize_truecolor(image, self.previous.as_ref(), self.threshold);
+        self.previous = Some(image.clone());
+    }
+}
+
+/// Dithers against a palette fitted to each scene (see [colorful::adaptive]), instead of a fixed one.
+/// A new palette is fitted whenever the current one represents a frame noticeably worse than it did the frame it was fitted to,
+/// which catches scene cuts as well as slower changes; the frame that switches palettes carries it along to the encoder.
+pub struct AdaptivePalette {
+    pub config: crate::tool_utils::DitherConfig,
+    pub threshold: f32, // how far the mean ΔE may rise before refitting
+    current: Option<(Arc<Palette>, f32)>,
+    ditherer: Option<Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send>>,
+}
+
+impl AdaptivePalette {
+    pub fn new(mut config: crate::tool_utils::DitherConfig, threshold: f32) -> AdaptivePalette {
+        // every scene gets its own palette, and so its own lookup table; those aren't worth keeping around
+        config.lookup_table_cache = None;
+
+        AdaptivePalette {
+            config,
+            threshold,
+            current: None,
+            ditherer: None,
+        }
+    }
+}
+
+impl PreProcessor<crate::video_encoder::DecodedVideoFrame> for AdaptivePalette {
+    fn map(&mut self, frame: &mut crate::video_encoder::DecodedVideoFrame) {
+        let image = frame.image.as_full_color();
+        let samples = adaptive::sample_pixels(image, adaptive::MAX_SAMPLES);
+
+        let fits = match &self.current {
+            Some((palette, fitted_error)) => {
+                adaptive::mean_error(palette, &samples) <= fitted_error + self.threshold
+            }
+            None => false,
+        };
+
+        if !fits {
+            let palette = Arc::new(Palette::adaptive(
+                &self.config.palette,
+                image,
+                adaptive::RESERVED_COLORS,
+            ));
+            let fitted_error = adaptive::mean_error(&palette, &samples);
+
+            self.ditherer = Some(self.config.build_with_palette(palette.clone()));
+            self.current = Some((palette.clone(), fitted_error));
+            frame.palette = Some(palette);
+        }
+
+        if let Some(ditherer) = self.ditherer.as_mut() {
+            ditherer.map(frame);
+        }
+    }
+}
+
+/// Tab-separated log of how closely tracks' frames match their source (see [colorful::metrics]), shared by all tracks.
+/// Every track gets a line per frame, then its averages and worst values once it's done.
+pub struct QualityReport(Mutex<BufWriter<File>>);
+
+impl QualityReport {
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<QualityReport> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "track\tframe\tpts\tpsnr\tssim\tmean ΔE\tmax ΔE")?;
+        Ok(QualityReport(Mutex::new(out)))
+    }
+
+    fn write(
+        &self,
+        track_id: usize,
+        frame: &dyn Display,
+        pts: &dyn Display,
+        metrics: &Metrics,
+    ) -> std::io::Result<()> {
+        let mut out = self.0.lock().unwrap();
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{:.3}\t{:.5}\t{:.3}\t{:.3}",
+            track_id,
+            frame,
+            pts,
+            metrics.psnr,
+            metrics.ssim,
+            metrics.mean_delta_e,
+            metrics.max_delta_e
+        )
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Measures the frames another pre-processor outputs against the ones it was given, logging them to a [QualityReport].
+pub struct Measured {
+    pub processor: Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send>,
+    pub track_id: usize,
+    report: Arc<QualityReport>,
+    palette: Arc<Palette>, // the one 256 color frames are currently dithered against
+    summary: Summary,
+    error: Option<std::io::Error>, // the first write that failed, reported by finish
+}
+
+impl Measured {
+    pub fn new(
+        processor: Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send>,
+        track_id: usize,
+        palette: Arc<Palette>,
+        report: Arc<QualityReport>,
+    ) -> Measured {
+        Measured {
+            processor,
+            track_id,
+            report,
+            palette,
+            summary: Summary::default(),
+            error: None,
+        }
+    }
+}
+
+impl PreProcessor<crate::video_encoder::DecodedVideoFrame> for Measured {
+    fn map(&mut self, frame: &mut crate::video_encoder::DecodedVideoFrame) {
+        let source = frame.image.as_full_color().clone();
+        self.processor.map(frame);
+
+        if let Some(palette) = &frame.palette {
+            self.palette = palette.clone();
+        }
+
+        let result = match &frame.image {
+            VideoImage::FullColor(image) => metrics::compare(&source, image),
+            VideoImage::EightBit { data, .. } => {
+                metrics::compare_indexed(&source, data, &self.palette)
+            }
+        };
+
+        let written = self.report.write(
+            self.track_id,
+            &self.summary.frames,
+            &format_args!("{:.3}", frame.pts.as_secs_f64()),
+            &result,
+        );
+        if let Err(e) = written {
+            self.error.get_or_insert(e);
+        }
+        self.summary.add(&result);
+    }
+
+    /// Writes the track's averages and worst values, and reports the first line that couldn't be written if any.
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.processor.finish()?;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        if let (Some(mean), Some(worst)) = (self.summary.mean(), self.summary.worst()) {
+            self.report.write(self.track_id, &"mean", &"-", &mean)?;
+            self.report.write(self.track_id, &"worst", &"-", &worst)?;
+        }
+        self.report.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video_encoder::DecodedVideoFrame;
+    use image::{Rgb, RgbImage};
+    use std::time::Duration;
+
+    fn frame(pts: u64, shade: u8) -> DecodedVideoFrame {
+        DecodedVideoFrame {
+            pts: Duration::from_secs(pts),
+            duration: Duration::from_secs(1),
+            image: VideoImage::FullColor(RgbImage::from_pixel(4, 2, Rgb([shade; 3]))),
+            palette: None,
+        }
+    }
+
+    fn gradient(shift: u8) -> DecodedVideoFrame {
+        DecodedVideoFrame {
+            image: VideoImage::FullColor(RgbImage::from_fn(24, 16, |x, y| {
+                Rgb([(x * 10) as u8, (y * 15) as u8, 90].map(|v| v.saturating_add(shift)))
+            })),
+            ..frame(0, 0)
+        }
+    }
+
+    fn indices(frame: &DecodedVideoFrame) -> &[u8] {
+        match &frame.image {
+            VideoImage::EightBit { data, .. } => data,
+            VideoImage::FullColor(_) => panic!("frame wasn't dithered"),
+        }
+    }
+
+    #[test]
+    fn plain_floyd_steinberg_matches_the_image_crate() {
+        let mut frame = gradient(0);
+        let mut expected = frame.image.as_full_color().clone();
+        let map = colorful::palette::AnsiColorMap::<colorful::palette::CIE76>::new();
+        image::imageops::dither(&mut expected, &map);
+
+        ditherers::FloydSteinberg::<colorful::palette::CIE76>::new().map(&mut frame);
+        let palette = Palette::default();
+        let dithered: Vec<u8> = indices(&frame)
+            .iter()
+            .flat_map(|&idx| palette.get(idx as usize))
+            .collect();
+        assert_eq!(dithered, expected.into_raw());
+    }
+
+    #[test]
+    fn temporal_ditherers_keep_indices_of_pixels_that_barely_change() {
+        let mut temporal = ditherers::Temporal::new(
+            ditherers::FloydSteinberg::<colorful::palette::CIE76>::new(),
+            3.0,
+        );
+
+        let mut first = gradient(0);
+        temporal.map(&mut first);
+        let mut nudged = gradient(1);
+        temporal.map(&mut nudged);
+        assert_eq!(indices(&nudged), indices(&first));
+
+        // a cut to something else entirely is dithered anew
+        let mut cut = gradient(120);
+        temporal.map(&mut cut);
+        let mut fresh = gradient(120);
+        ditherers::Temporal::new(
+            ditherers::FloydSteinberg::<colorful::palette::CIE76>::new(),
+            3.0,
+        )
+        .map(&mut fresh);
+        assert_eq!(indices(&cut), indices(&fresh));
+    }
+
+    #[test]
+    fn measured_tracks_write_their_summary_when_finished() {
+        let path = std::env::temp_dir().join(format!("quality-{}.tsv", std::process::id()));
+        let report = Arc::new(QualityReport::create(&path).unwrap());
+
+        let measured = Measured::new(Box::new(()), 3, Arc::new(Palette::default()), report);
+        // finishing has to reach it through whatever it's wrapped in
+        let mut pipeline = ProcessorPipeline::new();
+        pipeline.add(Adjusted {
+            adjustments: Adjustments::default(),
+            processor: Box::new(measured),
+        });
+        for (pts, shade) in [(0, 10), (1, 200)] {
+            pipeline.map(&mut frame(pts, shade));
+        }
+        pipeline.finish().unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<Vec<&str>> = written
+            .lines()
+            .map(|line| line.split('\t').collect())
+            .collect();
+        assert_eq!(lines.len(), 5, "{written}");
+        assert_eq!(lines[0][..3], ["track", "frame", "pts"]);
+        assert_eq!(lines[1][..3], ["3", "0", "0.000"]);
+        assert_eq!(lines[2][..3], ["3", "1", "1.000"]);
+        assert_eq!(lines[3][..3], ["3", "mean", "-"]);
+        assert_eq!(lines[4][..3], ["3", "worst", "-"]);
+        // nothing changed the frames, so they match their sources exactly
+        assert_eq!(lines[1][3], "inf");
+    }
+}
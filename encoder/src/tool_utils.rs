@@ -1,12 +1,22 @@
 use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 
 #[cfg(feature = "cuda")]
 use crate::cuda::CudaDitherer;
+use colorful::adjust::Adjustments;
+use colorful::colorspace::{Matrix, Range, ToneMap, Transfer};
+use colorful::error_diffusion::Kernel;
+use colorful::palette::{AnsiColorMap, DistanceMethod, Palette, DEFAULT_PALETTE};
 use colorful::pattern_dithering::MatrixSize;
-use container::metadata::ColorMode;
+use colorful::yliluoma::Algorithm;
+use container::metadata::{ColorMode, Crop, KeyframeSettings};
 use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
 
-use crate::{ditherers, PreProcessor};
+use crate::ditherers::{self, Dither};
+use crate::{Adjusted, AdaptivePalette, Measured, PreProcessor, QualityReport, TrueColorQuantizer};
 
 #[derive(TryFromPrimitive, Debug, PartialEq)]
 #[repr(u8)]
@@ -35,6 +45,7 @@ pub struct SourceStreamMetadata {
     pub source_kind: SourceKind,
     pub codec_name: Option<&'static str>,
     pub title: Option<&'static str>,
+    pub language: Option<&'static str>,
 }
 
 impl Display for SourceStreamMetadata {
@@ -57,9 +68,11 @@ impl Display for SourceStreamMetadata {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AnsiTrack {
+    #[serde(rename = "subtitles")]
     SubtitleTrack(SubtitleTrack),
+    #[serde(rename = "video")]
     VideoTrack(VideoTrack),
 }
 
@@ -75,32 +88,80 @@ impl From<VideoTrack> for AnsiTrack {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SubtitleTrack {
+    #[serde(skip)]
     pub source_stream_index: usize,
+    #[serde(skip)]
     pub track_id: usize,
     pub track_name: String,
+    pub layers: Vec<isize>, // ssa layers to keep, or all of them if empty
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VideoTrack {
+    #[serde(skip)]
     pub source_stream_index: usize,
+    #[serde(skip)]
     pub track_id: usize,
     pub track_name: String,
     pub track_height: usize,
     pub track_width: usize,
     pub color_mode: ColorMode,
+    #[serde(skip)] // set for the whole encode
+    pub keyframes: KeyframeSettings,
+    pub truecolor_threshold: Option<f32>, // ΔE below which true color tracks reuse on-screen colors
+    pub linear_light: bool, // downscale, and dither, in linear light rather than on sRGB values
+    pub sizing: Sizing,
+    pub cell_aspect: f32, // width / height of a terminal cell, which shows two pixels stacked
+    pub source_aspect: Option<f32>, // display aspect ratio to assume instead of the source's, for mis-flagged video
+    pub padding: Option<[u8; 3]>, // color to letterbox or pillarbox the picture with, to fill the whole track size
+    pub auto_crop: bool, // look for black bars in the source and crop them, unless there's a crop set
+    pub scaler: ScalingAlgorithm,
+    pub downscale: Downscale,
+    // tables last, as TOML puts them after plain values
+    pub crop: Option<Crop>, // part of the source to show, in source pixels
+    pub source_color: SourceColor,
+    pub adjustments: Adjustments, // made to frames before dithering
     pub dither_mode: DitherConfig,
 }
 
-#[derive(Debug, Clone)]
+/// Where a video track's picture goes, see [VideoTrack::layout].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub width: usize, // of the picture
+    pub height: usize,
+    pub frame_width: usize, // of the picture and its padding
+    pub frame_height: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DitherConfig {
     pub method: DitherMethod,
     pub distance_function: DistanceFunction,
     pub matrix_size: MatrixSize,
     pub multiplier: f32,
+    pub kernel: Kernel,      // for error diffusion
+    pub serpentine: bool,    // scan every other row right to left when diffusing error
+    pub error_strength: f32, // fraction of the error that gets diffused
+    #[serde(skip)]
     pub width: u32,
+    #[serde(skip)]
     pub height: u32,
+    // the palette and lookup table are set for the whole encode
+    #[serde(skip)]
+    pub palette: Arc<Palette>, // colors the terminal will show; the cuda ditherer always uses the xterm palette
+    pub adaptive_palette: Option<f32>, // refit threshold (ΔE) for per-scene palettes, which need a terminal that supports OSC 4
+    #[serde(skip)]
+    pub lookup_table: Option<u8>, // bits per channel of the RGB -> palette index table, or None to always search the palette
+    #[serde(skip)]
+    pub lookup_table_cache: Option<PathBuf>, // directory to keep lookup tables in across runs
+    pub temporal_threshold: Option<f32>, // ΔE below which pixels keep last frame's index, to stop the dither pattern from shimmering
+    #[serde(skip)] // set from the track's [VideoTrack::linear_light]
+    pub linear_light: bool, // accumulate and diffuse error in linear light; yliluoma's plans are mixed in the perceptual space either way
 }
 
 impl Default for DitherConfig {
@@ -110,43 +171,188 @@ impl Default for DitherConfig {
             distance_function: DistanceFunction::CAM02,
             matrix_size: MatrixSize::Four,
             multiplier: 0.09,
+            kernel: Kernel::FloydSteinberg,
+            serpentine: true,
+            error_strength: 1.0,
             width: 192,
             height: 108,
+            palette: DEFAULT_PALETTE.clone(),
+            adaptive_palette: None,
+            lookup_table: None,
+            lookup_table_cache: None,
+            temporal_threshold: None,
+            linear_light: false,
         }
     }
 }
 
 impl DitherConfig {
     pub fn build(&self) -> Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send> {
+        match self.adaptive_palette {
+            // the cuda kernels have the xterm palette baked in
+            Some(threshold) if self.method != DitherMethod::Cuda => {
+                Box::new(AdaptivePalette::new(self.clone(), threshold))
+            }
+            _ => self.build_with_palette(self.palette.clone()),
+        }
+    }
+
+    /// Color map for a palette, looking colors up in a table if [DitherConfig::lookup_table] is set.
+    pub fn color_map<T: DistanceMethod>(&self, palette: Arc<Palette>) -> AnsiColorMap<T> {
+        let map = AnsiColorMap::with_palette(palette);
+        match (self.lookup_table, self.lookup_table_cache.as_ref()) {
+            (Some(bits), Some(dir)) => map.with_cached_lut(bits, dir),
+            (Some(bits), None) => map.with_lut(bits),
+            (None, _) => map,
+        }
+    }
+
+    /// Boxes a ditherer, keeping it steady across frames if [DitherConfig::temporal_threshold] is set.
+    fn boxed<D>(
+        &self,
+        ditherer: D,
+    ) -> Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send>
+    where
+        D: Dither + PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send + 'static,
+    {
+        match self.temporal_threshold {
+            Some(threshold) => Box::new(ditherers::Temporal::new(ditherer, threshold)),
+            None => Box::new(ditherer),
+        }
+    }
+
+    /// Builds the ditherer for a given palette, ignoring [DitherConfig::adaptive_palette].
+    pub fn build_with_palette(
+        &self,
+        palette: Arc<Palette>,
+    ) -> Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send> {
+        if self.linear_light && self.method == DitherMethod::FloydSteinberg {
+            // plain floyd-steinberg diffuses error in sRGB values, so use error diffusion with the same kernel in linear light
+            return DitherConfig {
+                method: DitherMethod::ErrorDiffusion,
+                kernel: Kernel::FloydSteinberg,
+                serpentine: false,
+                error_strength: 1.0,
+                ..self.clone()
+            }
+            .build_with_palette(palette);
+        }
+
         match self.method {
             DitherMethod::FloydSteinberg => match self.distance_function {
-                DistanceFunction::CAM02 => {
-                    Box::new(ditherers::FloydSteinberg::<colorful::palette::CAM02>::new())
-                }
-                DistanceFunction::CIE94 => {
-                    Box::new(ditherers::FloydSteinberg::<colorful::palette::CIE94>::new())
-                }
-                DistanceFunction::CIE76 => {
-                    Box::new(ditherers::FloydSteinberg::<colorful::palette::CIE76>::new())
-                }
+                DistanceFunction::CAM02 => self.boxed(ditherers::FloydSteinberg(
+                    self.color_map::<colorful::palette::CAM02>(palette),
+                )),
+                DistanceFunction::CIE94 => self.boxed(ditherers::FloydSteinberg(
+                    self.color_map::<colorful::palette::CIE94>(palette),
+                )),
+                DistanceFunction::CIE76 => self.boxed(ditherers::FloydSteinberg(
+                    self.color_map::<colorful::palette::CIE76>(palette),
+                )),
+                DistanceFunction::CIEDE2000 => self.boxed(ditherers::FloydSteinberg(
+                    self.color_map::<colorful::palette::CIEDE2000>(palette),
+                )),
+                DistanceFunction::Oklab => self.boxed(ditherers::FloydSteinberg(
+                    self.color_map::<colorful::palette::Oklab>(palette),
+                )),
             },
-            DitherMethod::Pattern => {
+            DitherMethod::Pattern => match self.distance_function {
+                DistanceFunction::CAM02 => self.boxed(ditherers::Pattern {
+                    map: self.color_map::<colorful::palette::CAM02>(palette),
+                    matrix_size: self.matrix_size,
+                    multiplier: self.multiplier,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::CIE94 => self.boxed(ditherers::Pattern {
+                    map: self.color_map::<colorful::palette::CIE94>(palette),
+                    matrix_size: self.matrix_size,
+                    multiplier: self.multiplier,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::CIE76 => self.boxed(ditherers::Pattern {
+                    map: self.color_map::<colorful::palette::CIE76>(palette),
+                    matrix_size: self.matrix_size,
+                    multiplier: self.multiplier,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::CIEDE2000 => self.boxed(ditherers::Pattern {
+                    map: self.color_map::<colorful::palette::CIEDE2000>(palette),
+                    matrix_size: self.matrix_size,
+                    multiplier: self.multiplier,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::Oklab => self.boxed(ditherers::Pattern {
+                    map: self.color_map::<colorful::palette::Oklab>(palette),
+                    matrix_size: self.matrix_size,
+                    multiplier: self.multiplier,
+                    linear: self.linear_light,
+                }),
+            },
+            DitherMethod::ErrorDiffusion => match self.distance_function {
+                DistanceFunction::CAM02 => self.boxed(ditherers::ErrorDiffusion {
+                    map: self.color_map::<colorful::palette::CAM02>(palette),
+                    kernel: self.kernel,
+                    serpentine: self.serpentine,
+                    strength: self.error_strength,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::CIE94 => self.boxed(ditherers::ErrorDiffusion {
+                    map: self.color_map::<colorful::palette::CIE94>(palette),
+                    kernel: self.kernel,
+                    serpentine: self.serpentine,
+                    strength: self.error_strength,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::CIE76 => self.boxed(ditherers::ErrorDiffusion {
+                    map: self.color_map::<colorful::palette::CIE76>(palette),
+                    kernel: self.kernel,
+                    serpentine: self.serpentine,
+                    strength: self.error_strength,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::CIEDE2000 => self.boxed(ditherers::ErrorDiffusion {
+                    map: self.color_map::<colorful::palette::CIEDE2000>(palette),
+                    kernel: self.kernel,
+                    serpentine: self.serpentine,
+                    strength: self.error_strength,
+                    linear: self.linear_light,
+                }),
+                DistanceFunction::Oklab => self.boxed(ditherers::ErrorDiffusion {
+                    map: self.color_map::<colorful::palette::Oklab>(palette),
+                    kernel: self.kernel,
+                    serpentine: self.serpentine,
+                    strength: self.error_strength,
+                    linear: self.linear_light,
+                }),
+            },
+            DitherMethod::Yliluoma1 | DitherMethod::Yliluoma2 | DitherMethod::Yliluoma3 => {
+                let algorithm = self.method.yliluoma_algorithm().unwrap();
                 match self.distance_function {
-                    DistanceFunction::CAM02 => Box::new(ditherers::Pattern::<
-                        colorful::palette::CAM02,
-                    >::new(
-                        self.matrix_size, self.multiplier
-                    )),
-                    DistanceFunction::CIE94 => Box::new(ditherers::Pattern::<
-                        colorful::palette::CIE94,
-                    >::new(
-                        self.matrix_size, self.multiplier
-                    )),
-                    DistanceFunction::CIE76 => Box::new(ditherers::Pattern::<
-                        colorful::palette::CIE76,
-                    >::new(
-                        self.matrix_size, self.multiplier
-                    )),
+                    DistanceFunction::CAM02 => self.boxed(ditherers::Yliluoma {
+                        map: self.color_map::<colorful::palette::CAM02>(palette),
+                        algorithm,
+                        matrix_size: self.matrix_size,
+                    }),
+                    DistanceFunction::CIE94 => self.boxed(ditherers::Yliluoma {
+                        map: self.color_map::<colorful::palette::CIE94>(palette),
+                        algorithm,
+                        matrix_size: self.matrix_size,
+                    }),
+                    DistanceFunction::CIE76 => self.boxed(ditherers::Yliluoma {
+                        map: self.color_map::<colorful::palette::CIE76>(palette),
+                        algorithm,
+                        matrix_size: self.matrix_size,
+                    }),
+                    DistanceFunction::CIEDE2000 => self.boxed(ditherers::Yliluoma {
+                        map: self.color_map::<colorful::palette::CIEDE2000>(palette),
+                        algorithm,
+                        matrix_size: self.matrix_size,
+                    }),
+                    DistanceFunction::Oklab => self.boxed(ditherers::Yliluoma {
+                        map: self.color_map::<colorful::palette::Oklab>(palette),
+                        algorithm,
+                        matrix_size: self.matrix_size,
+                    }),
                 }
             }
             #[cfg(feature = "cuda")]
@@ -160,12 +366,222 @@ impl DitherConfig {
     }
 }
 
-#[derive(TryFromPrimitive, Debug, Clone, PartialEq)]
+impl VideoTrack {
+    /// Whether the track redefines palette colors as it plays.
+    pub fn uses_adaptive_palette(&self) -> bool {
+        self.color_mode == ColorMode::EightBit
+            && self.dither_mode.adaptive_palette.is_some()
+            && self.dither_mode.method != DitherMethod::Cuda
+    }
+
+    /// Display aspect ratio of the part of the source the track shows, given the whole source's and its size in pixels.
+    pub fn picture_aspect(
+        &self,
+        display_aspect: Option<f32>,
+        source_width: usize,
+        source_height: usize,
+    ) -> Option<f32> {
+        let aspect = self.source_aspect.or(display_aspect)?;
+        Some(match self.crop {
+            Some(crop) => {
+                aspect * (crop.width as f32 / source_width as f32)
+                    / (crop.height as f32 / source_height as f32)
+            }
+            None => aspect,
+        })
+    }
+
+    /// Size of a picture with the given display aspect ratio (width / height, sample aspect ratio included), and of
+    /// the frame it's padded to if [VideoTrack::padding] is set. Heights are even, so that the picture starts and ends
+    /// on a cell boundary. Stretches if the aspect ratio isn't known.
+    pub fn layout(&self, aspect: Option<f32>) -> Layout {
+        let (sizing, aspect) = match aspect {
+            Some(aspect) => (self.sizing, aspect),
+            None => (Sizing::Stretch, 1.0),
+        };
+        // a cell holds two pixels stacked, so pixels are twice as wide, relative to their height, as the cell
+        let pixel_aspect = self.cell_aspect * 2.0;
+
+        let even = |height: f32| ((height / 2.0).round() as usize * 2).max(2);
+        let height_for = |width: usize| even(width as f32 * pixel_aspect / aspect);
+        let width_for = |height: usize| ((height as f32 * aspect / pixel_aspect).round() as usize).max(1);
+
+        let (width, height) = match sizing {
+            Sizing::Stretch => (self.track_width, self.track_height),
+            Sizing::FitColumns => (self.track_width, height_for(self.track_width)),
+            Sizing::FitRows => {
+                let height = even(self.track_height as f32);
+                (width_for(height), height)
+            }
+            Sizing::FitBox => match height_for(self.track_width) {
+                height if height <= self.track_height => (self.track_width, height),
+                _ => {
+                    let height = (self.track_height / 2 * 2).max(2);
+                    (width_for(height).min(self.track_width), height)
+                }
+            },
+        };
+
+        let (frame_width, frame_height) = match self.padding {
+            Some(_) => (
+                width.max(self.track_width),
+                height.max(self.track_height + self.track_height % 2),
+            ),
+            None => (width, height),
+        };
+
+        Layout {
+            width,
+            height,
+            frame_width,
+            frame_height,
+        }
+    }
+
+    /// Sizes the track for its source, given its display aspect ratio and size in pixels, see [VideoTrack::layout].
+    /// The track takes the frame's size, and the picture's is returned to scale the (cropped) source to.
+    pub fn fit_to_source(
+        &mut self,
+        display_aspect: Option<f32>,
+        source_width: usize,
+        source_height: usize,
+    ) -> Layout {
+        if let Some(warning) = self.aspect_warning(source_width, source_height) {
+            eprintln!("{warning}");
+        }
+
+        let layout = self.layout(self.picture_aspect(display_aspect, source_width, source_height));
+        self.track_width = layout.frame_width;
+        self.track_height = layout.frame_height;
+        self.dither_mode.width = layout.frame_width as u32;
+        self.dither_mode.height = layout.frame_height as u32;
+        layout
+    }
+
+    /// Warns about sources whose size is one that's usually stored with non-square pixels, like DVD video. The display
+    /// aspect ratio is taken from the source's size, as ac-ffmpeg doesn't pass on the sample aspect ratio, so those come
+    /// out squashed or stretched unless [VideoTrack::source_aspect] is set. Stretched tracks ignore the aspect anyway.
+    pub fn aspect_warning(&self, source_width: usize, source_height: usize) -> Option<String> {
+        if self.source_aspect.is_some() || self.sizing == Sizing::Stretch {
+            return None;
+        }
+
+        let shown_as = match (source_width, source_height) {
+            (720 | 704, 480 | 486 | 576) | (480, 480 | 576) => "4:3 or 16:9",
+            (352, 480 | 576) => "4:3",
+            (1440 | 1280, 1080) | (960, 720) => "16:9",
+            _ => return None,
+        };
+
+        Some(format!(
+            "warning: video track {} has a {source_width}x{source_height} source, which is usually shown as {shown_as} \
+            with non-square pixels. its aspect ratio is taken from its size, so set source-aspect if it looks squashed or stretched",
+            self.track_id
+        ))
+    }
+
+    /// Builds the pre-processor that turns decoded frames into what the track's color mode needs, making the track's
+    /// [VideoTrack::adjustments] first.
+    pub fn build_processor(
+        &self,
+    ) -> Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send> {
+        self.adjusted(self.build_color_processor())
+    }
+
+    fn build_color_processor(
+        &self,
+    ) -> Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send> {
+        match self.color_mode {
+            // the track's setting is the only one, covering scaling as well as dithering
+            ColorMode::EightBit => DitherConfig {
+                linear_light: self.linear_light,
+                ..self.dither_mode.clone()
+            }
+            .build(),
+            ColorMode::True => match self.truecolor_threshold {
+                Some(threshold) => Box::new(TrueColorQuantizer::new(threshold)),
+                None => Box::new(()),
+            },
+        }
+    }
+
+    /// Makes the track's adjustments before a pre-processor, if there are any to make.
+    fn adjusted(
+        &self,
+        processor: Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send>,
+    ) -> Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send> {
+        if self.adjustments.is_identity() {
+            return processor;
+        }
+
+        Box::new(Adjusted {
+            adjustments: self.adjustments,
+            processor,
+        })
+    }
+
+    /// Like [VideoTrack::build_processor], also logging how closely every frame matches its source if there's a report.
+    /// Frames are measured against the adjusted source, so that the report is about the color mode alone.
+    pub fn build_measured_processor(
+        &self,
+        report: Option<&Arc<QualityReport>>,
+    ) -> Box<dyn PreProcessor<crate::video_encoder::DecodedVideoFrame> + Send> {
+        let processor = self.build_color_processor();
+        let report = match report {
+            Some(report) => report.clone(),
+            None => return self.adjusted(processor),
+        };
+
+        // the cuda kernels have the xterm palette baked in
+        let palette = if self.dither_mode.method == DitherMethod::Cuda {
+            DEFAULT_PALETTE.clone()
+        } else {
+            self.dither_mode.palette.clone()
+        };
+
+        self.adjusted(Box::new(Measured::new(processor, self.track_id, palette, report)))
+    }
+}
+
+impl SubtitleTrack {
+    /// Filter for ssa packets that keeps only [SubtitleTrack::layers], given the header packets are laid out by.
+    /// None if every packet is kept, or if the header has no event format to find the layer with.
+    pub fn packet_filter(&self, codec_private: &[u8]) -> Option<impl Fn(&[u8]) -> bool + Send> {
+        if self.layers.is_empty() {
+            return None;
+        }
+
+        let mut contents = String::from_utf8_lossy(codec_private).to_string();
+        let mut header = None;
+        while let Ok((input, (_, section))) = substation::parser::section_with_input(&contents) {
+            if let Some(h) = section.as_event_header() {
+                header = Some(h.clone());
+            }
+
+            contents = input.trim_start().to_owned();
+        }
+
+        let header = header?;
+        let layers = self.layers.clone();
+        Some(move |data: &[u8]| {
+            match substation::parser::subtitle(&String::from_utf8_lossy(data), &header) {
+                Ok((_, entry)) => entry.layer.is_none_or(|layer| layers.contains(&layer)),
+                // keep what can't be parsed, rather than losing lines silently
+                Err(_) => true,
+            }
+        })
+    }
+}
+
+#[derive(TryFromPrimitive, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[repr(u8)]
+#[serde(rename_all = "lowercase")]
 pub enum DistanceFunction {
     CAM02 = 0,
     CIE94 = 1,
     CIE76 = 2,
+    CIEDE2000 = 3,
+    Oklab = 4,
 }
 
 impl Display for DistanceFunction {
@@ -174,16 +590,43 @@ impl Display for DistanceFunction {
             DistanceFunction::CAM02 => write!(f, "CAM02"),
             DistanceFunction::CIE94 => write!(f, "CIE94"),
             DistanceFunction::CIE76 => write!(f, "CIE76"),
+            DistanceFunction::CIEDE2000 => write!(f, "CIEDE2000"),
+            DistanceFunction::Oklab => write!(f, "Oklab"),
         }
     }
 }
 
-#[derive(TryFromPrimitive, Debug, Clone, PartialEq)]
+impl FromStr for DistanceFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DistanceFunction, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "cam02" => Ok(DistanceFunction::CAM02),
+            "cie94" => Ok(DistanceFunction::CIE94),
+            "cie76" => Ok(DistanceFunction::CIE76),
+            "ciede2000" => Ok(DistanceFunction::CIEDE2000),
+            "oklab" => Ok(DistanceFunction::Oklab),
+            _ => Err(format!(
+                "unknown distance function {s:?} (expected cam02, cie94, cie76, ciede2000 or oklab)"
+            )),
+        }
+    }
+}
+
+#[derive(TryFromPrimitive, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[repr(u8)]
+#[serde(rename_all = "kebab-case")]
 pub enum DitherMethod {
     FloydSteinberg = 0,
     Pattern = 1,
     Cuda = 2,
+    ErrorDiffusion = 3,
+    #[serde(rename = "yliluoma-1")]
+    Yliluoma1 = 4,
+    #[serde(rename = "yliluoma-2")]
+    Yliluoma2 = 5,
+    #[serde(rename = "yliluoma-3")]
+    Yliluoma3 = 6,
 }
 
 impl Display for DitherMethod {
@@ -192,16 +635,280 @@ impl Display for DitherMethod {
             &Self::FloydSteinberg => write!(f, "floyd-steinberg"),
             &Self::Pattern => write!(f, "ordered pattern"),
             &Self::Cuda => write!(f, "cuda-accelerated ordered pattern"),
+            &Self::ErrorDiffusion => write!(f, "error diffusion"),
+            &Self::Yliluoma1 => write!(f, "yliluoma 1"),
+            &Self::Yliluoma2 => write!(f, "yliluoma 2"),
+            &Self::Yliluoma3 => write!(f, "yliluoma 3"),
+        }
+    }
+}
+
+impl FromStr for DitherMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DitherMethod, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "floyd-steinberg" => Ok(DitherMethod::FloydSteinberg),
+            "pattern" => Ok(DitherMethod::Pattern),
+            "cuda" => Ok(DitherMethod::Cuda),
+            "error-diffusion" => Ok(DitherMethod::ErrorDiffusion),
+            "yliluoma-1" => Ok(DitherMethod::Yliluoma1),
+            "yliluoma-2" => Ok(DitherMethod::Yliluoma2),
+            "yliluoma-3" => Ok(DitherMethod::Yliluoma3),
+            _ => Err(format!(
+                "unknown dither method {s:?} (expected floyd-steinberg, pattern, cuda, error-diffusion or yliluoma-1/2/3)"
+            )),
+        }
+    }
+}
+
+impl DitherMethod {
+    /// The [colorful::yliluoma] algorithm this method uses, if any.
+    pub fn yliluoma_algorithm(&self) -> Option<Algorithm> {
+        match self {
+            DitherMethod::Yliluoma1 => Some(Algorithm::One),
+            DitherMethod::Yliluoma2 => Some(Algorithm::Two),
+            DitherMethod::Yliluoma3 => Some(Algorithm::Three),
+            _ => None,
+        }
+    }
+}
+
+/// How a video track's size follows from its source's aspect ratio.
+#[derive(TryFromPrimitive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sizing {
+    Stretch = 0,    // exactly the track's width and height, whatever the aspect ratio
+    FitColumns = 1, // the track's width, and as many rows as the aspect ratio needs
+    FitRows = 2,    // the track's height, and as many columns as the aspect ratio needs
+    FitBox = 3,     // as large as fits within the track's width and height
+}
+
+impl Display for Sizing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sizing::Stretch => write!(f, "stretch"),
+            Sizing::FitColumns => write!(f, "fit columns"),
+            Sizing::FitRows => write!(f, "fit rows"),
+            Sizing::FitBox => write!(f, "fit box"),
+        }
+    }
+}
+
+impl FromStr for Sizing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Sizing, String> {
+        match s.to_ascii_lowercase().replace(' ', "-").as_str() {
+            "stretch" => Ok(Sizing::Stretch),
+            "fit-columns" => Ok(Sizing::FitColumns),
+            "fit-rows" => Ok(Sizing::FitRows),
+            "fit-box" => Ok(Sizing::FitBox),
+            _ => Err(format!(
+                "unknown sizing {s:?} (expected stretch, fit-columns, fit-rows or fit-box)"
+            )),
+        }
+    }
+}
+
+/// The ffmpeg scaling algorithm a video track's frames are resized with.
+#[derive(TryFromPrimitive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScalingAlgorithm {
+    Lanczos = 0,
+    Bicubic = 1,
+    Bilinear = 2,
+    FastBilinear = 3,
+    Area = 4,
+    Point = 5, // nearest neighbour
+    Gauss = 6,
+    Sinc = 7,
+    Spline = 8,
+}
+
+impl ScalingAlgorithm {
+    pub const ALL: [ScalingAlgorithm; 9] = [
+        ScalingAlgorithm::Lanczos,
+        ScalingAlgorithm::Bicubic,
+        ScalingAlgorithm::Bilinear,
+        ScalingAlgorithm::FastBilinear,
+        ScalingAlgorithm::Area,
+        ScalingAlgorithm::Point,
+        ScalingAlgorithm::Gauss,
+        ScalingAlgorithm::Sinc,
+        ScalingAlgorithm::Spline,
+    ];
+
+    pub fn algorithm(&self) -> ac_ffmpeg::codec::video::scaler::Algorithm {
+        use ac_ffmpeg::codec::video::scaler::Algorithm;
+
+        match self {
+            ScalingAlgorithm::Lanczos => Algorithm::Lanczos,
+            ScalingAlgorithm::Bicubic => Algorithm::Bicubic,
+            ScalingAlgorithm::Bilinear => Algorithm::Bilinear,
+            ScalingAlgorithm::FastBilinear => Algorithm::FastBilinear,
+            ScalingAlgorithm::Area => Algorithm::Area,
+            ScalingAlgorithm::Point => Algorithm::Point,
+            ScalingAlgorithm::Gauss => Algorithm::Gauss,
+            ScalingAlgorithm::Sinc => Algorithm::Sinc,
+            ScalingAlgorithm::Spline => Algorithm::Spline,
+        }
+    }
+}
+
+impl Display for ScalingAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScalingAlgorithm::Lanczos => write!(f, "lanczos"),
+            ScalingAlgorithm::Bicubic => write!(f, "bicubic"),
+            ScalingAlgorithm::Bilinear => write!(f, "bilinear"),
+            ScalingAlgorithm::FastBilinear => write!(f, "fast-bilinear"),
+            ScalingAlgorithm::Area => write!(f, "area"),
+            ScalingAlgorithm::Point => write!(f, "point"),
+            ScalingAlgorithm::Gauss => write!(f, "gauss"),
+            ScalingAlgorithm::Sinc => write!(f, "sinc"),
+            ScalingAlgorithm::Spline => write!(f, "spline"),
+        }
+    }
+}
+
+impl FromStr for ScalingAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ScalingAlgorithm, String> {
+        let name = s.to_ascii_lowercase();
+        ScalingAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.to_string() == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown scaling algorithm {s:?} (expected lanczos, bicubic, bilinear, fast-bilinear, area, point, gauss, sinc or spline)"
+                )
+            })
+    }
+}
+
+/// How a video track's frames get from the source's size down to the track's.
+/// Scaling far down in one step leaves ringing and aliasing, which dithering then makes worse.
+#[derive(TryFromPrimitive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+#[serde(rename_all = "kebab-case")]
+pub enum Downscale {
+    Direct = 0,    // straight to the track's size with the scaling algorithm
+    Area = 1,      // average the area every pixel covers, at full resolution
+    MultiStep = 2, // the scaling algorithm down to a power of two of the track's size, then halve until there
+}
+
+impl Display for Downscale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Downscale::Direct => write!(f, "direct"),
+            Downscale::Area => write!(f, "area averaging"),
+            Downscale::MultiStep => write!(f, "multi-step"),
+        }
+    }
+}
+
+impl FromStr for Downscale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Downscale, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "direct" => Ok(Downscale::Direct),
+            "area" => Ok(Downscale::Area),
+            "multi-step" => Ok(Downscale::MultiStep),
+            _ => Err(format!(
+                "unknown downscale mode {s:?} (expected direct, area or multi-step)"
+            )),
+        }
+    }
+}
+
+/// How a video track's source encodes color, see [crate::FFMpegVideoDecoder::with_source_color].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SourceColor {
+    pub matrix: Option<Matrix>, // guessed from the frame size if not set
+    pub range: Option<Range>,   // guessed from the pixel format if not set
+    pub transfer: Transfer,     // pq or hlg for HDR sources, which get tone mapped
+    pub tone_map: ToneMap,
+    pub peak: f32, // brightest HDR highlights, in nits, that tone mapping keeps detail in
+}
+
+impl Default for SourceColor {
+    fn default() -> Self {
+        Self {
+            matrix: None,
+            range: None,
+            transfer: Transfer::Sdr,
+            tone_map: ToneMap::Reinhard,
+            peak: 1000.0,
         }
     }
 }
 
+/// Parses an aspect ratio, either as a fraction like "16:9" or as a number like "2.39".
+pub fn parse_aspect(s: &str) -> Result<f32, String> {
+    let aspect = match s.split_once(':') {
+        Some((w, h)) => w
+            .trim()
+            .parse::<f32>()
+            .and_then(|w| h.trim().parse::<f32>().map(|h| w / h)),
+        None => s.trim().parse::<f32>(),
+    }
+    .map_err(|e| format!("invalid aspect ratio {s:?}: {e}"))?;
+
+    if aspect.is_finite() && aspect > 0.0 {
+        Ok(aspect)
+    } else {
+        Err(format!("invalid aspect ratio {s:?}"))
+    }
+}
+
+/// Parses a crop as `width:height:x:y` in source pixels, like ffmpeg's crop filter takes it.
+pub fn parse_crop(s: &str) -> Result<Crop, String> {
+    let values = s
+        .split(':')
+        .map(|v| v.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|e| format!("invalid crop {s:?}: {e}"))?;
+
+    match values[..] {
+        [width, height, x, y] if width > 0 && height > 0 => Ok(Crop {
+            width,
+            height,
+            x,
+            y,
+        }),
+        _ => Err(format!("invalid crop {s:?} (expected width:height:x:y)")),
+    }
+}
+
+/// Parses a color given as six hex digits, with or without a leading '#'.
+pub fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(format!("invalid color {s:?} (expected RRGGBB)"));
+    }
+
+    let mut color = [0; 3];
+    for (i, c) in color.iter_mut().enumerate() {
+        *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("invalid color {s:?} (expected RRGGBB)"))?;
+    }
+
+    Ok(color)
+}
+
 impl Default for SubtitleTrack {
     fn default() -> Self {
         Self {
             source_stream_index: usize::MAX,
             track_id: 0,
             track_name: "Subtitle 1".to_owned(),
+            layers: Vec::new(),
         }
     }
 }
@@ -216,6 +923,19 @@ impl Default for VideoTrack {
             track_width: 192,
             color_mode: ColorMode::True,
             dither_mode: DitherConfig::default(),
+            keyframes: KeyframeSettings::default(),
+            truecolor_threshold: None,
+            linear_light: false,
+            sizing: Sizing::Stretch,
+            cell_aspect: 0.5,
+            source_aspect: None,
+            padding: None,
+            scaler: ScalingAlgorithm::Lanczos,
+            downscale: Downscale::Direct,
+            auto_crop: false,
+            crop: None,
+            source_color: SourceColor::default(),
+            adjustments: Adjustments::default(),
         }
     }
 }
@@ -335,6 +1055,64 @@ impl VideoTrack {
 
         ui.end_row();
 
+        ui.label("Crop black bars");
+        ui.checkbox(&mut self.auto_crop, "");
+        ui.end_row();
+
+        ui.label("Sizing");
+        egui::ComboBox::from_id_source("Sizing")
+            .selected_text(format!("{}", self.sizing))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.sizing, Sizing::Stretch, "stretch");
+                ui.selectable_value(&mut self.sizing, Sizing::FitColumns, "fit columns");
+                ui.selectable_value(&mut self.sizing, Sizing::FitRows, "fit rows");
+                ui.selectable_value(&mut self.sizing, Sizing::FitBox, "fit box");
+            });
+        ui.end_row();
+
+        if self.sizing != Sizing::Stretch {
+            ui.label("Cell aspect ratio");
+            ui.add(
+                egui::DragValue::new(&mut self.cell_aspect)
+                    .speed(0.01)
+                    .clamp_range(0.1..=2.0),
+            );
+            ui.end_row();
+
+            ui.label("Letterbox");
+            ui.horizontal(|ui| {
+                let mut enabled = self.padding.is_some();
+                ui.checkbox(&mut enabled, "");
+                match (enabled, self.padding.as_mut()) {
+                    (true, Some(color)) => {
+                        ui.color_edit_button_srgb(color);
+                    }
+                    (true, None) => self.padding = Some([0, 0, 0]),
+                    (false, _) => self.padding = None,
+                }
+            });
+            ui.end_row();
+        }
+
+        ui.label("Scaling");
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("Scaling algorithm")
+                .selected_text(format!("{}", self.scaler))
+                .show_ui(ui, |ui| {
+                    for algorithm in ScalingAlgorithm::ALL {
+                        ui.selectable_value(&mut self.scaler, algorithm, algorithm.to_string());
+                    }
+                });
+            egui::ComboBox::from_id_source("Downscale")
+                .selected_text(format!("{}", self.downscale))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.downscale, Downscale::Direct, "direct");
+                    ui.selectable_value(&mut self.downscale, Downscale::Area, "area averaging");
+                    ui.selectable_value(&mut self.downscale, Downscale::MultiStep, "multi-step");
+                });
+        });
+        ui.end_row();
+
         ui.label("Color mode");
         egui::ComboBox::from_id_source("Color mode")
             .selected_text(format!("{}", self.color_mode))
@@ -344,6 +1122,28 @@ impl VideoTrack {
             });
         ui.end_row();
 
+        ui.label("Linear light");
+        ui.checkbox(&mut self.linear_light, "");
+        ui.end_row();
+
+        ui.collapsing("Source color", |ui| {
+            egui::Grid::new("source_color_grid")
+                .num_columns(2)
+                .spacing([40.0, 7.0])
+                .striped(false)
+                .show(ui, |ui| self.source_color_settings(ui));
+        });
+        ui.end_row();
+
+        ui.collapsing("Adjustments", |ui| {
+            egui::Grid::new("adjustment_grid")
+                .num_columns(2)
+                .spacing([40.0, 7.0])
+                .striped(false)
+                .show(ui, |ui| self.adjustment_settings(ui));
+        });
+        ui.end_row();
+
         if self.color_mode == ColorMode::EightBit {
             ui.collapsing("Dithering settings", |ui| {
                 egui::Grid::new("dither_grid")
@@ -357,6 +1157,78 @@ impl VideoTrack {
         ui.end_row();
     }
 
+    pub fn source_color_settings(&mut self, ui: &mut egui::Ui) {
+        let color = &mut self.source_color;
+        let show = |v: Option<String>| v.unwrap_or_else(|| "auto".to_string());
+
+        ui.label("Colorspace");
+        egui::ComboBox::from_id_source("Colorspace")
+            .selected_text(show(color.matrix.map(|v| v.to_string())))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut color.matrix, None, "auto");
+                for matrix in [Matrix::Bt601, Matrix::Bt709, Matrix::Bt2020] {
+                    ui.selectable_value(&mut color.matrix, Some(matrix), matrix.to_string());
+                }
+            });
+        ui.end_row();
+
+        ui.label("Range");
+        egui::ComboBox::from_id_source("Range")
+            .selected_text(show(color.range.map(|v| v.to_string())))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut color.range, None, "auto");
+                for range in [Range::Limited, Range::Full] {
+                    ui.selectable_value(&mut color.range, Some(range), range.to_string());
+                }
+            });
+        ui.end_row();
+
+        ui.label("Transfer");
+        egui::ComboBox::from_id_source("Transfer")
+            .selected_text(color.transfer.to_string())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut color.transfer, Transfer::Sdr, "SDR");
+                ui.selectable_value(&mut color.transfer, Transfer::Pq, "HDR10 (PQ)");
+                ui.selectable_value(&mut color.transfer, Transfer::Hlg, "HLG");
+            });
+        ui.end_row();
+
+        if color.transfer != Transfer::Sdr {
+            ui.label("Tone mapping");
+            egui::ComboBox::from_id_source("Tone mapping")
+                .selected_text(color.tone_map.to_string())
+                .show_ui(ui, |ui| {
+                    for tone_map in [ToneMap::Reinhard, ToneMap::Hable, ToneMap::Clip] {
+                        ui.selectable_value(&mut color.tone_map, tone_map, tone_map.to_string());
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Peak (nits)");
+            ui.add(egui::Slider::new(&mut color.peak, 203.0..=10000.0).logarithmic(true));
+            ui.end_row();
+        }
+    }
+
+    pub fn adjustment_settings(&mut self, ui: &mut egui::Ui) {
+        let adjustments = &mut self.adjustments;
+        let sliders = [
+            ("Denoise", &mut adjustments.denoise, 0.0..=1.0),
+            ("Brightness", &mut adjustments.brightness, -1.0..=1.0),
+            ("Contrast", &mut adjustments.contrast, 0.0..=3.0),
+            ("Gamma", &mut adjustments.gamma, 0.1..=3.0),
+            ("Saturation", &mut adjustments.saturation, 0.0..=3.0),
+            ("Sharpen", &mut adjustments.sharpen, 0.0..=3.0),
+            ("Sharpen radius", &mut adjustments.sharpen_radius, 0.1..=5.0),
+        ];
+
+        for (label, value, range) in sliders {
+            ui.label(label);
+            ui.add(egui::Slider::new(value, range));
+            ui.end_row();
+        }
+    }
+
     pub fn dither_settings(&mut self, ui: &mut egui::Ui) {
         ui.label("Method");
         egui::ComboBox::from_id_source("Dither mode")
@@ -372,6 +1244,26 @@ impl VideoTrack {
                     DitherMethod::Pattern,
                     "ordered pattern",
                 );
+                ui.selectable_value(
+                    &mut self.dither_mode.method,
+                    DitherMethod::ErrorDiffusion,
+                    "error diffusion",
+                );
+                ui.selectable_value(
+                    &mut self.dither_mode.method,
+                    DitherMethod::Yliluoma1,
+                    "yliluoma 1",
+                );
+                ui.selectable_value(
+                    &mut self.dither_mode.method,
+                    DitherMethod::Yliluoma2,
+                    "yliluoma 2",
+                );
+                ui.selectable_value(
+                    &mut self.dither_mode.method,
+                    DitherMethod::Yliluoma3,
+                    "yliluoma 3",
+                );
             });
         ui.end_row();
 
@@ -394,10 +1286,36 @@ impl VideoTrack {
                     DistanceFunction::CIE76,
                     "CIE76 (fastest)",
                 );
+                ui.selectable_value(
+                    &mut self.dither_mode.distance_function,
+                    DistanceFunction::CIEDE2000,
+                    "CIEDE2000 (most accurate, slowest)",
+                );
+                ui.selectable_value(
+                    &mut self.dither_mode.distance_function,
+                    DistanceFunction::Oklab,
+                    "Oklab (fast)",
+                );
             });
         ui.end_row();
 
-        if self.dither_mode.method == DitherMethod::Pattern {
+        let mut temporal = self.dither_mode.temporal_threshold.is_some();
+        ui.label("Temporal stability");
+        ui.checkbox(&mut temporal, "");
+        ui.end_row();
+
+        if temporal {
+            let threshold = self.dither_mode.temporal_threshold.get_or_insert(2.0);
+            ui.label("Stability threshold (ΔE)");
+            ui.add(egui::DragValue::new(threshold).speed(0.1));
+            ui.end_row();
+        } else {
+            self.dither_mode.temporal_threshold = None;
+        }
+
+        if self.dither_mode.method == DitherMethod::Pattern
+            || self.dither_mode.method.yliluoma_algorithm().is_some()
+        {
             ui.label("Matrix size");
             egui::ComboBox::from_id_source("matrix size")
                 .selected_text(format!("{}", self.dither_mode.matrix_size))
@@ -413,12 +1331,171 @@ impl VideoTrack {
                         MatrixSize::Two,
                         "2x2 (fastest)",
                     );
+                    ui.selectable_value(
+                        &mut self.dither_mode.matrix_size,
+                        MatrixSize::BlueNoise,
+                        "blue noise",
+                    );
                 });
             ui.end_row();
 
+        }
+
+        if self.dither_mode.method == DitherMethod::Pattern {
             ui.label("Error multiplier");
             ui.add(egui::DragValue::new(&mut self.dither_mode.multiplier).speed(0.01));
             ui.end_row();
         }
+
+        if self.dither_mode.method == DitherMethod::ErrorDiffusion {
+            ui.label("Kernel");
+            egui::ComboBox::from_id_source("kernel")
+                .selected_text(format!("{}", self.dither_mode.kernel))
+                .show_ui(ui, |ui| {
+                    for kernel in Kernel::ALL {
+                        ui.selectable_value(&mut self.dither_mode.kernel, kernel, kernel.to_string());
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Serpentine scanning");
+            ui.checkbox(&mut self.dither_mode.serpentine, "");
+            ui.end_row();
+
+            ui.label("Error strength");
+            ui.add(egui::DragValue::new(&mut self.dither_mode.error_strength).speed(0.01));
+            ui.end_row();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(sizing: Sizing, width: usize, height: usize) -> VideoTrack {
+        VideoTrack {
+            sizing,
+            track_width: width,
+            track_height: height,
+            ..VideoTrack::default()
+        }
+    }
+
+    fn layout(width: usize, height: usize, frame_width: usize, frame_height: usize) -> Layout {
+        Layout {
+            width,
+            height,
+            frame_width,
+            frame_height,
+        }
+    }
+
+    const WIDE: f32 = 16.0 / 9.0;
+    const STANDARD: f32 = 4.0 / 3.0;
+    const SCOPE: f32 = 2.39;
+
+    #[test]
+    fn stretching_ignores_the_aspect_ratio() {
+        for aspect in [None, Some(WIDE), Some(STANDARD)] {
+            assert_eq!(track(Sizing::Stretch, 192, 108).layout(aspect), layout(192, 108, 192, 108));
+        }
+    }
+
+    #[test]
+    fn fitting_keeps_the_aspect_ratio() {
+        // a cell is half as wide as it's tall and shows two pixels stacked, so pixels come out square
+        assert_eq!(track(Sizing::FitColumns, 192, 50).layout(Some(WIDE)), layout(192, 108, 192, 108));
+        assert_eq!(track(Sizing::FitColumns, 192, 50).layout(Some(STANDARD)), layout(192, 144, 192, 144));
+        assert_eq!(track(Sizing::FitRows, 10, 108).layout(Some(WIDE)), layout(192, 108, 192, 108));
+        assert_eq!(track(Sizing::FitRows, 10, 108).layout(Some(STANDARD)), layout(144, 108, 144, 108));
+
+        // fit-box keeps to whichever side runs out first
+        assert_eq!(track(Sizing::FitBox, 192, 108).layout(Some(STANDARD)), layout(144, 108, 144, 108));
+        assert_eq!(track(Sizing::FitBox, 192, 108).layout(Some(SCOPE)), layout(192, 80, 192, 80));
+
+        // narrower cells need more rows for the same picture
+        let narrow = VideoTrack {
+            cell_aspect: 0.4,
+            ..track(Sizing::FitColumns, 192, 108)
+        };
+        assert_eq!(narrow.layout(Some(WIDE)), layout(192, 86, 192, 86));
+    }
+
+    #[test]
+    fn heights_are_even() {
+        // 192 / 1.85 is 103.8 rows of pixels
+        assert_eq!(track(Sizing::FitColumns, 192, 108).layout(Some(1.85)), layout(192, 104, 192, 104));
+        assert_eq!(track(Sizing::FitRows, 192, 107).layout(Some(WIDE)), layout(192, 108, 192, 108));
+        assert_eq!(track(Sizing::FitBox, 192, 107).layout(Some(STANDARD)), layout(141, 106, 141, 106));
+        assert_eq!(track(Sizing::FitColumns, 4, 108).layout(Some(100.0)), layout(4, 2, 4, 2));
+    }
+
+    #[test]
+    fn unknown_aspect_ratios_stretch() {
+        assert_eq!(track(Sizing::FitBox, 192, 108).layout(None), layout(192, 108, 192, 108));
+    }
+
+    #[test]
+    fn padding_fills_the_track_size() {
+        let padded = VideoTrack {
+            padding: Some([0, 0, 0]),
+            ..track(Sizing::FitBox, 192, 108)
+        };
+        assert_eq!(padded.layout(Some(STANDARD)), layout(144, 108, 192, 108));
+        assert_eq!(padded.layout(Some(SCOPE)), layout(192, 80, 192, 108));
+
+        let odd = VideoTrack {
+            track_height: 107,
+            ..padded
+        };
+        assert_eq!(odd.layout(Some(SCOPE)), layout(192, 80, 192, 108));
+    }
+
+    #[test]
+    fn tracks_take_the_size_of_their_frame() {
+        let mut t = track(Sizing::FitBox, 192, 108);
+        assert_eq!(t.fit_to_source(Some(WIDE), 1920, 1080), layout(192, 108, 192, 108));
+
+        // a letterbox cropped off leaves a wider picture
+        let mut t = VideoTrack {
+            crop: Some(Crop {
+                width: 1920,
+                height: 800,
+                x: 0,
+                y: 140,
+            }),
+            ..track(Sizing::FitBox, 192, 108)
+        };
+        assert_eq!(t.fit_to_source(Some(WIDE), 1920, 1080), layout(192, 80, 192, 80));
+        assert_eq!((t.track_width, t.track_height), (192, 80));
+        assert_eq!((t.dither_mode.width, t.dither_mode.height), (192, 80));
+
+        // an aspect ratio given for the source wins over the one its size suggests
+        let mut t = VideoTrack {
+            source_aspect: Some(WIDE),
+            ..track(Sizing::FitBox, 192, 108)
+        };
+        assert_eq!(t.fit_to_source(Some(1.5), 720, 480), layout(192, 108, 192, 108));
+    }
+
+    #[test]
+    fn non_square_pixel_sizes_are_warned_about() {
+        let t = track(Sizing::FitBox, 192, 108);
+        for (width, height) in [(720, 480), (720, 576), (704, 576), (1440, 1080), (960, 720)] {
+            let warning = t.aspect_warning(width, height).unwrap();
+            assert!(warning.contains(&format!("{width}x{height}")), "{warning}");
+            assert!(warning.contains("source-aspect"), "{warning}");
+        }
+        assert_eq!(t.aspect_warning(1920, 1080), None);
+        assert_eq!(t.aspect_warning(640, 480), None);
+
+        // nothing to warn about once the aspect ratio is given, or if it doesn't matter
+        let given = VideoTrack {
+            source_aspect: Some(STANDARD),
+            ..t.clone()
+        };
+        assert_eq!(given.aspect_warning(720, 480), None);
+        assert_eq!(track(Sizing::Stretch, 192, 108).aspect_warning(720, 480), None);
     }
 }
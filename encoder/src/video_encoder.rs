@@ -1,14 +1,15 @@
-use std::{convert::Infallible, time::Duration};
+use std::{convert::Infallible, sync::Arc, time::Duration};
 
 use bytes::BytesMut;
+use colorful::{adaptive::RESERVED_COLORS, palette::Palette};
 use container::{
-    metadata::ColorMode,
+    metadata::{ColorMode, KeyframeSettings},
     packet::{Packet, ToPacket},
     TinyMap, VideoPacketFlags, VIDEO_PACKET_KEY,
 };
 use enumflags2::{make_bitflags, BitFlags};
 use image::RgbImage;
-use img2ansi::{AnsiEncoder, VideoImage};
+use img2ansi::{writer, AnsiEncoder, VideoImage};
 
 pub struct FrameEncoder {
     pub stream_index: u16,
@@ -17,6 +18,39 @@ pub struct FrameEncoder {
     pub color: ColorMode,
     pub use_diffing: bool,
     pub last_frame: Option<VideoImage>,
+    pub keyframes: KeyframeSettings,
+    pub frames_since_keyframe: u32,
+    pub last_keyframe_time: Option<Duration>,
+    pub current_time: Duration,
+    pub palette: Option<Arc<Palette>>, // adaptive palette in effect, re-sent before every keyframe
+    force_keyframe: bool,
+    preceding: Option<Packet<BytesMut>>,
+}
+
+impl FrameEncoder {
+    /// Creates an encoder with inter-frame diffing enabled and the default [KeyframeSettings].
+    pub fn new(stream_index: u16, width: u32, height: u32, color: ColorMode) -> FrameEncoder {
+        FrameEncoder {
+            stream_index,
+            width,
+            height,
+            color,
+            use_diffing: true,
+            last_frame: None,
+            keyframes: KeyframeSettings::default(),
+            frames_since_keyframe: 0,
+            last_keyframe_time: None,
+            current_time: Duration::ZERO,
+            palette: None,
+            force_keyframe: false,
+            preceding: None,
+        }
+    }
+
+    pub fn with_keyframes(mut self, keyframes: KeyframeSettings) -> FrameEncoder {
+        self.keyframes = keyframes;
+        self
+    }
 }
 
 impl AnsiEncoder for FrameEncoder {
@@ -39,12 +73,45 @@ impl AnsiEncoder for FrameEncoder {
     fn use_diffing(&self) -> bool {
         self.use_diffing
     }
+
+    fn needs_keyframe(&mut self, image: &VideoImage) -> bool {
+        // cells already on screen change color along with the palette, so a new palette has to repaint everything
+        if std::mem::take(&mut self.force_keyframe) {
+            return true;
+        }
+
+        let KeyframeSettings {
+            max_interval,
+            max_frames,
+            scene_cut_threshold,
+        } = self.keyframes;
+
+        if matches!(max_frames, Some(frames) if self.frames_since_keyframe + 1 >= frames) {
+            return true;
+        }
+
+        if let (Some(interval), Some(last)) = (max_interval, self.last_keyframe_time) {
+            if self.current_time.saturating_sub(last) >= interval {
+                return true;
+            }
+        }
+
+        if let (Some(threshold), Some(last_frame)) = (scene_cut_threshold, self.last_frame.as_ref())
+        {
+            return writer::changed_fraction(image, last_frame)
+                .map(|v| v >= threshold)
+                .unwrap_or(true);
+        }
+
+        false
+    }
 }
 
 pub struct DecodedVideoFrame {
     pub pts: Duration,
     pub duration: Duration,
     pub image: VideoImage,
+    pub palette: Option<Arc<Palette>>, // set when the frame switches to a new adaptive palette
 }
 
 impl ToPacket for FrameEncoder {
@@ -52,13 +119,43 @@ impl ToPacket for FrameEncoder {
     type Err = Infallible;
 
     fn encode_packet(&mut self, src: Self::Source) -> Result<Packet<BytesMut>, Infallible> {
+        self.current_time = src.pts;
+        if let Some(palette) = src.palette {
+            self.palette = Some(palette);
+            self.force_keyframe = true;
+        }
+
         let (encoded, keyframe) = self.encode_best(&src.image);
+        if keyframe {
+            self.frames_since_keyframe = 0;
+            self.last_keyframe_time = Some(src.pts);
+        } else {
+            self.frames_since_keyframe += 1;
+        }
+
         let flags: BitFlags<VideoPacketFlags> = if keyframe {
             make_bitflags!(VideoPacketFlags::{Keyframe})
         } else {
             BitFlags::empty()
         };
 
+        // every keyframe carries the palette with it, so playback can start from any of them
+        if let (true, Some(palette)) = (keyframe, self.palette.as_ref()) {
+            let mut extra_data = TinyMap::new();
+            extra_data.insert(
+                VIDEO_PACKET_KEY,
+                make_bitflags!(VideoPacketFlags::{Palette}).bits(),
+            );
+
+            self.preceding = Some(Packet {
+                stream_index: self.stream_index,
+                presentation_length: Duration::ZERO,
+                presentation_time: src.pts,
+                data: writer::encode_palette(palette, RESERVED_COLORS..256),
+                extra_data,
+            });
+        }
+
         let mut extra_data = TinyMap::new();
         extra_data.insert(VIDEO_PACKET_KEY, flags.bits());
 
@@ -70,4 +167,145 @@ impl ToPacket for FrameEncoder {
             extra_data,
         })
     }
+
+    fn take_preceding(&mut self) -> Option<Packet<BytesMut>> {
+        self.preceding.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16x8 frame (16x4 cells) whose first `columns` columns are lit and the rest black.
+    fn frame(columns: u32) -> VideoImage {
+        let mut image = VideoImage::new(16, 8, ColorMode::EightBit);
+        for y in 0..8 {
+            for x in 0..columns {
+                image.set_pixel(x, y, img2ansi::Pixel::EightBit(200));
+            }
+        }
+
+        image
+    }
+
+    /// Encodes frames at the given times (in ms), returning which came out as keyframes.
+    fn keyframes(settings: KeyframeSettings, frames: &[(u64, VideoImage)]) -> Vec<bool> {
+        let mut encoder = FrameEncoder::new(0, 16, 8, ColorMode::EightBit).with_keyframes(settings);
+        frames
+            .iter()
+            .map(|(ms, image)| {
+                let packet = encoder
+                    .encode_packet(DecodedVideoFrame {
+                        pts: Duration::from_millis(*ms),
+                        duration: Duration::from_millis(40),
+                        image: image.clone(),
+                        palette: None,
+                    })
+                    .unwrap();
+
+                BitFlags::<VideoPacketFlags>::from_bits_truncate(
+                    packet.extra_data.get(VIDEO_PACKET_KEY).unwrap(),
+                )
+                .contains(VideoPacketFlags::Keyframe)
+            })
+            .collect()
+    }
+
+    const NONE: KeyframeSettings = KeyframeSettings {
+        max_interval: None,
+        max_frames: None,
+        scene_cut_threshold: None,
+    };
+
+    #[test]
+    fn unchanged_frames_are_diffed() {
+        let frames: Vec<_> = (0..6).map(|i| (i * 40, frame(0))).collect();
+        assert_eq!(
+            keyframes(NONE, &frames),
+            [true, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn keyframes_come_every_max_frames() {
+        let settings = KeyframeSettings {
+            max_frames: Some(3),
+            ..NONE
+        };
+        let frames: Vec<_> = (0..7).map(|i| (i * 40, frame(0))).collect();
+        assert_eq!(
+            keyframes(settings, &frames),
+            [true, false, false, true, false, false, true]
+        );
+    }
+
+    #[test]
+    fn keyframes_come_every_max_interval() {
+        let settings = KeyframeSettings {
+            max_interval: Some(Duration::from_secs(1)),
+            ..NONE
+        };
+        // counted from the last keyframe, not in fixed steps
+        let frames: Vec<_> = [0, 400, 800, 1200, 1600, 2199, 2200]
+            .into_iter()
+            .map(|ms| (ms, frame(0)))
+            .collect();
+        assert_eq!(
+            keyframes(settings, &frames),
+            [true, false, false, true, false, false, true]
+        );
+    }
+
+    #[test]
+    fn scene_cuts_start_a_keyframe() {
+        let settings = KeyframeSettings {
+            scene_cut_threshold: Some(0.5),
+            ..NONE
+        };
+        // 2 of 16 columns changing is small, 8 of 16 reaches the threshold
+        let frames = [
+            (0, frame(0)),
+            (40, frame(2)),
+            (80, frame(10)),
+            (120, frame(10)),
+            (160, frame(12)),
+        ];
+        assert_eq!(
+            keyframes(settings, &frames),
+            [true, false, true, false, false]
+        );
+
+        let mut encoder = FrameEncoder::new(0, 16, 8, ColorMode::EightBit).with_keyframes(settings);
+        encoder.last_frame = Some(frame(0));
+        assert!(!encoder.needs_keyframe(&frame(7)));
+        assert!(encoder.needs_keyframe(&frame(8)));
+        // frames that can't be compared count as a cut
+        assert!(encoder.needs_keyframe(&VideoImage::new(8, 8, ColorMode::EightBit)));
+    }
+
+    #[test]
+    fn new_palettes_start_a_keyframe() {
+        let mut encoder = FrameEncoder::new(0, 16, 8, ColorMode::EightBit).with_keyframes(NONE);
+        let mut send = |ms, palette| {
+            encoder
+                .encode_packet(DecodedVideoFrame {
+                    pts: Duration::from_millis(ms),
+                    duration: Duration::from_millis(40),
+                    image: frame(0),
+                    palette,
+                })
+                .unwrap();
+            (
+                encoder.frames_since_keyframe == 0,
+                encoder.take_preceding().is_some(),
+            )
+        };
+
+        assert_eq!(send(0, None), (true, false));
+        assert_eq!(send(40, None), (false, false));
+        // the palette is sent ahead of the keyframe, and again ahead of every later one
+        assert_eq!(send(80, Some(Arc::new(Palette::default()))), (true, true));
+        assert_eq!(send(120, None), (false, false));
+    }
 }
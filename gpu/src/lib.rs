@@ -22,6 +22,9 @@ const BAYER_8X8: [usize; 64] = [
 const BAYER_4X4: [usize; 16] = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5];
 const BAYER_2X2: [usize; 4] = [0, 2, 3, 1];
 
+// the same map as colorful's pattern dithering
+const BLUE_NOISE_32X32: [usize; 1024] = include!("../../colorful/src/blue_noise_32x32.in");
+
 #[inline]
 unsafe fn smallest_dist(c: &Jab<UCS>) -> usize {
     let mut min_dist: f32 = f32::MAX;
@@ -43,54 +46,154 @@ fn to_luma(c: [u8; 3]) -> f32 {
     (c[0] as f32 * 299.0 + c[1] as f32 * 587.0 + c[2] as f32 * 114.0) / 255000.0
 }
 
+/// The body of the dither kernels, for the pixel at one thread index: returns the palette index to write, or None for
+/// threads past the end of the image. Plain code, so that it compiles for the host as well (see [host]).
+#[inline]
+pub fn dither_pixel<const TOTAL_SIZE: usize, const MATRIX_SIZE: usize>(
+    colors: &[u8],
+    idx: u32,
+    width: u32,
+    mult: f32,
+    matrix: &[usize],
+) -> Option<u8> {
+    let idx_rgb = (idx * 3) as usize;
+    if idx_rgb + 2 >= colors.len() {
+        return None;
+    }
+
+    let (x, y) = (idx % width, idx / width);
+
+    let mut err_acc: [u8; 3] = [0, 0, 0];
+    let color = [colors[idx_rgb], colors[idx_rgb + 1], colors[idx_rgb + 2]];
+
+    let mut candidates: [([u8; 3], u8); TOTAL_SIZE] = [([0, 0, 0], 0); TOTAL_SIZE];
+
+    for candidate in candidates.iter_mut() {
+        let tmp = [
+            (color[0] as f32 + (err_acc[0] as f32 * mult)).clamp(0.0, 255.0) as u8,
+            (color[1] as f32 + (err_acc[1] as f32 * mult)).clamp(0.0, 255.0) as u8,
+            (color[2] as f32 + (err_acc[2] as f32 * mult)).clamp(0.0, 255.0) as u8,
+        ];
+
+        let tmp_jab = Jab::<UCS>::from(tmp);
+        let (chosen_idx, chosen) = unsafe {
+            let chosen_idx: usize = smallest_dist(&tmp_jab);
+            (chosen_idx, PALETTE.get_unchecked(chosen_idx))
+        };
+        *candidate = (*chosen, chosen_idx as u8);
+
+        err_acc[0] = err_acc[0].saturating_add(color[0].saturating_sub(chosen[0]));
+        err_acc[1] = err_acc[1].saturating_add(color[1].saturating_sub(chosen[1]));
+        err_acc[2] = err_acc[2].saturating_add(color[2].saturating_sub(chosen[2]));
+    }
+
+    candidates.sort_by(|a, b| to_luma(a.0).partial_cmp(&to_luma(b.0)).unwrap());
+
+    let (_, chosen_idx) =
+        candidates[matrix[(y as usize % MATRIX_SIZE) * MATRIX_SIZE + (x as usize % MATRIX_SIZE)]];
+    Some(chosen_idx)
+}
+
 macro_rules! dither_def {
     ($name:ident, $total_size: literal, $matrix_size:literal, $matrix:ident) => {
         #[kernel]
         pub unsafe fn $name(colors: &[u8], c: *mut u8, width: u32, mult: f32) {
             let idx = thread::index_1d();
-            let idx_rgb = (idx * 3) as usize;
-            if idx_rgb + 2 >= colors.len() {
-                return;
+            if let Some(chosen_idx) =
+                dither_pixel::<$total_size, $matrix_size>(colors, idx, width, mult, &$matrix)
+            {
+                let elem = &mut *c.add(idx as usize);
+                *elem = chosen_idx;
             }
+        }
+    };
+}
 
-            let (x, y) = (idx % width, idx / width);
-
-            let mut err_acc: [u8; 3] = [0, 0, 0];
+dither_def!(dither_2x2, 4, 2, BAYER_2X2);
 
-            let color = [colors[idx_rgb], colors[idx_rgb + 1], colors[idx_rgb + 2]];
+dither_def!(dither_4x4, 16, 4, BAYER_4X4);
 
-            let mut candidates: [([u8; 3], u8); $total_size] = [([0, 0, 0], 0); $total_size];
+dither_def!(dither_8x8, 64, 8, BAYER_8X8);
 
-            for j in 0..$total_size {
-                let tmp = [
-                    (color[0] as f32 + (err_acc[0] as f32 * mult)).clamp(0.0, 255.0) as u8,
-                    (color[1] as f32 + (err_acc[1] as f32 * mult)).clamp(0.0, 255.0) as u8,
-                    (color[2] as f32 + (err_acc[2] as f32 * mult)).clamp(0.0, 255.0) as u8,
-                ];
+dither_def!(dither_blue_noise, 64, 32, BLUE_NOISE_32X32);
 
-                let tmp_jab = Jab::<UCS>::from(tmp);
-                let chosen_idx: usize = smallest_dist(&tmp_jab);
-                let chosen = PALETTE.get_unchecked(chosen_idx);
+/// Runs the kernels on the cpu, so that what they output can be checked without an nvidia gpu or toolchain.
+#[cfg(not(target_os = "cuda"))]
+pub mod host {
+    use super::*;
 
-                candidates[j] = (*chosen, chosen_idx as u8);
+    /// Runs a kernel body for every thread index a launch with `threads` threads would have, one after another,
+    /// writing into `out` the way the kernel writes into its output buffer.
+    pub fn launch(threads: u32, out: &mut [u8], body: impl Fn(u32) -> Option<u8>) {
+        for idx in 0..threads {
+            if let Some(v) = body(idx) {
+                out[idx as usize] = v;
+            }
+        }
+    }
 
-                err_acc[0] = err_acc[0].saturating_add(color[0].saturating_sub(chosen[0]));
-                err_acc[1] = err_acc[1].saturating_add(color[1].saturating_sub(chosen[1]));
-                err_acc[2] = err_acc[2].saturating_add(color[2].saturating_sub(chosen[2]));
+    macro_rules! host_def {
+        ($name:ident, $total_size: literal, $matrix_size:literal, $matrix:ident) => {
+            /// Runs the kernel of the same name with one thread per pixel of `out`.
+            pub fn $name(colors: &[u8], out: &mut [u8], width: u32, mult: f32) {
+                launch(out.len() as u32, out, |idx| {
+                    dither_pixel::<$total_size, $matrix_size>(colors, idx, width, mult, &$matrix)
+                });
             }
+        };
+    }
 
-            candidates.sort_by(|a, b| to_luma(a.0).partial_cmp(&to_luma(b.0)).unwrap());
-            let (_, chosen_idx) = candidates
-                [$matrix[(y as usize % $matrix_size) * $matrix_size + (x as usize % $matrix_size)]];
+    host_def!(dither_2x2, 4, 2, BAYER_2X2);
 
-            let elem = &mut *c.add(idx as usize);
-            *elem = chosen_idx as u8;
-        }
-    };
+    host_def!(dither_4x4, 16, 4, BAYER_4X4);
+
+    host_def!(dither_8x8, 64, 8, BAYER_8X8);
+
+    host_def!(dither_blue_noise, 64, 32, BLUE_NOISE_32X32);
 }
 
-dither_def!(dither_2x2, 4, 2, BAYER_2X2);
+#[cfg(all(test, not(target_os = "cuda")))]
+mod tests {
+    use super::*;
+    use colorful::palette::{AnsiColorMap, CAM02, DEFAULT_PALETTE};
+    use colorful::pattern_dithering::{self, MatrixSize};
+    use image::{Rgb, RgbImage};
 
-dither_def!(dither_4x4, 16, 4, BAYER_4X4);
+    type HostKernel = fn(&[u8], &mut [u8], u32, f32);
 
-dither_def!(dither_8x8, 64, 8, BAYER_8X8);
+    #[test]
+    fn palette_matches_colorful() {
+        assert_eq!(&PALETTE, DEFAULT_PALETTE.colors());
+    }
+
+    #[test]
+    fn kernels_match_pattern_dithering() {
+        // a size that doesn't line up with any threshold map, with a fine diagonal texture over a gradient so
+        // neighbouring pixels cross different thresholds
+        let image = RgbImage::from_fn(67, 37, |x, y| {
+            let texture = ((x * 2 + y * 5) % 9) as u8;
+            Rgb([
+                (x * 3) as u8 + texture,
+                (y * 5) as u8 + texture,
+                200 - (x + y) as u8,
+            ])
+        });
+        let color_map = AnsiColorMap::<CAM02>::new();
+        let mult = 0.09;
+
+        let kernels: [(HostKernel, MatrixSize); 4] = [
+            (host::dither_2x2, MatrixSize::Two),
+            (host::dither_4x4, MatrixSize::Four),
+            (host::dither_8x8, MatrixSize::Eight),
+            (host::dither_blue_noise, MatrixSize::BlueNoise),
+        ];
+
+        for (kernel, matrix_size) in kernels {
+            let mut out = vec![0; (image.width() * image.height()) as usize];
+            kernel(image.as_raw(), &mut out, image.width(), mult);
+
+            let expected = pattern_dithering::dither(&image, matrix_size, mult, &color_map);
+            assert_eq!(out, expected, "{}", matrix_size);
+        }
+    }
+}
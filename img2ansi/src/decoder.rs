@@ -0,0 +1,462 @@
+//! A small virtual terminal that interprets the subset of escape sequences the encoders emit, turning packets back into images.
+//!
+//! Supported: SGR (reset, 38/48 in both `5` and `2` forms, 39/49; other attributes are ignored), CUP/HVP, CHA, CNL/CPL, CUU/CUD/CUF/CUB,
+//! REP, ECH, EL, CR/LF and the `▀`, `▄`, `█` and space glyphs. Like the player, every packet starts at the frame's top left corner with default colors.
+
+use std::fmt;
+
+use colorful::palette::PALETTE;
+use container::metadata::ColorMode;
+use image::Rgb;
+
+use crate::{Pixel, VideoImage};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedByte(usize, u8),
+    UnsupportedSequence(usize, String),
+    MalformedColor(usize),
+    ColorModeMismatch(usize),
+    Truncated,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedByte(pos, b) => {
+                write!(f, "unexpected byte {:#04x} at {}", b, pos)
+            }
+            DecodeError::UnsupportedSequence(pos, s) => {
+                write!(f, "unsupported escape sequence {:?} at {}", s, pos)
+            }
+            DecodeError::MalformedColor(pos) => write!(f, "malformed color sequence at {}", pos),
+            DecodeError::ColorModeMismatch(pos) => {
+                write!(f, "true color sequence in an eight-bit image at {}", pos)
+            }
+            DecodeError::Truncated => write!(f, "packet ends in the middle of a sequence"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Glyph {
+    Upper,
+    Lower,
+    Full,
+    Space,
+}
+
+/// Keeps the last decoded image around, so that diff packets can be applied on top of it.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    image: VideoImage,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder whose initial image is black.
+    pub fn new(width: u32, height: u32, color: ColorMode) -> FrameDecoder {
+        FrameDecoder {
+            image: VideoImage::new(width, height, color),
+        }
+    }
+
+    /// Starts from an existing image, e.g. the last frame before a diff packet.
+    pub fn with_image(image: VideoImage) -> FrameDecoder {
+        FrameDecoder { image }
+    }
+
+    pub fn image(&self) -> &VideoImage {
+        &self.image
+    }
+
+    pub fn into_image(self) -> VideoImage {
+        self.image
+    }
+
+    /// Applies a packet (keyframe or diff) to the current image.
+    pub fn decode(&mut self, data: &[u8]) -> Result<&VideoImage, DecodeError> {
+        Terminal::new(&mut self.image).run(data)?;
+        Ok(&self.image)
+    }
+}
+
+/// Decodes a single keyframe.
+pub fn decode_frame(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorMode,
+) -> Result<VideoImage, DecodeError> {
+    let mut decoder = FrameDecoder::new(width, height, color);
+    decoder.decode(data)?;
+    Ok(decoder.into_image())
+}
+
+struct Terminal<'a> {
+    image: &'a mut VideoImage,
+    fg: Pixel,
+    bg: Pixel,
+    x: u32,
+    row: u32,
+    last_glyph: Option<Glyph>,
+}
+
+impl<'a> Terminal<'a> {
+    fn new(image: &'a mut VideoImage) -> Terminal<'a> {
+        let default = image.default_pixel();
+        Terminal {
+            image,
+            fg: default,
+            bg: default,
+            x: 0,
+            row: 0,
+            last_glyph: None,
+        }
+    }
+
+    fn rows(&self) -> u32 {
+        self.image.height() / 2
+    }
+
+    /// Cursor positions come from the packet, so they're kept to the frame, with the right and bottom edges standing
+    /// for anywhere past them, where drawing is clipped.
+    fn move_to(&mut self, x: u32, row: u32) {
+        self.x = x.min(self.image.width());
+        self.row = row.min(self.rows());
+    }
+
+    fn paint(&mut self, x: u32, upper: Pixel, lower: Pixel) {
+        // like a terminal, drawing outside of the frame is clipped
+        if x < self.image.width() && self.row < self.rows() {
+            self.image.set_pixel(x, self.row * 2, upper);
+            self.image.set_pixel(x, self.row * 2 + 1, lower);
+        }
+    }
+
+    fn put(&mut self, glyph: Glyph) {
+        let (upper, lower) = match glyph {
+            Glyph::Upper => (self.fg, self.bg),
+            Glyph::Lower => (self.bg, self.fg),
+            Glyph::Full => (self.fg, self.fg),
+            Glyph::Space => (self.bg, self.bg),
+        };
+
+        self.paint(self.x, upper, lower);
+        self.move_to(self.x.saturating_add(1), self.row);
+        self.last_glyph = Some(glyph);
+    }
+
+    fn erase(&mut self, from: u32, to: u32) {
+        for x in from..to.min(self.image.width()) {
+            self.paint(x, self.bg, self.bg);
+        }
+    }
+
+    fn run(mut self, data: &[u8]) -> Result<(), DecodeError> {
+        let mut i = 0;
+        while i < data.len() {
+            match data[i] {
+                0x1b => {
+                    i = self.csi(data, i)?;
+                    continue;
+                }
+                b' ' => self.put(Glyph::Space),
+                b'\r' => {
+                    self.x = 0;
+                    self.last_glyph = None;
+                }
+                b'\n' => {
+                    self.move_to(self.x, self.row.saturating_add(1));
+                    self.last_glyph = None;
+                }
+                0xe2 => {
+                    let glyph = match data.get(i + 1..i + 3) {
+                        Some([0x96, 0x80]) => Glyph::Upper,
+                        Some([0x96, 0x84]) => Glyph::Lower,
+                        Some([0x96, 0x88]) => Glyph::Full,
+                        Some(_) => return Err(DecodeError::UnexpectedByte(i, data[i])),
+                        None => return Err(DecodeError::Truncated),
+                    };
+                    self.put(glyph);
+                    i += 3;
+                    continue;
+                }
+                b => return Err(DecodeError::UnexpectedByte(i, b)),
+            }
+
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Interprets the CSI sequence starting at `start`, returning the position right after it.
+    fn csi(&mut self, data: &[u8], start: usize) -> Result<usize, DecodeError> {
+        if data.get(start + 1) != Some(&b'[') {
+            return Err(data
+                .get(start + 1)
+                .map(|b| DecodeError::UnexpectedByte(start + 1, *b))
+                .unwrap_or(DecodeError::Truncated));
+        }
+
+        let params_start = start + 2;
+        let params_len = data[params_start..]
+            .iter()
+            .position(|b| !(b.is_ascii_digit() || *b == b';'))
+            .ok_or(DecodeError::Truncated)?;
+        let end = params_start + params_len;
+        let terminator = data[end];
+
+        let params = data[params_start..end]
+            .split(|b| *b == b';')
+            .map(|v| {
+                std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|v| v.parse::<u32>().ok())
+            })
+            .collect::<Vec<Option<u32>>>();
+        // first parameter, with 0 or nothing meaning the default of 1
+        let n = params
+            .first()
+            .copied()
+            .flatten()
+            .filter(|v| *v > 0)
+            .unwrap_or(1);
+
+        if terminator != b'b' {
+            self.last_glyph = None;
+        }
+
+        match terminator {
+            b'm' => self.sgr(&params, start)?,
+            b'H' | b'f' => {
+                let col = params
+                    .get(1)
+                    .copied()
+                    .flatten()
+                    .filter(|v| *v > 0)
+                    .unwrap_or(1);
+                self.move_to(col - 1, n - 1);
+            }
+            b'G' => self.move_to(n - 1, self.row),
+            b'E' => self.move_to(0, self.row.saturating_add(n)),
+            b'F' => {
+                self.row = self.row.saturating_sub(n);
+                self.x = 0;
+            }
+            b'A' => self.row = self.row.saturating_sub(n),
+            b'B' => self.move_to(self.x, self.row.saturating_add(n)),
+            b'C' => self.move_to(self.x.saturating_add(n), self.row),
+            b'D' => self.x = self.x.saturating_sub(n),
+            b'b' => {
+                if let Some(glyph) = self.last_glyph {
+                    // repeats past the right edge would all be clipped
+                    for _ in 0..n.min(self.image.width() - self.x) {
+                        self.put(glyph);
+                    }
+                }
+            }
+            b'X' => self.erase(self.x, self.x.saturating_add(n)),
+            b'K' => match params.first().copied().flatten().unwrap_or(0) {
+                0 => self.erase(self.x, self.image.width()),
+                1 => self.erase(0, self.x.saturating_add(1)),
+                2 => self.erase(0, self.image.width()),
+                _ => return Err(self.unsupported(data, start, end)),
+            },
+            _ => return Err(self.unsupported(data, start, end)),
+        }
+
+        Ok(end + 1)
+    }
+
+    fn unsupported(&self, data: &[u8], start: usize, end: usize) -> DecodeError {
+        DecodeError::UnsupportedSequence(
+            start,
+            String::from_utf8_lossy(&data[start + 1..=end]).into_owned(),
+        )
+    }
+
+    fn sgr(&mut self, params: &[Option<u32>], pos: usize) -> Result<(), DecodeError> {
+        let mut i = 0;
+        while i < params.len() {
+            match params[i].unwrap_or(0) {
+                0 => {
+                    self.fg = self.image.default_pixel();
+                    self.bg = self.image.default_pixel();
+                }
+                39 => self.fg = self.image.default_pixel(),
+                49 => self.bg = self.image.default_pixel(),
+                code @ (38 | 48) => {
+                    let (pixel, used) = self.color(&params[i + 1..], pos)?;
+                    if code == 38 {
+                        self.fg = pixel;
+                    } else {
+                        self.bg = pixel;
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Parses the parameters after a 38/48, returning the color and the number of parameters it used.
+    fn color(&self, params: &[Option<u32>], pos: usize) -> Result<(Pixel, usize), DecodeError> {
+        let byte = |v: Option<&Option<u32>>| -> Result<u8, DecodeError> {
+            v.copied()
+                .flatten()
+                .and_then(|v| u8::try_from(v).ok())
+                .ok_or(DecodeError::MalformedColor(pos))
+        };
+
+        match params.first().copied().flatten() {
+            Some(5) => {
+                let idx = byte(params.get(1))?;
+                let pixel = match self.image {
+                    VideoImage::EightBit { .. } => Pixel::EightBit(idx),
+                    VideoImage::FullColor(_) => Pixel::Rgb(Rgb(PALETTE[idx as usize])),
+                };
+                Ok((pixel, 2))
+            }
+            Some(2) => {
+                if let VideoImage::EightBit { .. } = self.image {
+                    return Err(DecodeError::ColorModeMismatch(pos));
+                }
+
+                let rgb = [
+                    byte(params.get(1))?,
+                    byte(params.get(2))?,
+                    byte(params.get(3))?,
+                ];
+                Ok((Pixel::Rgb(Rgb(rgb)), 4))
+            }
+            _ => Err(DecodeError::MalformedColor(pos)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{encode_diff, encode_keyframe};
+    use image::RgbImage;
+    use proptest::prelude::*;
+
+    /// A frame and the changes after it: each a mask of the pixels that change, and what they change to.
+    type Frames = (
+        ColorMode,
+        u32,
+        Vec<[u8; 3]>,
+        Vec<usize>,
+        Vec<(Vec<bool>, Vec<usize>)>,
+    );
+
+    /// Sizes, color modes and palettes of a few random colors, so that runs, solid cells and every glyph show up.
+    fn frames(changes: usize) -> impl Strategy<Value = Frames> {
+        (
+            prop_oneof![Just(ColorMode::True), Just(ColorMode::EightBit)],
+            1..=80u32,
+            1..=24u32,
+            1..=6usize,
+        )
+            .prop_flat_map(move |(color, width, rows, colors)| {
+                let pixels = (width * rows * 2) as usize;
+                (
+                    Just(color),
+                    Just(width),
+                    prop::collection::vec(any::<[u8; 3]>(), colors),
+                    prop::collection::vec(0..colors, pixels),
+                    prop::collection::vec(
+                        (
+                            prop::collection::vec(prop::bool::weighted(0.2), pixels),
+                            prop::collection::vec(0..colors, pixels),
+                        ),
+                        changes,
+                    ),
+                )
+            })
+    }
+
+    /// Builds the frames [frames] describes, the first one and then one per change.
+    fn images((color, width, palette, first, changes): Frames) -> Vec<VideoImage> {
+        let height = first.len() as u32 / width;
+        let pixel = |idx: usize| {
+            let [r, g, b] = palette[idx];
+            match color {
+                ColorMode::True => Pixel::Rgb(Rgb([r, g, b])),
+                ColorMode::EightBit => Pixel::EightBit(r),
+            }
+        };
+
+        let mut image = VideoImage::new(width, height, color);
+        for (i, idx) in first.into_iter().enumerate() {
+            image.set_pixel(i as u32 % width, i as u32 / width, pixel(idx));
+        }
+
+        let mut images = vec![image];
+        for (mask, indices) in changes {
+            let mut image = images.last().unwrap().clone();
+            for (i, idx) in indices.into_iter().enumerate().filter(|(i, _)| mask[*i]) {
+                image.set_pixel(i as u32 % width, i as u32 / width, pixel(idx));
+            }
+            images.push(image);
+        }
+
+        images
+    }
+
+    proptest! {
+        #[test]
+        fn keyframes_round_trip(frames in frames(0)) {
+            let color = frames.0;
+            let image = &images(frames)[0];
+            let data = encode_keyframe(image);
+            prop_assert_eq!(
+                &decode_frame(&data, image.width(), image.height(), color).unwrap(),
+                image
+            );
+        }
+
+        #[test]
+        fn diffs_round_trip(frames in frames(3)) {
+            let images = images(frames);
+            let mut decoder = FrameDecoder::with_image(images[0].clone());
+            for pair in images.windows(2) {
+                let data = encode_diff(&pair[1], &pair[0]).unwrap();
+                prop_assert_eq!(decoder.decode(&data).unwrap(), &pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn huge_parameters_are_clipped() {
+        let mut decoder = FrameDecoder::with_image(VideoImage::FullColor(RgbImage::new(8, 4)));
+        for sequence in [
+            "\x1b[4294967295C ",
+            "\x1b[4294967295B ",
+            "\x1b[4294967295E ",
+            "\x1b[4294967295;4294967295H ",
+            "\x1b[4294967295G ",
+            "\x1b[4294967295C\x1b[1K",
+            " \x1b[4294967295b",
+            "\x1b[4294967295C\x1b[4294967295C\x1b[4294967295b\n\n\n\n\n",
+        ] {
+            decoder.decode(sequence.as_bytes()).unwrap();
+        }
+
+        // erasing up to the clipped cursor still covers the rest of the row
+        decoder
+            .decode(b"\x1b[48;2;255;0;0m\x1b[4294967295X")
+            .unwrap();
+        assert_eq!(
+            decoder.image().get_pixel(7, 0),
+            Pixel::Rgb(Rgb([255, 0, 0]))
+        );
+    }
+}
@@ -4,7 +4,10 @@ use bytes::{BufMut, BytesMut};
 use colorful::palette::*;
 use container::metadata::*;
 use image::{Rgb, RgbImage};
-use std::fmt::Write;
+
+pub mod decoder;
+pub mod quantize;
+pub mod writer;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Pixel {
@@ -23,6 +26,37 @@ pub enum VideoImage {
 }
 
 impl VideoImage {
+    /// creates a black image
+    pub fn new(width: u32, height: u32, color: ColorMode) -> VideoImage {
+        match color {
+            ColorMode::True => VideoImage::FullColor(RgbImage::new(width, height)),
+            ColorMode::EightBit => VideoImage::EightBit {
+                width,
+                height,
+                data: vec![0; (width * height) as usize],
+            },
+        }
+    }
+
+    /// black, in the image's color mode
+    pub fn default_pixel(&self) -> Pixel {
+        match self {
+            VideoImage::FullColor(_) => Pixel::Rgb(Rgb([0, 0, 0])),
+            VideoImage::EightBit { .. } => Pixel::EightBit(0),
+        }
+    }
+
+    /// panics if the pixel's color mode doesn't match the image's
+    pub fn set_pixel(&mut self, x: u32, y: u32, pixel: Pixel) {
+        match (self, pixel) {
+            (VideoImage::FullColor(i), Pixel::Rgb(p)) => i.put_pixel(x, y, p),
+            (VideoImage::EightBit { width, data, .. }, Pixel::EightBit(p)) => {
+                data[(y * *width + x) as usize] = p
+            }
+            _ => panic!(),
+        }
+    }
+
     /// panics if image is not full color
     pub fn as_full_color(&self) -> &RgbImage {
         match self {
@@ -99,89 +133,32 @@ pub trait AnsiEncoder {
     }
 
     fn encode_frame(&mut self, image: &VideoImage) -> BytesMut {
-        let mut last_upper: Option<Pixel> = None;
-        let mut last_lower: Option<Pixel> = None;
-
-        let mut frame = BytesMut::with_capacity((image.width() * image.height() * 20) as usize);
-        for y in (0..image.height() - 1).step_by(2) {
-            for x in 0..image.width() {
-                let upper = image.get_pixel(x, y);
-                let lower = image.get_pixel(x, y + 1);
-
-                if last_upper.is_none() || last_upper.unwrap() != upper {
-                    self.color(&upper, true, &mut frame);
-                }
-
-                if last_lower.is_none() || last_lower.unwrap() != lower {
-                    self.color(&lower, false, &mut frame);
-                }
-
-                frame.put_slice(b"\xE2\x96\x80");
-
-                last_upper = Some(upper);
-                last_lower = Some(lower);
-            }
-
-            frame.put_slice(b"\x1b[1E");
-        }
-
-        frame
+        writer::encode_keyframe(image)
     }
 
+    /// Encodes only what changed since `old_img`, falling back to a full frame if the two images aren't compatible.
     fn encode_diffed_frame(&self, image: &VideoImage, old_img: &VideoImage) -> BytesMut {
-        let mut last_upper: Option<Pixel> = None;
-        let mut last_lower: Option<Pixel> = None;
-
-        let mut last_x = 0;
-
-        let mut frame = BytesMut::with_capacity((image.width() * image.height()) as usize);
-        for y in (0..image.height() - 1).step_by(2) {
-            for x in 0..image.width() {
-                let upper = image.get_pixel(x, y);
-                let lower = image.get_pixel(x, y + 1);
-
-                if old_img.get_pixel(x, y) != upper || old_img.get_pixel(x, y + 1) != lower {
-                    if last_x != x + 1 {
-                        write!(frame, "\x1b[{}G", x + 1);
-                    }
-
-                    if last_upper.is_none() || last_upper.unwrap() != upper {
-                        self.color(&upper, true, &mut frame);
-                    }
-
-                    if last_lower.is_none() || last_lower.unwrap() != lower {
-                        self.color(&lower, false, &mut frame);
-                    }
-
-                    frame.put_slice(b"\xE2\x96\x80"); // "▀"
-
-                    last_upper = Some(upper);
-                    last_lower = Some(lower);
-
-                    last_x = x;
-                }
-            }
-
-            frame.put_slice(b"\x1b[1E");
-            last_x = 0;
-        }
-
-        frame
+        writer::encode_diff(image, old_img).unwrap_or_else(|| writer::encode_keyframe(image))
     }
 
     fn encode_best(&mut self, image: &VideoImage) -> (BytesMut, bool) {
         let use_diffing = self.use_diffing();
+        let force_keyframe = self.needs_keyframe(image);
 
         if use_diffing {
             if let Some(last_frame) = self.replace_last_frame(image.clone()) {
                 let non_diffed = self.encode_frame(image);
-                let diffed = self.encode_diffed_frame(image, &last_frame);
-
-                if diffed.len() > non_diffed.len() {
+                if force_keyframe {
                     return (non_diffed, true);
-                } else {
-                    return (diffed, false);
                 }
+
+                if let Some(diffed) = writer::encode_diff(image, &last_frame) {
+                    if diffed.len() < non_diffed.len() {
+                        return (diffed, false);
+                    }
+                }
+
+                return (non_diffed, true);
             }
         }
 
@@ -196,5 +173,10 @@ pub trait AnsiEncoder {
         false
     }
 
+    /// Whether the next frame has to be a keyframe regardless of size, e.g. to bound how far back a seek has to go.
+    fn needs_keyframe(&mut self, _image: &VideoImage) -> bool {
+        false
+    }
+
     fn replace_last_frame(&mut self, new_frame: VideoImage) -> Option<VideoImage>;
 }
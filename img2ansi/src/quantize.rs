@@ -0,0 +1,204 @@
+//! Lossy truecolor quantization: colors that are perceptually close to one that's already on screen are replaced by it,
+//! so the encoder can skip unchanged cells and doesn't need a new SGR sequence for every small RGB change.
+
+use image::{Rgb, RgbImage};
+use lab::Lab;
+
+/// How much a quantization pass changed an image.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuantizeStats {
+    pub pixels: u64,
+    pub reused: u64, // pixels replaced with an already shown color
+    pub delta_e_sum: f64,
+    pub delta_e_max: f32,
+}
+
+impl QuantizeStats {
+    pub fn mean_delta_e(&self) -> f64 {
+        if self.pixels == 0 {
+            0.0
+        } else {
+            self.delta_e_sum / self.pixels as f64
+        }
+    }
+
+    pub fn reused_fraction(&self) -> f64 {
+        if self.pixels == 0 {
+            0.0
+        } else {
+            self.reused as f64 / self.pixels as f64
+        }
+    }
+
+    pub fn merge(&mut self, other: &QuantizeStats) {
+        self.pixels += other.pixels;
+        self.reused += other.reused;
+        self.delta_e_sum += other.delta_e_sum;
+        self.delta_e_max = self.delta_e_max.max(other.delta_e_max);
+    }
+}
+
+#[inline(always)]
+fn delta_e(a: &Lab, b: &Lab) -> f32 {
+    a.squared_distance(b).sqrt()
+}
+
+/// Replaces every pixel whose CIE76 ΔE to a reusable color is below `threshold`. In order of preference, reusable colors are
+/// the same pixel in the previously shown frame (so the cell doesn't change), the pixel to the left in the same half of the row
+/// (so the current SGR color carries over) and, for the lower half of a cell, the upper half (so the cell becomes solid).
+///
+/// `previous` must be the *quantized* previous frame, so that errors can't build up over time.
+pub fn quantize_truecolor(
+    image: &mut RgbImage,
+    previous: Option<&RgbImage>,
+    threshold: f32,
+) -> QuantizeStats {
+    let mut stats = QuantizeStats::default();
+    let (width, height) = image.dimensions();
+    let previous = previous.filter(|v| v.dimensions() == (width, height));
+
+    let mut row_labs: Vec<Lab> = Vec::with_capacity(width as usize * 2);
+
+    for cell_y in (0..height.saturating_sub(1)).step_by(2) {
+        row_labs.clear();
+
+        for x in 0..width {
+            for y in [cell_y, cell_y + 1] {
+                let source = image.get_pixel(x, y).0;
+                let source_lab = Lab::from_rgb(&source);
+
+                let mut best: Option<([u8; 3], Lab, f32)> = None;
+                let mut consider = |candidate: [u8; 3], candidate_lab: Lab| {
+                    if best.is_some() {
+                        return;
+                    }
+
+                    let distance = delta_e(&source_lab, &candidate_lab);
+                    if distance < threshold {
+                        best = Some((candidate, candidate_lab, distance));
+                    }
+                };
+
+                if let Some(previous) = previous {
+                    let candidate = previous.get_pixel(x, y).0;
+                    consider(candidate, Lab::from_rgb(&candidate));
+                }
+
+                if x > 0 {
+                    let half = (y - cell_y) as usize;
+                    let left = row_labs[(x as usize - 1) * 2 + half];
+                    consider(image.get_pixel(x - 1, y).0, left);
+                }
+
+                if y == cell_y + 1 {
+                    let upper = *row_labs.last().unwrap();
+                    consider(image.get_pixel(x, cell_y).0, upper);
+                }
+
+                stats.pixels += 1;
+
+                match best {
+                    Some((color, lab, distance)) if color != source => {
+                        image.put_pixel(x, y, Rgb(color));
+                        row_labs.push(lab);
+
+                        stats.reused += 1;
+                        stats.delta_e_sum += distance as f64;
+                        stats.delta_e_max = stats.delta_e_max.max(distance);
+                    }
+                    _ => row_labs.push(source_lab),
+                }
+            }
+        }
+    }
+
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::encode_keyframe;
+    use crate::VideoImage;
+
+    /// A smooth gradient with a grain of up to 4 levels on every channel, like decoded video. Different `frame`s
+    /// shift the grain, like the noise from one frame of video to the next.
+    fn grainy_gradient(width: u32, height: u32, frame: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let grain = ((x * 3 + y * 7 + frame * 2) % 5) as u8;
+            Rgb([(x * 2) as u8 + grain, (y * 3) as u8 + grain, 120 + grain])
+        })
+    }
+
+    fn max_delta_e(a: &RgbImage, b: &RgbImage) -> f32 {
+        a.pixels()
+            .zip(b.pixels())
+            .map(|(a, b)| delta_e(&Lab::from_rgb(&a.0), &Lab::from_rgb(&b.0)))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn zero_threshold_is_lossless() {
+        let source = grainy_gradient(64, 32, 1);
+        let mut image = source.clone();
+        let stats = quantize_truecolor(&mut image, None, 0.0);
+        assert_eq!(image, source);
+        assert_eq!(stats.reused, 0);
+        assert_eq!(stats.pixels, 64 * 32);
+    }
+
+    #[test]
+    fn error_stays_below_threshold() {
+        let source = grainy_gradient(96, 48, 2);
+        let mut reused = 0.0;
+        let mut size = encode_keyframe(&VideoImage::FullColor(source.clone())).len();
+
+        for threshold in [0.5, 1.0, 2.0, 4.0, 8.0] {
+            let mut image = source.clone();
+            let stats = quantize_truecolor(&mut image, None, threshold);
+
+            assert!(stats.delta_e_max < threshold);
+            assert!(max_delta_e(&source, &image) < threshold);
+            assert!(stats.mean_delta_e() <= stats.delta_e_max as f64);
+
+            // higher thresholds reuse more colors and make for smaller frames
+            assert!(stats.reused_fraction() >= reused);
+            reused = stats.reused_fraction();
+            let quantized_size = encode_keyframe(&VideoImage::FullColor(image)).len();
+            assert!(quantized_size <= size);
+            size = quantized_size;
+        }
+
+        assert!(reused > 0.5);
+    }
+
+    #[test]
+    fn close_colors_merge_into_runs() {
+        // every pixel a little off from its left neighbour, but within the threshold
+        let mut image = RgbImage::from_fn(40, 2, |x, _| Rgb([100 + (x % 2) as u8, 50, 50]));
+        let stats = quantize_truecolor(&mut image, None, 2.0);
+
+        assert_eq!(stats.reused, 40); // the pixels that were off
+        assert!(image.pixels().all(|p| *p == Rgb([100, 50, 50])));
+    }
+
+    #[test]
+    fn distant_colors_stay() {
+        let source = RgbImage::from_fn(40, 2, |x, _| {
+            Rgb([if x % 2 == 0 { 0 } else { 255 }, 50, 50])
+        });
+        let mut image = source.clone();
+        quantize_truecolor(&mut image, None, 2.0);
+        assert_eq!(image, source);
+    }
+
+    #[test]
+    fn previous_frame_is_preferred() {
+        let previous = grainy_gradient(32, 16, 3);
+        let mut image = grainy_gradient(32, 16, 4);
+        quantize_truecolor(&mut image, Some(&previous), 5.0);
+
+        // the grain is well within the threshold, so nothing changes on screen
+        assert_eq!(image, previous);
+    }
+}
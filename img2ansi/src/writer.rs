@@ -0,0 +1,577 @@
+//! Cursor- and color-state aware writer for half-block cells, shared by keyframe and diff encoding.
+//!
+//! Every cell (two vertically stacked pixels) is written with whichever glyph needs the fewest color changes
+//! (`▀`, `▄`, `█` or a plain space), runs of identical cells are collapsed with REP (`CSI n b`) and solid runs
+//! at the end of a span are painted with ECH (`CSI n X`). EL isn't used, as it would paint past the right edge of the frame.
+
+use std::ops::Range;
+
+use bytes::{BufMut, BytesMut};
+use colorful::palette::Palette;
+
+use crate::{Pixel, VideoImage};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Cell {
+    pub upper: Pixel,
+    pub lower: Pixel,
+}
+
+impl Cell {
+    #[inline(always)]
+    pub fn is_solid(&self) -> bool {
+        self.upper == self.lower
+    }
+}
+
+/// Number of cell rows in an image (the last pixel row of an odd-height image is dropped).
+#[inline(always)]
+pub fn cell_rows(image: &VideoImage) -> u32 {
+    image.height() / 2
+}
+
+#[inline(always)]
+pub fn cell_at(image: &VideoImage, x: u32, row: u32) -> Cell {
+    Cell {
+        upper: image.get_pixel(x, row * 2),
+        lower: image.get_pixel(x, row * 2 + 1),
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Glyph {
+    Upper,
+    Lower,
+    Full,
+    Space,
+}
+
+impl Glyph {
+    #[inline(always)]
+    fn bytes(&self) -> &'static [u8] {
+        match self {
+            Glyph::Upper => "▀".as_bytes(),
+            Glyph::Lower => "▄".as_bytes(),
+            Glyph::Full => "█".as_bytes(),
+            Glyph::Space => b" ",
+        }
+    }
+}
+
+#[inline(always)]
+fn digits(n: u32) -> usize {
+    match n {
+        0..=9 => 1,
+        10..=99 => 2,
+        100..=999 => 3,
+        1000..=9999 => 4,
+        _ => n.to_string().len(),
+    }
+}
+
+/// Length of a CSI sequence with a single numeric parameter; a parameter of 1 is left out, as it's the default.
+#[inline(always)]
+fn csi_len(n: u32) -> usize {
+    if n == 1 {
+        3
+    } else {
+        3 + digits(n)
+    }
+}
+
+#[inline(always)]
+fn write_csi(out: &mut BytesMut, n: u32, terminator: u8) {
+    out.put_slice(b"\x1b[");
+    if n != 1 {
+        out.put_slice(itoa::Buffer::new().format(n).as_bytes());
+    }
+    out.put_u8(terminator);
+}
+
+#[inline(always)]
+fn color_params_len(pixel: &Pixel) -> usize {
+    match pixel {
+        Pixel::EightBit(b) => 5 + digits(*b as u32),
+        Pixel::Rgb(c) => 7 + digits(c[0] as u32) + digits(c[1] as u32) + digits(c[2] as u32),
+    }
+}
+
+#[inline(always)]
+fn write_color_params(out: &mut BytesMut, pixel: &Pixel, fg: bool) {
+    let mut buffer = itoa::Buffer::new();
+    match pixel {
+        Pixel::EightBit(b) => {
+            out.put_slice(if fg { b"38;5;" } else { b"48;5;" });
+            out.put_slice(buffer.format(*b).as_bytes());
+        }
+        Pixel::Rgb(c) => {
+            out.put_slice(if fg { b"38;2;" } else { b"48;2;" });
+            out.put_slice(buffer.format(c[0]).as_bytes());
+            out.put_u8(b';');
+            out.put_slice(buffer.format(c[1]).as_bytes());
+            out.put_u8(b';');
+            out.put_slice(buffer.format(c[2]).as_bytes());
+        }
+    }
+}
+
+/// Length of a (merged) SGR sequence setting the given colors.
+#[inline(always)]
+fn sgr_len(fg: Option<&Pixel>, bg: Option<&Pixel>) -> usize {
+    match (fg, bg) {
+        (None, None) => 0,
+        (Some(c), None) | (None, Some(c)) => 3 + color_params_len(c),
+        (Some(f), Some(b)) => 4 + color_params_len(f) + color_params_len(b),
+    }
+}
+
+/// Writes a single SGR sequence setting the given foreground and/or background colors.
+#[inline(always)]
+pub fn write_sgr(out: &mut BytesMut, fg: Option<&Pixel>, bg: Option<&Pixel>) {
+    if fg.is_none() && bg.is_none() {
+        return;
+    }
+
+    out.put_slice(b"\x1b[");
+    if let Some(fg) = fg {
+        write_color_params(out, fg, true);
+    }
+    if let Some(bg) = bg {
+        if fg.is_some() {
+            out.put_u8(b';');
+        }
+        write_color_params(out, bg, false);
+    }
+    out.put_u8(b'm');
+}
+
+/// Tracks the terminal state (colors and cursor column) while writing a frame.
+///
+/// The frame is assumed to start at column 1 of its first row with unknown colors, which is what the player sets up before writing a packet.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CellWriter {
+    fg: Option<Pixel>,
+    bg: Option<Pixel>,
+    col: u32,
+    pending: Option<(Cell, u32)>,
+}
+
+impl CellWriter {
+    pub fn new() -> CellWriter {
+        CellWriter::default()
+    }
+
+    /// Queues a cell at the cursor position; runs of identical cells are written out once they end.
+    #[inline(always)]
+    pub fn push(&mut self, cell: Cell, out: &mut BytesMut) {
+        match self.pending {
+            Some((pending, ref mut count)) if pending == cell => *count += 1,
+            _ => {
+                self.flush(false, out);
+                self.pending = Some((cell, 1));
+            }
+        }
+    }
+
+    /// Writes out the pending run. Solid runs may be painted with ECH, which leaves the cursor in place,
+    /// so this must be followed by an absolute cursor movement.
+    pub fn close(&mut self, out: &mut BytesMut) {
+        self.flush(true, out);
+    }
+
+    /// Moves the cursor forward to the given column on the current row, picking the shorter of CUF and CHA.
+    pub fn move_to_col(&mut self, col: u32, out: &mut BytesMut) {
+        self.close(out);
+        if col == self.col {
+            return;
+        }
+
+        if col > self.col && csi_len(col - self.col) <= csi_len(col + 1) {
+            write_csi(out, col - self.col, b'C');
+        } else {
+            write_csi(out, col + 1, b'G');
+        }
+
+        self.col = col;
+    }
+
+    /// Moves the cursor down by the given number of rows, to the first column (CNL).
+    pub fn next_line(&mut self, rows: u32, out: &mut BytesMut) {
+        self.close(out);
+        if rows > 0 {
+            write_csi(out, rows, b'E');
+            self.col = 0;
+        }
+    }
+
+    /// Picks the glyph that needs the fewest color changes for a cell, alongside the colors that need changing.
+    #[inline(always)]
+    fn plan(&self, cell: &Cell) -> (Glyph, Option<Pixel>, Option<Pixel>) {
+        if cell.is_solid() {
+            if self.bg == Some(cell.upper) {
+                (Glyph::Space, None, None)
+            } else if self.fg == Some(cell.upper) {
+                (Glyph::Full, None, None)
+            } else {
+                (Glyph::Space, None, Some(cell.upper))
+            }
+        } else {
+            let fg_for = |c: Pixel| (self.fg != Some(c)).then_some(c);
+            let bg_for = |c: Pixel| (self.bg != Some(c)).then_some(c);
+
+            let upper = (fg_for(cell.upper), bg_for(cell.lower));
+            let lower = (fg_for(cell.lower), bg_for(cell.upper));
+
+            let upper_cost = sgr_len(upper.0.as_ref(), upper.1.as_ref());
+            let lower_cost = sgr_len(lower.0.as_ref(), lower.1.as_ref());
+
+            if lower_cost < upper_cost {
+                (Glyph::Lower, lower.0, lower.1)
+            } else {
+                (Glyph::Upper, upper.0, upper.1)
+            }
+        }
+    }
+
+    fn flush(&mut self, allow_erase: bool, out: &mut BytesMut) {
+        let (cell, count) = match self.pending.take() {
+            Some(v) => v,
+            None => return,
+        };
+
+        let (glyph, fg, bg) = self.plan(&cell);
+        let glyph_len = glyph.bytes().len();
+        let repeat_len = if count > 1 {
+            (glyph_len * (count as usize - 1)).min(csi_len(count - 1))
+        } else {
+            0
+        };
+        let draw_len = sgr_len(fg.as_ref(), bg.as_ref()) + glyph_len + repeat_len;
+
+        if allow_erase && cell.is_solid() {
+            let erase_bg = (self.bg != Some(cell.upper)).then_some(cell.upper);
+            let erase_len = sgr_len(None, erase_bg.as_ref()) + csi_len(count);
+
+            if erase_len < draw_len {
+                write_sgr(out, None, erase_bg.as_ref());
+                write_csi(out, count, b'X');
+                self.bg = Some(cell.upper);
+                return;
+            }
+        }
+
+        write_sgr(out, fg.as_ref(), bg.as_ref());
+        if let Some(fg) = fg {
+            self.fg = Some(fg);
+        }
+        if let Some(bg) = bg {
+            self.bg = Some(bg);
+        }
+
+        out.put_slice(glyph.bytes());
+        if count > 1 {
+            if csi_len(count - 1) < glyph_len * (count as usize - 1) {
+                write_csi(out, count - 1, b'b');
+            } else {
+                for _ in 1..count {
+                    out.put_slice(glyph.bytes());
+                }
+            }
+        }
+
+        self.col += count;
+    }
+}
+
+#[inline(always)]
+fn comparable(image: &VideoImage, old_img: &VideoImage) -> bool {
+    image.width() == old_img.width()
+        && image.height() == old_img.height()
+        && std::mem::discriminant(image) == std::mem::discriminant(old_img)
+}
+
+/// Fraction of cells (0.0 - 1.0) that differ between two images, or [None] if their dimensions or color modes don't match.
+pub fn changed_fraction(image: &VideoImage, old_img: &VideoImage) -> Option<f32> {
+    if !comparable(image, old_img) {
+        return None;
+    }
+
+    let rows = cell_rows(image);
+    let total = image.width() * rows;
+    if total == 0 {
+        return Some(0.0);
+    }
+
+    let mut changed = 0u32;
+    for row in 0..rows {
+        for x in 0..image.width() {
+            if cell_at(image, x, row) != cell_at(old_img, x, row) {
+                changed += 1;
+            }
+        }
+    }
+
+    Some(changed as f32 / total as f32)
+}
+
+/// Encodes a full frame, row by row.
+pub fn encode_keyframe(image: &VideoImage) -> BytesMut {
+    let mut frame = BytesMut::with_capacity((image.width() * image.height() * 10) as usize);
+    let mut writer = CellWriter::new();
+
+    for row in 0..cell_rows(image) {
+        for x in 0..image.width() {
+            writer.push(cell_at(image, x, row), &mut frame);
+        }
+
+        writer.next_line(1, &mut frame);
+    }
+
+    frame
+}
+
+/// Encodes only the cells that changed since `old_img`, skipping unchanged rows and spans with cursor movements.
+/// Each changed row is written either as a full repaint or as a set of spans, whichever is shorter.
+///
+/// The cursor ends up in the same place as after a keyframe, at the start of the row below the image.
+/// Returns [None] if the images' dimensions or color modes don't match.
+pub fn encode_diff(image: &VideoImage, old_img: &VideoImage) -> Option<BytesMut> {
+    if !comparable(image, old_img) {
+        return None;
+    }
+
+    let width = image.width();
+    let rows = cell_rows(image);
+
+    let mut frame = BytesMut::with_capacity((width * rows * 4) as usize);
+    let mut writer = CellWriter::new();
+    let mut current_row = 0;
+
+    let mut cells: Vec<Cell> = Vec::with_capacity(width as usize);
+    let mut changed: Vec<bool> = Vec::with_capacity(width as usize);
+
+    let mut full_row = BytesMut::new();
+    let mut spans = BytesMut::new();
+    let mut scratch = BytesMut::new();
+
+    for row in 0..rows {
+        cells.clear();
+        changed.clear();
+
+        for x in 0..width {
+            let cell = cell_at(image, x, row);
+            changed.push(cell != cell_at(old_img, x, row));
+            cells.push(cell);
+        }
+
+        if !changed.contains(&true) {
+            continue;
+        }
+
+        writer.next_line(row - current_row, &mut frame);
+        current_row = row;
+
+        // option one: repaint the whole row
+        full_row.clear();
+        let mut full_writer = writer;
+        for cell in cells.iter() {
+            full_writer.push(*cell, &mut full_row);
+        }
+        full_writer.close(&mut full_row);
+
+        // option two: paint changed spans only, repainting unchanged gaps when that's cheaper than skipping them
+        spans.clear();
+        let mut span_writer = writer;
+        let mut x = 0usize;
+        let mut in_span = false;
+        while x < cells.len() {
+            if changed[x] {
+                if !in_span {
+                    span_writer.move_to_col(x as u32, &mut spans);
+                    in_span = true;
+                }
+                span_writer.push(cells[x], &mut spans);
+                x += 1;
+                continue;
+            }
+
+            let gap_end = changed[x..]
+                .iter()
+                .position(|v| *v)
+                .map(|v| v + x)
+                .unwrap_or(cells.len());
+
+            if in_span && gap_end < cells.len() {
+                scratch.clear();
+                let mut repaint = span_writer;
+                for cell in &cells[x..gap_end] {
+                    repaint.push(*cell, &mut scratch);
+                }
+                repaint.push(cells[gap_end], &mut scratch);
+                repaint.close(&mut scratch);
+                let repaint_len = scratch.len();
+
+                scratch.clear();
+                let mut skip = span_writer;
+                skip.move_to_col(gap_end as u32, &mut scratch);
+                skip.push(cells[gap_end], &mut scratch);
+                skip.close(&mut scratch);
+
+                if repaint_len <= scratch.len() {
+                    for cell in &cells[x..gap_end] {
+                        span_writer.push(*cell, &mut spans);
+                    }
+                } else {
+                    in_span = false;
+                }
+            }
+
+            x = gap_end;
+        }
+        span_writer.close(&mut spans);
+
+        if full_row.len() <= spans.len() {
+            frame.extend_from_slice(&full_row);
+            writer = full_writer;
+        } else {
+            frame.extend_from_slice(&spans);
+            writer = span_writer;
+        }
+    }
+
+    writer.next_line(rows - current_row, &mut frame);
+
+    Some(frame)
+}
+
+/// Encodes OSC 4 sequences that redefine the terminal's palette entries in `indices` to the colors of `palette`.
+/// Each entry gets its own sequence, as not every terminal accepts several in one.
+pub fn encode_palette(palette: &Palette, indices: Range<usize>) -> BytesMut {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let mut out = BytesMut::with_capacity(indices.len() * 24);
+    for idx in indices {
+        out.put_slice(b"\x1b]4;");
+        out.put_slice(itoa::Buffer::new().format(idx).as_bytes());
+        out.put_slice(b";rgb:");
+        for (i, c) in palette.get(idx).iter().enumerate() {
+            if i > 0 {
+                out.put_u8(b'/');
+            }
+            out.put_u8(HEX[(c >> 4) as usize]);
+            out.put_u8(HEX[(c & 0xf) as usize]);
+        }
+        out.put_slice(b"\x1b\\");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use container::metadata::ColorMode;
+    use image::{Rgb, RgbImage};
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    /// An image where no two neighbouring cells are the same, so nothing collapses into runs.
+    fn no_runs(width: u32, height: u32) -> VideoImage {
+        VideoImage::FullColor(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 7) as u8, (y * 11) as u8, ((x + y) * 3) as u8])
+        }))
+    }
+
+    #[test]
+    fn diff_is_no_larger_than_keyframe() {
+        let old = no_runs(64, 32);
+        let mut new = old.clone();
+        for x in 10..20 {
+            new.set_pixel(x, 7, Pixel::Rgb(Rgb([255, 0, 0])));
+        }
+
+        let keyframe = encode_keyframe(&new);
+        let diff = encode_diff(&new, &old).unwrap();
+        assert!(diff.len() <= keyframe.len());
+        assert!(diff.len() < keyframe.len() / 10);
+
+        // even when everything changed, a diff never costs more than repainting every row
+        let all_changed = no_runs(64, 32);
+        let diff = encode_diff(&all_changed, &VideoImage::new(64, 32, ColorMode::True)).unwrap();
+        assert!(diff.len() <= encode_keyframe(&all_changed).len());
+    }
+
+    #[test]
+    fn identical_frames_diff_to_cursor_movement() {
+        let image = no_runs(32, 16);
+        assert_eq!(&encode_diff(&image, &image).unwrap()[..], b"\x1b[8E");
+    }
+
+    #[test]
+    fn mismatched_frames_dont_diff() {
+        assert!(encode_diff(&no_runs(32, 16), &no_runs(32, 18)).is_none());
+        assert!(encode_diff(
+            &no_runs(32, 16),
+            &VideoImage::new(32, 16, ColorMode::EightBit)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn runs_use_rep() {
+        // cells that aren't solid can't be erased, so runs of them are repeated
+        let image = VideoImage::FullColor(RgbImage::from_fn(20, 2, |_, y| {
+            if y == 0 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        }));
+
+        let frame = encode_keyframe(&image);
+        assert!(contains(&frame, "▀\x1b[19b".as_bytes()));
+        assert_eq!(frame.iter().filter(|b| **b == b'm').count(), 1);
+    }
+
+    #[test]
+    fn solid_runs_use_ech() {
+        let image = VideoImage::FullColor(RgbImage::from_pixel(20, 2, Rgb([0, 128, 0])));
+
+        let frame = encode_keyframe(&image);
+        assert_eq!(&frame[..], b"\x1b[48;2;0;128;0m\x1b[20X\x1b[E");
+    }
+
+    #[test]
+    fn short_runs_are_written_out() {
+        // repeating one cell takes as many bytes as drawing it again
+        let image = VideoImage::FullColor(RgbImage::from_fn(2, 2, |_, y| {
+            if y == 0 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        }));
+
+        let frame = encode_keyframe(&image);
+        assert!(contains(&frame, "▀▀".as_bytes()));
+        assert!(!contains(&frame, b"b"));
+    }
+
+    #[test]
+    fn palette_entries_get_one_osc_4_each() {
+        let palette =
+            Palette::default().with_overrides([(16, [0x12, 0xab, 0x00]), (17, [255, 0, 15])]);
+
+        assert_eq!(
+            &encode_palette(&palette, 16..18)[..],
+            b"\x1b]4;16;rgb:12/ab/00\x1b\\\x1b]4;17;rgb:ff/00/0f\x1b\\"
+        );
+        assert_eq!(
+            &encode_palette(&palette, 255..256)[..],
+            b"\x1b]4;255;rgb:ee/ee/ee\x1b\\"
+        );
+        assert!(encode_palette(&palette, 16..16).is_empty());
+    }
+}
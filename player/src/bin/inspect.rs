@@ -51,6 +51,14 @@ async fn main() -> std::io::Result<()> {
                 v.color_mode
             );
 
+            if let Some(keyframes) = v.keyframes {
+                println!("keyframes: {}", keyframes);
+            }
+
+            if v.adaptive_palette {
+                println!("palette: adaptive (redefined with OSC 4)");
+            }
+
             if matches.is_present("SHOW_CODEC_PRIVATE") {
                 if let Some(data) = v.codec_private {
                     println!("codec private:\n{}", String::from_utf8_lossy(&data));
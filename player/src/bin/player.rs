@@ -7,7 +7,10 @@ use container::{
 };
 
 use futures::StreamExt;
-use player::{play, subtitles::SSAParser, PacketDecompressor, PacketFilterTransformer};
+use player::{
+    play, subtitles::SSAParser, terminal::SyncMode, PacketDecompressor, PacketFilterTransformer,
+    PlayOptions,
+};
 
 use tokio::{fs::File, io::AsyncReadExt};
 use tokio_stream::wrappers::ReceiverStream;
@@ -23,6 +26,7 @@ async fn main() -> std::io::Result<()> {
         (@arg INPUT: +required "input file or url for the encoder")
         (@arg SHOW_SSA_NAMES: --subtitle_names "show subtitle entry character names in ssa subtitles (not always used)")
         (@arg SHOW_SSA_LAYERS: --ssa_layer ... +takes_value "ssa layers to show (all if not passed)")
+        (@arg SYNC: --sync +takes_value "wrap frames in synchronized updates to avoid tearing: auto (ask the terminal), on or off [default: auto]")
     )
     .get_matches();
 
@@ -36,6 +40,11 @@ async fn main() -> std::io::Result<()> {
         })
         .unwrap_or_default();
     let show_ssa_names = matches.is_present("SHOW_SSA_NAMES");
+    let sync_mode = matches
+        .value_of("SYNC")
+        .unwrap_or("auto")
+        .parse::<SyncMode>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
     let meta_len = input.read_u64_le().await?;
     let mut metadata = vec![0u8; meta_len as usize];
@@ -137,20 +146,37 @@ async fn main() -> std::io::Result<()> {
     let (video_tx, video_rx) = tokio::sync::mpsc::channel::<Packet<Bytes>>(255);
     let (subtitle_tx, subtitle_rx) = tokio::sync::mpsc::channel::<Packet<Bytes>>(8000);
 
-    tokio::task::spawn(play(
-        ReceiverStream::new(video_rx),
-        ReceiverStream::new(subtitle_rx)
-            .filter_map(move |f| futures::future::ready(subtitle_mapper.filter_map_packet(f))),
-    ));
-
-    while let Some(packet_res) = framed_read.next().await {
-        let packet = packet_res.unwrap();
-        match packet.stream_index {
-            y if y == video_track_idx => video_tx.send(packet).await,
-            x if has_subtitles && x == subtitle_track_idx => subtitle_tx.send(packet).await,
-            _ => continue,
-        };
-    }
-
-    Ok(())
+    // ask the terminal only once the prompts are done, so its reply can't get mixed up with their input
+    let play_options = PlayOptions {
+        synchronized_update: sync_mode.enabled(),
+    };
+
+    // read on this task, as the packet mapper isn't Send
+    let read = async move {
+        while let Some(packet_res) = framed_read.next().await {
+            let packet = packet_res.unwrap();
+            let sent = match packet.stream_index {
+                y if y == video_track_idx => video_tx.send(packet).await,
+                x if has_subtitles && x == subtitle_track_idx => subtitle_tx.send(packet).await,
+                _ => continue,
+            };
+
+            // playback stopped early, like on ctrl-c
+            if sent.is_err() {
+                break;
+            }
+        }
+    };
+
+    // playback decides when we're done, so the tail of the file gets shown and the terminal restored
+    let (played, ()) = futures::join!(
+        play(
+            ReceiverStream::new(video_rx),
+            ReceiverStream::new(subtitle_rx)
+                .filter_map(move |f| futures::future::ready(subtitle_mapper.filter_map_packet(f))),
+            play_options,
+        ),
+        read
+    );
+    played
 }
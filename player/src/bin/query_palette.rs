@@ -0,0 +1,40 @@
+use clap::clap_app;
+use player::terminal;
+
+use std::time::Duration;
+
+fn main() -> std::io::Result<()> {
+    let matches = clap_app!(query_palette =>
+        (version: "1.0")
+        (author: "emily signet <emily@cat-girl.gay>")
+        (about: "asks the terminal for its colors and writes them out as a theme the encoder's --palette option understands")
+        (@arg OUTPUT: -o --output +takes_value "output file (stdout if not passed)")
+        (@arg TIMEOUT: --timeout +takes_value "milliseconds to wait for the terminal to answer (default 1000)")
+    )
+    .get_matches();
+
+    let timeout = matches
+        .value_of("TIMEOUT")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(1000);
+
+    let colors = terminal::query_colors(Duration::from_millis(timeout)).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the terminal didn't report its colors",
+        )
+    })?;
+
+    let missing = colors.colors.iter().filter(|v| v.is_none()).count();
+    if missing > 0 {
+        eprintln!("the terminal didn't report {missing} of the 16 base colors; they'll keep their xterm defaults");
+    }
+
+    match matches.value_of("OUTPUT") {
+        Some(path) => std::fs::write(path, colors.to_theme()),
+        None => {
+            print!("{}", colors.to_theme());
+            Ok(())
+        }
+    }
+}
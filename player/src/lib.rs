@@ -9,6 +9,7 @@ pub mod playing;
 pub use playing::*;
 
 pub mod subtitles;
+pub mod terminal;
 
 #[cfg(feature = "compression")]
 pub struct PacketDecompressor {
@@ -1,33 +1,107 @@
 use bytes::Bytes;
 
-use container::packet::Packet;
+use container::{packet::Packet, VideoPacketFlags, VIDEO_PACKET_KEY};
 
+use enumflags2::BitFlags;
 use futures::{Stream, StreamExt};
+use std::io::Write;
 use std::time::Duration;
 use tokio::{io::AsyncWriteExt, pin, time::Instant};
 
 use postage::{sink::Sink, stream::Stream as PostageStream, watch};
+use tokio::sync::mpsc::error::TrySendError;
 
+use crate::terminal::{BEGIN_SYNCHRONIZED_UPDATE, END_SYNCHRONIZED_UPDATE, RESET_PALETTE};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlayOptions {
+    /// wrap every frame in a synchronized update, so terminals that support it never show half drawn frames
+    pub synchronized_update: bool,
+}
+
+/// Frames that can wait to be drawn before the player counts as fallen behind.
+const VIDEO_QUEUE: usize = 8;
+
+fn video_flags(packet: &Packet<Bytes>) -> BitFlags<VideoPacketFlags> {
+    packet
+        .extra_data
+        .get(VIDEO_PACKET_KEY)
+        .map(BitFlags::<VideoPacketFlags>::from_bits_truncate)
+        .unwrap_or_default()
+}
+
+/// Puts the terminal back the way it was once playback ends, whichever way it ends, including a failed write.
+#[derive(Default)]
+struct RestoreTerminal {
+    palette_changed: bool, // whether the video redefined palette colors, which then need resetting
+}
+
+impl Drop for RestoreTerminal {
+    fn drop(&mut self) {
+        // a synchronous write, as there's no awaiting in drop; playback's own writes are flushed or abandoned by now
+        let mut stdout = std::io::stdout();
+        if self.palette_changed {
+            let _ = stdout.write_all(RESET_PALETTE);
+        }
+
+        let _ = stdout.write_all(b"\x1b[0m\x1b[?25h");
+        let _ = stdout.flush();
+    }
+}
+
+/// Plays video and subtitle packets on stdout until both streams end or ctrl-c is pressed,
+/// then restores the terminal, including any palette colors the video redefined, also when it fails part way.
 pub async fn play(
     mut video: impl Stream<Item = Packet<Bytes>> + Unpin + Send + 'static,
     mut subs: impl Stream<Item = Packet<Bytes>> + Unpin + Send + 'static,
+    options: PlayOptions,
 ) -> std::io::Result<()> {
     let (mut subtitle_tx, mut subtitle_rx) = watch::channel_with_option::<Packet<Bytes>>();
-    let (mut video_tx, mut video_rx) = watch::channel_with_option::<Packet<Bytes>>();
+    // frames are mostly diffs against the one before, so none can be dropped without corrupting the screen.
+    // palette changes go through the same queue, so they land right before the keyframe they're for
+    let (video_tx, mut video_rx) = tokio::sync::mpsc::channel::<Packet<Bytes>>(VIDEO_QUEUE);
 
     let start = Instant::now();
-    tokio::task::spawn(async move {
+    let video_task = tokio::task::spawn(async move {
         let timer = tokio::time::sleep(Duration::from_millis(0));
         pin!(timer);
 
+        // set when drawing fell behind and frames are being dropped, until a keyframe redraws the whole screen
+        let mut skipping = false;
+
         while let Some(packet) = video.next().await {
             timer.as_mut().reset(start + packet.presentation_time);
             timer.as_mut().await;
-            video_tx.send(Some(packet)).await;
+
+            let flags = video_flags(&packet);
+            if flags.contains(VideoPacketFlags::Palette) {
+                if video_tx.send(packet).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+
+            if flags.contains(VideoPacketFlags::Keyframe) {
+                skipping = false;
+                if video_tx.send(packet).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+
+            if skipping {
+                continue;
+            }
+
+            match video_tx.try_send(packet) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => skipping = true,
+                Err(TrySendError::Closed(_)) => break,
+            }
         }
     });
 
-    tokio::task::spawn(async move {
+    let subtitle_task = tokio::task::spawn(async move {
         let timer = tokio::time::sleep(Duration::from_millis(0));
         pin!(timer);
 
@@ -44,19 +118,50 @@ pub async fn play(
         }
     });
 
+    let ctrl_c = tokio::signal::ctrl_c();
+    let finished = futures::future::join(video_task, subtitle_task);
+    pin!(ctrl_c, finished);
+
     let mut stdout = tokio::io::stdout();
+    let mut restore = RestoreTerminal::default();
+    // palette changes wait for the frame after them, so the screen never shows the last frame in the new colors
+    let mut palette: Option<Bytes> = None;
     stdout.write_all(b"\x1b[1;1H\x1b[?25l").await?;
     loop {
         tokio::select! {
             biased;
 
-            Some(Some(v)) = video_rx.recv() => {
+            _ = &mut ctrl_c => break,
+            Some(v) = video_rx.recv() => {
+                if video_flags(&v).contains(VideoPacketFlags::Palette) {
+                    palette = Some(v.data);
+                    continue;
+                }
+
+                if options.synchronized_update {
+                    stdout.write_all(BEGIN_SYNCHRONIZED_UPDATE).await?;
+                }
+
+                if let Some(palette) = palette.take() {
+                    stdout.write_all(&palette).await?;
+                    restore.palette_changed = true;
+                }
+
                 stdout.write_all(b"\x1b[0m\x1b[1;1H").await?;
                 stdout.write_all(&v.data).await?;
                 stdout.write_all(b"\x1b[0m\n").await?;
+
+                if options.synchronized_update {
+                    stdout.write_all(END_SYNCHRONIZED_UPDATE).await?;
+                }
+
                 stdout.flush().await?;
             }
             Some(v) = subtitle_rx.recv() => {
+                if options.synchronized_update {
+                    stdout.write_all(BEGIN_SYNCHRONIZED_UPDATE).await?;
+                }
+
                 stdout.write_all(b"\x1b[s\x1b[0m\x1b[0J ").await?;
 
                 if let Some(sub) = v {
@@ -65,11 +170,19 @@ pub async fn play(
 
                 stdout.write_all(b"\x1b[u").await?;
 
+                if options.synchronized_update {
+                    stdout.write_all(END_SYNCHRONIZED_UPDATE).await?;
+                }
+
                 stdout.flush().await?;
             }
-            // _ =>
+            // only once the last frame and subtitle have been shown, as the branches above come first
+            _ = &mut finished => break,
         }
     }
 
+    stdout.flush().await?;
+    drop(restore);
+
     Ok(())
 }
@@ -0,0 +1,401 @@
+//! Terminal capability detection and queries.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use colorful::theme::parse_color;
+
+/// Begin/end synchronized update (DEC private mode 2026). Terminals that support it hold off on rendering until the end sequence,
+/// so frames never show up half drawn; terminals that don't simply ignore the sequences.
+pub const BEGIN_SYNCHRONIZED_UPDATE: &[u8] = b"\x1b[?2026h";
+pub const END_SYNCHRONIZED_UPDATE: &[u8] = b"\x1b[?2026l";
+
+/// Resets every palette color redefined with OSC 4 to the terminal's configured value (OSC 104).
+pub const RESET_PALETTE: &[u8] = b"\x1b]104\x1b\\";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// ask the terminal, and only synchronize if it reports support
+    Auto,
+    Always,
+    Never,
+}
+
+impl SyncMode {
+    /// Resolves the mode into whether frames should be wrapped in synchronized updates, querying the terminal if needed.
+    pub fn enabled(self) -> bool {
+        match self {
+            SyncMode::Auto => supports_synchronized_update(Duration::from_millis(500)),
+            SyncMode::Always => true,
+            SyncMode::Never => false,
+        }
+    }
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SyncMode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(SyncMode::Auto),
+            "on" | "always" | "yes" => Ok(SyncMode::Always),
+            "off" | "never" | "no" => Ok(SyncMode::Never),
+            _ => Err(format!(
+                "unknown sync mode {s:?} (expected auto, on or off)"
+            )),
+        }
+    }
+}
+
+/// Checks whether the terminal supports synchronized updates, using a DECRQM query for mode 2026.
+/// Returns false if stdout isn't a terminal, or if nothing answers in time.
+pub fn supports_synchronized_update(timeout: Duration) -> bool {
+    if !stdout_is_terminal() {
+        return false;
+    }
+
+    query_terminal(b"\x1b[?2026$p", timeout, |response| {
+        decrqm_state(response, b"2026").is_some()
+    })
+    .and_then(|response| decrqm_state(&response, b"2026"))
+    // 1 = set, 2 = reset, 3 = permanently set; 0 and 4 mean unsupported
+    .map(|state| matches!(state, 1..=3))
+    .unwrap_or(false)
+}
+
+/// The colors a terminal reported for the 16 base palette entries and its default foreground and background.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TerminalColors {
+    pub colors: [Option<[u8; 3]>; 16],
+    pub foreground: Option<[u8; 3]>,
+    pub background: Option<[u8; 3]>,
+}
+
+impl TerminalColors {
+    pub fn is_empty(&self) -> bool {
+        self.colors.iter().all(Option::is_none)
+            && self.foreground.is_none()
+            && self.background.is_none()
+    }
+
+    /// Describes the colors in kitty's theme format, which the encoder's `--palette` option can load.
+    pub fn to_theme(&self) -> String {
+        let mut out = String::new();
+        let mut line = |name: &str, color: &Option<[u8; 3]>| {
+            if let Some([r, g, b]) = color {
+                out += &format!("{name} #{r:02x}{g:02x}{b:02x}\n");
+            }
+        };
+
+        line("foreground", &self.foreground);
+        line("background", &self.background);
+        for (i, color) in self.colors.iter().enumerate() {
+            line(&format!("color{i}"), color);
+        }
+
+        out
+    }
+}
+
+/// Asks the terminal for its base 16 colors and default foreground/background, using OSC 4, 10 and 11.
+/// Returns None if there's no controlling terminal or if it didn't answer any of the queries in time.
+pub fn query_colors(timeout: Duration) -> Option<TerminalColors> {
+    let mut request = Vec::with_capacity(256);
+    for i in 0..16 {
+        request.extend_from_slice(format!("\x1b]4;{i};?\x1b\\").as_bytes());
+    }
+    request.extend_from_slice(b"\x1b]10;?\x1b\\\x1b]11;?\x1b\\");
+
+    // terminals that don't support a query skip it, so any reply at all is worth going through
+    let response = query_terminal(&request, timeout, |response| {
+        osc_replies(response).next().is_some()
+    })?;
+
+    parse_colors(&response)
+}
+
+/// Collects the OSC 4, 10 and 11 color replies in a buffer, returning None if there weren't any usable ones.
+fn parse_colors(response: &[u8]) -> Option<TerminalColors> {
+    let mut colors = TerminalColors::default();
+    for (params, spec) in osc_replies(response) {
+        // X11 `rgb:r/g/b` specs, which the theme parser takes too
+        let color = match parse_color(spec) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        match params.split_once(';') {
+            Some(("4", idx)) => {
+                if let Some(slot) = idx
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| colors.colors.get_mut(i))
+                {
+                    *slot = Some(color);
+                }
+            }
+            _ if params == "10" => colors.foreground = Some(color),
+            _ if params == "11" => colors.background = Some(color),
+            _ => {}
+        }
+    }
+
+    (!colors.is_empty()).then_some(colors)
+}
+
+/// Iterates over the `ESC ] <params> ; <value> ST` replies in a buffer, splitting off the value after the last `;`.
+/// Both ST (`ESC \`) and BEL terminators are accepted.
+fn osc_replies(response: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    response
+        .split(|b| *b == 0x1b)
+        // whatever comes before the first ESC isn't part of a reply
+        .skip(1)
+        .filter_map(|chunk| chunk.strip_prefix(b"]"))
+        .filter_map(|chunk| {
+            let body = std::str::from_utf8(chunk).ok()?;
+            // a BEL terminated reply ends here; an ST terminated one was cut at its ESC already
+            let body = body.split('\x07').next()?;
+            body.rsplit_once(';')
+        })
+}
+
+#[cfg(unix)]
+fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+fn stdout_is_terminal() -> bool {
+    false
+}
+
+/// Writes a query to the controlling terminal and collects the reply, returning it if `is_complete` accepts it.
+/// A primary device attributes request is sent after the query; every terminal answers that one, and in order,
+/// so reading stops once its reply arrives. That way a terminal that ignores the query doesn't make us wait for
+/// the whole timeout, and the reply doesn't end up on stdin once the terminal is back in canonical mode.
+#[cfg(unix)]
+fn query_terminal(
+    request: &[u8],
+    timeout: Duration,
+    is_complete: impl FnOnce(&[u8]) -> bool,
+) -> Option<Vec<u8>> {
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    // talk to the controlling terminal directly, so this works with redirected output too
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    let _raw = RawMode::enable(tty.as_raw_fd())?;
+
+    tty.write_all(request)
+        .and_then(|_| tty.write_all(b"\x1b[c"))
+        .and_then(|_| tty.flush())
+        .ok()?;
+
+    let deadline = Instant::now() + timeout;
+    let mut response = Vec::with_capacity(256);
+    let mut buf = [0u8; 256];
+
+    while Instant::now() < deadline {
+        // reads time out after 100ms thanks to VTIME, so the deadline is checked regularly
+        let n = tty.read(&mut buf).ok()?;
+        response.extend_from_slice(&buf[..n]);
+
+        if is_device_attributes_response(&response) {
+            break;
+        }
+    }
+
+    is_complete(&response).then_some(response)
+}
+
+#[cfg(not(unix))]
+fn query_terminal(
+    _request: &[u8],
+    _timeout: Duration,
+    _is_complete: impl FnOnce(&[u8]) -> bool,
+) -> Option<Vec<u8>> {
+    None
+}
+
+/// Finds a `CSI ? <mode> ; <state> $ y` report in a response buffer.
+fn decrqm_state(response: &[u8], mode: &[u8]) -> Option<u8> {
+    let prefix = [b"\x1b[?".as_slice(), mode, b";"].concat();
+    let start = response.windows(prefix.len()).position(|w| w == prefix)? + prefix.len();
+    let rest = &response[start..];
+    let end = rest.windows(2).position(|w| w == b"$y")?;
+
+    std::str::from_utf8(&rest[..end]).ok()?.parse().ok()
+}
+
+/// Whether a buffer contains a complete `CSI ? ... c` reply.
+fn is_device_attributes_response(response: &[u8]) -> bool {
+    response.windows(3).enumerate().any(|(start, w)| {
+        w == b"\x1b[?"
+            && response[start + 3..]
+                .iter()
+                .find(|b| !(b.is_ascii_digit() || **b == b';'))
+                == Some(&b'c')
+    })
+}
+
+/// Puts a terminal in non-canonical, no-echo mode for as long as it's alive, so replies to queries can be read without the user seeing them.
+#[cfg(unix)]
+struct RawMode {
+    fd: libc::c_int,
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable(fd: libc::c_int) -> Option<RawMode> {
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut original) != 0 {
+                return None;
+            }
+
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 1;
+
+            if libc::tcsetattr(fd, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+
+            Some(RawMode { fd, original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DA1: &[u8] = b"\x1b[?62;22c";
+
+    #[test]
+    fn decrqm_reports_the_mode_state() {
+        assert_eq!(decrqm_state(b"\x1b[?2026;1$y", b"2026"), Some(1));
+        assert_eq!(decrqm_state(b"\x1b[?2026;2$y", b"2026"), Some(2));
+        assert_eq!(decrqm_state(b"\x1b[?2026;3$y", b"2026"), Some(3));
+        assert_eq!(decrqm_state(b"\x1b[?2026;0$y", b"2026"), Some(0));
+        assert_eq!(decrqm_state(b"\x1b[?2026;4$y", b"2026"), Some(4));
+    }
+
+    #[test]
+    fn decrqm_ignores_other_modes_and_noise() {
+        assert_eq!(decrqm_state(b"\x1b[?25;1$y", b"2026"), None);
+        assert_eq!(decrqm_state(DA1, b"2026"), None);
+        assert_eq!(decrqm_state(b"", b"2026"), None);
+        assert_eq!(decrqm_state(b"\x1b[?2026;x$y", b"2026"), None);
+
+        let interleaved = [b"\x1b[?25;2$y".as_slice(), b"\x1b[?2026;2$y", DA1].concat();
+        assert_eq!(decrqm_state(&interleaved, b"2026"), Some(2));
+    }
+
+    #[test]
+    fn truncated_decrqm_replies_have_no_state() {
+        assert_eq!(decrqm_state(b"\x1b[?2026;1", b"2026"), None);
+        assert_eq!(decrqm_state(b"\x1b[?2026;1$", b"2026"), None);
+        assert_eq!(decrqm_state(b"\x1b[?202", b"2026"), None);
+    }
+
+    #[test]
+    fn device_attributes_replies_are_recognised() {
+        assert!(is_device_attributes_response(DA1));
+        assert!(is_device_attributes_response(b"\x1b[?1;2c"));
+        assert!(is_device_attributes_response(b"\x1b[?6c"));
+
+        let after_decrqm = [b"\x1b[?2026;2$y".as_slice(), DA1].concat();
+        assert!(is_device_attributes_response(&after_decrqm));
+    }
+
+    #[test]
+    fn other_replies_are_not_device_attributes() {
+        assert!(!is_device_attributes_response(b""));
+        assert!(!is_device_attributes_response(b"\x1b[?62;22"));
+        assert!(!is_device_attributes_response(b"\x1b[?2026;1$y"));
+        assert!(!is_device_attributes_response(
+            b"\x1b]11;rgb:0000/0000/0000\x1b\\"
+        ));
+    }
+
+    #[test]
+    fn osc_replies_accept_both_terminators() {
+        let response = b"\x1b]4;1;rgb:cd/00/00\x1b\\\x1b]10;rgb:ff/ff/ff\x07\x1b[?62;22c";
+        let replies: Vec<_> = osc_replies(response).collect();
+        assert_eq!(replies, [("4;1", "rgb:cd/00/00"), ("10", "rgb:ff/ff/ff")]);
+    }
+
+    #[test]
+    fn colors_are_read_from_replies() {
+        let response = [
+            b"\x1b]4;0;rgb:0000/0000/0000\x1b\\".as_slice(),
+            b"\x1b]4;1;rgb:c/0/0\x07",
+            b"\x1b]4;15;rgb:fff/8000/00\x1b\\",
+            b"\x1b]10;rgb:d3/d7/cf\x1b\\",
+            b"\x1b]11;rgb:2e2e/3434/3636\x07",
+            DA1,
+        ]
+        .concat();
+
+        let colors = parse_colors(&response).unwrap();
+        assert_eq!(colors.colors[0], Some([0, 0, 0]));
+        assert_eq!(colors.colors[1], Some([204, 0, 0]));
+        assert_eq!(colors.colors[15], Some([255, 128, 0]));
+        assert_eq!(colors.foreground, Some([0xd3, 0xd7, 0xcf]));
+        assert_eq!(colors.background, Some([0x2e, 0x34, 0x36]));
+    }
+
+    #[test]
+    fn unanswered_indexes_stay_unknown() {
+        let response = b"\x1b]4;3;rgb:c4/a0/00\x1b\\\x1b[?62;22c";
+        let colors = parse_colors(response).unwrap();
+
+        for (i, color) in colors.colors.iter().enumerate() {
+            assert_eq!(color.is_some(), i == 3, "color {i}");
+        }
+        assert_eq!(colors.foreground, None);
+        assert_eq!(colors.background, None);
+        assert_eq!(colors.to_theme(), "color3 #c4a000\n");
+    }
+
+    #[test]
+    fn garbage_is_skipped() {
+        for response in [
+            b"".as_slice(),
+            DA1,
+            b"\x1b]4;1;rgb:zz/00/00\x1b\\",
+            b"\x1b]4;1;rgb:12345/0/0\x1b\\",
+            b"\x1b]4;1;rgb:ff/ff\x1b\\",
+            b"\x1b]4;16;rgb:ff/ff/ff\x1b\\",
+            b"\x1b]4;x;rgb:ff/ff/ff\x1b\\",
+            b"\x1b]12;rgb:ff/ff/ff\x1b\\",
+            b"\x1b]4;1;\xff\xfe\x1b\\",
+            b"]4;1;rgb:ff/ff/ff",
+        ] {
+            assert_eq!(parse_colors(response), None, "{response:?}");
+        }
+
+        let mixed = b"\x1b]4;1;rgb:zz/00/00\x1b\\\x1b]4;2;rgb:4e/9a/06\x07";
+        assert_eq!(
+            parse_colors(mixed).unwrap().colors[2],
+            Some([0x4e, 0x9a, 0x06])
+        );
+    }
+}